@@ -0,0 +1,91 @@
+//! Benchmarks of rewriting, squashing and extraction.
+//!
+//! All graphs are deterministic chains of [`SimpleGraph`] nodes. Run with
+//!
+//! ```sh
+//! cargo bench
+//! ```
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use portdiff::simple_graph::{SimpleGraph, SimpleNode};
+use portdiff::{Graph, PortDiff, PortDiffGraph};
+
+const SIZES: [usize; 3] = [10, 100, 1000];
+
+/// A chain of `n` sequential rewrites, each copying a node of the previous
+/// diff.
+fn rewrite_chain(n: usize) -> Vec<PortDiff<SimpleGraph>> {
+    let mut diffs = vec![PortDiff::from_graph(SimpleGraph::linear_chain(3))];
+    for _ in 0..n {
+        let last = diffs.last().unwrap();
+        let node = last.graph().nodes_iter().next().unwrap();
+        diffs.push(last.select_subgraph([node]));
+    }
+    diffs
+}
+
+/// `k` children of a chain of `k` nodes, each copying a distinct node.
+fn parallel_children(k: usize) -> Vec<PortDiff<SimpleGraph>> {
+    let root = PortDiff::from_graph(SimpleGraph::linear_chain(k));
+    (0..k)
+        .map(|i| root.select_subgraph([SimpleNode::new(i)]))
+        .collect()
+}
+
+/// `k` children of a chain of `2k` nodes, each deleting every other node,
+/// either by replacing it with identity wires or by copying it.
+fn every_other_node(k: usize, wires: bool) -> Vec<PortDiff<SimpleGraph>> {
+    let root = PortDiff::from_graph(SimpleGraph::linear_chain(2 * k));
+    (0..k)
+        .map(|i| {
+            let node = SimpleNode::new(2 * i + 1);
+            if wires {
+                root.rewrite_node_to_wires(node).unwrap()
+            } else {
+                root.select_subgraph([node])
+            }
+        })
+        .collect()
+}
+
+fn bench_rewrite(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rewrite");
+    for n in SIZES {
+        group.bench_with_input(BenchmarkId::new("sequential", n), &n, |b, &n| {
+            b.iter(|| rewrite_chain(black_box(n)))
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", n), &n, |b, &n| {
+            b.iter(|| parallel_children(black_box(n)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_squash(c: &mut Criterion) {
+    let mut group = c.benchmark_group("squash");
+    for depth in SIZES {
+        let diffs = rewrite_chain(depth);
+        let graph = PortDiffGraph::from_sinks([diffs.last().unwrap().clone()]);
+        group.bench_with_input(BenchmarkId::new("depth", depth), &graph, |b, graph| {
+            b.iter(|| graph.try_squash().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_extract(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extract");
+    for k in SIZES {
+        for (name, wires) in [("copies", false), ("wires", true)] {
+            let diffs = every_other_node(k, wires);
+            group.bench_with_input(BenchmarkId::new(name, k), &diffs, |b, diffs| {
+                b.iter(|| PortDiff::extract_graph(diffs.clone()).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rewrite, bench_squash, bench_extract);
+criterion_main!(benches);
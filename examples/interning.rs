@@ -0,0 +1,96 @@
+//! Memory used by identical replacement graphs, with and without interning.
+//!
+//! Replaces every node of a long chain with the same small graph, once with
+//! a fresh copy of the replacement graph per rewrite and once with graphs
+//! shared through a [`GraphInterner`]. Run with
+//!
+//! ```sh
+//! cargo run --release --example interning
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use portdiff::rc_graph::{GraphInterner, RcGraph};
+use portdiff::simple_graph::{SimpleGraph, SimpleNode, SimplePort};
+use portdiff::{Graph, PortDiff, Site};
+
+/// An allocator keeping track of the number of bytes in use.
+struct CountingAlloc;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+const N_NODES: usize = 2000;
+
+/// The replacement graph of every rewrite: a chain of three nodes.
+fn replacement() -> SimpleGraph {
+    SimpleGraph::linear_chain(3)
+}
+
+/// Replace every node of the chain of `root`, creating the replacement graphs
+/// with `new_graph`.
+fn rewrite_all(
+    root: &PortDiff<RcGraph<SimpleGraph>>,
+    mut new_graph: impl FnMut() -> RcGraph<SimpleGraph>,
+) -> Vec<PortDiff<RcGraph<SimpleGraph>>> {
+    let nodes = root.graph().nodes_iter().collect::<Vec<_>>();
+    nodes
+        .into_iter()
+        .map(|node| {
+            root.replace_node(node, new_graph(), |port| {
+                // Inputs to the first node, outputs from the last
+                let node = match port {
+                    SimplePort::In(_) => SimpleNode::new(0),
+                    SimplePort::Out(_) => SimpleNode::new(2),
+                };
+                Site { node, port }
+            })
+            .unwrap()
+        })
+        .collect()
+}
+
+/// The number of bytes allocated while building the diffs with `new_graph`.
+fn measure(
+    root: &PortDiff<RcGraph<SimpleGraph>>,
+    new_graph: impl FnMut() -> RcGraph<SimpleGraph>,
+) -> usize {
+    let before = IN_USE.load(Ordering::Relaxed);
+    let diffs = rewrite_all(root, new_graph);
+    let after = IN_USE.load(Ordering::Relaxed);
+    assert_eq!(diffs.len(), N_NODES);
+    after - before
+}
+
+fn main() {
+    let root = PortDiff::from_graph(RcGraph::new(SimpleGraph::linear_chain(N_NODES)));
+
+    let fresh = measure(&root, || RcGraph::new(replacement()));
+
+    let mut interner = GraphInterner::new();
+    let interned = measure(&root, || interner.intern(replacement()));
+    assert_eq!(interner.len(), 1);
+
+    println!("{N_NODES} rewrites with identical replacement graphs");
+    println!("  fresh graphs:    {fresh:>10} bytes");
+    println!("  interned graphs: {interned:>10} bytes");
+    println!(
+        "  saved:           {:>9.1}%",
+        100. * (fresh as f64 - interned as f64) / fresh as f64
+    );
+}
@@ -0,0 +1,56 @@
+/* C API of portdiff, see `src/ffi.rs`. Requires the `ffi` feature.
+ *
+ * Functions that fail, including on internal panics, return a null pointer
+ * and record an error message, see `portdiff_last_error`.
+ *
+ * Handles wrap reference-counted pointers that are not thread-safe: a handle,
+ * and any handle obtained from it, must only be used and freed on the thread
+ * that created it. */
+
+#ifndef PORTDIFF_H
+#define PORTDIFF_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct PortDiffHandle PortDiffHandle;
+typedef struct PortDiffGraphHandle PortDiffGraphHandle;
+
+typedef struct PortdiffSite {
+    size_t node;
+    bool outgoing;
+    size_t offset;
+} PortdiffSite;
+
+typedef struct PortdiffSiteMap {
+    PortdiffSite from;
+    PortdiffSite to;
+} PortdiffSiteMap;
+
+const char *portdiff_last_error(void);
+void portdiff_string_free(char *s);
+
+PortDiffHandle *portdiff_from_graph_json(const char *graph_json);
+void portdiff_free(PortDiffHandle *diff);
+PortDiffHandle *portdiff_rewrite(const PortDiffHandle *diff, const size_t *nodes,
+                                 size_t n_nodes, const char *new_graph_json,
+                                 const PortdiffSiteMap *site_map, size_t n_site_map);
+char *portdiff_extract_json(const PortDiffHandle *const *diffs, size_t n_diffs);
+
+PortDiffGraphHandle *portdiff_graph_from_sinks(const PortDiffHandle *const *diffs,
+                                               size_t n_diffs);
+PortDiffGraphHandle *portdiff_graph_from_json(const char *json);
+char *portdiff_graph_to_json(const PortDiffGraphHandle *graph);
+size_t portdiff_graph_n_sinks(const PortDiffGraphHandle *graph);
+PortDiffHandle *portdiff_graph_sink(const PortDiffGraphHandle *graph, size_t index);
+void portdiff_graph_free(PortDiffGraphHandle *graph);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PORTDIFF_H */
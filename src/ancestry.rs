@@ -0,0 +1,205 @@
+//! Ancestry queries over the diff hierarchy.
+//!
+//! Diffs form a DAG, with edges from parents to children. [`Ancestry`]
+//! answers reachability queries on this DAG, memoizing the ancestors and
+//! depth of every diff it has seen, so that repeated queries over the same
+//! hierarchy are cheap.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+use derive_where::derive_where;
+use itertools::Itertools;
+
+use crate::{Graph, NodeId, PortDiff, PortDiffGraph};
+
+/// Memoized ancestry queries over the diff hierarchy.
+///
+/// The strict ancestors of every diff are computed once, from the ancestors
+/// of its parents, and cached. Diffs are immutable, so cached entries never
+/// need to be invalidated.
+#[derive_where(Default; G: Graph)]
+pub struct Ancestry<G: Graph, M = ()> {
+    /// The strict ancestors of every diff seen so far.
+    ancestors: BTreeMap<NodeId<G, M>, Rc<BTreeSet<NodeId<G, M>>>>,
+    /// The length of the longest path from a root to every diff seen so far.
+    depths: BTreeMap<NodeId<G, M>, usize>,
+    /// The diffs seen so far.
+    diffs: BTreeMap<NodeId<G, M>, PortDiff<G, M>>,
+}
+
+impl<G: Graph, M: Clone> Ancestry<G, M> {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `ancestor` is a strict ancestor of `diff`.
+    pub fn is_ancestor(&mut self, ancestor: &PortDiff<G, M>, diff: &PortDiff<G, M>) -> bool {
+        self.ancestor_set(diff).contains(&NodeId::from(ancestor))
+    }
+
+    /// The strict ancestors of `diff`, in topological order.
+    pub fn ancestors(&mut self, diff: &PortDiff<G, M>) -> Vec<PortDiff<G, M>> {
+        self.common_ancestors([diff])
+            .into_iter()
+            .filter(|d| d != diff)
+            .collect()
+    }
+
+    /// The diffs that are ancestors of all `diffs`, or one of `diffs`.
+    ///
+    /// A diff counts as an ancestor of itself, so that the common ancestors
+    /// of a diff and one of its descendants include the diff. Diffs are in
+    /// topological order, i.e. parents before their children, with the order
+    /// of [`PortDiffGraph::all_nodes`] on the ancestors of the first diff.
+    pub fn common_ancestors<'a>(
+        &mut self,
+        diffs: impl IntoIterator<Item = &'a PortDiff<G, M>>,
+    ) -> Vec<PortDiff<G, M>>
+    where
+        G: 'a,
+        M: 'a,
+    {
+        let diffs = diffs.into_iter().collect_vec();
+        let Some(&first) = diffs.first() else {
+            return Vec::new();
+        };
+        let sets = diffs
+            .iter()
+            .map(|&d| (NodeId::from(d), self.ancestor_set(d)))
+            .collect_vec();
+        let is_common = |n: &NodeId<G, M>| sets.iter().all(|(d, set)| d == n || set.contains(n));
+        let graph = PortDiffGraph::from_sinks([first.clone()]);
+        graph
+            .all_nodes()
+            .filter(is_common)
+            .map(|n| self.diffs[&n].clone())
+            .collect()
+    }
+
+    /// The length of the longest path from a root to `diff`.
+    ///
+    /// Roots have depth 0.
+    pub fn depth(&mut self, diff: &PortDiff<G, M>) -> usize {
+        self.ancestor_set(diff);
+        self.depths[&NodeId::from(diff)]
+    }
+
+    /// A path of diffs from `ancestor` to `diff`, following parent-child
+    /// edges.
+    ///
+    /// The path starts with `ancestor` and ends with `diff`. Returns `None`
+    /// if `ancestor` is not an ancestor of `diff` or `diff` itself. If there
+    /// are several paths, the one through the first parents is returned.
+    pub fn path_between(
+        &mut self,
+        ancestor: &PortDiff<G, M>,
+        diff: &PortDiff<G, M>,
+    ) -> Option<Vec<PortDiff<G, M>>> {
+        let target = NodeId::from(ancestor);
+        let mut path = vec![diff.clone()];
+        let mut curr = diff.clone();
+        while curr != *ancestor {
+            let next = curr
+                .all_parents()
+                .find(|p| NodeId::from(p) == target || self.ancestor_set(p).contains(&target))?;
+            path.push(next.clone());
+            curr = next;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// The strict ancestors of `diff`, computed from those of its parents.
+    fn ancestor_set(&mut self, diff: &PortDiff<G, M>) -> Rc<BTreeSet<NodeId<G, M>>> {
+        // Iterative post-order traversal, so that deep hierarchies do not
+        // overflow the stack
+        let mut stack = vec![(diff.clone(), false)];
+        while let Some((d, is_expanded)) = stack.pop() {
+            let id = NodeId::from(&d);
+            if self.ancestors.contains_key(&id) {
+                continue;
+            }
+            let parents = d.all_parents().collect_vec();
+            if !is_expanded {
+                stack.push((d, true));
+                stack.extend(parents.into_iter().map(|p| (p, false)));
+                continue;
+            }
+            let mut set = BTreeSet::new();
+            let mut depth = 0;
+            for p in &parents {
+                let p_id = NodeId::from(p);
+                set.insert(p_id);
+                set.extend(self.ancestors[&p_id].iter().copied());
+                depth = depth.max(self.depths[&p_id] + 1);
+            }
+            self.ancestors.insert(id, Rc::new(set));
+            self.depths.insert(id, depth);
+            self.diffs.insert(id, d);
+        }
+        self.ancestors[&NodeId::from(diff)].clone()
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Whether `self` is a strict ancestor of `other`.
+    ///
+    /// Use [`Ancestry`] to run many queries on the same hierarchy.
+    pub fn is_ancestor_of(&self, other: &Self) -> bool {
+        Ancestry::new().is_ancestor(self, other)
+    }
+
+    /// The length of the longest path from a root to `self`.
+    ///
+    /// Use [`Ancestry`] to run many queries on the same hierarchy.
+    pub fn depth(&self) -> usize {
+        Ancestry::new().depth(self)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_ancestry(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [root, child_1, child_2] = parent_two_children_diffs;
+        let grandchild = child_1.select_subgraph(Graph::nodes_iter(child_1.graph()));
+
+        let mut ancestry = Ancestry::new();
+        assert!(ancestry.is_ancestor(&root, &grandchild));
+        assert!(!ancestry.is_ancestor(&child_2, &grandchild));
+        assert!(!ancestry.is_ancestor(&root, &root));
+        assert_eq!(ancestry.depth(&root), 0);
+        assert_eq!(ancestry.depth(&grandchild), 2);
+        assert_eq!(
+            ancestry.ancestors(&grandchild),
+            vec![root.clone(), child_1.clone()]
+        );
+
+        assert_eq!(
+            ancestry.common_ancestors([&grandchild, &child_2]),
+            vec![root.clone()]
+        );
+        assert_eq!(
+            ancestry.common_ancestors([&grandchild, &child_1]),
+            vec![root.clone(), child_1.clone()]
+        );
+
+        assert_eq!(
+            ancestry.path_between(&root, &grandchild),
+            Some(vec![root.clone(), child_1.clone(), grandchild.clone()])
+        );
+        assert_eq!(ancestry.path_between(&child_2, &grandchild), None);
+
+        assert!(child_1.is_ancestor_of(&grandchild));
+        assert_eq!(child_2.depth(), 1);
+    }
+}
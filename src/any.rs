@@ -0,0 +1,261 @@
+//! Type-erased diff graphs.
+//!
+//! [`AnyPortDiffGraph`] wraps a [`PortDiffGraph`] of any graph type behind a
+//! common interface, so that applications can store hierarchies over
+//! different graph types uniformly. Diffs are referred to by their index in
+//! [`PortDiffGraph::all_nodes`], and graphs are exchanged in their JSON
+//! serialized form.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use petgraph::visit::{EdgeRef, IntoEdges};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::{port_diff::SquashError, Graph, NodeId, PortDiff, PortDiffGraph};
+
+/// Errors when querying or extracting a type-erased diff graph.
+#[derive(Debug, Error)]
+pub enum AnyGraphError {
+    /// There is no diff with the index.
+    #[error("Unknown diff {0}")]
+    UnknownDiff(usize),
+    /// The graph of the diffs could not be extracted.
+    #[error("Cannot extract graph: {0}")]
+    Squash(#[from] SquashError),
+    /// The graph could not be (de)serialized.
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// A diff graph of any graph type.
+///
+/// The graph type is identified by a name chosen by the application, e.g.
+/// to pick the type to deserialize extracted graphs into.
+pub struct AnyPortDiffGraph {
+    graph_type: String,
+    inner: Box<dyn ErasedDiffGraph>,
+}
+
+impl AnyPortDiffGraph {
+    /// Wrap `graph`, with graph type `graph_type`.
+    pub fn new<G, M>(graph_type: impl Into<String>, graph: PortDiffGraph<G, M>) -> Self
+    where
+        G: Graph + Serialize + 'static,
+        M: Clone + 'static,
+        PortDiffGraph<G, M>: Serialize,
+    {
+        let nodes = graph.all_nodes().collect_vec();
+        let index = nodes.iter().enumerate().map(|(i, &n)| (n, i)).collect();
+        let inner = Box::new(Erased {
+            graph,
+            nodes,
+            index,
+        });
+        Self {
+            graph_type: graph_type.into(),
+            inner,
+        }
+    }
+
+    /// Deserialize a graph of type `graph_type` from JSON.
+    pub fn from_json<G, M>(graph_type: impl Into<String>, json: &str) -> Result<Self, AnyGraphError>
+    where
+        G: Graph + Serialize + 'static,
+        M: Clone + 'static,
+        PortDiffGraph<G, M>: Serialize + DeserializeOwned,
+    {
+        let graph: PortDiffGraph<G, M> = serde_json::from_str(json)?;
+        Ok(Self::new(graph_type, graph))
+    }
+
+    /// The name of the graph type.
+    pub fn graph_type(&self) -> &str {
+        &self.graph_type
+    }
+
+    /// The wrapped graph, if it is a `PortDiffGraph<G, M>`.
+    pub fn downcast_ref<G: Graph + 'static, M: 'static>(&self) -> Option<&PortDiffGraph<G, M>> {
+        self.inner.as_any().downcast_ref()
+    }
+
+    /// The number of diffs in the graph.
+    ///
+    /// Diffs are indexed from `0` to `n_diffs() - 1`, parents before their
+    /// children.
+    pub fn n_diffs(&self) -> usize {
+        self.inner.n_diffs()
+    }
+
+    /// The indices of the diffs without children.
+    pub fn sinks(&self) -> Vec<usize> {
+        self.inner.sinks()
+    }
+
+    /// The indices of the parents of `diff`.
+    pub fn parents(&self, diff: usize) -> Result<Vec<usize>, AnyGraphError> {
+        self.inner.parents(diff)
+    }
+
+    /// The indices of the children of `diff`.
+    pub fn children(&self, diff: usize) -> Result<Vec<usize>, AnyGraphError> {
+        self.inner.children(diff)
+    }
+
+    /// All parent-child pairs of the hierarchy.
+    pub fn hierarchy(&self) -> Vec<(usize, usize)> {
+        (0..self.n_diffs())
+            .flat_map(|parent| {
+                let children = self.children(parent).unwrap();
+                children.into_iter().map(move |child| (parent, child))
+            })
+            .collect()
+    }
+
+    /// Whether `diffs` are compatible.
+    pub fn are_compatible(&self, diffs: &[usize]) -> Result<bool, AnyGraphError> {
+        self.inner.are_compatible(diffs)
+    }
+
+    /// Serialize the graph to JSON.
+    pub fn to_json(&self) -> Result<String, AnyGraphError> {
+        self.inner.to_json()
+    }
+
+    /// Extract the graph of `diffs`, serialized to JSON.
+    pub fn extract_json(&self, diffs: &[usize]) -> Result<String, AnyGraphError> {
+        self.inner.extract_json(diffs)
+    }
+}
+
+/// The operations on a diff graph that do not depend on its graph type.
+trait ErasedDiffGraph {
+    fn as_any(&self) -> &dyn Any;
+    fn n_diffs(&self) -> usize;
+    fn sinks(&self) -> Vec<usize>;
+    fn parents(&self, diff: usize) -> Result<Vec<usize>, AnyGraphError>;
+    fn children(&self, diff: usize) -> Result<Vec<usize>, AnyGraphError>;
+    fn are_compatible(&self, diffs: &[usize]) -> Result<bool, AnyGraphError>;
+    fn to_json(&self) -> Result<String, AnyGraphError>;
+    fn extract_json(&self, diffs: &[usize]) -> Result<String, AnyGraphError>;
+}
+
+/// A diff graph along with the indices of its diffs.
+struct Erased<G: Graph, M> {
+    graph: PortDiffGraph<G, M>,
+    nodes: Vec<NodeId<G, M>>,
+    index: BTreeMap<NodeId<G, M>, usize>,
+}
+
+impl<G: Graph, M: Clone> Erased<G, M> {
+    fn node(&self, diff: usize) -> Result<NodeId<G, M>, AnyGraphError> {
+        self.nodes
+            .get(diff)
+            .copied()
+            .ok_or(AnyGraphError::UnknownDiff(diff))
+    }
+
+    fn diffs(&self, diffs: &[usize]) -> Result<Vec<PortDiff<G, M>>, AnyGraphError> {
+        diffs
+            .iter()
+            .map(|&diff| Ok(self.graph.get_diff(self.node(diff)?)))
+            .collect()
+    }
+}
+
+impl<G, M> ErasedDiffGraph for Erased<G, M>
+where
+    G: Graph + Serialize + 'static,
+    M: Clone + 'static,
+    PortDiffGraph<G, M>: Serialize,
+{
+    fn as_any(&self) -> &dyn Any {
+        &self.graph
+    }
+
+    fn n_diffs(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn sinks(&self) -> Vec<usize> {
+        self.graph
+            .sinks()
+            .map(|diff| self.index[&NodeId::from(&diff)])
+            .sorted()
+            .collect()
+    }
+
+    fn parents(&self, diff: usize) -> Result<Vec<usize>, AnyGraphError> {
+        let diff = self.graph.get_diff(self.node(diff)?);
+        let parents = diff
+            .all_parents()
+            .filter_map(|parent| self.index.get(&NodeId::from(&parent)).copied())
+            .sorted()
+            .dedup()
+            .collect();
+        Ok(parents)
+    }
+
+    fn children(&self, diff: usize) -> Result<Vec<usize>, AnyGraphError> {
+        let node = self.node(diff)?;
+        let children = self
+            .graph
+            .inner()
+            .edges(node.into())
+            .map(|e| self.index[&NodeId::from(e.target())])
+            .sorted()
+            .dedup()
+            .collect();
+        Ok(children)
+    }
+
+    fn are_compatible(&self, diffs: &[usize]) -> Result<bool, AnyGraphError> {
+        Ok(PortDiff::are_compatible(&self.diffs(diffs)?))
+    }
+
+    fn to_json(&self) -> Result<String, AnyGraphError> {
+        Ok(serde_json::to_string(&self.graph)?)
+    }
+
+    fn extract_json(&self, diffs: &[usize]) -> Result<String, AnyGraphError> {
+        let graph = PortDiff::extract_graph(self.diffs(diffs)?)?;
+        Ok(serde_json::to_string(&graph)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_graph::{SimpleGraph, SimpleNode};
+
+    use super::*;
+
+    #[test]
+    fn test_any_port_diff_graph() {
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let [first, last] = [0, 2].map(|i| root.select_subgraph([SimpleNode::new(i)]));
+        let graph = PortDiffGraph::from_sinks([first.clone(), last.clone()]);
+        let any = AnyPortDiffGraph::new("simple", graph);
+
+        assert_eq!(any.graph_type(), "simple");
+        assert_eq!(any.n_diffs(), 3);
+        assert_eq!(any.sinks(), vec![1, 2]);
+        assert_eq!(any.parents(1).unwrap(), vec![0]);
+        assert_eq!(any.children(0).unwrap(), vec![1, 2]);
+        assert_eq!(any.hierarchy(), vec![(0, 1), (0, 2)]);
+        assert!(any.are_compatible(&[1, 2]).unwrap());
+        assert!(matches!(any.parents(3), Err(AnyGraphError::UnknownDiff(3))));
+
+        let extracted: SimpleGraph =
+            serde_json::from_str(&any.extract_json(&[1, 2]).unwrap()).unwrap();
+        let expected = PortDiff::extract_graph(vec![first, last]).unwrap();
+        assert_eq!(extracted, expected);
+
+        let json = any.to_json().unwrap();
+        let any = AnyPortDiffGraph::from_json::<SimpleGraph, ()>("simple", &json).unwrap();
+        assert_eq!(any.n_diffs(), 3);
+        assert!(any.downcast_ref::<SimpleGraph, ()>().is_some());
+        assert!(any.downcast_ref::<SimpleGraph, usize>().is_none());
+    }
+}
@@ -0,0 +1,170 @@
+//! Inspect serialized diff hierarchies.
+//!
+//! Loads `PortDiffGraph<PortGraph>` JSON files, such as the ones in
+//! `test_files/`, and prints their hierarchy, validates them, extracts graphs
+//! to DOT or compares two files. Requires the `cli` feature.
+
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+
+use portdiff::{port_diff::TraversalDirection, Graph, NodeId, PortDiff, PortDiffGraph};
+use portgraph::{render::DotFormat, PortGraph};
+
+const USAGE: &str = "\
+Usage: portdiff-cli <command> <args>
+
+Commands:
+  show <file>                Print the diff hierarchy
+  validate <file>            Check the diff hierarchy for consistency
+  extract <file> [<diff>..]  Print the graph extracted from the given diffs
+                             (all sinks by default) in the DOT format
+  diff <file> <file>         Compare two diff hierarchies
+
+Diffs are numbered in topological order, as printed by `show`.";
+
+type DiffGraph = PortDiffGraph<PortGraph>;
+
+/// The diffs of a hierarchy, in topological order.
+struct Hierarchy {
+    graph: DiffGraph,
+    diffs: Vec<PortDiff<PortGraph>>,
+    indices: BTreeMap<NodeId<PortGraph>, usize>,
+}
+
+impl Hierarchy {
+    fn load(path: &str) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+        let graph: DiffGraph =
+            serde_json::from_str(&json).map_err(|e| format!("cannot load {path}: {e}"))?;
+        let roots = graph.roots().map(|d| NodeId::from(&d)).collect::<Vec<_>>();
+        let diffs = graph
+            .traverse(roots, TraversalDirection::Descendants)
+            .collect::<Vec<_>>();
+        let indices = diffs
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (NodeId::from(d), i))
+            .collect();
+        Ok(Self {
+            graph,
+            diffs,
+            indices,
+        })
+    }
+
+    fn index(&self, diff: &PortDiff<PortGraph>) -> usize {
+        self.indices[&NodeId::from(diff)]
+    }
+
+    fn get(&self, index: &str) -> Result<PortDiff<PortGraph>, String> {
+        index
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| self.diffs.get(i).cloned())
+            .ok_or_else(|| format!("no diff with index {index}"))
+    }
+}
+
+fn show(path: &str) -> Result<bool, String> {
+    let hierarchy = Hierarchy::load(path)?;
+    for (i, diff) in hierarchy.diffs.iter().enumerate() {
+        let parents = diff
+            .all_parents()
+            .map(|p| hierarchy.index(&p).to_string())
+            .collect::<Vec<_>>();
+        print!(
+            "[{i}] nodes: {}, edges: {}, boundary: {}, parents: [{}]",
+            Graph::nodes_iter(diff.graph()).count(),
+            Graph::edges_iter(diff.graph()).count(),
+            diff.n_boundary_ports(),
+            parents.join(", ")
+        );
+        if let Some(key) = diff.key() {
+            print!(", key: {key}");
+        }
+        if !diff.has_any_descendants() {
+            print!(" (sink)");
+        }
+        println!();
+    }
+    Ok(true)
+}
+
+fn validate(path: &str) -> Result<bool, String> {
+    // Loading already checks the diffs, see `PortDiffGraph::validate`
+    let hierarchy = Hierarchy::load(path)?;
+    if let Err(errors) = hierarchy.graph.validate() {
+        for error in errors {
+            println!("{error}");
+        }
+        return Ok(false);
+    }
+    println!("{path}: {} valid diffs", hierarchy.diffs.len());
+    Ok(true)
+}
+
+fn extract(path: &str, selection: &[String]) -> Result<bool, String> {
+    let hierarchy = Hierarchy::load(path)?;
+    let diffs = if selection.is_empty() {
+        hierarchy.graph.sinks().collect()
+    } else {
+        selection
+            .iter()
+            .map(|i| hierarchy.get(i))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let graph = PortDiff::extract_graph(diffs).map_err(|e| format!("cannot extract: {e}"))?;
+    println!("{}", graph.dot_string());
+    Ok(true)
+}
+
+fn diff(path_a: &str, path_b: &str) -> Result<bool, String> {
+    let a = Hierarchy::load(path_a)?;
+    let b = Hierarchy::load(path_b)?;
+    let mut identical = a.diffs.len() == b.diffs.len();
+    if !identical {
+        println!("number of diffs: {} != {}", a.diffs.len(), b.diffs.len());
+    }
+    for (i, (diff_a, diff_b)) in a.diffs.iter().zip(&b.diffs).enumerate() {
+        if diff_a.structurally_eq(diff_b) {
+            continue;
+        }
+        identical = false;
+        let report = diff_a.diff_against(diff_b);
+        println!(
+            "[{i}] nodes: +{} -{}, edges: +{} -{}, boundary mismatches: {}, subgraph mismatches: {}",
+            report.added_nodes.len(),
+            report.removed_nodes.len(),
+            report.added_edges.len(),
+            report.removed_edges.len(),
+            report.boundary_mismatches.len(),
+            report.subgraph_mismatches.len()
+        );
+    }
+    if identical {
+        println!("identical");
+    }
+    Ok(identical)
+}
+
+fn run(args: &[String]) -> Result<bool, String> {
+    match args {
+        [cmd, file] if cmd == "show" => show(file),
+        [cmd, file] if cmd == "validate" => validate(file),
+        [cmd, file, selection @ ..] if cmd == "extract" => extract(file, selection),
+        [cmd, file_a, file_b] if cmd == "diff" => diff(file_a, file_b),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(2)
+        }
+    }
+}
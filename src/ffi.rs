@@ -0,0 +1,511 @@
+//! A C API for the core diff operations on [`PortGraph`]s.
+//!
+//! Diffs and diff graphs are passed across the boundary as opaque handles,
+//! created and freed by the functions of this module. Graphs are exchanged
+//! as JSON strings, in the serde formats of [`PortGraph`] and
+//! [`PortDiffGraph`]. Functions that fail return a null pointer and record
+//! an error message, see [`portdiff_last_error`]. Panics are caught and
+//! reported in the same way.
+//!
+//! Handles wrap reference-counted pointers that are not thread-safe: a
+//! handle, and any handle obtained from it, must only be used and freed on
+//! the thread that created it.
+//!
+//! Requires the `ffi` feature. The C declarations are in
+//! `include/portdiff.h`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use portgraph::{NodeIndex, PortGraph, PortOffset};
+
+use crate::{port_diff::Owned, BoundarySite, EdgeEnd, PortDiff, PortDiffGraph, Site};
+
+/// An opaque handle to a [`PortDiff`].
+pub struct PortDiffHandle(PortDiff<PortGraph>);
+
+/// An opaque handle to a [`PortDiffGraph`].
+pub struct PortDiffGraphHandle(PortDiffGraph<PortGraph>);
+
+/// A site of a [`PortGraph`]: a port of a node.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortdiffSite {
+    /// The index of the node.
+    pub node: usize,
+    /// Whether the port is an output port.
+    pub outgoing: bool,
+    /// The offset of the port among the inputs or outputs of the node.
+    pub offset: usize,
+}
+
+/// The site of a rewritten node mapped to a site of the replacement graph.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PortdiffSiteMap {
+    /// The site in the graph of the rewritten diff.
+    pub from: PortdiffSite,
+    /// The site in the replacement graph.
+    pub to: PortdiffSite,
+}
+
+impl From<PortdiffSite> for Site<NodeIndex, PortOffset> {
+    fn from(site: PortdiffSite) -> Self {
+        let port = if site.outgoing {
+            PortOffset::new_outgoing(site.offset)
+        } else {
+            PortOffset::new_incoming(site.offset)
+        };
+        Site {
+            node: NodeIndex::new(site.node),
+            port,
+        }
+    }
+}
+
+impl From<Site<NodeIndex, PortOffset>> for PortdiffSite {
+    fn from(site: Site<NodeIndex, PortOffset>) -> Self {
+        Self {
+            node: site.node.index(),
+            outgoing: site.port.direction() == portgraph::Direction::Outgoing,
+            offset: site.port.index(),
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(msg: impl ToString) {
+    let msg = CString::new(msg.to_string().replace('\0', " ")).expect("no nul bytes");
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, turning panics into errors.
+///
+/// Unwinding out of an `extern "C"` function aborts the process, so the
+/// body of every entry point is run through this function.
+fn catch<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(format!("panic: {msg}"))
+    })
+}
+
+/// Record the error of `result`, if any, and return the value or `default`.
+fn or_default<T>(result: Result<T, String>, default: T) -> T {
+    result.unwrap_or_else(|msg| {
+        set_error(msg);
+        default
+    })
+}
+
+/// Record the error of `result`, if any, and return a pointer to the value.
+fn into_ptr<T>(result: Result<T, String>) -> *mut T {
+    match result {
+        Ok(value) => Box::into_raw(Box::new(value)),
+        Err(msg) => {
+            set_error(msg);
+            ptr::null_mut()
+        }
+    }
+}
+
+fn into_c_string(result: Result<String, String>) -> *mut c_char {
+    match result.and_then(|s| CString::new(s).map_err(|e| e.to_string())) {
+        Ok(s) => s.into_raw(),
+        Err(msg) => {
+            set_error(msg);
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe fn read_str<'a>(s: *const c_char) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err("null string".to_string());
+    }
+    CStr::from_ptr(s).to_str().map_err(|e| e.to_string())
+}
+
+unsafe fn read_slice<'a, T>(ptr: *const T, len: usize) -> &'a [T] {
+    if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, len)
+    }
+}
+
+unsafe fn read_diffs(
+    diffs: *const *const PortDiffHandle,
+    n_diffs: usize,
+) -> Vec<PortDiff<PortGraph>> {
+    read_slice(diffs, n_diffs)
+        .iter()
+        .map(|&d| (*d).0.clone())
+        .collect()
+}
+
+/// The message of the last error on the current thread, or null.
+///
+/// The string is owned by the library and valid until the next error on the
+/// same thread.
+#[no_mangle]
+pub extern "C" fn portdiff_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+/// Free a string returned by the library.
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by the library that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_string_free(s: *mut c_char) {
+    or_default(
+        catch(|| {
+            if !s.is_null() {
+                drop(CString::from_raw(s));
+            }
+            Ok(())
+        }),
+        (),
+    )
+}
+
+/// Create a root diff from a JSON serialized graph.
+///
+/// # Safety
+///
+/// `graph_json` must be a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_from_graph_json(
+    graph_json: *const c_char,
+) -> *mut PortDiffHandle {
+    into_ptr(catch(|| {
+        let json = read_str(graph_json)?;
+        let graph: PortGraph = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Ok(PortDiffHandle(PortDiff::from_graph(graph)))
+    }))
+}
+
+/// Free a diff handle.
+///
+/// The diff itself is kept alive as long as it is referenced by other diffs
+/// or diff graphs.
+///
+/// # Safety
+///
+/// `diff` must be null or a handle returned by the library that was not
+/// freed.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_free(diff: *mut PortDiffHandle) {
+    or_default(
+        catch(|| {
+            if !diff.is_null() {
+                drop(Box::from_raw(diff));
+            }
+            Ok(())
+        }),
+        (),
+    )
+}
+
+/// Rewrite the subgraph of `diff` induced by `nodes` into the JSON serialized
+/// graph `new_graph_json`.
+///
+/// Every boundary port of the rewrite is mapped to the site of the
+/// replacement graph given by the entry of `site_map` for its site in
+/// `diff`. Fails if a boundary port has no entry or is not at a site.
+///
+/// # Safety
+///
+/// `diff` must be a valid handle, `nodes` and `site_map` must point to
+/// arrays of `n_nodes` and `n_site_map` elements respectively, and
+/// `new_graph_json` must be a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_rewrite(
+    diff: *const PortDiffHandle,
+    nodes: *const usize,
+    n_nodes: usize,
+    new_graph_json: *const c_char,
+    site_map: *const PortdiffSiteMap,
+    n_site_map: usize,
+) -> *mut PortDiffHandle {
+    into_ptr(catch(|| {
+        let diff = &(*diff).0;
+        let nodes: BTreeSet<_> = read_slice(nodes, n_nodes)
+            .iter()
+            .map(|&n| NodeIndex::new(n))
+            .collect();
+        let site_map: BTreeMap<_, _> = read_slice(site_map, n_site_map)
+            .iter()
+            .map(|m| (m.from, m.to))
+            .collect();
+        let json = read_str(new_graph_json)?;
+        let new_graph: PortGraph = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let mut unmapped = None;
+        let new_diff = diff
+            .rewrite_induced(&nodes, new_graph, |port| {
+                let site = Owned::new(port, diff.clone()).site();
+                match site.and_then(|s| site_map.get(&PortdiffSite::from(s))) {
+                    Some(&to) => Site::from(to).into(),
+                    None => {
+                        // The diff is discarded below
+                        unmapped = Some(site);
+                        BoundarySite::wire(usize::MAX, EdgeEnd::Left)
+                    }
+                }
+            })
+            .map_err(|e| e.to_string());
+        if let Some(site) = unmapped {
+            return Err(match site {
+                Some(site) => format!("no site given for boundary site {site:?}"),
+                None => "boundary port is not at a site".to_string(),
+            });
+        }
+        Ok(PortDiffHandle(new_diff?))
+    }))
+}
+
+/// Extract the graph of the compatible diffs `diffs`, serialized as JSON.
+///
+/// The string must be freed with [`portdiff_string_free`].
+///
+/// # Safety
+///
+/// `diffs` must point to an array of `n_diffs` valid handles.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_extract_json(
+    diffs: *const *const PortDiffHandle,
+    n_diffs: usize,
+) -> *mut c_char {
+    into_c_string(catch(|| {
+        let diffs = read_diffs(diffs, n_diffs);
+        let graph = PortDiff::extract_graph(diffs).map_err(|e| e.to_string())?;
+        serde_json::to_string(&graph).map_err(|e| e.to_string())
+    }))
+}
+
+/// Create the graph of `diffs` and all their ancestors.
+///
+/// # Safety
+///
+/// `diffs` must point to an array of `n_diffs` valid handles.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_graph_from_sinks(
+    diffs: *const *const PortDiffHandle,
+    n_diffs: usize,
+) -> *mut PortDiffGraphHandle {
+    into_ptr(catch(|| {
+        let diffs = read_diffs(diffs, n_diffs);
+        Ok(PortDiffGraphHandle(PortDiffGraph::from_sinks(diffs)))
+    }))
+}
+
+/// Load a diff graph from JSON.
+///
+/// # Safety
+///
+/// `json` must be a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_graph_from_json(json: *const c_char) -> *mut PortDiffGraphHandle {
+    into_ptr(catch(|| {
+        let json = read_str(json)?;
+        let graph = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Ok(PortDiffGraphHandle(graph))
+    }))
+}
+
+/// Serialize a diff graph to JSON.
+///
+/// The string must be freed with [`portdiff_string_free`].
+///
+/// # Safety
+///
+/// `graph` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_graph_to_json(graph: *const PortDiffGraphHandle) -> *mut c_char {
+    into_c_string(catch(|| {
+        serde_json::to_string(&(*graph).0).map_err(|e| e.to_string())
+    }))
+}
+
+/// The number of sinks of a diff graph.
+///
+/// # Safety
+///
+/// `graph` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_graph_n_sinks(graph: *const PortDiffGraphHandle) -> usize {
+    or_default(catch(|| Ok((*graph).0.sinks().count())), 0)
+}
+
+/// A new handle to the sink of a diff graph at `index`.
+///
+/// # Safety
+///
+/// `graph` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_graph_sink(
+    graph: *const PortDiffGraphHandle,
+    index: usize,
+) -> *mut PortDiffHandle {
+    into_ptr(catch(|| {
+        let sink = (*graph).0.sinks().nth(index);
+        sink.map(PortDiffHandle)
+            .ok_or_else(|| format!("no sink at index {index}"))
+    }))
+}
+
+/// Free a diff graph handle.
+///
+/// # Safety
+///
+/// `graph` must be null or a handle returned by the library that was not
+/// freed.
+#[no_mangle]
+pub unsafe extern "C" fn portdiff_graph_free(graph: *mut PortDiffGraphHandle) {
+    or_default(
+        catch(|| {
+            if !graph.is_null() {
+                drop(Box::from_raw(graph));
+            }
+            Ok(())
+        }),
+        (),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkMut, LinkView, PortMut, PortView};
+
+    use super::*;
+
+    fn to_c(s: String) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    #[test]
+    fn test_ffi_rewrite() {
+        // a -> b -> c
+        let mut graph = PortGraph::new();
+        let a = graph.add_node(0, 1);
+        let b = graph.add_node(1, 1);
+        let c = graph.add_node(1, 0);
+        graph.link_nodes(a, 0, b, 0).unwrap();
+        graph.link_nodes(b, 0, c, 0).unwrap();
+
+        // Replace b with two nodes in sequence
+        let mut rhs = PortGraph::new();
+        let b1 = rhs.add_node(1, 1);
+        let b2 = rhs.add_node(1, 1);
+        rhs.link_nodes(b1, 0, b2, 0).unwrap();
+        let site = |node: NodeIndex, outgoing| PortdiffSite {
+            node: node.index(),
+            outgoing,
+            offset: 0,
+        };
+        let site_map = [
+            PortdiffSiteMap {
+                from: site(b, false),
+                to: site(b1, false),
+            },
+            PortdiffSiteMap {
+                from: site(b, true),
+                to: site(b2, true),
+            },
+        ];
+
+        unsafe {
+            let json = to_c(serde_json::to_string(&graph).unwrap());
+            let root = portdiff_from_graph_json(json.as_ptr());
+            assert!(!root.is_null());
+
+            let rhs_json = to_c(serde_json::to_string(&rhs).unwrap());
+            let nodes = [b.index()];
+            let child = portdiff_rewrite(
+                root,
+                nodes.as_ptr(),
+                nodes.len(),
+                rhs_json.as_ptr(),
+                site_map.as_ptr(),
+                site_map.len(),
+            );
+            assert!(!child.is_null());
+
+            // A missing site fails
+            let failed = portdiff_rewrite(
+                root,
+                nodes.as_ptr(),
+                nodes.len(),
+                rhs_json.as_ptr(),
+                site_map.as_ptr(),
+                1,
+            );
+            assert!(failed.is_null());
+            assert!(!portdiff_last_error().is_null());
+
+            let diffs = [child as *const _];
+            let extracted = portdiff_extract_json(diffs.as_ptr(), diffs.len());
+            let extracted_str = CStr::from_ptr(extracted).to_str().unwrap();
+            let extracted_graph: PortGraph = serde_json::from_str(extracted_str).unwrap();
+            assert_eq!(extracted_graph.node_count(), 4);
+            assert_eq!(extracted_graph.link_count(), 3);
+            portdiff_string_free(extracted);
+
+            // Round-trip the diff graph through JSON
+            let graph = portdiff_graph_from_sinks(diffs.as_ptr(), diffs.len());
+            let graph_json = portdiff_graph_to_json(graph);
+            let loaded = portdiff_graph_from_json(graph_json);
+            assert_eq!(portdiff_graph_n_sinks(loaded), 1);
+            let sink = portdiff_graph_sink(loaded, 0);
+            assert!(!sink.is_null());
+            assert!(portdiff_graph_sink(loaded, 1).is_null());
+
+            portdiff_string_free(graph_json);
+            portdiff_free(sink);
+            portdiff_graph_free(loaded);
+            portdiff_graph_free(graph);
+            portdiff_free(child);
+            portdiff_free(root);
+        }
+    }
+
+    #[test]
+    fn test_ffi_catch_panics() {
+        let mut graph = PortGraph::new();
+        graph.add_node(0, 0);
+        let rhs_json = to_c(serde_json::to_string(&PortGraph::new()).unwrap());
+
+        unsafe {
+            let json = to_c(serde_json::to_string(&graph).unwrap());
+            let root = portdiff_from_graph_json(json.as_ptr());
+
+            // Out of range node index
+            let nodes = [usize::MAX];
+            let failed = portdiff_rewrite(
+                root,
+                nodes.as_ptr(),
+                nodes.len(),
+                rhs_json.as_ptr(),
+                ptr::null(),
+                0,
+            );
+            assert!(failed.is_null());
+            let error = CStr::from_ptr(portdiff_last_error()).to_str().unwrap();
+            assert!(error.starts_with("panic: "));
+
+            portdiff_free(root);
+        }
+    }
+}
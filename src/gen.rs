@@ -0,0 +1,196 @@
+//! Deterministic pseudo-random generators of graphs and rewrites.
+//!
+//! Generators are seeded by a `u64`, and the same seed always gives the same
+//! output, so that failures found by fuzzing can be reproduced. They are
+//! intended to check the invariants of the diff hierarchy, e.g. that
+//! squashing rewrites gives the same graph as applying them directly.
+//!
+//! Selections and rewrites are generic over the graph type, so that other
+//! [`Graph`] implementations can be fuzzed in the same way as
+//! [`SimpleGraph`]. See also the proptest strategies of the `testing`
+//! feature.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+
+use crate::{
+    port::{BoundPort, EdgeEnd},
+    port_diff::Owned,
+    simple_graph::SimpleGraph,
+    AddNodes, Graph, PortDiff,
+};
+
+/// A minimal pseudo-random number generator (SplitMix64).
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    pub(crate) fn next_below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Shuffle `values` uniformly at random (Fisher-Yates).
+    fn shuffle<T>(&mut self, values: &mut [T]) {
+        for i in (1..values.len()).rev() {
+            values.swap(i, self.next_below(i + 1));
+        }
+    }
+}
+
+/// A random graph with at most `max_nodes` nodes and `max_edges` edges.
+///
+/// The number of nodes and edges are chosen at random, then the graph is
+/// created with [`SimpleGraph::random`].
+pub fn random_graph(max_nodes: usize, max_edges: usize, seed: u64) -> SimpleGraph {
+    let mut rng = SplitMix64(seed);
+    let n_nodes = rng.next_below(max_nodes + 1);
+    let n_edges = rng.next_below(max_edges + 1);
+    SimpleGraph::random(n_nodes, n_edges, rng.next_u64())
+}
+
+/// A random connected set of at most `max_size` nodes of `graph`.
+///
+/// The set is grown from a random node, adding a random neighbour at every
+/// step. It is empty if `graph` has no nodes or `max_size` is 0.
+pub fn random_connected_nodes<G: Graph>(
+    graph: &G,
+    max_size: usize,
+    seed: u64,
+) -> BTreeSet<G::Node> {
+    let nodes = graph.nodes_iter().collect_vec();
+    if nodes.is_empty() || max_size == 0 {
+        return BTreeSet::new();
+    }
+    let mut rng = SplitMix64(seed);
+    let size = 1 + rng.next_below(max_size);
+    let mut selected = BTreeSet::from([nodes[rng.next_below(nodes.len())]]);
+    while selected.len() < size {
+        let frontier = selected
+            .iter()
+            .flat_map(|&n| graph.neighbours(n))
+            .filter(|n| !selected.contains(n))
+            .sorted()
+            .dedup()
+            .collect_vec();
+        if frontier.is_empty() {
+            break;
+        }
+        selected.insert(frontier[rng.next_below(frontier.len())]);
+    }
+    selected
+}
+
+/// Rewrite the subgraph of `diff` induced by `nodes` into a random graph
+/// with the same boundary.
+///
+/// The replacement graph has a copy of every node in `nodes`, with the same
+/// sites, and every boundary port is mapped to the copy of its site. The
+/// edges between `nodes` are relinked at random: their right ends are
+/// shuffled, and every edge is dropped with probability 1/4. The boundary of
+/// the rewrite is thus always consistent with the replacement graph.
+pub fn random_rewrite<G: AddNodes, M: Clone + Default>(
+    diff: &PortDiff<G, M>,
+    nodes: &BTreeSet<G::Node>,
+    seed: u64,
+) -> PortDiff<G, M> {
+    let mut rng = SplitMix64(seed);
+    let graph = diff.graph();
+    let mut new_graph = G::default();
+    let node_map: BTreeMap<_, _> = nodes
+        .iter()
+        .map(|&n| {
+            let sites = graph.get_sites(n).map(|site| site.port);
+            (n, new_graph.add_node_with_sites(sites.collect_vec()))
+        })
+        .collect();
+
+    let (lefts, mut rights): (Vec<_>, Vec<_>) = graph
+        .edges_iter()
+        .filter(|&e| graph.incident_nodes(e).iter().all(|n| nodes.contains(n)))
+        .map(|edge| {
+            let [left, right] = [EdgeEnd::Left, EdgeEnd::Right]
+                .map(|end| graph.get_port_site(BoundPort { edge, end }));
+            (left, right)
+        })
+        .unzip();
+    rng.shuffle(&mut rights);
+    for (left, right) in lefts.into_iter().zip(rights) {
+        if rng.next_below(4) > 0 {
+            new_graph.link_sites(
+                left.map_node(|n| node_map[&n]),
+                right.map_node(|n| node_map[&n]),
+            );
+        }
+    }
+
+    diff.rewrite_induced(nodes, new_graph, |port| {
+        let site = Owned::new(port, diff.clone())
+            .site()
+            .expect("rewritten boundary ports are on sites");
+        site.map_node(|n| node_map[&n]).into()
+    })
+    .expect("induced rewrites of a single diff are valid")
+}
+
+/// A random diff hierarchy rooted at `graph`, made of up to `n_rewrites`
+/// rewrites.
+///
+/// Every rewrite replaces a random connected set of at most `max_nodes`
+/// nodes of the root or of a previous rewrite, see [`random_rewrite`].
+/// Rewrites of diffs without nodes are skipped. Returns all diffs, starting
+/// with the root, in creation order.
+pub fn random_diffs<G: AddNodes>(
+    graph: G,
+    n_rewrites: usize,
+    max_nodes: usize,
+    seed: u64,
+) -> Vec<PortDiff<G>> {
+    let mut rng = SplitMix64(seed);
+    let mut diffs = vec![PortDiff::from_graph(graph)];
+    for _ in 0..n_rewrites {
+        let parent = diffs[rng.next_below(diffs.len())].clone();
+        let nodes = random_connected_nodes(parent.graph(), max_nodes, rng.next_u64());
+        let seed = rng.next_u64();
+        if nodes.is_empty() {
+            continue;
+        }
+        diffs.push(random_rewrite(&parent, &nodes, seed));
+    }
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generators() {
+        for seed in 0..20 {
+            let graph = random_graph(6, 10, seed);
+            assert_eq!(graph, random_graph(6, 10, seed));
+
+            let diffs = random_diffs(graph.clone(), 5, 3, seed);
+            let again = random_diffs(graph.clone(), 5, 3, seed);
+            assert_eq!(diffs.len(), again.len());
+            for (diff, other) in diffs.iter().zip(&again) {
+                assert_eq!(diff.graph(), other.graph());
+                assert_eq!(diff.n_boundary_ports(), other.n_boundary_ports());
+            }
+
+            // Rewrites keep the nodes of the graph
+            for diff in &diffs {
+                let extracted = PortDiff::extract_graph(vec![diff.clone()]).unwrap();
+                assert_eq!(extracted.node_count(), graph.node_count());
+            }
+        }
+    }
+}
@@ -1,4 +1,8 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::hash::Hash;
+
+use derive_where::derive_where;
+use itertools::Itertools;
 
 use crate::{port::BoundPort, Site};
 
@@ -12,6 +16,14 @@ use super::port::EdgeEnd;
 ///
 /// Incident edges can furthermore be distinguished using a port label type,
 /// attached to the edge ends.
+///
+/// Graphs may be multigraphs: a site may be incident to any number of edges,
+/// and several parallel edges may link the same pair of sites. In that case,
+/// [`Graph::link_sites`] must add a new edge on every call and
+/// [`Graph::get_bound_ports`] must return all edges at a site. Rewriting and
+/// squashing identify edges by their [`Graph::Edge`], never by their end
+/// sites, so that the multiplicities of parallel edges are preserved. Use
+/// [`Graph::parallel_edges`] to list the edges between two sites.
 pub trait Graph: Default + Clone {
     type Node: Ord + Copy;
     type Edge: Ord + Copy;
@@ -48,12 +60,91 @@ pub trait Graph: Default + Clone {
         self.get_port_site(bound_port).node
     }
 
+    /// The edges with their left end at `left` and their right end at `right`.
+    ///
+    /// There may be several such parallel edges in a multigraph.
+    fn parallel_edges(
+        &self,
+        left: Site<Self::Node, Self::PortLabel>,
+        right: Site<Self::Node, Self::PortLabel>,
+    ) -> impl Iterator<Item = Self::Edge> + '_ {
+        self.get_bound_ports(left)
+            .filter(|p| p.end == EdgeEnd::Left)
+            .map(|p| p.edge)
+            .filter(move |&edge| {
+                let end = EdgeEnd::Right;
+                self.get_port_site(BoundPort { edge, end }) == right
+            })
+    }
+
+    /// The nodes at the left and right ends of `edge`.
+    fn incident_nodes(&self, edge: Self::Edge) -> [Self::Node; 2] {
+        [EdgeEnd::Left, EdgeEnd::Right].map(|end| self.incident_node(edge, end))
+    }
+
+    /// The number of edge ends at the sites of `node`.
+    ///
+    /// Self-loops are counted twice.
+    fn degree(&self, node: Self::Node) -> usize {
+        self.get_sites(node)
+            .map(|site| self.get_bound_ports(site).count())
+            .sum()
+    }
+
+    /// The nodes linked to `node` by an edge, in increasing order and
+    /// without repetitions.
+    ///
+    /// `node` is its own neighbour if it has a self-loop.
+    fn neighbours(&self, node: Self::Node) -> impl Iterator<Item = Self::Node> + '_ {
+        self.get_sites(node)
+            .flat_map(move |site| self.get_bound_ports(site))
+            .map(move |port| self.get_port_site(port.opposite()).node)
+            .sorted()
+            .dedup()
+    }
+
+    /// Whether an edge links `a` and `b`, in either direction.
+    fn has_edge_between(&self, a: Self::Node, b: Self::Node) -> bool {
+        self.get_sites(a)
+            .flat_map(move |site| self.get_bound_ports(site))
+            .any(|port| self.get_port_site(port.opposite()).node == b)
+    }
+
+    /// Add an edge from the `left` site to the `right` site.
+    ///
+    /// A new edge is added even if the sites are already linked.
     fn link_sites(
         &mut self,
         left: Site<Self::Node, Self::PortLabel>,
         right: Site<Self::Node, Self::PortLabel>,
     );
 
+    /// Whether `site` can be the `end` of an edge added by
+    /// [`Graph::link_sites`].
+    ///
+    /// By default, every site can be either end of an edge.
+    fn is_valid_end(&self, site: Site<Self::Node, Self::PortLabel>, end: EdgeEnd) -> bool {
+        let _ = (site, end);
+        true
+    }
+
+    /// Add an edge from the `left` site to the `right` site, in place of
+    /// `edge` of `graph`.
+    ///
+    /// Used when squashing diffs, to relink edges of ancestor graphs. Graphs
+    /// with edge weights should copy the weight of `edge`. By default, this
+    /// is [`Graph::link_sites`].
+    fn link_sites_like(
+        &mut self,
+        left: Site<Self::Node, Self::PortLabel>,
+        right: Site<Self::Node, Self::PortLabel>,
+        graph: &Self,
+        edge: Self::Edge,
+    ) {
+        let _ = (graph, edge);
+        self.link_sites(left, right)
+    }
+
     /// Add a subgraph of `graph` to `self`.
     ///
     /// Add the subgraph of `graph` that is induced by `nodes`.
@@ -65,3 +156,140 @@ pub trait Graph: Default + Clone {
         nodes: &BTreeSet<Self::Node>,
     ) -> BTreeMap<Self::Node, Self::Node>;
 }
+
+/// A graph whose weights are part of its content.
+///
+/// Graphs are compared by content when interning them, see
+/// [`crate::rc_graph::GraphInterner`], when comparing diffs by content, see
+/// [`crate::PortDiff::content_hash`], and when ordering diffs in the stable
+/// serialization format. Graphs without weights use `()` as keys.
+pub trait ContentKey: Graph {
+    /// The key of a node weight.
+    type NodeKey: Ord;
+    /// The key of an edge weight.
+    type EdgeKey: Ord;
+
+    /// The key of the weight of `node`.
+    fn node_key(&self, node: Self::Node) -> Self::NodeKey;
+
+    /// The key of the weight of `edge`.
+    fn edge_key(&self, edge: Self::Edge) -> Self::EdgeKey;
+}
+
+/// A graph whose nodes carry a weight that can be updated in place.
+///
+/// Required to relabel nodes without changing the graph topology, see
+/// [`crate::PortDiff::relabel_nodes`].
+pub trait NodeWeights: Graph {
+    type NodeWeight;
+
+    /// Replace the weight of `node`.
+    fn set_node_weight(&mut self, node: Self::Node, weight: Self::NodeWeight);
+}
+
+/// A graph to which nodes with given sites can be added.
+///
+/// Required to build replacement graphs generically, see
+/// [`crate::PortDiff::replace_node_with_sites`].
+pub trait AddNodes: Graph {
+    /// Add a node with the sites labelled by `sites`.
+    ///
+    /// The node may have more sites than requested, e.g. if sites are
+    /// indexed contiguously or if every node has the same sites, but it has
+    /// at least one site for every label in `sites`.
+    fn add_node_with_sites(
+        &mut self,
+        sites: impl IntoIterator<Item = Self::PortLabel>,
+    ) -> Self::Node;
+}
+
+/// A graph whose sites have a type, e.g. quantum or classical wires.
+///
+/// Typed rewrites only map boundary ports to sites of the same type as their
+/// parent port, see [`crate::PortDiff::rewrite_typed`] and
+/// [`crate::PortDiffGraph::try_squash_typed`].
+pub trait TypedGraph: Graph {
+    type PortType: Eq;
+
+    /// The type of the ports at `site`.
+    fn port_type(&self, site: Site<Self::Node, Self::PortLabel>) -> Self::PortType;
+}
+
+/// A graph from which nodes can be removed.
+///
+/// Required to apply a rewrite to a graph in place, see
+/// [`crate::PortDiff::apply_to`].
+pub trait RemoveNodes: Graph {
+    /// Remove `node` and all its incident edges.
+    fn remove_node(&mut self, node: Self::Node);
+}
+
+/// A graph that can be rendered in the DOT format.
+///
+/// Used to embed the replacement graphs of diffs when rendering the diff
+/// hierarchy, see [`crate::PortDiffGraph::to_dot_with_graphs`].
+pub trait DotGraph: Graph {
+    /// The label of `node` in the rendering.
+    fn dot_node_label(&self, node: Self::Node) -> String;
+}
+
+/// The content of a graph, as sorted lists of weighted nodes, sites and
+/// weighted links.
+///
+/// Two graphs with equal content are identical, up to the identifiers of
+/// their edges. This is an exact identity and not a canonical form: graphs
+/// that are isomorphic but number their nodes differently have different
+/// content.
+#[derive_where(PartialEq, Eq, PartialOrd, Ord; G: ContentKey)]
+#[derive_where(
+    Hash;
+    G: ContentKey,
+    G::Node: Hash,
+    G::PortLabel: Hash,
+    G::NodeKey: Hash,
+    G::EdgeKey: Hash,
+)]
+pub(crate) struct GraphContent<G: ContentKey> {
+    nodes: Vec<(G::Node, G::NodeKey)>,
+    sites: Vec<Site<G::Node, G::PortLabel>>,
+    links: Vec<(
+        Site<G::Node, G::PortLabel>,
+        Site<G::Node, G::PortLabel>,
+        G::EdgeKey,
+    )>,
+}
+
+impl<G: ContentKey> GraphContent<G> {
+    pub(crate) fn new(graph: &G) -> Self {
+        let nodes = graph
+            .nodes_iter()
+            .sorted()
+            .map(|n| (n, graph.node_key(n)))
+            .collect_vec();
+        let sites = nodes
+            .iter()
+            .flat_map(|&(n, _)| graph.get_sites(n))
+            .sorted()
+            .collect();
+        let links = graph
+            .edges_iter()
+            .map(|edge| {
+                let left = graph.get_port_site(BoundPort {
+                    edge,
+                    end: EdgeEnd::Left,
+                });
+                let right = graph.get_port_site(BoundPort {
+                    edge,
+                    end: EdgeEnd::Right,
+                });
+                (left, right, graph.edge_key(edge))
+            })
+            .sorted()
+            .collect();
+        Self {
+            nodes,
+            sites,
+            links,
+        }
+    }
+}
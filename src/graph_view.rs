@@ -1,88 +1,355 @@
+mod best;
+mod branch;
+mod changeset;
+mod compact;
+mod compatible;
+mod convert;
+mod dot;
+mod filter;
+mod frozen;
+mod layout;
+mod merge;
+mod merged;
+mod observer;
+mod partial;
+mod positions;
+mod range;
+mod remove;
+mod stable;
+mod validate;
+mod value;
+
 use std::borrow::Borrow;
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use derive_more::{From, Into};
 use derive_where::derive_where;
 use itertools::Itertools;
 use petgraph::visit::{EdgeRef, IntoEdges};
-use relrc::{edge::InnerEdgeData, graph_view::RelRcGraphSerializer, RelRcGraph};
-use serde::{Deserialize, Serialize};
+use relrc::{edge::InnerEdgeData, RelRcGraph};
+use serde::{Serialize, Serializer};
 
 use crate::{
-    port_diff::{EdgeData, IncompatiblePortDiff, PortDiffData},
+    port_diff::{
+        CompatibilityPolicy, DiffTraverser, DisjointSubgraphs, EdgeData, IncompatiblePortDiff,
+        PortDiffData, SquashError, TraversalDirection,
+    },
+    trace::{debug_event, debug_span},
     Graph, PortDiff,
 };
 
+pub use branch::BranchError;
+pub use changeset::{BoundaryJoin, Changeset, ChangesetError};
+pub use compact::CompactionStats;
+pub use convert::convert_graph;
+pub use frozen::FrozenDiffGraph;
+pub use merge::{ConflictResolution, MergeStrategy};
+pub use merged::{MergedEdge, MergedNode, MergedView};
+pub use observer::DiffObserver;
+pub use positions::{LayoutStore, Position, SerialLayout};
+
+use branch::Branches;
+use observer::ObserverCell;
+pub use range::SquashRangeError;
+pub use remove::RemoveDiffError;
+pub use stable::{StableFormatError, StablePortDiffGraph, STABLE_FORMAT_VERSION};
+pub use validate::ValidationError;
+
 /// A view into a graph that only shows a subset of the nodes.
-#[derive(From, Into, Serialize, Deserialize)]
-#[derive_where(Clone, Default; G: Graph)]
-#[serde(bound(
-    serialize = "G: Serialize, G::Node: Serialize, G::PortLabel: Serialize, G::Edge: Serialize",
-    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>, G::Edge: Deserialize<'de>"
-))]
-pub struct PortDiffGraph<G: Graph>(RelRcGraph<PortDiffData<G>, EdgeData<G>>);
+///
+/// Deserialization validates the diffs, see [`PortDiffGraph::validate`].
+/// Branches are only kept by the stable format, see [`Self::to_stable`].
+#[derive_where(Default; G: Graph)]
+pub struct PortDiffGraph<G: Graph, M = ()>(
+    RelRcGraph<PortDiffData<G, M>, EdgeData<G>>,
+    ObserverCell<G, M>,
+    Branches<G, M>,
+    OnceCell<Vec<NodeId<G, M>>>,
+);
+
+impl<G: Graph, M> Clone for PortDiffGraph<G, M> {
+    /// Clone the graph, without its observer.
+    fn clone(&self) -> Self {
+        Self(
+            self.0.clone(),
+            Default::default(),
+            self.2.clone(),
+            self.3.clone(),
+        )
+    }
+}
+
+impl<G: Graph, M> From<RelRcGraph<PortDiffData<G, M>, EdgeData<G>>> for PortDiffGraph<G, M> {
+    fn from(graph: RelRcGraph<PortDiffData<G, M>, EdgeData<G>>) -> Self {
+        Self(
+            graph,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        )
+    }
+}
+
+impl<G: Graph, M> From<PortDiffGraph<G, M>> for RelRcGraph<PortDiffData<G, M>, EdgeData<G>> {
+    fn from(graph: PortDiffGraph<G, M>) -> Self {
+        graph.0
+    }
+}
+
+impl<G: Graph, M: Clone> Serialize for PortDiffGraph<G, M>
+where
+    PortDiffData<G, M>: Serialize,
+    EdgeData<G>: Serialize,
+{
+    /// Serialize the graph in the same layout as a [`RelRcGraph`], with the
+    /// diffs in the order of [`Self::all_nodes`], so that the output is the
+    /// same across runs.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let diffs = self.all_nodes().map(|n| self.get_diff(n)).collect_vec();
+        let index: BTreeMap<_, _> = self.all_nodes().enumerate().map(|(i, n)| (n, i)).collect();
+        let all_nodes = diffs
+            .iter()
+            .map(|diff| SerialNode {
+                value: diff,
+                incoming: diff
+                    .all_incoming()
+                    .iter()
+                    .map(|edge| {
+                        let source = PortDiff::from(edge.source().clone());
+                        SerialEdge {
+                            source: index[&NodeId::from(&source)],
+                            value: edge.value(),
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        let sinks = self.sinks().map(|d| index[&NodeId::from(&d)]).collect();
+        let graph = SerialGraph { sinks, all_nodes };
+        serializer.serialize_newtype_struct("PortDiffGraph", &graph)
+    }
+}
+
+/// The serialized layout of a [`RelRcGraph`], see [`PortDiffGraph::serialize`].
+#[derive(Serialize)]
+#[serde(bound(serialize = "PortDiffData<G, M>: Serialize, EdgeData<G>: Serialize"))]
+struct SerialGraph<'a, G: Graph, M> {
+    sinks: Vec<usize>,
+    all_nodes: Vec<SerialNode<'a, G, M>>,
+}
+
+#[derive(Serialize)]
+#[serde(bound(serialize = "PortDiffData<G, M>: Serialize, EdgeData<G>: Serialize"))]
+struct SerialNode<'a, G: Graph, M> {
+    value: &'a PortDiffData<G, M>,
+    incoming: Vec<SerialEdge<'a, G>>,
+}
+
+#[derive(Serialize)]
+#[serde(bound(serialize = "EdgeData<G>: Serialize"))]
+struct SerialEdge<'a, G: Graph> {
+    source: usize,
+    value: &'a EdgeData<G>,
+}
 
 /// A handle to a node in a graph view.
 #[derive(From, Into)]
 #[derive_where(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord; G: Graph)]
-pub struct NodeId<G: Graph>(pub(crate) relrc::NodeId<PortDiffData<G>, EdgeData<G>>);
+pub struct NodeId<G: Graph, M = ()>(pub(crate) relrc::NodeId<PortDiffData<G, M>, EdgeData<G>>);
 
-impl<'a, G: Graph> From<&'a PortDiff<G>> for NodeId<G> {
-    fn from(value: &'a PortDiff<G>) -> Self {
+impl<'a, G: Graph, M> From<&'a PortDiff<G, M>> for NodeId<G, M> {
+    fn from(value: &'a PortDiff<G, M>) -> Self {
         let node_id: relrc::NodeId<_, _> = (&value.data).into();
         node_id.into()
     }
 }
 
-impl<G: Graph> PortDiffGraph<G> {
-    pub fn all_nodes(&self) -> impl Iterator<Item = NodeId<G>> + '_ {
-        self.0.all_nodes().iter().copied().map(|n| n.into())
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// All diffs in the graph, in a deterministic topological order.
+    ///
+    /// Parents come before their children. The order only depends on the
+    /// order in which the diffs were created, and not on memory addresses, so
+    /// that it is the same across runs.
+    ///
+    /// The order is computed in O(N log N) on the first call and cached until
+    /// the graph is modified.
+    pub fn all_nodes(&self) -> impl Iterator<Item = NodeId<G, M>> + '_ {
+        self.3
+            .get_or_init(|| self.topological_order())
+            .iter()
+            .copied()
+    }
+
+    /// The diffs of the graph in breadth-first order from the roots,
+    /// visiting children in the order they were created.
+    ///
+    /// A diff is only visited once all its parents in the graph have been.
+    /// If there are several roots, they are ordered by a depth-first search
+    /// from the sinks to their ancestors.
+    fn topological_order(&self) -> Vec<NodeId<G, M>> {
+        let parents = |n: NodeId<G, M>| {
+            self.get_diff(n)
+                .all_parents()
+                .map(|p| NodeId::from(&p))
+                .filter(|&p| self.contains(p))
+                .collect_vec()
+        };
+
+        // Find the roots and count the parents of every diff
+        let mut n_parents = BTreeMap::new();
+        let mut roots = Vec::new();
+        let mut stack = self.sinks().map(|d| NodeId::from(&d)).collect_vec();
+        stack.reverse();
+        while let Some(n) = stack.pop() {
+            if n_parents.contains_key(&n) {
+                continue;
+            }
+            let parents = parents(n);
+            if parents.is_empty() {
+                roots.push(n);
+            }
+            n_parents.insert(n, parents.len());
+            stack.extend(parents.into_iter().rev());
+        }
+        // Every diff is an ancestor of a sink
+        debug_assert_eq!(n_parents.len(), self.0.all_nodes().len());
+
+        let mut order = Vec::with_capacity(n_parents.len());
+        let mut queue = VecDeque::from(roots);
+        while let Some(n) = queue.pop_front() {
+            order.push(n);
+            let children = self.get_diff(n).all_children().map(|c| NodeId::from(&c));
+            for child in children.unique() {
+                let Some(n_pending) = n_parents.get_mut(&child) else {
+                    continue;
+                };
+                *n_pending -= 1;
+                if *n_pending == 0 {
+                    queue.push_back(child);
+                }
+            }
+        }
+        order
+    }
+
+    /// Mutable access to the underlying graph, invalidating the cached order
+    /// of [`Self::all_nodes`].
+    fn inner_mut(&mut self) -> &mut RelRcGraph<PortDiffData<G, M>, EdgeData<G>> {
+        self.3.take();
+        &mut self.0
     }
 
-    pub fn from_sinks(sinks: impl IntoIterator<Item = PortDiff<G>>) -> Self {
-        Self(RelRcGraph::from_sinks(
-            sinks.into_iter().map(|n| n.data).collect(),
-        ))
+    pub fn from_sinks(sinks: impl IntoIterator<Item = PortDiff<G, M>>) -> Self {
+        RelRcGraph::from_sinks(sinks.into_iter().map(|n| n.data).collect()).into()
     }
 
     pub fn from_sinks_while(
-        sinks: impl IntoIterator<Item = PortDiff<G>>,
-        predicate: impl Fn(&PortDiff<G>) -> bool,
+        sinks: impl IntoIterator<Item = PortDiff<G, M>>,
+        predicate: impl Fn(&PortDiff<G, M>) -> bool,
     ) -> Self {
-        Self(RelRcGraph::from_sinks_while(
-            sinks.into_iter().map(|n| n.data).collect(),
-            |n| predicate(&PortDiff { data: n.clone() }),
-        ))
+        RelRcGraph::from_sinks_while(sinks.into_iter().map(|n| n.data).collect(), |n| {
+            predicate(&PortDiff { data: n.clone() })
+        })
+        .into()
     }
 
-    pub fn sinks(&self) -> impl Iterator<Item = PortDiff<G>> + '_ {
+    /// The diffs in the graph that have no children in the graph, in the
+    /// order they were given at construction.
+    pub fn sinks(&self) -> impl Iterator<Item = PortDiff<G, M>> + '_ {
         self.0.sinks().iter().map(|n| n.clone().into())
     }
 
-    pub fn get_diff(&self, id: NodeId<G>) -> PortDiff<G> {
+    pub fn get_diff(&self, id: NodeId<G, M>) -> PortDiff<G, M> {
         self.0.get_node_rc(id.into()).into()
     }
 
+    /// The diff in the graph with the user-supplied `key`, if any.
+    ///
+    /// Keys are set at creation, see e.g. [`PortDiff::rewrite_with_key`]. If
+    /// several diffs share the same key, the first one in the order of
+    /// [`Self::all_nodes`] is returned.
+    pub fn get_by_key(&self, key: &str) -> Option<PortDiff<G, M>> {
+        self.all_nodes()
+            .map(|n| self.get_diff(n))
+            .find(|diff| diff.key() == Some(key))
+    }
+
+    /// The diffs in the graph that have no parents.
+    ///
+    /// There may be several roots, see [`PortDiff::roots`]. They are in the
+    /// order of [`Self::all_nodes`].
+    pub fn roots(&self) -> impl Iterator<Item = PortDiff<G, M>> + '_ {
+        self.all_nodes()
+            .map(|n| self.get_diff(n))
+            .filter(|diff| diff.is_root())
+    }
+
+    /// Whether the diff `id` is in the graph.
+    pub fn contains(&self, id: NodeId<G, M>) -> bool {
+        self.0.all_nodes().contains(&id.0)
+    }
+
+    /// Traverse the diffs reachable from `start` in `direction`.
+    ///
+    /// See [`DiffTraverser`] for more details.
+    pub fn traverse(
+        &self,
+        start: impl IntoIterator<Item = NodeId<G, M>>,
+        direction: TraversalDirection,
+    ) -> DiffTraverser<'_, G, M> {
+        DiffTraverser::new(self, start, direction)
+    }
+
     /// Merge two graphs.
     ///
     /// If `strategy` is `MergeStrategy::IgnoreConflicts`, conflicting edges are
     /// ignored. If `strategy` is `MergeStrategy::FailOnConflicts`, conflicting
-    /// edges cause an error and the merge is not performed.
+    /// edges cause an error and the merge is not performed. The other
+    /// strategies resolve conflicts by dropping the diffs on one side of every
+    /// conflict, see [`MergeStrategy`].
+    ///
+    /// The branches of `other` are added, except those with the same name as
+    /// a branch of `self`.
     pub fn merge(
         &mut self,
         other: Self,
-        strategy: MergeStrategy,
+        strategy: MergeStrategy<G, M>,
+    ) -> Result<(), IncompatiblePortDiff> {
+        self.merge_with_policy(other, strategy, &DisjointSubgraphs)
+    }
+
+    /// Merge two graphs, detecting conflicts with `policy`.
+    ///
+    /// As [`Self::merge`], but edges are conflicting if `policy` considers
+    /// them incompatible.
+    pub fn merge_with_policy(
+        &mut self,
+        other: Self,
+        strategy: MergeStrategy<G, M>,
+        policy: &impl CompatibilityPolicy<G>,
     ) -> Result<(), IncompatiblePortDiff> {
+        debug_span!(
+            "merge",
+            n_diffs = self.all_nodes().count(),
+            n_other_diffs = other.all_nodes().count()
+        );
         let merge_callback =
             |_, self_edges: &[&InnerEdgeData<_, _>], other_edges: &[&InnerEdgeData<_, _>]| {
-                match strategy {
-                    MergeStrategy::IgnoreConflicts => Ok(()),
+                match &strategy {
+                    // Conflicts are resolved after merging
+                    MergeStrategy::IgnoreConflicts
+                    | MergeStrategy::PreferNewer
+                    | MergeStrategy::PreferLowerValue(_)
+                    | MergeStrategy::Resolve(_) => Ok(()),
                     MergeStrategy::FailOnConflicts => {
-                        if !EdgeData::are_compatible(
-                            self_edges
-                                .iter()
-                                .chain(other_edges.iter())
-                                .map(|e| e.value()),
-                        ) {
+                        let edges = self_edges
+                            .iter()
+                            .chain(other_edges.iter())
+                            .map(|e| e.value())
+                            .collect_vec();
+                        if !policy.are_compatible(&edges) {
+                            debug_event!(n_edges = edges.len(), "conflicting edges");
                             Err(IncompatiblePortDiff)
                         } else {
                             Ok(())
@@ -90,16 +357,34 @@ impl<G: Graph> PortDiffGraph<G> {
                     }
                 }
             };
-        self.0
+        let old_nodes = self.all_nodes().collect::<BTreeSet<_>>();
+        self.inner_mut()
             .merge(other.0, merge_callback)
-            .map_err(|_| IncompatiblePortDiff)
+            .map_err(|_| IncompatiblePortDiff)?;
+        for (name, tips) in other.2 {
+            self.2.entry(name).or_insert(tips);
+        }
+        self.resolve_conflicts(&old_nodes, &strategy, policy);
+        let new_diffs = self
+            .all_nodes()
+            .filter(|n| !old_nodes.contains(n))
+            .map(|n| self.get_diff(n))
+            .collect_vec();
+        debug_event!(n_new_diffs = new_diffs.len(), "merged");
+        self.notify(|observer| {
+            for diff in &new_diffs {
+                observer.on_new_diff(diff);
+            }
+            observer.on_merge(&new_diffs);
+        });
+        Ok(())
     }
 
-    pub fn inner(&self) -> &RelRcGraph<PortDiffData<G>, EdgeData<G>> {
+    pub fn inner(&self) -> &RelRcGraph<PortDiffData<G, M>, EdgeData<G>> {
         &self.0
     }
 
-    pub fn lowest_common_ancestors(graphs: &[Self]) -> impl Iterator<Item = NodeId<G>> + '_ {
+    pub fn lowest_common_ancestors(graphs: &[Self]) -> impl Iterator<Item = NodeId<G, M>> + '_ {
         RelRcGraph::lowest_common_ancestors(graphs).map(|n| n.into())
     }
 
@@ -118,40 +403,65 @@ impl<G: Graph> PortDiffGraph<G> {
 
     /// Squash all diffs in the graph view into a single equivalent diff.
     ///
-    /// Errors if `is_squashable` returns false on `self`.
-    pub fn try_squash(&self) -> Result<PortDiff<G>, IncompatiblePortDiff> {
+    /// The value of the squashed diff is `M::default()`.
+    ///
+    /// Errors if `is_squashable` returns false on `self` or if the data of
+    /// the diffs is inconsistent, see [`SquashError`].
+    pub fn try_squash(&self) -> Result<PortDiff<G, M>, SquashError>
+    where
+        M: Default,
+    {
         if !self.is_squashable() {
-            return Err(IncompatiblePortDiff);
+            return Err(SquashError::IncompatibleDiffs);
         }
-        let diff = PortDiff::squash(self);
-        Ok(diff)
+        let squashed = PortDiff::squash(self)?;
+        self.notify(|observer| observer.on_squash(&squashed));
+        Ok(squashed)
+    }
+
+    /// Squash all diffs in the graph view into a single equivalent diff,
+    /// combining their values with `aggregate`.
+    ///
+    /// `aggregate` is passed the values of all diffs in the graph and returns
+    /// the value of the squashed diff, e.g. the sum of the diff costs.
+    ///
+    /// Errors if `is_squashable` returns false on `self` or if the data of
+    /// the diffs is inconsistent, see [`SquashError`].
+    pub fn try_squash_with(
+        &self,
+        aggregate: impl FnOnce(&[M]) -> M,
+    ) -> Result<PortDiff<G, M>, SquashError> {
+        if !self.is_squashable() {
+            return Err(SquashError::IncompatibleDiffs);
+        }
+        let squashed = PortDiff::squash_with(self, aggregate)?;
+        self.notify(|observer| observer.on_squash(&squashed));
+        Ok(squashed)
     }
 
     /// Set the diff values and create a new `PortDiffGraph`.
     ///
     /// The returned graph is identical to `self`, except with the diff values
-    /// set to the values returned by `f`.
-    pub fn map_value(&self, f: impl Fn(&PortDiffData<G>) -> Option<usize>) -> PortDiffGraph<G> {
-        PortDiffGraph(self.0.map(
-            |n| PortDiffData {
-                value: f(n),
-                ..n.clone()
-            },
-            |e| e.clone(),
-        ))
+    /// set to the values returned by `f`. The type of the values may change.
+    pub fn map_value<N>(&self, f: impl Fn(&PortDiffData<G, M>) -> N) -> PortDiffGraph<G, N> {
+        self.0
+            .map(
+                |n| PortDiffData {
+                    graph: n.graph.clone(),
+                    boundary: n.boundary.clone(),
+                    boundary_labels: n.boundary_labels.clone(),
+                    value: f(n),
+                    key: n.key.clone(),
+                    bound_ancestors: Default::default(),
+                },
+                |e| e.clone(),
+            )
+            .into()
     }
 }
 
-impl<G: Graph> Borrow<RelRcGraph<PortDiffData<G>, EdgeData<G>>> for PortDiffGraph<G> {
-    fn borrow(&self) -> &RelRcGraph<PortDiffData<G>, EdgeData<G>> {
+impl<G: Graph, M> Borrow<RelRcGraph<PortDiffData<G, M>, EdgeData<G>>> for PortDiffGraph<G, M> {
+    fn borrow(&self) -> &RelRcGraph<PortDiffData<G, M>, EdgeData<G>> {
         &self.0
     }
 }
-
-/// Strategy for merging two graphs.
-pub enum MergeStrategy {
-    /// Ignore conflicts and merge the graphs.
-    IgnoreConflicts,
-    /// Fail if conflicts are detected.
-    FailOnConflicts,
-}
@@ -0,0 +1,152 @@
+//! Cost-guided extraction of the best compatible set of diffs.
+
+use itertools::Itertools;
+
+use crate::{port_diff::Extractor, Graph, PortDiff};
+
+use super::PortDiffGraph;
+
+/// A lower bound on the cost of the extractions reachable from a selection.
+type LowerBound<'a, G, M, C> = &'a dyn Fn(&[PortDiff<G, M>], &[PortDiff<G, M>]) -> Option<C>;
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Extract the graph of minimum cost over all compatible sets of sinks.
+    ///
+    /// Every non-empty compatible subset of the sinks of `self` is extracted
+    /// and evaluated with `cost`. Returns the graph of minimum cost along with
+    /// the sinks it was extracted from. If several sets have the same cost,
+    /// the first one found is returned.
+    ///
+    /// If `self` is empty, an empty graph and no diffs are returned.
+    pub fn extract_best<C: Ord>(&self, cost: impl Fn(&G) -> C) -> (G, Vec<PortDiff<G, M>>) {
+        self.extract_best_bounded(cost, |_, _| None)
+    }
+
+    /// Extract the graph of minimum cost over all compatible sets of sinks,
+    /// pruning the search with a lower bound.
+    ///
+    /// As [`Self::extract_best`], but branches of the search are pruned using
+    /// `lower_bound`. Given the current selection of sinks and the sinks that
+    /// may still be added to it, `lower_bound` returns a lower bound on the
+    /// cost of all extractions that contain the selection, or `None` if no
+    /// bound is known. Diff values are typically used to compute the bound,
+    /// e.g. if they store the cost delta of each rewrite.
+    ///
+    /// The result is only guaranteed to be optimal if `lower_bound` never
+    /// overestimates the cost.
+    pub fn extract_best_bounded<C: Ord>(
+        &self,
+        cost: impl Fn(&G) -> C,
+        lower_bound: impl Fn(&[PortDiff<G, M>], &[PortDiff<G, M>]) -> Option<C>,
+    ) -> (G, Vec<PortDiff<G, M>>) {
+        let sinks = self.sinks().collect_vec();
+        let mut search = BestSearch {
+            sinks: &sinks,
+            cost: &cost,
+            lower_bound: &lower_bound,
+            extractor: Extractor::new(),
+            selection: Vec::new(),
+            best: None,
+        };
+        search.explore(0);
+        search
+            .best
+            .map(|(_, graph, diffs)| (graph, diffs))
+            .unwrap_or_default()
+    }
+}
+
+/// A branch-and-bound search over the compatible subsets of sinks.
+struct BestSearch<'a, G: Graph, M, C> {
+    /// The sinks to choose from.
+    sinks: &'a [PortDiff<G, M>],
+    /// The cost function to minimise.
+    cost: &'a dyn Fn(&G) -> C,
+    /// The lower bound used for pruning.
+    lower_bound: LowerBound<'a, G, M, C>,
+    /// The extractor for the current selection.
+    extractor: Extractor<G, M>,
+    /// The current selection, in the order of `sinks`.
+    selection: Vec<PortDiff<G, M>>,
+    /// The best extraction found so far.
+    best: Option<(C, G, Vec<PortDiff<G, M>>)>,
+}
+
+impl<G: Graph, M: Clone, C: Ord> BestSearch<'_, G, M, C> {
+    /// Explore all extensions of the current selection with sinks from
+    /// index `start` onwards.
+    fn explore(&mut self, start: usize) {
+        let sinks = self.sinks;
+        for (i, sink) in sinks.iter().enumerate().skip(start) {
+            if self.extractor.select(sink.clone()).is_err() {
+                // Incompatible: no superset of the selection can contain `sink`
+                continue;
+            }
+            self.selection.push(sink.clone());
+            if !self.is_pruned(&sinks[i + 1..]) {
+                self.evaluate();
+                self.explore(i + 1);
+            }
+            self.selection.pop();
+            self.extractor.deselect(sink);
+        }
+    }
+
+    /// Whether no extension of the selection can improve on the best cost.
+    fn is_pruned(&self, remaining: &[PortDiff<G, M>]) -> bool {
+        let Some((best_cost, _, _)) = &self.best else {
+            return false;
+        };
+        (self.lower_bound)(&self.selection, remaining).is_some_and(|bound| &bound >= best_cost)
+    }
+
+    /// Extract the current selection and update the best extraction.
+    ///
+    /// Selections that cannot be squashed are not candidates.
+    fn evaluate(&mut self) {
+        let Ok(graph) = self.extractor.extract_graph() else {
+            return;
+        };
+        let cost = (self.cost)(&graph);
+        let is_better = match &self.best {
+            Some((best_cost, _, _)) => &cost < best_cost,
+            None => true,
+        };
+        if is_better {
+            self.best = Some((cost, graph, self.selection.clone()));
+        }
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use std::cmp::Reverse;
+
+    use portgraph::PortView;
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_extract_best(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+        let sinks = graph.sinks().collect_vec();
+
+        let (best, diffs) = graph.extract_best(|g| g.node_count());
+        assert_eq!(best.node_count(), 2);
+        assert_eq!(diffs, sinks);
+
+        let (best, diffs) = graph.extract_best(|g| Reverse(g.node_count()));
+        assert_eq!(best.node_count(), 3);
+        assert_eq!(diffs, vec![sinks[0].clone()]);
+
+        // A (wrong) bound that prunes everything after the first extraction
+        let (best, diffs) = graph.extract_best_bounded(|g| g.node_count(), |_, _| Some(usize::MAX));
+        assert_eq!(best.node_count(), 3);
+        assert_eq!(diffs, vec![sinks[0].clone()]);
+    }
+}
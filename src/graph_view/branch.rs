@@ -0,0 +1,192 @@
+//! Named branches of diffs.
+//!
+//! A branch is a name for a set of compatible tips in the diff hierarchy,
+//! much like a branch in a version control system. The diffs of a branch
+//! are its tips along with all their ancestors. Branches give embedders a
+//! vocabulary for alternative explorations of rewrites.
+
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::{
+    port_diff::{TraversalDirection, WeakPortDiff},
+    Graph, NodeId, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+/// The tips of every branch, by name.
+///
+/// Tips are held by weak reference rather than by ID: the ID of a tip that
+/// was dropped from the graph may be reused by a new diff, whereas a weak
+/// reference to it no longer upgrades.
+pub(super) type Branches<G, M> = BTreeMap<String, Vec<WeakPortDiff<G, M>>>;
+
+/// Errors when creating or updating branches.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum BranchError {
+    /// A branch with the same name already exists.
+    #[error("Branch {0} already exists")]
+    DuplicateBranch(String),
+    /// There is no branch with the name.
+    #[error("Unknown branch {0}")]
+    UnknownBranch(String),
+    /// A tip of the branch is not in the graph.
+    #[error("The tips of a branch must be in the graph")]
+    UnknownDiff,
+    /// The tips of the branch are not compatible.
+    #[error("The tips of a branch must be compatible")]
+    IncompatibleTips,
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Create a branch `name` with a single tip `base`.
+    ///
+    /// Fails if a branch with the same name exists or if `base` is not in the
+    /// graph.
+    pub fn create_branch(
+        &mut self,
+        name: impl Into<String>,
+        base: &PortDiff<G, M>,
+    ) -> Result<(), BranchError> {
+        let name = name.into();
+        if self.2.contains_key(&name) {
+            return Err(BranchError::DuplicateBranch(name));
+        }
+        if !self.contains(NodeId::from(base)) {
+            return Err(BranchError::UnknownDiff);
+        }
+        self.2.insert(name, vec![base.downgrade()]);
+        Ok(())
+    }
+
+    /// Move the branch `name` to `tips`.
+    ///
+    /// Fails if there is no such branch, or if the tips are not compatible
+    /// diffs of the graph, in which case the branch is unchanged.
+    pub fn set_branch_tips(
+        &mut self,
+        name: &str,
+        tips: impl IntoIterator<Item = PortDiff<G, M>>,
+    ) -> Result<(), BranchError> {
+        if !self.2.contains_key(name) {
+            return Err(BranchError::UnknownBranch(name.to_string()));
+        }
+        let tips = tips.into_iter().unique().collect_vec();
+        if !tips.iter().all(|tip| self.contains(tip.into())) {
+            return Err(BranchError::UnknownDiff);
+        }
+        if !PortDiff::are_compatible(&tips) {
+            return Err(BranchError::IncompatibleTips);
+        }
+        self.2.insert(
+            name.to_string(),
+            tips.iter().map(PortDiff::downgrade).collect(),
+        );
+        Ok(())
+    }
+
+    /// Remove the branch `name`, keeping its diffs.
+    ///
+    /// Returns whether the branch existed.
+    pub fn remove_branch(&mut self, name: &str) -> bool {
+        self.2.remove(name).is_some()
+    }
+
+    /// The tips of the branch `name`, if it exists.
+    ///
+    /// Tips that have since been removed from the graph are skipped.
+    pub fn branch(&self, name: &str) -> Option<Vec<PortDiff<G, M>>> {
+        let tips = self.2.get(name)?;
+        let tips = tips
+            .iter()
+            .filter_map(WeakPortDiff::upgrade)
+            .filter(|tip| self.contains(tip.into()))
+            .collect();
+        Some(tips)
+    }
+
+    /// The names of all branches along with their tips, in increasing order
+    /// of name.
+    ///
+    /// See [`Self::branch`].
+    pub fn branch_tips(&self) -> impl Iterator<Item = (&str, Vec<PortDiff<G, M>>)> + '_ {
+        self.2
+            .keys()
+            .map(|name| (name.as_str(), self.branch(name).unwrap()))
+    }
+
+    /// All diffs of the branch `name`, i.e. its tips and their ancestors, if
+    /// the branch exists.
+    ///
+    /// Tips come first, ancestors after their descendants.
+    pub fn branch_diffs(&self, name: &str) -> Option<Vec<PortDiff<G, M>>> {
+        let tips = self.branch(name)?;
+        let diffs = self
+            .traverse(tips.iter().map(NodeId::from), TraversalDirection::Ancestors)
+            .collect();
+        Some(diffs)
+    }
+
+    /// The names of the branches that `diff` is part of.
+    pub fn branches_of(&self, diff: &PortDiff<G, M>) -> Vec<&str> {
+        self.2
+            .keys()
+            .filter(|name| self.branch_diffs(name).unwrap().contains(diff))
+            .map(|name| name.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_graph::{SimpleGraph, SimpleNode};
+
+    use super::*;
+
+    #[test]
+    fn test_branches() {
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let [first, last] = [0, 2].map(|i| root.select_subgraph([SimpleNode::new(i)]));
+        let mut graph = PortDiffGraph::from_sinks([first.clone(), last.clone()]);
+
+        graph.create_branch("main", &root).unwrap();
+        graph.create_branch("first", &first).unwrap();
+        assert_eq!(
+            graph.create_branch("main", &first),
+            Err(BranchError::DuplicateBranch("main".to_string()))
+        );
+        let other_root = PortDiff::from_graph(SimpleGraph::linear_chain(2));
+        assert_eq!(
+            graph.create_branch("other", &other_root),
+            Err(BranchError::UnknownDiff)
+        );
+
+        graph
+            .set_branch_tips("main", [first.clone(), last.clone()])
+            .unwrap();
+        assert_eq!(
+            graph.set_branch_tips("last", [last.clone()]),
+            Err(BranchError::UnknownBranch("last".to_string()))
+        );
+        let tips = graph.branch_tips().collect_vec();
+        assert_eq!(
+            tips,
+            vec![
+                ("first", vec![first.clone()]),
+                ("main", vec![first.clone(), last.clone()])
+            ]
+        );
+
+        assert_eq!(
+            graph.branch_diffs("first"),
+            Some(vec![first.clone(), root.clone()])
+        );
+        assert_eq!(graph.branches_of(&root), vec!["first", "main"]);
+        assert_eq!(graph.branches_of(&last), vec!["main"]);
+        assert!(graph.remove_branch("first"));
+        assert_eq!(graph.branch("first"), None);
+    }
+}
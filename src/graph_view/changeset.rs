@@ -0,0 +1,299 @@
+//! Conversion between diff graphs and lists of changesets.
+//!
+//! A changeset lists the nodes and edges that a diff removes from its
+//! parents, the graph it adds and how the boundary of the added graph is
+//! joined to the parents. Changesets are listed in topological order and
+//! refer to their parents by position, so that a diff hierarchy can be
+//! exported as a linear patch log and imported from external tools.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bimap::BiBTreeMap;
+use derive_where::derive_where;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    port::BoundaryIndex,
+    port_diff::{EdgeData, IncomingEdgeIndex, PortDiffData, TraversalDirection},
+    subgraph::Subgraph,
+    BoundarySite, EdgeEnd, Graph, NodeId, Port, PortDiff,
+};
+
+use super::{validate::validate_diff, PortDiffGraph};
+
+/// The changes of a diff with respect to its parents.
+///
+/// Diffs are referred to by position: 0 is the root and `i + 1` is the diff
+/// of the `i`-th changeset. Every incoming edge of the diff is given by the
+/// position of its parent in [`Self::parents`], and the removed nodes, the
+/// removed edges and the boundary joins refer to the incoming edges by index.
+#[derive(Serialize, Deserialize)]
+#[derive_where(Clone; G: Graph, M: Clone)]
+#[derive_where(Debug; G: Graph + std::fmt::Debug, G::Node: std::fmt::Debug, G::Edge: std::fmt::Debug, G::PortLabel: std::fmt::Debug, M: std::fmt::Debug)]
+#[serde(bound(
+    serialize = "G: Serialize, G::Node: Serialize, G::PortLabel: Serialize, G::Edge: Serialize, M: Serialize",
+    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>, G::Edge: Deserialize<'de>, M: Deserialize<'de>"
+))]
+pub struct Changeset<G: Graph, M = ()> {
+    /// The position of the parent of every incoming edge.
+    pub parents: Vec<usize>,
+    /// The nodes removed from the parents, along with their incoming edge.
+    pub removed_nodes: Vec<(usize, G::Node)>,
+    /// The edges removed from the parents, along with their incoming edge.
+    pub removed_edges: Vec<(usize, G::Edge)>,
+    /// The graph added in place of the removed nodes and edges.
+    pub added_graph: G,
+    /// The boundary ports of the added graph, in order.
+    pub boundary_joins: Vec<BoundaryJoin<G>>,
+    /// The value of the diff.
+    pub value: M,
+    /// The user-supplied key of the diff, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+/// A boundary port of a [`Changeset`], joining a site of the added graph to
+/// a port of a parent.
+#[derive(Serialize, Deserialize)]
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: std::fmt::Debug, G::Edge: std::fmt::Debug, G::PortLabel: std::fmt::Debug)]
+#[serde(bound(
+    serialize = "G::Node: Serialize, G::PortLabel: Serialize, G::Edge: Serialize",
+    deserialize = "G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>, G::Edge: Deserialize<'de>"
+))]
+pub struct BoundaryJoin<G: Graph> {
+    /// The site of the boundary port in the added graph.
+    pub site: BoundarySite<G>,
+    /// The index of the incoming edge of the parent port.
+    pub edge: usize,
+    /// The port of the parent that the boundary port is joined to.
+    pub port: Port<G>,
+    /// The label of the boundary port, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// Errors when converting between diff graphs and changesets.
+#[derive(Error, Debug)]
+pub enum ChangesetError {
+    #[error("the diff graph must have exactly one root")]
+    NotSingleRoot,
+    #[error("changeset {0} refers to a parent that is not listed before it")]
+    InvalidParent(usize),
+    #[error("changeset {0} removes nodes or edges that are not in its parents")]
+    InvalidSubgraph(usize),
+    #[error("changeset {0} has inconsistent boundary joins")]
+    InvalidBoundary(usize),
+    #[error("the parents of changeset {0} are not compatible")]
+    IncompatibleParents(usize),
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Convert the diffs of the graph to a list of changesets.
+    ///
+    /// The root of the graph is not converted: it must be passed separately
+    /// to [`Self::from_changesets`]. Errors if the graph does not have
+    /// exactly one root.
+    pub fn to_changesets(&self) -> Result<Vec<Changeset<G, M>>, ChangesetError> {
+        let Ok(root) = self.roots().exactly_one() else {
+            return Err(ChangesetError::NotSingleRoot);
+        };
+        let mut positions = BTreeMap::new();
+        let mut changesets = Vec::new();
+        for diff in self.traverse([NodeId::from(&root)], TraversalDirection::Descendants) {
+            positions.insert(NodeId::from(&diff), positions.len());
+            if diff == root {
+                continue;
+            }
+            let mut changeset = Changeset {
+                parents: Vec::new(),
+                removed_nodes: Vec::new(),
+                removed_edges: Vec::new(),
+                added_graph: diff.graph().clone(),
+                boundary_joins: Vec::new(),
+                value: diff.value().clone(),
+                key: diff.key.clone(),
+            };
+            for (index, (parent, edge)) in diff.incoming_edges().enumerate() {
+                changeset.parents.push(positions[&NodeId::from(&parent)]);
+                let subgraph = edge.subgraph();
+                changeset
+                    .removed_nodes
+                    .extend(subgraph.nodes().iter().map(|&n| (index, n)));
+                changeset
+                    .removed_edges
+                    .extend(subgraph.edges().iter().map(|&e| (index, e)));
+            }
+            for boundary in diff.boundary_iter() {
+                let (site, edge) = diff.boundary.get(usize::from(boundary));
+                changeset.boundary_joins.push(BoundaryJoin {
+                    site,
+                    edge: edge.into(),
+                    port: diff.parent_port(boundary).data,
+                    label: diff.boundary_label(boundary).map(str::to_string),
+                });
+            }
+            changesets.push(changeset);
+        }
+        Ok(changesets)
+    }
+
+    /// Reconstruct a diff graph from `root` and a list of changesets.
+    ///
+    /// Inverse of [`Self::to_changesets`]: the changesets are applied in
+    /// order, each creating a new diff. Errors if a changeset is not
+    /// consistent with its parents.
+    pub fn from_changesets(
+        root: PortDiff<G, M>,
+        changesets: impl IntoIterator<Item = Changeset<G, M>>,
+    ) -> Result<Self, ChangesetError> {
+        let mut diffs = vec![root];
+        let mut has_children = vec![false];
+        for (i, changeset) in changesets.into_iter().enumerate() {
+            let diff = build_diff(&diffs, changeset, i)?;
+            for parent in diff.all_parents() {
+                let pos = diffs.iter().position(|d| d == &parent).unwrap();
+                has_children[pos] = true;
+            }
+            diffs.push(diff);
+            has_children.push(false);
+        }
+        let sinks = diffs
+            .into_iter()
+            .zip(has_children)
+            .filter(|(_, has_children)| !has_children)
+            .map(|(d, _)| d);
+        Ok(Self::from_sinks(sinks))
+    }
+}
+
+/// Create the diff of the `index`-th changeset, a child of `diffs`.
+fn build_diff<G: Graph, M: Clone>(
+    diffs: &[PortDiff<G, M>],
+    changeset: Changeset<G, M>,
+    index: usize,
+) -> Result<PortDiff<G, M>, ChangesetError> {
+    let parents = changeset
+        .parents
+        .iter()
+        .map(|&p| diffs.get(p).cloned())
+        .collect::<Option<Vec<_>>>()
+        .ok_or(ChangesetError::InvalidParent(index))?;
+
+    // The subgraphs removed from the parents
+    let mut subgraphs = vec![(BTreeSet::new(), BTreeSet::new()); parents.len()];
+    for (edge, node) in changeset.removed_nodes {
+        let (nodes, _) = subgraphs
+            .get_mut(edge)
+            .ok_or(ChangesetError::InvalidSubgraph(index))?;
+        nodes.insert(node);
+    }
+    for (edge, e) in changeset.removed_edges {
+        let (_, edges) = subgraphs
+            .get_mut(edge)
+            .ok_or(ChangesetError::InvalidSubgraph(index))?;
+        edges.insert(e);
+    }
+    let mut edge_data = Vec::with_capacity(parents.len());
+    for (parent, (nodes, edges)) in parents.iter().zip(subgraphs) {
+        let graph = parent.graph();
+        let all_nodes = graph.nodes_iter().collect::<BTreeSet<_>>();
+        let all_edges = graph.edges_iter().collect::<BTreeSet<_>>();
+        if !nodes.is_subset(&all_nodes) || !edges.is_subset(&all_edges) {
+            return Err(ChangesetError::InvalidSubgraph(index));
+        }
+        let is_induced = edges.iter().all(|&e| {
+            nodes.contains(&graph.incident_node(e, EdgeEnd::Left))
+                && nodes.contains(&graph.incident_node(e, EdgeEnd::Right))
+        });
+        if !is_induced {
+            return Err(ChangesetError::InvalidSubgraph(index));
+        }
+        edge_data.push(EdgeData {
+            subgraph: Subgraph::new(graph, nodes, edges),
+            port_map: BiBTreeMap::new(),
+        });
+    }
+
+    // The boundary ports, joined to the parents
+    let mut boundary = Vec::with_capacity(changeset.boundary_joins.len());
+    let mut boundary_labels = BTreeMap::new();
+    for (i, join) in changeset.boundary_joins.into_iter().enumerate() {
+        let boundary_index = BoundaryIndex::from(i);
+        let port_map = &mut edge_data
+            .get_mut(join.edge)
+            .ok_or(ChangesetError::InvalidBoundary(index))?
+            .port_map;
+        if port_map
+            .insert_no_overwrite(join.port, boundary_index)
+            .is_err()
+        {
+            return Err(ChangesetError::InvalidBoundary(index));
+        }
+        boundary.push((join.site, IncomingEdgeIndex::from(join.edge)));
+        boundary_labels.extend(join.label.map(|l| (boundary_index, l)));
+    }
+
+    let data = PortDiffData {
+        graph: changeset.added_graph,
+        boundary: boundary.into_iter().collect(),
+        boundary_labels,
+        value: changeset.value,
+        key: changeset.key,
+        bound_ancestors: Default::default(),
+    };
+    let diff = PortDiff::try_with_parents(data, parents.into_iter().zip(edge_data).collect())
+        .map_err(|_| ChangesetError::IncompatibleParents(index))?;
+    let mut errors = Vec::new();
+    validate_diff(&diff, &mut errors);
+    if !errors.is_empty() {
+        return Err(ChangesetError::InvalidBoundary(index));
+    }
+    Ok(diff)
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::LinkView;
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_changesets(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        let graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+
+        let changesets = graph.to_changesets().unwrap();
+        assert_eq!(changesets.len(), 2);
+        assert!(changesets.iter().all(|c| c.parents == vec![0]));
+
+        // Round trip through JSON
+        let json = serde_json::to_string(&changesets).unwrap();
+        let changesets: Vec<Changeset<_>> = serde_json::from_str(&json).unwrap();
+        let rebuilt = PortDiffGraph::from_changesets(parent.clone(), changesets).unwrap();
+        assert_eq!(rebuilt.all_nodes().count(), 3);
+        let sinks: Vec<_> = rebuilt.sinks().collect();
+        assert_eq!(sinks.len(), 2);
+        for (old, new) in [child_1, child_2].iter().zip(&sinks) {
+            assert!(old.diff_against(new).is_empty());
+            let old_graph = PortDiff::extract_graph(vec![old.clone()]).unwrap();
+            let new_graph = PortDiff::extract_graph(vec![new.clone()]).unwrap();
+            assert_eq!(old_graph.node_count(), new_graph.node_count());
+            assert_eq!(old_graph.link_count(), new_graph.link_count());
+        }
+
+        // A changeset with a parent that does not exist
+        let mut invalid = graph.to_changesets().unwrap();
+        invalid[0].parents = vec![5];
+        assert!(matches!(
+            PortDiffGraph::from_changesets(parent, invalid),
+            Err(ChangesetError::InvalidParent(0))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+//! Dropping the diffs of a `PortDiffGraph` that are no longer needed.
+
+use std::collections::BTreeSet;
+use std::mem::size_of;
+
+use crate::{
+    port_diff::{IncomingEdgeIndex, PortDiffData},
+    BoundarySite, Graph, NodeId, Port, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+/// Statistics returned when compacting a `PortDiffGraph`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// The number of diffs removed from the graph.
+    pub nodes_dropped: usize,
+    /// A rough estimate of the memory used by the removed diffs, in bytes.
+    ///
+    /// The memory is only freed if no other handle to the removed diffs is
+    /// alive. Heap allocations internal to the graphs of the diffs are not
+    /// accounted for.
+    pub memory_estimate: usize,
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Keep only the diffs that are ancestors of `sinks`.
+    ///
+    /// All other diffs are dropped from the graph and the graph is rebuilt,
+    /// so that the dropped diffs can be freed. Sinks that are not in the
+    /// graph are ignored.
+    pub fn retain_reachable(
+        &mut self,
+        sinks: impl IntoIterator<Item = NodeId<G, M>>,
+    ) -> CompactionStats {
+        let sinks: BTreeSet<_> = sinks.into_iter().filter(|&n| self.contains(n)).collect();
+        let retained = Self::from_sinks(sinks.into_iter().map(|n| self.get_diff(n)));
+
+        let mut stats = CompactionStats::default();
+        for n in self.all_nodes() {
+            if !retained.contains(n) {
+                stats.nodes_dropped += 1;
+                stats.memory_estimate += memory_estimate(&self.get_diff(n));
+            }
+        }
+        *self.inner_mut() = retained.0;
+        stats
+    }
+
+    /// Drop the diffs that are not ancestors of the sinks of the graph.
+    ///
+    /// See [`Self::retain_reachable`].
+    pub fn compact(&mut self) -> CompactionStats {
+        let sinks: Vec<_> = self.sinks().map(|d| NodeId::from(&d)).collect();
+        self.retain_reachable(sinks)
+    }
+}
+
+/// A rough estimate of the memory used by a diff, in bytes.
+fn memory_estimate<G: Graph, M: Clone>(diff: &PortDiff<G, M>) -> usize {
+    let graph = diff.graph();
+    let mut size = size_of::<PortDiffData<G, M>>()
+        + graph.nodes_iter().count() * size_of::<G::Node>()
+        + graph.edges_iter().count() * size_of::<G::Edge>()
+        + diff.n_boundary_ports() * size_of::<(BoundarySite<G>, IncomingEdgeIndex)>();
+    for (_, edge) in diff.incoming_edges() {
+        size += edge.subgraph().nodes().len() * size_of::<G::Node>()
+            + edge.subgraph().edges().len() * size_of::<G::Edge>()
+            + edge.boundary_port_map().len() * 2 * size_of::<Port<G>>();
+    }
+    size
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::graph_view::MergeStrategy;
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_retain_reachable(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        let mut graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+        assert_eq!(graph.all_nodes().count(), 3);
+
+        // All diffs are ancestors of the sinks
+        assert_eq!(graph.compact(), CompactionStats::default());
+
+        let stats = graph.retain_reachable([NodeId::from(&child_1)]);
+        assert_eq!(stats.nodes_dropped, 1);
+        assert!(stats.memory_estimate > 0);
+        assert!(graph.contains(NodeId::from(&parent)));
+        assert!(!graph.contains(NodeId::from(&child_2)));
+
+        let other = PortDiffGraph::from_sinks([child_2]);
+        graph.merge(other, MergeStrategy::FailOnConflicts).unwrap();
+        let stats = graph.retain_reachable([NodeId::from(&parent)]);
+        assert_eq!(stats.nodes_dropped, 2);
+        assert_eq!(graph.all_nodes().count(), 1);
+    }
+}
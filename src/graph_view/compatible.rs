@@ -0,0 +1,191 @@
+//! Enumeration of the maximal sets of compatible diffs.
+
+use std::collections::BTreeSet;
+
+use itertools::Itertools;
+
+use crate::{port_diff::Extractor, Graph, NodeId, PortDiff};
+
+use super::PortDiffGraph;
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Enumerate all maximal subsets of `diffs` that are mutually compatible.
+    ///
+    /// Compatibility of diffs is determined pairwise, so the compatible
+    /// subsets are the cliques of the pairwise compatibility graph. These are
+    /// enumerated lazily using the Bron–Kerbosch algorithm with pivoting,
+    /// which prunes all branches that cannot lead to a maximal clique.
+    ///
+    /// Within each set, diffs are returned in the order of `diffs`. Duplicate
+    /// diffs are only considered once.
+    pub fn maximal_compatible_sets(
+        diffs: &[PortDiff<G, M>],
+    ) -> impl Iterator<Item = Vec<PortDiff<G, M>>> {
+        let diffs = diffs.iter().unique().cloned().collect_vec();
+        let mut neighbours = vec![BTreeSet::new(); diffs.len()];
+        for (i, j) in (0..diffs.len()).tuple_combinations() {
+            if diffs[i].is_compatible(&diffs[j]) {
+                neighbours[i].insert(j);
+                neighbours[j].insert(i);
+            }
+        }
+        MaximalCliques {
+            neighbours,
+            stack: vec![Frame {
+                clique: Vec::new(),
+                candidates: (0..diffs.len()).collect(),
+                excluded: BTreeSet::new(),
+            }],
+        }
+        .map(move |clique| clique.into_iter().map(|i| diffs[i].clone()).collect())
+    }
+
+    /// Lazily extract the graph of every maximal compatible set of sinks.
+    ///
+    /// Yields the selected sinks, in the order of [`Self::sinks`], along with
+    /// the graph extracted from them. Selections are enumerated as in
+    /// [`Self::maximal_compatible_sets`]. A single [`Extractor`] is shared
+    /// between iterations, so that the ancestor graphs of the sinks are only
+    /// computed once. Selections that cannot be merged or squashed as a whole
+    /// are skipped.
+    pub fn enumerate_extractions(&self) -> impl Iterator<Item = (Vec<NodeId<G, M>>, G)> + '_ {
+        let sinks = self.sinks().collect_vec();
+        let mut extractor = Extractor::new();
+        Self::maximal_compatible_sets(&sinks).filter_map(move |selection| {
+            let deselected = extractor
+                .selection()
+                .filter(|&d| !selection.contains(d))
+                .cloned()
+                .collect_vec();
+            for diff in &deselected {
+                extractor.deselect(diff);
+            }
+            for diff in &selection {
+                // Compatibility is not transitive for all policies: skip the
+                // selection if it cannot be extracted as a whole
+                if extractor.select(diff.clone()).is_err() {
+                    for diff in &selection {
+                        extractor.deselect(diff);
+                    }
+                    return None;
+                }
+            }
+            let ids = selection.iter().map(NodeId::from).collect();
+            Some((ids, extractor.extract_graph().ok()?))
+        })
+    }
+}
+
+/// A lazy iterator over the maximal cliques of a graph.
+struct MaximalCliques {
+    /// The adjacency sets of the graph.
+    neighbours: Vec<BTreeSet<usize>>,
+    /// The Bron–Kerbosch calls left to process.
+    stack: Vec<Frame>,
+}
+
+/// A Bron–Kerbosch call.
+struct Frame {
+    /// The clique being grown.
+    clique: Vec<usize>,
+    /// The vertices that may extend `clique`.
+    candidates: BTreeSet<usize>,
+    /// The vertices that extend `clique` but have already been explored.
+    excluded: BTreeSet<usize>,
+}
+
+impl Iterator for MaximalCliques {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Frame {
+            clique,
+            mut candidates,
+            mut excluded,
+        }) = self.stack.pop()
+        {
+            if candidates.is_empty() {
+                if excluded.is_empty() {
+                    let mut clique = clique;
+                    clique.sort_unstable();
+                    return Some(clique);
+                }
+                // Not maximal: it can be extended by an excluded vertex
+                continue;
+            }
+            // Only branch on vertices that are not neighbours of the pivot:
+            // any maximal clique contains the pivot or one of its non-neighbours.
+            let pivot = candidates
+                .union(&excluded)
+                .copied()
+                .max_by_key(|&u| self.neighbours[u].intersection(&candidates).count())
+                .expect("candidates is not empty");
+            let branches = candidates
+                .difference(&self.neighbours[pivot])
+                .copied()
+                .collect_vec();
+            let mut frames = Vec::with_capacity(branches.len());
+            for v in branches {
+                let nbs = &self.neighbours[v];
+                let mut new_clique = clique.clone();
+                new_clique.push(v);
+                frames.push(Frame {
+                    clique: new_clique,
+                    candidates: candidates.intersection(nbs).copied().collect(),
+                    excluded: excluded.intersection(nbs).copied().collect(),
+                });
+                candidates.remove(&v);
+                excluded.insert(v);
+            }
+            // Process the branches in order
+            self.stack.extend(frames.into_iter().rev());
+        }
+        None
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkView, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{
+        parent_two_children_diffs, parent_two_children_overlapping_diffs, TestPortDiff,
+    };
+
+    use super::*;
+
+    #[rstest]
+    fn test_maximal_compatible_sets(
+        parent_two_children_diffs: [TestPortDiff; 3],
+        parent_two_children_overlapping_diffs: [TestPortDiff; 3],
+    ) {
+        let sets = PortDiffGraph::maximal_compatible_sets(&parent_two_children_diffs).collect_vec();
+        assert_eq!(sets, vec![parent_two_children_diffs.to_vec()]);
+
+        let [root, child_1, child_2] = parent_two_children_overlapping_diffs;
+        let diffs = [
+            root.clone(),
+            child_1.clone(),
+            child_2.clone(),
+            child_1.clone(),
+        ];
+        let sets = PortDiffGraph::maximal_compatible_sets(&diffs).collect_vec();
+        assert_eq!(sets, vec![vec![root.clone(), child_1], vec![root, child_2]]);
+    }
+
+    #[rstest]
+    fn test_enumerate_extractions(parent_two_children_overlapping_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_overlapping_diffs;
+        let graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+        let extractions = graph.enumerate_extractions().collect_vec();
+        assert_eq!(extractions.len(), 2);
+        for ((ids, extracted), child) in extractions.into_iter().zip([child_1, child_2]) {
+            assert_eq!(ids, vec![NodeId::from(&child)]);
+            let expected = PortDiff::extract_graph(vec![child]).unwrap();
+            assert_eq!(extracted.node_count(), expected.node_count());
+            assert_eq!(extracted.link_count(), expected.link_count());
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Conversion of diff graphs between graph backends.
+
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use relrc::RelRc;
+
+use crate::{
+    port::{BoundPort, EdgeEnd, Port, Site},
+    port_diff::{EdgeData, PortDiffData},
+    subgraph::Subgraph,
+    AddNodes, BoundarySite, Graph, NodeId, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Convert the graph to the graph backend `H`.
+    ///
+    /// `f` maps every replacement graph to a graph of type `H`, along with a
+    /// map from its nodes to the nodes of the new graph, e.g.
+    /// [`convert_graph`]. The subgraphs, port maps and boundaries of every
+    /// diff are translated accordingly, and port labels are converted with
+    /// `Into`. Edges are identified by their end sites, parallel edges are
+    /// matched in order.
+    ///
+    /// All ancestors of the diffs in `self` must be in `self`.
+    ///
+    /// # Panics
+    ///
+    /// If the graphs returned by `f` do not have the same nodes and edges as
+    /// the replacement graphs.
+    pub fn map_graph<H: Graph>(
+        &self,
+        f: impl Fn(&G) -> (H, BTreeMap<G::Node, H::Node>),
+    ) -> PortDiffGraph<H, M>
+    where
+        G::PortLabel: Into<H::PortLabel>,
+    {
+        let mut translations = BTreeMap::new();
+        let mut new_diffs: BTreeMap<NodeId<G, M>, PortDiff<H, M>> = BTreeMap::new();
+        for n in self.all_nodes() {
+            let diff = self.get_diff(n);
+            let (graph, nodes) = f(diff.graph());
+            let translation = Translation::new(diff.graph(), &graph, nodes);
+            let parents = diff
+                .all_incoming()
+                .iter()
+                .map(|e| {
+                    let parent = NodeId::from(&PortDiff::from(e.source().clone()));
+                    let new_parent = &new_diffs[&parent];
+                    let edge = translations[&parent].edge_data(e.value(), new_parent.graph());
+                    (new_parent.data.clone(), edge)
+                })
+                .collect_vec();
+            let boundary = diff
+                .boundary
+                .iter()
+                .map(|(site, edge)| (translation.boundary_site(&site), edge))
+                .collect();
+            let data = PortDiffData {
+                graph,
+                boundary,
+                boundary_labels: diff.boundary_labels.clone(),
+                value: diff.value.clone(),
+                key: diff.key.clone(),
+                bound_ancestors: Default::default(),
+            };
+            new_diffs.insert(n, RelRc::with_parents(data, parents).into());
+            translations.insert(n, translation);
+        }
+
+        let sinks = self.sinks().map(|d| new_diffs[&NodeId::from(&d)].clone());
+        let mut graph = PortDiffGraph::from_sinks(sinks.collect_vec());
+        for (name, tips) in self.branch_tips() {
+            let tips = tips
+                .iter()
+                .filter_map(|tip| new_diffs.get(&NodeId::from(tip)));
+            graph
+                .2
+                .insert(name.to_string(), tips.map(PortDiff::downgrade).collect());
+        }
+        graph
+    }
+}
+
+/// Copy `graph` into a new graph of type `H`.
+///
+/// Returns the new graph along with the map from the nodes of `graph` to
+/// the nodes of the new graph. Nodes are added in the order of
+/// [`Graph::nodes_iter`], with the sites of the original nodes, and edges in
+/// the order of [`Graph::edges_iter`]. Pass to [`PortDiffGraph::map_graph`]
+/// to convert a diff graph, e.g. between `PortGraph` and `SimpleGraph`.
+pub fn convert_graph<G: Graph, H: AddNodes>(graph: &G) -> (H, BTreeMap<G::Node, H::Node>)
+where
+    G::PortLabel: Into<H::PortLabel>,
+{
+    let mut new_graph = H::default();
+    let nodes_map: BTreeMap<_, _> = graph
+        .nodes_iter()
+        .map(|n| {
+            let sites = graph.get_sites(n).map(|site| site.port.into());
+            (n, new_graph.add_node_with_sites(sites))
+        })
+        .collect();
+    let map_site = |site: Site<G::Node, G::PortLabel>| Site {
+        node: nodes_map[&site.node],
+        port: site.port.into(),
+    };
+    for edge in graph.edges_iter() {
+        let [left, right] = [EdgeEnd::Left, EdgeEnd::Right]
+            .map(|end| map_site(graph.get_port_site(BoundPort { edge, end })));
+        new_graph.link_sites(left, right);
+    }
+    (new_graph, nodes_map)
+}
+
+/// The translation of the nodes and edges of a replacement graph to the
+/// nodes and edges of its converted graph.
+struct Translation<G: Graph, H: Graph> {
+    nodes: BTreeMap<G::Node, H::Node>,
+    edges: BTreeMap<G::Edge, H::Edge>,
+}
+
+impl<G: Graph, H: Graph> Translation<G, H>
+where
+    G::PortLabel: Into<H::PortLabel>,
+{
+    fn new(graph: &G, new_graph: &H, nodes: BTreeMap<G::Node, H::Node>) -> Self {
+        let mut translation = Self {
+            nodes,
+            edges: BTreeMap::new(),
+        };
+        let mut parallel_edges: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for edge in graph.edges_iter() {
+            let ends = [EdgeEnd::Left, EdgeEnd::Right]
+                .map(|end| translation.site(graph.get_port_site(BoundPort { edge, end })));
+            parallel_edges.entry(ends).or_default().push(edge);
+        }
+        for ([left, right], edges) in parallel_edges {
+            let new_edges = new_graph.parallel_edges(left, right).sorted().collect_vec();
+            assert_eq!(edges.len(), new_edges.len(), "edges are not preserved");
+            translation
+                .edges
+                .extend(edges.into_iter().sorted().zip(new_edges));
+        }
+        translation
+    }
+
+    fn site(&self, site: Site<G::Node, G::PortLabel>) -> Site<H::Node, H::PortLabel> {
+        Site {
+            node: self.nodes[&site.node],
+            port: site.port.into(),
+        }
+    }
+
+    fn boundary_site(&self, site: &BoundarySite<G>) -> BoundarySite<H> {
+        match site {
+            BoundarySite::Site(site) => BoundarySite::Site(self.site(site.clone())),
+            &BoundarySite::Wire { id, end } => BoundarySite::Wire { id, end },
+        }
+    }
+
+    fn port(&self, port: &Port<G>) -> Port<H> {
+        match *port {
+            Port::Boundary(index) => Port::Boundary(index),
+            Port::Bound(BoundPort { edge, end }) => Port::Bound(BoundPort {
+                edge: self.edges[&edge],
+                end,
+            }),
+        }
+    }
+
+    /// Translate an edge from the diff of `self`, of which `new_graph` is the
+    /// converted graph.
+    fn edge_data(&self, edge: &EdgeData<G>, new_graph: &H) -> EdgeData<H> {
+        let subgraph = Subgraph::new(
+            new_graph,
+            edge.subgraph
+                .nodes()
+                .iter()
+                .map(|n| self.nodes[n])
+                .collect(),
+            edge.subgraph
+                .edges()
+                .iter()
+                .map(|e| self.edges[e])
+                .collect(),
+        );
+        let port_map = edge
+            .port_map
+            .iter()
+            .map(|(port, &index)| (self.port(port), index))
+            .collect();
+        EdgeData { subgraph, port_map }
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::PortGraph;
+    use rstest::rstest;
+
+    use crate::{
+        iso::are_isomorphic,
+        port_diff::tests::{parent_two_children_diffs, TestPortDiff},
+        simple_graph::SimpleGraph,
+    };
+
+    use super::*;
+
+    #[rstest]
+    fn test_map_graph_roundtrip(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let mut graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+        graph.create_branch("main", &child_1).unwrap();
+        let expected = PortDiff::extract_graph(vec![child_1, child_2]).unwrap();
+
+        let simple = graph.map_graph(convert_graph::<PortGraph, SimpleGraph>);
+        assert_eq!(simple.all_nodes().count(), 3);
+        assert_eq!(simple.branch("main").unwrap().len(), 1);
+        let extracted = PortDiff::extract_graph(simple.sinks().collect()).unwrap();
+        let (expected_simple, _) = convert_graph::<_, SimpleGraph>(&expected);
+        assert!(are_isomorphic(&extracted, &expected_simple));
+
+        let roundtrip = simple.map_graph(convert_graph::<SimpleGraph, PortGraph>);
+        let extracted = PortDiff::extract_graph(roundtrip.sinks().collect()).unwrap();
+        assert!(are_isomorphic(&extracted, &expected));
+    }
+}
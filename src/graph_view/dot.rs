@@ -0,0 +1,119 @@
+//! Rendering of the diff hierarchy in the DOT format.
+
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Write};
+
+use petgraph::visit::{EdgeRef, IntoEdges};
+
+use crate::{port::EdgeEnd, BoundPort, DotGraph, Graph, NodeId, PortDiff};
+
+use super::PortDiffGraph;
+
+impl<G: Graph, M: Clone + Debug> PortDiffGraph<G, M> {
+    /// Render the diff hierarchy in the DOT format.
+    ///
+    /// Every diff is labelled with the size of its replacement graph, its
+    /// value and its number of boundary ports. Every edge is labelled with
+    /// the number of nodes of the subgraph it rewrites.
+    pub fn to_dot(&self) -> String {
+        self.render_dot(|_, _, _| {})
+    }
+
+    /// Render the diff hierarchy in the DOT format, along with the
+    /// replacement graph of every diff.
+    ///
+    /// As [`Self::to_dot`], but the replacement graph of each diff is
+    /// embedded as a cluster next to the diff.
+    pub fn to_dot_with_graphs(&self) -> String
+    where
+        G: DotGraph,
+    {
+        self.render_dot(|out, index, diff| {
+            let graph = diff.graph();
+            let node_names: BTreeMap<_, _> = graph
+                .nodes_iter()
+                .enumerate()
+                .map(|(i, n)| (n, format!("d{index}_n{i}")))
+                .collect();
+            let node_name = |n: G::Node| &node_names[&n];
+            writeln!(out, "    subgraph cluster_d{index} {{").unwrap();
+            writeln!(out, "        label=\"d{index}\";").unwrap();
+            for n in graph.nodes_iter() {
+                let label = escape(&graph.dot_node_label(n));
+                writeln!(out, "        {} [label=\"{label}\"];", node_name(n)).unwrap();
+            }
+            for edge in graph.edges_iter() {
+                let [left, right] = [EdgeEnd::Left, EdgeEnd::Right]
+                    .map(|end| graph.get_port_site(BoundPort { edge, end }).node);
+                writeln!(out, "        {} -> {};", node_name(left), node_name(right)).unwrap();
+            }
+            writeln!(out, "    }}").unwrap();
+        })
+    }
+
+    fn render_dot(
+        &self,
+        mut render_graph: impl FnMut(&mut String, usize, &PortDiff<G, M>),
+    ) -> String {
+        let index: BTreeMap<NodeId<G, M>, usize> =
+            self.all_nodes().enumerate().map(|(i, n)| (n, i)).collect();
+
+        let mut out = String::from("digraph {\n");
+        for n in self.all_nodes() {
+            let diff = self.get_diff(n);
+            let value = escape(&format!("{:?}", diff.value()));
+            writeln!(
+                out,
+                "    d{} [shape=box, label=\"nodes: {}\\nvalue: {value}\\nboundary: {}\"];",
+                index[&n],
+                diff.graph().nodes_iter().count(),
+                diff.n_boundary_ports(),
+            )
+            .unwrap();
+            render_graph(&mut out, index[&n], &diff);
+        }
+        for n in self.all_nodes() {
+            for edge in self.inner().edges(n.into()) {
+                writeln!(
+                    out,
+                    "    d{} -> d{} [label=\"{}\"];",
+                    index[&n],
+                    index[&NodeId::from(edge.target())],
+                    edge.weight().subgraph.nodes().len(),
+                )
+                .unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Escape a label for use in a DOT string.
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_to_dot(parent_child_diffs: [TestPortDiff; 2]) {
+        let [_, child] = parent_child_diffs;
+        let graph = PortDiffGraph::from_sinks([child]);
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph {"));
+        assert_eq!(dot.matches("shape=box").count(), 2);
+        assert_eq!(dot.matches(" -> ").count(), 1);
+
+        let dot = graph.to_dot_with_graphs();
+        assert_eq!(dot.matches("subgraph cluster_").count(), 2);
+    }
+}
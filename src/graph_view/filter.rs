@@ -0,0 +1,272 @@
+//! Filtering diffs out of a `PortDiffGraph`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bimap::BiBTreeMap;
+use itertools::Itertools;
+use relrc::RelRc;
+
+use crate::{
+    port::BoundaryIndex,
+    port_diff::{EdgeData, IncomingEdgeIndex, PortDiffData},
+    subgraph::Subgraph,
+    Graph, NodeId, Port, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+/// The incoming edges of a diff after filtering, along with the new incoming
+/// edge of every boundary port.
+struct Rewired<G: Graph, M> {
+    parents: Vec<(PortDiff<G, M>, EdgeData<G>)>,
+    boundary_edges: Vec<IncomingEdgeIndex>,
+}
+
+/// A diff after filtering.
+enum Filtered<G: Graph, M> {
+    /// The diff is kept, as the given new diff.
+    Kept(PortDiff<G, M>),
+    /// The diff is removed. Its edges to the kept diffs are given if they
+    /// can be composed with the edges to its children.
+    Removed(Option<Rewired<G, M>>),
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Create a new `PortDiffGraph` with only the diffs satisfying `pred`.
+    ///
+    /// The children of a removed diff are reconnected to its parents, by
+    /// composing the edges from the parents with the edges to the children:
+    /// the rewritten subgraphs in the parents are merged and the port maps
+    /// are composed. This is only possible for children that rewrite all
+    /// nodes and boundary ports of the removed diff. Other children depend
+    /// on the removed diff and are removed along with it, as are all their
+    /// descendants. Edges from a removed diff that rewrite nothing are
+    /// dropped. Diffs none of whose ancestors are removed are kept as they
+    /// are.
+    ///
+    /// Branch tips that are removed are dropped from their branch. All
+    /// ancestors of the diffs in `self` must be in `self`.
+    pub fn filter(&self, pred: impl Fn(&PortDiff<G, M>) -> bool) -> Self {
+        self.filter_with_dependents(pred).0
+    }
+
+    /// Filter the graph as [`Self::filter`], along with the diffs satisfying
+    /// `pred` that were removed as they depend on a removed diff.
+    pub(super) fn filter_with_dependents(
+        &self,
+        pred: impl Fn(&PortDiff<G, M>) -> bool,
+    ) -> (Self, Vec<PortDiff<G, M>>) {
+        let mut filtered: BTreeMap<NodeId<G, M>, Filtered<G, M>> = BTreeMap::new();
+        let mut dependents = Vec::new();
+        for n in self.all_nodes() {
+            let diff = self.get_diff(n);
+            let keep = pred(&diff);
+            let new_diff = match rewire(&diff, &filtered) {
+                Some(Rewired { parents, .. }) if keep && is_unchanged(&diff, &parents) => {
+                    Filtered::Kept(diff)
+                }
+                Some(Rewired {
+                    parents,
+                    boundary_edges,
+                }) if keep => {
+                    let boundary = diff
+                        .boundary
+                        .iter()
+                        .zip(boundary_edges)
+                        .map(|((site, _), index)| (site, index))
+                        .collect();
+                    let data = PortDiffData {
+                        boundary,
+                        bound_ancestors: Default::default(),
+                        ..PortDiffData::clone(&diff)
+                    };
+                    let parents = parents.into_iter().map(|(p, e)| (p.data, e));
+                    Filtered::Kept(RelRc::with_parents(data, parents.collect_vec()).into())
+                }
+                rewired => {
+                    if keep {
+                        dependents.push(diff);
+                    }
+                    Filtered::Removed(rewired)
+                }
+            };
+            filtered.insert(n, new_diff);
+        }
+
+        let kept: BTreeMap<_, _> = filtered
+            .into_iter()
+            .filter_map(|(n, f)| match f {
+                Filtered::Kept(diff) => Some((n, diff)),
+                Filtered::Removed(_) => None,
+            })
+            .collect();
+        let parents: BTreeSet<_> = kept.values().flat_map(|d| d.all_parents()).collect();
+        let sinks = self
+            .all_nodes()
+            .filter_map(|n| kept.get(&n))
+            .filter(|d| !parents.contains(d))
+            .cloned();
+        let mut graph = Self::from_sinks(sinks.collect_vec());
+        for (name, tips) in self.branch_tips() {
+            let tips = tips.iter().filter_map(|tip| kept.get(&NodeId::from(tip)));
+            graph
+                .2
+                .insert(name.to_string(), tips.map(PortDiff::downgrade).collect());
+        }
+        (graph, dependents)
+    }
+}
+
+/// The incoming edges of `diff` to the kept diffs, if they can be computed.
+fn rewire<G: Graph, M: Clone>(
+    diff: &PortDiff<G, M>,
+    filtered: &BTreeMap<NodeId<G, M>, Filtered<G, M>>,
+) -> Option<Rewired<G, M>> {
+    let mut parents = Vec::new();
+    // For every old incoming edge, the new incoming edge of the boundary
+    // ports on it
+    let mut edge_maps: Vec<BTreeMap<BoundaryIndex, IncomingEdgeIndex>> = Vec::new();
+    for edge in diff.all_incoming() {
+        let parent = PortDiff::from(edge.source().clone());
+        let edge = edge.value();
+        let mut edge_map = BTreeMap::new();
+        match &filtered[&NodeId::from(&parent)] {
+            Filtered::Kept(new_parent) => {
+                let index = IncomingEdgeIndex::from(parents.len());
+                edge_map.extend(edge.port_map.right_values().map(|&b| (b, index)));
+                parents.push((new_parent.clone(), edge.clone()));
+            }
+            Filtered::Removed(_)
+                if edge.subgraph.nodes().is_empty() && edge.port_map.is_empty() =>
+            {
+                // Edge is a no-op, drop it
+            }
+            Filtered::Removed(Some(removed)) if rewrites_all(&parent, edge) => {
+                // Compose the edges into the removed diff with `edge`,
+                // merging the edges from the same parent
+                let mut by_parent = BTreeMap::new();
+                let mut new_indices = Vec::with_capacity(removed.parents.len());
+                for (grandparent, grandparent_edge) in &removed.parents {
+                    let index = *by_parent
+                        .entry(NodeId::from(grandparent))
+                        .or_insert_with(|| {
+                            let new_edge = EdgeData {
+                                subgraph: grandparent_edge.subgraph.clone(),
+                                port_map: BiBTreeMap::new(),
+                            };
+                            parents.push((grandparent.clone(), new_edge));
+                            parents.len() - 1
+                        });
+                    let (_, new_edge) = &mut parents[index];
+                    new_edge.subgraph = union(
+                        grandparent.graph(),
+                        &new_edge.subgraph,
+                        &grandparent_edge.subgraph,
+                    );
+                    new_indices.push(index);
+                }
+                for (port, &boundary) in &edge.port_map {
+                    let &Port::Boundary(removed_boundary) = port else {
+                        unreachable!("only boundary ports of the removed diff are mapped")
+                    };
+                    let old_index = removed.boundary_edges[usize::from(removed_boundary)];
+                    let (_, grandparent_edge) = &removed.parents[usize::from(old_index)];
+                    let grandparent_port = grandparent_edge
+                        .port_map
+                        .get_by_right(&removed_boundary)
+                        .expect("boundary port is mapped to a parent port")
+                        .clone();
+                    let index = new_indices[usize::from(old_index)];
+                    let (_, new_edge) = &mut parents[index];
+                    new_edge.port_map.insert(grandparent_port, boundary);
+                    edge_map.insert(boundary, IncomingEdgeIndex::from(index));
+                }
+            }
+            Filtered::Removed(_) => return None,
+        }
+        edge_maps.push(edge_map);
+    }
+    let boundary_edges = diff
+        .boundary
+        .iter()
+        .enumerate()
+        .map(|(i, (_, old_index))| edge_maps[usize::from(old_index)][&BoundaryIndex::from(i)])
+        .collect();
+    Some(Rewired {
+        parents,
+        boundary_edges,
+    })
+}
+
+/// Whether the new incoming edges of `diff` are its current incoming edges.
+fn is_unchanged<G: Graph, M: Clone>(
+    diff: &PortDiff<G, M>,
+    parents: &[(PortDiff<G, M>, EdgeData<G>)],
+) -> bool {
+    let incoming = diff.all_incoming();
+    parents.len() == incoming.len()
+        && parents
+            .iter()
+            .zip(incoming)
+            .all(|((parent, _), edge)| parent == &PortDiff::from(edge.source().clone()))
+}
+
+/// Whether `edge` rewrites all nodes, edges and boundary ports of `diff`.
+fn rewrites_all<G: Graph, M: Clone>(diff: &PortDiff<G, M>, edge: &EdgeData<G>) -> bool {
+    diff.graph()
+        .nodes_iter()
+        .all(|n| edge.subgraph.nodes().contains(&n))
+        && edge
+            .port_map
+            .left_values()
+            .all(|port| matches!(port, Port::Boundary(_)))
+        && diff
+            .boundary_iter()
+            .all(|b| edge.port_map.contains_left(&Port::Boundary(b)))
+}
+
+/// The union of two subgraphs of `graph`.
+fn union<G: Graph>(graph: &G, a: &Subgraph<G>, b: &Subgraph<G>) -> Subgraph<G> {
+    let nodes = a.nodes().union(b.nodes()).copied().collect();
+    let edges = a.edges().union(b.edges()).copied().collect();
+    Subgraph::new(graph, nodes, edges)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        iso::are_isomorphic,
+        simple_graph::{SimpleGraph, SimpleNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let explored = root.select_subgraph([0, 1].map(SimpleNode::new));
+        // Rewrites all of `explored`, and part of it
+        let full = explored.select_subgraph(explored.graph().nodes_iter().collect_vec());
+        let partial = explored.select_subgraph([SimpleNode::new(0)]);
+        let mut graph = PortDiffGraph::from_sinks([full.clone(), partial.clone()]);
+        graph.create_branch("full", &full).unwrap();
+        graph.create_branch("partial", &partial).unwrap();
+
+        let filtered = graph.filter(|d| d != &explored);
+        assert_eq!(filtered.all_nodes().count(), 2);
+        let [new_full] = filtered.sinks().collect_vec().try_into().unwrap();
+        let [new_root] = new_full.all_parents().collect_vec().try_into().unwrap();
+        assert!(new_root.is_root());
+        assert_eq!(new_root.graph(), root.graph());
+        assert_eq!(filtered.branch("full").unwrap(), vec![new_full.clone()]);
+        assert!(filtered.branch("partial").unwrap().is_empty());
+        assert!(filtered.validate().is_ok());
+
+        let expected = PortDiff::extract_graph(vec![full]).unwrap();
+        let extracted = PortDiff::extract_graph(vec![new_full]).unwrap();
+        assert!(are_isomorphic(&extracted, &expected));
+
+        // Filtering the root drops all diffs rewriting part of it
+        assert_eq!(graph.filter(|d| d != &root).all_nodes().count(), 0);
+    }
+}
@@ -0,0 +1,259 @@
+//! Immutable snapshots of diff graphs that can be shared across threads.
+//!
+//! Diffs are reference counted without synchronisation and cannot be sent
+//! to other threads. A [`FrozenDiffGraph`] instead stores a deep copy of the
+//! data of every diff, behind an atomic reference count. Threads recreate
+//! the diffs they need from the snapshot, e.g. to extract graphs.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use derive_where::derive_where;
+use itertools::Itertools;
+use relrc::RelRc;
+
+use crate::{
+    port::BoundaryIndex,
+    port_diff::{Boundary, EdgeData, PortDiffData, SquashError},
+    Graph, NodeId, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+/// An immutable snapshot of a [`PortDiffGraph`].
+///
+/// Obtained with [`PortDiffGraph::freeze`]. Diffs are referred to by their
+/// index in [`PortDiffGraph::all_nodes`] at the time of freezing, so that
+/// parents come before their children. Cloning a snapshot is cheap.
+///
+/// The snapshot is `Send` and `Sync` if the graph, its nodes, edges and port
+/// labels and the diff values are, so that it can answer queries and
+/// extraction requests from several threads concurrently.
+///
+/// Methods taking diff indices panic if an index is out of bounds.
+#[derive_where(Clone; G: Graph)]
+pub struct FrozenDiffGraph<G: Graph, M = ()>(Arc<FrozenData<G, M>>);
+
+struct FrozenData<G: Graph, M> {
+    diffs: Vec<FrozenDiff<G, M>>,
+    sinks: Vec<usize>,
+    branches: BTreeMap<String, Vec<usize>>,
+}
+
+/// The data of a diff, with its parents and children given by index.
+struct FrozenDiff<G: Graph, M> {
+    graph: G,
+    boundary: Boundary<G>,
+    boundary_labels: BTreeMap<BoundaryIndex, String>,
+    value: M,
+    key: Option<String>,
+    incoming: Vec<(usize, EdgeData<G>)>,
+    children: Vec<usize>,
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Take an immutable snapshot of the graph that can be shared across
+    /// threads.
+    ///
+    /// All ancestors of the diffs in `self` must be in `self`.
+    pub fn freeze(&self) -> FrozenDiffGraph<G, M> {
+        let nodes = self.all_nodes().collect_vec();
+        let indices: BTreeMap<_, _> = nodes.iter().enumerate().map(|(i, &n)| (n, i)).collect();
+        let mut diffs = nodes
+            .iter()
+            .map(|&n| {
+                let diff = self.get_diff(n);
+                let incoming = diff
+                    .all_incoming()
+                    .iter()
+                    .map(|e| {
+                        let parent = NodeId::from(&PortDiff::from(e.source().clone()));
+                        (indices[&parent], e.value().clone())
+                    })
+                    .collect();
+                FrozenDiff {
+                    graph: diff.graph.clone(),
+                    boundary: diff.boundary.clone(),
+                    boundary_labels: diff.boundary_labels.clone(),
+                    value: diff.value.clone(),
+                    key: diff.key.clone(),
+                    incoming,
+                    children: Vec::new(),
+                }
+            })
+            .collect_vec();
+        for child in 0..diffs.len() {
+            let parents = diffs[child].incoming.iter().map(|&(p, _)| p).unique();
+            for parent in parents.collect_vec() {
+                diffs[parent].children.push(child);
+            }
+        }
+
+        let sinks = self.sinks().map(|d| indices[&NodeId::from(&d)]).collect();
+        let branches = self
+            .branch_tips()
+            .map(|(name, tips)| {
+                let tips = tips
+                    .iter()
+                    .filter_map(|tip| indices.get(&NodeId::from(tip)).copied());
+                (name.to_string(), tips.collect())
+            })
+            .collect();
+        FrozenDiffGraph(Arc::new(FrozenData {
+            diffs,
+            sinks,
+            branches,
+        }))
+    }
+}
+
+impl<G: Graph, M: Clone> FrozenDiffGraph<G, M> {
+    /// The number of diffs in the snapshot.
+    pub fn n_diffs(&self) -> usize {
+        self.0.diffs.len()
+    }
+
+    /// The diffs without children, in the order of
+    /// [`PortDiffGraph::sinks`].
+    pub fn sinks(&self) -> &[usize] {
+        &self.0.sinks
+    }
+
+    /// The parents of `diff`, in the order of the incoming edges, without
+    /// repetitions.
+    pub fn parents(&self, diff: usize) -> impl Iterator<Item = usize> + '_ {
+        self.0.diffs[diff].incoming.iter().map(|&(p, _)| p).unique()
+    }
+
+    /// The children of `diff`, in increasing order.
+    pub fn children(&self, diff: usize) -> &[usize] {
+        &self.0.diffs[diff].children
+    }
+
+    /// The replacement graph of `diff`.
+    pub fn graph(&self, diff: usize) -> &G {
+        &self.0.diffs[diff].graph
+    }
+
+    /// The value of `diff`.
+    pub fn value(&self, diff: usize) -> &M {
+        &self.0.diffs[diff].value
+    }
+
+    /// The key of `diff`, if any.
+    pub fn key(&self, diff: usize) -> Option<&str> {
+        self.0.diffs[diff].key.as_deref()
+    }
+
+    /// The tips of the branch `name`, if it exists.
+    pub fn branch(&self, name: &str) -> Option<&[usize]> {
+        self.0.branches.get(name).map(Vec::as_slice)
+    }
+
+    /// Recreate the diffs `diffs` along with their ancestors, by index.
+    ///
+    /// The diffs are only valid on the current thread, and distinct from the
+    /// diffs of the frozen graph and from previously recreated diffs.
+    pub fn thaw_diffs(&self, diffs: &[usize]) -> BTreeMap<usize, PortDiff<G, M>> {
+        let mut ancestors = BTreeSet::new();
+        let mut stack = diffs.to_vec();
+        while let Some(diff) = stack.pop() {
+            if ancestors.insert(diff) {
+                stack.extend(self.parents(diff));
+            }
+        }
+
+        // Parents have smaller indices than their children
+        let mut thawed = BTreeMap::new();
+        for i in ancestors {
+            let diff = &self.0.diffs[i];
+            let parents = diff
+                .incoming
+                .iter()
+                .map(|(p, edge)| {
+                    let parent: &PortDiff<G, M> = &thawed[p];
+                    (parent.data.clone(), edge.clone())
+                })
+                .collect_vec();
+            let data = PortDiffData {
+                graph: diff.graph.clone(),
+                boundary: diff.boundary.clone(),
+                boundary_labels: diff.boundary_labels.clone(),
+                value: diff.value.clone(),
+                key: diff.key.clone(),
+                bound_ancestors: Default::default(),
+            };
+            thawed.insert(i, RelRc::with_parents(data, parents).into());
+        }
+        thawed
+    }
+
+    /// Recreate the diff graph on the current thread.
+    pub fn thaw(&self) -> PortDiffGraph<G, M> {
+        let all_diffs = (0..self.n_diffs()).collect_vec();
+        let thawed = self.thaw_diffs(&all_diffs);
+        let sinks = self.sinks().iter().map(|i| thawed[i].clone());
+        let mut graph = PortDiffGraph::from_sinks(sinks.collect_vec());
+        for (name, tips) in &self.0.branches {
+            let tips = tips.iter().map(|i| thawed[i].downgrade());
+            graph.2.insert(name.clone(), tips.collect());
+        }
+        graph
+    }
+
+    /// Extract the graph of `diffs`.
+    ///
+    /// Only the diffs and their ancestors are recreated, see
+    /// [`Self::thaw_diffs`]. Equivalent to [`PortDiff::extract_graph`].
+    pub fn extract_graph(&self, diffs: &[usize]) -> Result<G, SquashError> {
+        let thawed = self.thaw_diffs(diffs);
+        let diffs = diffs.iter().map(|i| thawed[i].clone()).collect();
+        PortDiff::extract_graph(diffs)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use portgraph::{LinkView, PortGraph, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[rstest]
+    fn test_frozen_graph(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let mut graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+        graph.create_branch("main", &child_1).unwrap();
+        let expected = PortDiff::extract_graph(vec![child_1, child_2]).unwrap();
+
+        let frozen = graph.freeze();
+        assert_send_sync(&frozen);
+        assert_eq!(frozen.n_diffs(), 3);
+        assert_eq!(frozen.sinks(), [1, 2]);
+        assert_eq!(frozen.children(0), [1, 2]);
+        assert_eq!(frozen.parents(2).collect_vec(), vec![0]);
+        assert_eq!(frozen.branch("main"), Some(&[1][..]));
+
+        let extracted: Vec<PortGraph> = thread::scope(|scope| {
+            let handles = (0..4)
+                .map(|_| scope.spawn(|| frozen.extract_graph(&[1, 2]).unwrap()))
+                .collect_vec();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for g in extracted {
+            assert_eq!(g.node_count(), expected.node_count());
+            assert_eq!(g.link_count(), expected.link_count());
+        }
+
+        let thawed = frozen.thaw();
+        assert_eq!(thawed.all_nodes().count(), 3);
+        assert_eq!(thawed.branch("main").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! Layered layout of the diff hierarchy.
+
+use std::collections::BTreeMap;
+
+use crate::{Graph, NodeId};
+
+use super::PortDiffGraph;
+
+/// The number of median ordering sweeps, alternating downwards and upwards.
+const N_SWEEPS: usize = 4;
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Compute layered coordinates to draw the diff hierarchy.
+    ///
+    /// Returns the `(layer, position)` of every diff. Diffs are assigned to
+    /// layers by longest path from the roots, so that every diff is in a
+    /// deeper layer than all its parents. Within each layer, diffs are
+    /// numbered from 0 and ordered by the median position of their parents
+    /// and children, to reduce edge crossings.
+    pub fn layered_layout(&self) -> BTreeMap<NodeId<G, M>, (usize, usize)> {
+        let nodes: Vec<_> = self.all_nodes().collect();
+        let mut parents: BTreeMap<NodeId<G, M>, Vec<NodeId<G, M>>> = BTreeMap::new();
+        let mut children: BTreeMap<NodeId<G, M>, Vec<NodeId<G, M>>> = BTreeMap::new();
+        for &node in &nodes {
+            let diff = self.get_diff(node);
+            for parent in diff.all_parents() {
+                let parent = NodeId::from(&parent);
+                if self.contains(parent) {
+                    parents.entry(node).or_default().push(parent);
+                    children.entry(parent).or_default().push(node);
+                }
+            }
+        }
+
+        // Longest path layering
+        let mut layer_of = BTreeMap::new();
+        for &node in &nodes {
+            assign_layer(node, &parents, &mut layer_of);
+        }
+        let n_layers = layer_of.values().max().map_or(0, |&l| l + 1);
+        let mut layers = vec![Vec::new(); n_layers];
+        for &node in &nodes {
+            layers[layer_of[&node]].push(node);
+        }
+
+        // Median ordering heuristic
+        for sweep in 0..N_SWEEPS {
+            let downwards = sweep % 2 == 0;
+            let indices: Vec<_> = if downwards {
+                (1..n_layers).collect()
+            } else {
+                (0..n_layers.saturating_sub(1)).rev().collect()
+            };
+            let neighbours = if downwards { &parents } else { &children };
+            for layer in indices {
+                let adjacent = if downwards { layer - 1 } else { layer + 1 };
+                let position: BTreeMap<_, _> = layers[adjacent]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &n)| (n, i))
+                    .collect();
+                let current: BTreeMap<_, _> = layers[layer]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &n)| (n, i))
+                    .collect();
+                // Diffs without neighbours in the adjacent layer keep their place
+                layers[layer].sort_by_cached_key(|n| {
+                    let mut positions: Vec<_> = neighbours
+                        .get(n)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|m| position.get(m))
+                        .map(|&p| 2 * p)
+                        .collect();
+                    positions.sort_unstable();
+                    let median = match positions.len() {
+                        0 => 2 * current[n],
+                        len if len % 2 == 1 => positions[len / 2],
+                        len => (positions[len / 2 - 1] + positions[len / 2]) / 2,
+                    };
+                    (median, current[n])
+                });
+            }
+        }
+
+        layers
+            .into_iter()
+            .enumerate()
+            .flat_map(|(layer, nodes)| {
+                nodes
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(position, node)| (node, (layer, position)))
+            })
+            .collect()
+    }
+}
+
+/// Assign `node` to one layer below its deepest parent, recursively.
+fn assign_layer<G: Graph, M>(
+    node: NodeId<G, M>,
+    parents: &BTreeMap<NodeId<G, M>, Vec<NodeId<G, M>>>,
+    layer_of: &mut BTreeMap<NodeId<G, M>, usize>,
+) -> usize {
+    if let Some(&layer) = layer_of.get(&node) {
+        return layer;
+    }
+    let layer = parents
+        .get(&node)
+        .into_iter()
+        .flatten()
+        .map(|&p| assign_layer(p, parents, layer_of) + 1)
+        .max()
+        .unwrap_or(0);
+    layer_of.insert(node, layer);
+    layer
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_layered_layout(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        let graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+
+        let layout = graph.layered_layout();
+        assert_eq!(layout.len(), 3);
+        assert_eq!(layout[&NodeId::from(&parent)], (0, 0));
+        let [(layer_1, pos_1), (layer_2, pos_2)] =
+            [&child_1, &child_2].map(|c| layout[&NodeId::from(c)]);
+        assert_eq!((layer_1, layer_2), (1, 1));
+        assert_ne!(pos_1, pos_2);
+        assert!(pos_1 < 2 && pos_2 < 2);
+    }
+}
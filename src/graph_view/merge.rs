@@ -0,0 +1,282 @@
+//! Strategies to resolve conflicts when merging diff graphs.
+
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+use itertools::Itertools;
+use petgraph::visit::{EdgeRef, IntoEdges};
+
+use crate::{
+    port_diff::CompatibilityPolicy,
+    trace::{debug_event, debug_span},
+    Graph, NodeId, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+/// Strategy for merging two graphs.
+///
+/// When merging `other` into a graph, the diffs of `other` that are not yet
+/// in the graph are new, all other diffs are existing. Two diffs conflict if
+/// their edges from a common parent are not compatible. Strategies that
+/// resolve conflicts drop the conflicting diffs on one side, along with
+/// their descendants.
+pub enum MergeStrategy<G: Graph, M = ()> {
+    /// Ignore conflicts and merge the graphs.
+    IgnoreConflicts,
+    /// Fail if conflicts are detected.
+    FailOnConflicts,
+    /// Keep the new diffs, dropping the existing diffs they conflict with.
+    PreferNewer,
+    /// Keep the side whose conflicting diffs have the lowest value, as
+    /// ordered by the given function, e.g. `Ord::cmp`.
+    ///
+    /// On ties, the existing diffs are kept.
+    PreferLowerValue(fn(&M, &M) -> Ordering),
+    /// Decide which side to keep with a callback.
+    ///
+    /// The callback is called for every parent with conflicting children,
+    /// with the parent, the existing conflicting children and the new
+    /// conflicting children.
+    #[allow(clippy::type_complexity)]
+    Resolve(
+        Box<dyn Fn(&PortDiff<G, M>, &[PortDiff<G, M>], &[PortDiff<G, M>]) -> ConflictResolution>,
+    ),
+}
+
+/// Which side of a conflict to keep, see [`MergeStrategy::Resolve`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictResolution {
+    /// Keep the diffs that were in the graph before the merge.
+    KeepExisting,
+    /// Keep the diffs that were added by the merge.
+    KeepNew,
+}
+
+impl<G: Graph, M> MergeStrategy<G, M> {
+    /// Which side of a conflict at `parent` to keep, or `None` if conflicts
+    /// are not resolved by this strategy.
+    fn resolve(
+        &self,
+        parent: &PortDiff<G, M>,
+        existing: &[PortDiff<G, M>],
+        new: &[PortDiff<G, M>],
+    ) -> Option<ConflictResolution> {
+        match self {
+            Self::IgnoreConflicts | Self::FailOnConflicts => None,
+            Self::PreferNewer => Some(ConflictResolution::KeepNew),
+            Self::PreferLowerValue(cmp) => {
+                let lowest = |diffs: &[PortDiff<G, M>]| {
+                    diffs.iter().map(|d| d.value()).min_by(|a, b| cmp(a, b))
+                };
+                let (Some(existing), Some(new)) = (lowest(existing), lowest(new)) else {
+                    return Some(ConflictResolution::KeepExisting);
+                };
+                if cmp(new, existing) == Ordering::Less {
+                    Some(ConflictResolution::KeepNew)
+                } else {
+                    Some(ConflictResolution::KeepExisting)
+                }
+            }
+            Self::Resolve(callback) => Some(callback(parent, existing, new)),
+        }
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Drop the diffs that lose a conflict according to `strategy`, along with
+    /// their descendants.
+    ///
+    /// The diffs in `old_nodes` are existing, all others are new.
+    pub(super) fn resolve_conflicts(
+        &mut self,
+        old_nodes: &BTreeSet<NodeId<G, M>>,
+        strategy: &MergeStrategy<G, M>,
+        policy: &impl CompatibilityPolicy<G>,
+    ) {
+        if matches!(
+            strategy,
+            MergeStrategy::IgnoreConflicts | MergeStrategy::FailOnConflicts
+        ) {
+            return;
+        }
+        debug_span!("resolve_conflicts");
+        let mut dropped = BTreeSet::new();
+        for n in self.all_nodes() {
+            let (existing, new): (Vec<_>, Vec<_>) = self
+                .inner()
+                .edges(n.into())
+                .map(|e| (NodeId::from(e.target()), e.weight()))
+                .partition(|(child, _)| old_nodes.contains(child));
+            let mut existing_conflicts = BTreeSet::new();
+            let mut new_conflicts = BTreeSet::new();
+            for ((e_child, e_edge), (n_child, n_edge)) in existing.iter().cartesian_product(&new) {
+                if !policy.are_compatible(&[*e_edge, *n_edge]) {
+                    existing_conflicts.insert(*e_child);
+                    new_conflicts.insert(*n_child);
+                }
+            }
+            if existing_conflicts.is_empty() {
+                continue;
+            }
+            let diffs = |ids: &BTreeSet<_>| ids.iter().map(|&id| self.get_diff(id)).collect_vec();
+            let resolution = strategy.resolve(
+                &self.get_diff(n),
+                &diffs(&existing_conflicts),
+                &diffs(&new_conflicts),
+            );
+            match resolution {
+                Some(ConflictResolution::KeepExisting) => dropped.extend(new_conflicts),
+                Some(ConflictResolution::KeepNew) => dropped.extend(existing_conflicts),
+                None => return,
+            }
+        }
+        if dropped.is_empty() {
+            return;
+        }
+
+        // Parents come before their children, so that descendants of dropped
+        // diffs are dropped too
+        let mut removed = BTreeSet::new();
+        for n in self.all_nodes() {
+            let has_removed_parent = self
+                .get_diff(n)
+                .all_parents()
+                .any(|p| removed.contains(&NodeId::from(&p)));
+            if dropped.contains(&n) || has_removed_parent {
+                removed.insert(n);
+            }
+        }
+        let sinks = self
+            .all_nodes()
+            .filter(|n| !removed.contains(n))
+            .filter(|&n| {
+                self.inner()
+                    .edges(n.into())
+                    .all(|e| removed.contains(&NodeId::from(e.target())))
+            })
+            .map(|n| self.get_diff(n))
+            .collect_vec();
+        debug_event!(n_dropped = removed.len(), "resolved conflicts");
+        *self.inner_mut() = Self::from_sinks(sinks).0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        port_diff::Owned,
+        simple_graph::{SimpleGraph, SimpleNode},
+        Site,
+    };
+
+    use super::*;
+
+    type Diff = PortDiff<SimpleGraph, usize>;
+
+    /// A child of `root` replacing its middle node with a copy, with value
+    /// `value`.
+    fn copy_middle(root: &Diff, value: usize) -> Diff {
+        let mut new_graph = SimpleGraph::new();
+        let node = new_graph.add_node(1, 1);
+        let nodes = [Owned::new(SimpleNode::new(1), root.clone())];
+        PortDiff::rewrite_with_value(
+            nodes,
+            [],
+            new_graph,
+            |p| {
+                let port = p.site().unwrap().port;
+                Site { node, port }.into()
+            },
+            value,
+        )
+        .unwrap()
+    }
+
+    fn merge(
+        existing: &Diff,
+        new: &Diff,
+        strategy: MergeStrategy<SimpleGraph, usize>,
+    ) -> Vec<Diff> {
+        let mut graph = PortDiffGraph::from_sinks([existing.clone()]);
+        graph
+            .merge(PortDiffGraph::from_sinks([new.clone()]), strategy)
+            .unwrap();
+        graph.sinks().collect()
+    }
+
+    #[test]
+    fn test_merge_strategies() {
+        let root = PortDiff::from_graph_with_value(SimpleGraph::linear_chain(3), 0);
+        let [high, low] = [2, 1].map(|v| copy_middle(&root, v));
+        let grandchild = copy_middle(&root, 3).select_subgraph([SimpleNode::new(0)]);
+
+        let mut graph = PortDiffGraph::from_sinks([high.clone()]);
+        let other = PortDiffGraph::from_sinks([low.clone()]);
+        assert!(graph
+            .clone()
+            .merge(other.clone(), MergeStrategy::FailOnConflicts)
+            .is_err());
+        graph.merge(other, MergeStrategy::IgnoreConflicts).unwrap();
+        assert_eq!(graph.sinks().count(), 2);
+
+        assert_eq!(
+            merge(&high, &low, MergeStrategy::PreferNewer),
+            vec![low.clone()]
+        );
+        assert_eq!(
+            merge(&high, &low, MergeStrategy::PreferLowerValue(Ord::cmp)),
+            vec![low.clone()]
+        );
+        assert_eq!(
+            merge(&low, &high, MergeStrategy::PreferLowerValue(Ord::cmp)),
+            vec![low.clone()]
+        );
+        let keep_existing = MergeStrategy::Resolve(Box::new(
+            |parent: &Diff, existing: &[Diff], new: &[Diff]| {
+                assert_eq!(parent.value(), &0);
+                assert_eq!(existing.len(), 1);
+                assert_eq!(new.len(), 1);
+                ConflictResolution::KeepExisting
+            },
+        ));
+        assert_eq!(merge(&high, &low, keep_existing), vec![high.clone()]);
+
+        // Descendants of dropped diffs are dropped
+        let sinks = merge(&grandchild, &low, MergeStrategy::PreferNewer);
+        assert_eq!(sinks, vec![low]);
+    }
+
+    #[test]
+    fn test_merge_drops_branch_tip() {
+        let root = PortDiff::from_graph_with_value(SimpleGraph::linear_chain(3), 0);
+        let mut graph = PortDiffGraph::from_sinks([copy_middle(&root, 2)]);
+        let high = graph.sinks().next().unwrap();
+        graph.create_branch("high", &high).unwrap();
+        let weak_high = high.downgrade();
+        drop(high);
+
+        let low = copy_middle(&root, 1);
+        graph
+            .merge(
+                PortDiffGraph::from_sinks([low.clone()]),
+                MergeStrategy::PreferNewer,
+            )
+            .unwrap();
+        assert!(!weak_high.is_alive());
+        assert_eq!(graph.branch("high"), Some(vec![]));
+
+        // New diffs never become tips of the branch
+        for value in 3..10 {
+            let diff = copy_middle(&root, value);
+            graph
+                .merge(
+                    PortDiffGraph::from_sinks([diff]),
+                    MergeStrategy::IgnoreConflicts,
+                )
+                .unwrap();
+        }
+        assert_eq!(graph.branch("high"), Some(vec![]));
+        assert_eq!(graph.branch_diffs("high"), Some(vec![]));
+    }
+}
@@ -0,0 +1,415 @@
+//! A read-only view of the graph obtained by merging compatible diffs.
+
+use std::collections::BTreeMap;
+
+use derive_where::derive_where;
+use itertools::Itertools;
+
+use crate::{
+    port::{BoundaryIndex, EdgeEnd},
+    port_diff::{IncompatiblePortDiff, Owned},
+    BoundPort, BoundarySite, Graph, NodeId, Port, PortDiff, Site,
+};
+
+use super::PortDiffGraph;
+
+/// A node of a [`MergedView`]: a node of the replacement graph of a diff.
+#[derive_where(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord; G: Graph)]
+pub struct MergedNode<G: Graph, M = ()> {
+    /// The diff the node belongs to.
+    pub diff: NodeId<G, M>,
+    /// The node in the replacement graph of `diff`.
+    pub node: G::Node,
+}
+
+/// An edge of a [`MergedView`].
+///
+/// Every edge of the merged graph is identified by an edge of the
+/// replacement graph of a diff, whose ends may have been rewritten by
+/// descendants. Edges that are joined through wires are identified by the
+/// edge at their left end.
+#[derive_where(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord; G: Graph)]
+pub struct MergedEdge<G: Graph, M = ()> {
+    /// The diff the edge belongs to.
+    pub diff: NodeId<G, M>,
+    /// The edge in the replacement graph of `diff`.
+    pub edge: G::Edge,
+}
+
+/// A read-only view of the graph extracted from a set of compatible diffs.
+///
+/// Unlike [`PortDiff::extract_graph`], the merged graph is not copied into a
+/// new graph: nodes, edges and ports are resolved on demand by following the
+/// boundary maps of the diffs. The view implements [`Graph`], so that it can
+/// be queried like any other graph.
+///
+/// The view is read-only: [`Graph::link_sites`] and [`Graph::add_subgraph`]
+/// panic.
+#[derive_where(Clone, Default; G: Graph)]
+pub struct MergedView<G: Graph, M = ()> {
+    /// The merged diffs, along with all their ancestors.
+    graph: PortDiffGraph<G, M>,
+    /// For every diff, the nodes that are rewritten by a child in the view,
+    /// along with the child and the index of its incoming edge.
+    rewritten: BTreeMap<NodeId<G, M>, BTreeMap<G::Node, (NodeId<G, M>, usize)>>,
+}
+
+/// The result of following a port one step down the diff hierarchy.
+enum Step<G: Graph, M> {
+    /// The port is at a site of a node that is not rewritten in the view.
+    Site(Site<MergedNode<G, M>, G::PortLabel>),
+    /// The port is rewritten into a boundary port of a child.
+    Child(Owned<BoundaryIndex, G, M>),
+    /// The port is deleted by a child.
+    Deleted,
+}
+
+/// A port of a diff, resolved to a site in the view.
+struct Resolved<G: Graph, M> {
+    /// The site in the view.
+    site: Site<MergedNode<G, M>, G::PortLabel>,
+    /// The port at `site`, in the diff that owns its node.
+    port: Owned<Port<G>, G, M>,
+    /// The last edge that was traversed through a wire, if any.
+    last_edge: Option<MergedEdge<G, M>>,
+}
+
+impl<G: Graph, M: Clone> MergedView<G, M> {
+    /// Create a view of the graph obtained by merging `diffs`.
+    ///
+    /// Errors if `diffs` are not compatible.
+    pub fn new(
+        diffs: impl IntoIterator<Item = PortDiff<G, M>>,
+    ) -> Result<Self, IncompatiblePortDiff> {
+        let graph = PortDiff::try_merge(diffs)?;
+        let mut rewritten: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+        for child_id in graph.all_nodes() {
+            let child = graph.get_diff(child_id);
+            for (index, (parent, edge)) in child.incoming_edges().enumerate() {
+                let nodes = rewritten.entry(NodeId::from(&parent)).or_default();
+                for &node in edge.subgraph().nodes() {
+                    nodes.insert(node, (child_id, index));
+                }
+            }
+        }
+        Ok(Self { graph, rewritten })
+    }
+
+    /// The merged diffs, along with all their ancestors.
+    pub fn diff_graph(&self) -> &PortDiffGraph<G, M> {
+        &self.graph
+    }
+
+    /// Follow `port` one step down the diff hierarchy.
+    fn step(&self, port: &Owned<Port<G>, G, M>) -> Step<G, M> {
+        let Some(site) = port.site() else {
+            return Step::Deleted;
+        };
+        let diff = NodeId::from(&port.owner);
+        let Some(&(child_id, index)) = self.rewritten.get(&diff).and_then(|m| m.get(&site.node))
+        else {
+            let node = MergedNode {
+                diff,
+                node: site.node,
+            };
+            return Step::Site(Site {
+                node,
+                port: site.port,
+            });
+        };
+        let child = self.graph.get_diff(child_id);
+        let boundary = child
+            .incoming_edges()
+            .nth(index)
+            .and_then(|(_, edge)| edge.boundary_port_map().get_by_left(&port.data).copied());
+        match boundary {
+            Some(boundary) => Step::Child(Owned::new(boundary, child)),
+            None => Step::Deleted,
+        }
+    }
+
+    /// The node of a diff that `node` corresponds to.
+    pub fn owned_node(&self, node: MergedNode<G, M>) -> Owned<G::Node, G, M> {
+        Owned::new(node.node, self.graph.get_diff(node.diff))
+    }
+
+    /// The port of a diff that the end `port` of an edge of the view is
+    /// attached to.
+    ///
+    /// The port belongs to the diff that owns the node at `port`, so that
+    /// pairs of such ports can be passed to [`PortDiff::rewrite`].
+    pub(crate) fn owned_port(&self, port: BoundPort<MergedEdge<G, M>>) -> Owned<Port<G>, G, M> {
+        self.resolve(port).port
+    }
+
+    /// Resolve the end `port` of an edge of the view.
+    fn resolve(&self, BoundPort { edge, end }: BoundPort<MergedEdge<G, M>>) -> Resolved<G, M> {
+        let diff = self.graph.get_diff(edge.diff);
+        let port = Owned::new(
+            Port::Bound(BoundPort {
+                edge: edge.edge,
+                end,
+            }),
+            diff,
+        );
+        self.follow(port).expect("edge is not in the merged view")
+    }
+
+    /// Find the site in the view that `port` resolves to.
+    fn follow(&self, mut port: Owned<Port<G>, G, M>) -> Option<Resolved<G, M>> {
+        let mut last_edge = None;
+        loop {
+            let Owned {
+                data: boundary,
+                owner: child,
+            } = match self.step(&port) {
+                Step::Site(site) => {
+                    return Some(Resolved {
+                        site,
+                        port,
+                        last_edge,
+                    })
+                }
+                Step::Deleted => return None,
+                Step::Child(boundary) => boundary,
+            };
+            let wire = match child.boundary_site(boundary) {
+                BoundarySite::Site(_) => None,
+                BoundarySite::Wire { id, end } => Some((id, end)),
+            };
+            let Some((id, end)) = wire else {
+                port = Owned::new(Port::Boundary(boundary), child);
+                continue;
+            };
+            // Continue along the edge at the opposite end of the wire
+            let opposite = BoundarySite::wire(id, end.opposite());
+            let opp_boundary = child
+                .boundary_iter()
+                .find(|&b| child.boundary_site(b) == opposite)?;
+            let parent_port = child.parent_port(opp_boundary);
+            let Owned {
+                data: BoundPort { edge, end },
+                owner,
+            } = match parent_port.data {
+                Port::Bound(bound_port) => Owned::new(bound_port, parent_port.owner),
+                Port::Boundary(b) => parent_port.owner.bound_ancestor(b),
+            };
+            last_edge = Some(MergedEdge {
+                diff: NodeId::from(&owner),
+                edge,
+            });
+            let end = end.opposite();
+            port = Owned::new(Port::Bound(BoundPort { edge, end }), owner);
+        }
+    }
+
+    /// The port of the view that `port` of an edge of a diff belongs to.
+    ///
+    /// Returns `None` if the edge is deleted in the view.
+    fn merged_port(
+        &self,
+        port: Owned<BoundPort<G::Edge>, G, M>,
+    ) -> Option<BoundPort<MergedEdge<G, M>>> {
+        let Owned {
+            data: BoundPort { edge, end },
+            owner,
+        } = port;
+        let merged_edge = MergedEdge {
+            diff: NodeId::from(&owner),
+            edge,
+        };
+        let opposite = Owned::new(
+            Port::Bound(BoundPort {
+                edge,
+                end: end.opposite(),
+            }),
+            owner,
+        );
+        let Resolved { last_edge, .. } = self.follow(opposite)?;
+        let edge = match end {
+            EdgeEnd::Left => merged_edge,
+            // Edges joined through wires are identified by their left edge
+            EdgeEnd::Right => last_edge.unwrap_or(merged_edge),
+        };
+        Some(BoundPort { edge, end })
+    }
+
+    /// Whether `edge` is an edge of the view.
+    fn is_merged_edge(&self, edge: MergedEdge<G, M>) -> bool {
+        let diff = self.graph.get_diff(edge.diff);
+        let port = |end| {
+            Owned::new(
+                Port::Bound(BoundPort {
+                    edge: edge.edge,
+                    end,
+                }),
+                diff.clone(),
+            )
+        };
+        matches!(
+            self.follow(port(EdgeEnd::Left)),
+            Some(Resolved {
+                last_edge: None,
+                ..
+            })
+        ) && self.follow(port(EdgeEnd::Right)).is_some()
+    }
+}
+
+impl<G: Graph, M: Clone> Graph for MergedView<G, M> {
+    type Node = MergedNode<G, M>;
+
+    type Edge = MergedEdge<G, M>;
+
+    type PortLabel = G::PortLabel;
+
+    fn nodes_iter(&self) -> impl Iterator<Item = Self::Node> + '_ {
+        self.graph.all_nodes().flat_map(move |diff| {
+            let rewritten = self.rewritten.get(&diff);
+            let nodes = self.graph.get_diff(diff).graph().nodes_iter().collect_vec();
+            nodes
+                .into_iter()
+                .filter(move |n| !rewritten.is_some_and(|r| r.contains_key(n)))
+                .map(move |node| MergedNode { diff, node })
+        })
+    }
+
+    fn edges_iter(&self) -> impl Iterator<Item = Self::Edge> + '_ {
+        self.graph
+            .all_nodes()
+            .flat_map(move |diff| {
+                let edges = self.graph.get_diff(diff).graph().edges_iter().collect_vec();
+                edges.into_iter().map(move |edge| MergedEdge { diff, edge })
+            })
+            .filter(move |&edge| self.is_merged_edge(edge))
+    }
+
+    fn get_port_site(&self, port: BoundPort<Self::Edge>) -> Site<Self::Node, Self::PortLabel> {
+        self.resolve(port).site
+    }
+
+    fn get_bound_ports(
+        &self,
+        site: Site<Self::Node, Self::PortLabel>,
+    ) -> impl Iterator<Item = BoundPort<Self::Edge>> + '_ {
+        let diff = self.graph.get_diff(site.node.diff);
+        let site = Site {
+            node: site.node.node,
+            port: site.port,
+        };
+        let mut ports = Vec::new();
+        // Ports of edges of the diff
+        for port in diff.graph().get_bound_ports(site.clone()) {
+            ports.extend(self.merged_port(Owned::new(port, diff.clone())));
+        }
+        // Ports of edges of ancestors, at boundary ports of the diff
+        for boundary in diff.boundary_iter() {
+            if diff.boundary_site(boundary).try_into_site().ok().as_ref() == Some(&site) {
+                ports.extend(self.merged_port(diff.bound_ancestor(boundary)));
+            }
+        }
+        ports.into_iter()
+    }
+
+    fn get_sites(
+        &self,
+        node: Self::Node,
+    ) -> impl Iterator<Item = Site<Self::Node, Self::PortLabel>> + '_ {
+        let diff = self.graph.get_diff(node.diff);
+        let sites = diff.graph().get_sites(node.node).collect_vec();
+        sites.into_iter().map(move |site| Site {
+            node,
+            port: site.port,
+        })
+    }
+
+    fn link_sites(
+        &mut self,
+        _left: Site<Self::Node, Self::PortLabel>,
+        _right: Site<Self::Node, Self::PortLabel>,
+    ) {
+        panic!("MergedView is read-only")
+    }
+
+    fn add_subgraph(
+        &mut self,
+        _graph: &Self,
+        _nodes: &std::collections::BTreeSet<Self::Node>,
+    ) -> BTreeMap<Self::Node, Self::Node> {
+        panic!("MergedView is read-only")
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkView, NodeIndex, PortOffset, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_merged_view(parent_child_diffs: [TestPortDiff; 2]) {
+        let [_, child] = parent_child_diffs;
+        let view = MergedView::new([child.clone()]).unwrap();
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+
+        assert_eq!(view.nodes_iter().count(), extracted.node_count());
+        assert_eq!(view.edges_iter().count(), extracted.link_count());
+        for edge in view.edges_iter() {
+            for end in [EdgeEnd::Left, EdgeEnd::Right] {
+                let site = view.get_port_site(BoundPort { edge, end });
+                let ports = view.get_bound_ports(site).collect_vec();
+                assert_eq!(ports, vec![BoundPort { edge, end }]);
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_merged_view_wires(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, _] = parent_child_diffs;
+        let [n0, n1, n2, n3] = [0, 1, 2, 3].map(NodeIndex::new);
+        let no_b = parent.rewrite_node_to_wires(n1).unwrap();
+        let no_c = parent.rewrite_node_to_wires(n2).unwrap();
+        let view = MergedView::new([no_b, no_c]).unwrap();
+
+        let root = NodeId::from(&parent);
+        let nodes = view.nodes_iter().collect_vec();
+        assert_eq!(
+            nodes,
+            vec![
+                MergedNode {
+                    diff: root,
+                    node: n0
+                },
+                MergedNode {
+                    diff: root,
+                    node: n3
+                }
+            ]
+        );
+
+        // a -- [wire] -- [wire] -- d
+        let edge = view.edges_iter().exactly_one().ok().unwrap();
+        let left = view.get_port_site(BoundPort {
+            edge,
+            end: EdgeEnd::Left,
+        });
+        let right = view.get_port_site(BoundPort {
+            edge,
+            end: EdgeEnd::Right,
+        });
+        assert_eq!(left.node, nodes[0]);
+        assert_eq!(right.node, nodes[1]);
+        assert_eq!(right.port, PortOffset::Incoming(0));
+        assert_eq!(
+            view.get_bound_ports(right).collect_vec(),
+            vec![BoundPort {
+                edge,
+                end: EdgeEnd::Right
+            }]
+        );
+    }
+}
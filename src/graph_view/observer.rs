@@ -0,0 +1,113 @@
+//! Observers of the changes to a diff graph.
+
+use std::cell::RefCell;
+
+use crate::{Graph, PortDiff};
+
+use super::PortDiffGraph;
+
+/// The observer slot of a [`PortDiffGraph`].
+pub(super) type ObserverCell<G, M> = RefCell<Option<Box<dyn DiffObserver<G, M>>>>;
+
+/// Callbacks invoked when a [`PortDiffGraph`] changes.
+///
+/// Register an observer with [`PortDiffGraph::set_observer`] to keep
+/// application state, e.g. a map from nodes to the diffs that introduced them,
+/// in sync with the graph. All callbacks do nothing by default.
+pub trait DiffObserver<G: Graph, M = ()> {
+    /// Called for every diff added to the graph by a merge.
+    fn on_new_diff(&mut self, _diff: &PortDiff<G, M>) {}
+
+    /// Called after a merge, with the diffs it added to the graph.
+    ///
+    /// Called after [`Self::on_new_diff`] was called for each new diff.
+    fn on_merge(&mut self, _new_diffs: &[PortDiff<G, M>]) {}
+
+    /// Called after the graph was squashed into `squashed`.
+    fn on_squash(&mut self, _squashed: &PortDiff<G, M>) {}
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Register `observer`, to be notified of changes to the graph.
+    ///
+    /// Replaces the current observer, if any. Observers are not cloned: a
+    /// clone of the graph has no observer.
+    pub fn set_observer(&mut self, observer: Box<dyn DiffObserver<G, M>>) {
+        *self.1.get_mut() = Some(observer);
+    }
+
+    /// Remove the observer of the graph and return it.
+    pub fn take_observer(&mut self) -> Option<Box<dyn DiffObserver<G, M>>> {
+        self.1.get_mut().take()
+    }
+
+    /// Call `f` on the observer, if there is one.
+    pub(super) fn notify(&self, f: impl FnOnce(&mut dyn DiffObserver<G, M>)) {
+        if let Some(observer) = self.1.borrow_mut().as_mut() {
+            f(observer.as_mut());
+        }
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rstest::rstest;
+
+    use crate::graph_view::MergeStrategy;
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+    use crate::NodeId;
+
+    use super::*;
+
+    /// Records the events it is notified of.
+    #[derive(Default)]
+    struct Recorder {
+        new_diffs: Vec<NodeId<portgraph::PortGraph>>,
+        n_merges: usize,
+        n_squashes: usize,
+    }
+
+    impl DiffObserver<portgraph::PortGraph> for Rc<RefCell<Recorder>> {
+        fn on_new_diff(&mut self, diff: &TestPortDiff) {
+            self.borrow_mut().new_diffs.push(diff.into());
+        }
+
+        fn on_merge(&mut self, _new_diffs: &[TestPortDiff]) {
+            self.borrow_mut().n_merges += 1;
+        }
+
+        fn on_squash(&mut self, _squashed: &TestPortDiff) {
+            self.borrow_mut().n_squashes += 1;
+        }
+    }
+
+    #[rstest]
+    fn test_observer(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        let mut graph = PortDiffGraph::from_sinks([child_1]);
+        graph.set_observer(Box::new(recorder.clone()));
+
+        graph
+            .merge(
+                PortDiffGraph::from_sinks([child_2.clone()]),
+                MergeStrategy::IgnoreConflicts,
+            )
+            .unwrap();
+        assert_eq!(recorder.borrow().new_diffs, vec![NodeId::from(&child_2)]);
+        assert_eq!(recorder.borrow().n_merges, 1);
+
+        // Clones are not observed
+        graph.clone().try_squash().unwrap();
+        assert_eq!(recorder.borrow().n_squashes, 0);
+        graph.try_squash().unwrap();
+        assert_eq!(recorder.borrow().n_squashes, 1);
+
+        assert!(graph.take_observer().is_some());
+        graph.try_squash().unwrap();
+        assert_eq!(recorder.borrow().n_squashes, 1);
+    }
+}
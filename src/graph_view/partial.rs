@@ -0,0 +1,70 @@
+//! Extraction of a part of the diff hierarchy.
+
+use std::collections::BTreeSet;
+
+use crate::{port_diff::SquashError, BoundarySite, Graph, NodeId, PortDiff};
+
+use super::PortDiffGraph;
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Extract the graph of `diffs` only, without their ancestors.
+    ///
+    /// Unlike [`PortDiff::extract_graph`], the ancestors of `diffs` are not
+    /// included: the ports that would be linked to the graphs of the
+    /// ancestors are left dangling. They are returned as boundary sites,
+    /// either sites of the returned graph or wires between boundary ports.
+    /// This makes it possible to render a part of a rewrite history with its
+    /// open ports.
+    ///
+    /// Any diff between two of `diffs` in the hierarchy should also be in
+    /// `diffs`. Errors if `diffs` are not compatible.
+    pub fn extract_partial(
+        diffs: impl IntoIterator<Item = PortDiff<G, M>>,
+    ) -> Result<(G, Vec<BoundarySite<G>>), SquashError> {
+        let diffs: Vec<_> = diffs.into_iter().collect();
+        if diffs.is_empty() {
+            return Ok((G::default(), Vec::new()));
+        }
+        if !PortDiff::are_compatible(&diffs) {
+            return Err(SquashError::IncompatibleDiffs);
+        }
+        let selected: BTreeSet<NodeId<G, M>> = diffs.iter().map(NodeId::from).collect();
+        let graph = Self::from_sinks_while(diffs, |d| selected.contains(&NodeId::from(d)));
+        // The value of the squashed diff is discarded
+        let squashed = graph.try_squash_with(|values| values[0].clone())?;
+        let boundary = squashed
+            .boundary_iter()
+            .map(|b| squashed.boundary_site(b))
+            .collect();
+        Ok((squashed.graph().clone(), boundary))
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkView, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_extract_partial(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+
+        let (graph, boundary) = PortDiffGraph::extract_partial([child.clone()]).unwrap();
+        assert_eq!(graph.node_count(), child.graph().node_count());
+        assert_eq!(graph.link_count(), child.graph().link_count());
+        assert_eq!(boundary.len(), child.n_boundary_ports());
+        assert!(boundary.iter().all(|b| b.try_as_site_ref().is_some()));
+
+        // With the ancestors, there are no open ports
+        let (graph, boundary) = PortDiffGraph::extract_partial([parent, child.clone()]).unwrap();
+        let expected = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(graph.node_count(), expected.node_count());
+        assert_eq!(graph.link_count(), expected.link_count());
+        assert!(boundary.is_empty());
+    }
+}
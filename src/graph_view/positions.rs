@@ -0,0 +1,268 @@
+//! Node positions that are carried through rewrites and squashes.
+//!
+//! Graphical front-ends need the nodes of every diff to keep their
+//! coordinates as the hierarchy grows. A [`LayoutStore`] records positions
+//! per diff and node, and derives the positions of new nodes from the
+//! region they replace.
+
+use std::collections::BTreeMap;
+
+use derive_where::derive_where;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{port_diff::SquashError, ContentKey, Graph, NodeId, PortDiff};
+
+use super::PortDiffGraph;
+
+/// The position of a node in a drawing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Position {
+    /// Create a position at `(x, y)`.
+    pub fn new(x: f64, y: f64) -> Self {
+        Self { x, y }
+    }
+
+    /// The centroid of `positions`, or `None` if there are none.
+    fn centroid(positions: impl IntoIterator<Item = Position>) -> Option<Self> {
+        let (n, x, y) = positions
+            .into_iter()
+            .fold((0, 0., 0.), |(n, x, y), p| (n + 1, x + p.x, y + p.y));
+        (n > 0).then(|| Self::new(x / n as f64, y / n as f64))
+    }
+}
+
+/// The positions of the nodes of diffs.
+///
+/// Positions are keyed by diff and node of its replacement graph. Diffs are
+/// identified by [`NodeId`], so positions should be removed with
+/// [`LayoutStore::remove`] once a diff is dropped.
+#[derive_where(Clone, Default; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: std::fmt::Debug)]
+pub struct LayoutStore<G: Graph, M = ()> {
+    positions: BTreeMap<NodeId<G, M>, BTreeMap<G::Node, Position>>,
+}
+
+/// A [`LayoutStore`] in serializable form.
+///
+/// Diffs are given by their position in the [`StablePortDiffGraph`] of the
+/// hierarchy, so that the layout can be saved alongside it.
+///
+/// [`StablePortDiffGraph`]: super::StablePortDiffGraph
+#[derive(Serialize, Deserialize)]
+#[derive_where(Clone, Default; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: std::fmt::Debug)]
+#[serde(bound(
+    serialize = "G::Node: Serialize",
+    deserialize = "G::Node: Deserialize<'de>"
+))]
+pub struct SerialLayout<G: Graph> {
+    diffs: BTreeMap<usize, Vec<(G::Node, Position)>>,
+}
+
+impl<G: Graph, M: Clone> LayoutStore<G, M> {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the position of `node` in `diff`.
+    pub fn set(&mut self, diff: &PortDiff<G, M>, node: G::Node, position: Position) {
+        self.positions
+            .entry(NodeId::from(diff))
+            .or_default()
+            .insert(node, position);
+    }
+
+    /// The position of `node` in `diff`, if any.
+    pub fn get(&self, diff: &PortDiff<G, M>, node: G::Node) -> Option<Position> {
+        self.positions.get(&NodeId::from(diff))?.get(&node).copied()
+    }
+
+    /// Remove the positions of the nodes of `diff`.
+    pub fn remove(&mut self, diff: &PortDiff<G, M>) {
+        self.positions.remove(&NodeId::from(diff));
+    }
+
+    /// Set the positions of the nodes of `diff` that have none, from the
+    /// positions of the nodes they replace.
+    ///
+    /// A node with boundary ports is placed at the centroid of the nodes of
+    /// the parent ports. Other nodes are placed at the centroid of all nodes
+    /// rewritten by `diff`. Nodes are left without position if none of these
+    /// nodes have one. Call after every rewrite, parents first.
+    pub fn inherit(&mut self, diff: &PortDiff<G, M>) {
+        let mut anchors: BTreeMap<G::Node, Vec<Position>> = BTreeMap::new();
+        for boundary in diff.boundary_iter() {
+            let Ok(site) = diff.boundary_site(boundary).try_into_site() else {
+                continue;
+            };
+            let parent_port = diff.parent_port(boundary);
+            let parent_position = parent_port
+                .site()
+                .and_then(|s| self.get(&parent_port.owner, s.node));
+            anchors
+                .entry(site.node)
+                .or_default()
+                .extend(parent_position);
+        }
+        let rewritten = diff
+            .incoming_edges()
+            .flat_map(|(parent, edge)| {
+                let nodes = edge.subgraph().nodes().iter();
+                nodes.filter_map(|&n| self.get(&parent, n)).collect_vec()
+            })
+            .collect_vec();
+        let fallback = Position::centroid(rewritten);
+
+        let new_positions = diff
+            .graph()
+            .nodes_iter()
+            .filter(|&n| self.get(diff, n).is_none())
+            .filter_map(|n| {
+                let anchors = anchors.remove(&n).unwrap_or_default();
+                Some((n, Position::centroid(anchors).or(fallback)?))
+            })
+            .collect_vec();
+        for (n, position) in new_positions {
+            self.set(diff, n, position);
+        }
+    }
+
+    /// Squash `graph` into a single diff, see [`PortDiffGraph::try_squash`].
+    ///
+    /// The nodes of the squashed diff keep the positions they have in the
+    /// diffs of `graph`.
+    pub fn try_squash(&mut self, graph: &PortDiffGraph<G, M>) -> Result<PortDiff<G, M>, SquashError>
+    where
+        M: Default,
+    {
+        if !graph.is_squashable() {
+            return Err(SquashError::IncompatibleDiffs);
+        }
+        let (squashed, nodes_map) = PortDiff::squash_with_map(graph)?;
+        graph.notify(|observer| observer.on_squash(&squashed));
+        for (diff, map) in nodes_map {
+            let diff = graph.get_diff(diff);
+            for (old, new) in map {
+                if let Some(position) = self.get(&diff, old) {
+                    self.set(&squashed, new, position);
+                }
+            }
+        }
+        Ok(squashed)
+    }
+
+    /// Extract the graph of `diffs`, see [`PortDiff::extract_graph`], along
+    /// with the positions of its nodes.
+    ///
+    /// Nodes keep the positions they have in the diffs that introduced them.
+    #[allow(clippy::type_complexity)]
+    pub fn extract_graph(
+        &self,
+        diffs: Vec<PortDiff<G, M>>,
+    ) -> Result<(G, BTreeMap<G::Node, Position>), SquashError> {
+        let graph = PortDiff::try_merge(diffs)?;
+        let (extracted, nodes_map) = PortDiff::squash_graph_with_map(&graph)?;
+        let positions = nodes_map
+            .into_iter()
+            .flat_map(|(diff, map)| {
+                let diff = graph.get_diff(diff);
+                map.into_iter()
+                    .filter_map(move |(old, new)| Some((new, self.get(&diff, old)?)))
+            })
+            .collect();
+        Ok((extracted, positions))
+    }
+
+    /// Convert to the serializable form, for the diffs of `graph`.
+    ///
+    /// Positions of diffs that are not in `graph` are dropped.
+    pub fn to_serial(&self, graph: &PortDiffGraph<G, M>) -> SerialLayout<G>
+    where
+        G: ContentKey,
+        M: Ord,
+    {
+        let (_, indices) = graph.to_stable_with_indices();
+        let diffs = self
+            .positions
+            .iter()
+            .filter_map(|(diff, positions)| {
+                let positions = positions.iter().map(|(&n, &p)| (n, p)).collect();
+                Some((*indices.get(diff)?, positions))
+            })
+            .collect();
+        SerialLayout { diffs }
+    }
+
+    /// Load the positions of the diffs of `graph` from the serializable form.
+    ///
+    /// `graph` must be the hierarchy that the layout was serialized with,
+    /// e.g. loaded with [`PortDiffGraph::from_stable`].
+    pub fn from_serial(serial: &SerialLayout<G>, graph: &PortDiffGraph<G, M>) -> Self
+    where
+        G: ContentKey,
+        M: Ord,
+    {
+        let (_, indices) = graph.to_stable_with_indices();
+        let diffs: BTreeMap<usize, NodeId<G, M>> =
+            indices.into_iter().map(|(n, i)| (i, n)).collect();
+        let positions = serial
+            .diffs
+            .iter()
+            .filter_map(|(i, positions)| {
+                Some((*diffs.get(i)?, positions.iter().copied().collect()))
+            })
+            .collect();
+        Self { positions }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_graph::{SimpleGraph, SimpleNode};
+
+    use super::*;
+
+    #[test]
+    fn test_layout_store() {
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let mut layout = LayoutStore::new();
+        for i in 0..3 {
+            layout.set(&root, SimpleNode::new(i), Position::new(i as f64, 0.));
+        }
+
+        let child = root.select_subgraph([SimpleNode::new(1)]);
+        layout.inherit(&child);
+        let [node] = child.graph().nodes_iter().collect_vec().try_into().unwrap();
+        assert_eq!(layout.get(&child, node), Some(Position::new(1., 0.)));
+
+        let (extracted, positions) = layout.extract_graph(vec![child.clone()]).unwrap();
+        assert_eq!(positions.len(), extracted.nodes_iter().count());
+        let xs = positions.values().map(|p| p.x).sorted_by(f64::total_cmp);
+        assert_eq!(xs.collect_vec(), vec![0., 1., 2.]);
+
+        let graph = PortDiffGraph::from_sinks([child.clone()]);
+        let squashed = layout.try_squash(&graph).unwrap();
+        assert!(squashed
+            .graph()
+            .nodes_iter()
+            .all(|n| layout.get(&squashed, n).is_some()));
+
+        let serial = layout.to_serial(&graph);
+        let json = serde_json::to_string(&serial).unwrap();
+        let serial: SerialLayout<SimpleGraph> = serde_json::from_str(&json).unwrap();
+        let loaded = PortDiffGraph::from_stable(graph.to_stable()).unwrap();
+        let loaded_layout = LayoutStore::from_serial(&serial, &loaded);
+        let [loaded_child] = loaded.sinks().collect_vec().try_into().unwrap();
+        assert_eq!(
+            loaded_layout.get(&loaded_child, node),
+            Some(Position::new(1., 0.))
+        );
+    }
+}
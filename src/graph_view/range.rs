@@ -0,0 +1,127 @@
+//! Squashing a range of the diff hierarchy.
+
+use std::collections::BTreeSet;
+
+use derive_where::derive_where;
+use thiserror::Error;
+
+use crate::{
+    port_diff::{SquashError, TraversalDirection},
+    Graph, NodeId, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+/// Error when squashing a range of diffs.
+#[derive(Error)]
+#[derive_where(Debug; G: Graph)]
+pub enum SquashRangeError<G: Graph, M = ()> {
+    /// A diff at the end of the range is not a strict descendant of the
+    /// diffs at the start of the range.
+    #[error("diff {0:?} is not a descendant of the start of the range")]
+    NotInRange(NodeId<G, M>),
+    /// The diffs in the range cannot be squashed.
+    #[error("the diffs in the range cannot be squashed: {0}")]
+    Squash(#[from] SquashError),
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Squash the diffs between the ancestors `from` and the descendants `to`
+    /// into a single equivalent diff.
+    ///
+    /// The range is made of all diffs that are strict descendants of `from`
+    /// and ancestors of `to`, including `to` itself. The new diff is a child
+    /// of the parents of the range, i.e. of `from` and of any other diff
+    /// outside of the range that a diff in the range descends from. This
+    /// compacts deep histories without flattening them to the root.
+    ///
+    /// The diffs in the range are left unchanged, and so are their
+    /// descendants. The value of the squashed diff is `M::default()`, see
+    /// [`Self::squash_range_with`] to combine the values of the range.
+    pub fn squash_range(
+        &self,
+        from: &[NodeId<G, M>],
+        to: &[NodeId<G, M>],
+    ) -> Result<PortDiff<G, M>, SquashRangeError<G, M>>
+    where
+        M: Default,
+    {
+        self.range(from, to)?.try_squash().map_err(Into::into)
+    }
+
+    /// Squash the diffs between the ancestors `from` and the descendants `to`,
+    /// combining their values with `aggregate`.
+    ///
+    /// See [`Self::squash_range`] and [`Self::try_squash_with`].
+    pub fn squash_range_with(
+        &self,
+        from: &[NodeId<G, M>],
+        to: &[NodeId<G, M>],
+        aggregate: impl FnOnce(&[M]) -> M,
+    ) -> Result<PortDiff<G, M>, SquashRangeError<G, M>> {
+        self.range(from, to)?
+            .try_squash_with(aggregate)
+            .map_err(Into::into)
+    }
+
+    /// The graph of the diffs between `from` and `to`.
+    fn range(
+        &self,
+        from: &[NodeId<G, M>],
+        to: &[NodeId<G, M>],
+    ) -> Result<Self, SquashRangeError<G, M>> {
+        let descendants: BTreeSet<NodeId<G, M>> = self
+            .traverse(from.iter().copied(), TraversalDirection::Descendants)
+            .map(|d| NodeId::from(&d))
+            .filter(|n| !from.contains(n))
+            .collect();
+        if let Some(&n) = to.iter().find(|n| !descendants.contains(n)) {
+            return Err(SquashRangeError::NotInRange(n));
+        }
+        let ancestors: BTreeSet<NodeId<G, M>> = self
+            .traverse(to.iter().copied(), TraversalDirection::Ancestors)
+            .map(|d| NodeId::from(&d))
+            .collect();
+        Ok(Self::from_sinks_while(
+            to.iter().map(|&n| self.get_diff(n)),
+            |d| {
+                let n = NodeId::from(d);
+                ancestors.contains(&n) && descendants.contains(&n)
+            },
+        ))
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use portgraph::{LinkView, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_squash_range(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let graph = PortDiffGraph::from_sinks([child.clone()]);
+        let parent_id = NodeId::from(&parent);
+        let child_id = NodeId::from(&child);
+
+        let squashed = graph.squash_range(&[parent_id], &[child_id]).unwrap();
+        let parents = squashed.incoming_edges().map(|(p, _)| p).collect_vec();
+        assert_eq!(parents, vec![parent.clone()]);
+
+        let expected = PortDiff::extract_graph(vec![child]).unwrap();
+        let extracted = PortDiff::extract_graph(vec![squashed]).unwrap();
+        assert_eq!(extracted.node_count(), expected.node_count());
+        assert_eq!(extracted.link_count(), expected.link_count());
+
+        assert!(matches!(
+            graph.squash_range(&[child_id], &[parent_id]),
+            Err(SquashRangeError::NotInRange(n)) if n == parent_id
+        ));
+    }
+}
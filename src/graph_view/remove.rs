@@ -0,0 +1,97 @@
+//! Removing diffs from a `PortDiffGraph`.
+
+use derive_where::derive_where;
+use thiserror::Error;
+
+use crate::{Graph, NodeId, PortDiff};
+
+use super::PortDiffGraph;
+
+/// Error when removing a diff from a `PortDiffGraph`.
+///
+/// Lists the descendants of the removed diff that could not be rebased onto
+/// its parents.
+#[derive(Error)]
+#[derive_where(Debug; G: Graph)]
+#[error("{} descendants cannot be rebased", not_rebased.len())]
+pub struct RemoveDiffError<G: Graph, M = ()> {
+    /// The descendants that depend on the removed diff.
+    pub not_rebased: Vec<PortDiff<G, M>>,
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Remove the diff `id` from the graph.
+    ///
+    /// The descendants of the removed diff are rebased onto its parents, as
+    /// in [`Self::filter`]: the edges from the parents of the removed diff
+    /// are composed with the edges to its children. This is only possible
+    /// for children that rewrite all nodes and boundary ports of the removed
+    /// diff, or none of them. Otherwise an error listing all descendants that
+    /// cannot be rebased is returned and the graph is left unchanged.
+    ///
+    /// Branch tips at the removed diff are dropped from their branch.
+    pub fn remove_diff(&mut self, id: NodeId<G, M>) -> Result<(), RemoveDiffError<G, M>> {
+        let removed = self.get_diff(id);
+        let (graph, not_rebased) = self.filter_with_dependents(|d| d != &removed);
+        if !not_rebased.is_empty() {
+            return Err(RemoveDiffError { not_rebased });
+        }
+        *self.inner_mut() = graph.0;
+        self.2 = graph.2;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rstest::rstest;
+
+    use crate::{
+        iso::are_isomorphic,
+        port_diff::tests::{parent_child_diffs, TestPortDiff},
+        simple_graph::{SimpleGraph, SimpleNode},
+    };
+
+    use super::*;
+
+    #[rstest]
+    fn test_remove_sink(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let mut graph = PortDiffGraph::from_sinks([child.clone()]);
+        graph.remove_diff((&child).into()).unwrap();
+        assert_eq!(graph.all_nodes().count(), 1);
+        assert_eq!(graph.sinks().collect_vec(), vec![parent]);
+    }
+
+    #[rstest]
+    fn test_remove_with_dependent(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let mut graph = PortDiffGraph::from_sinks([child.clone()]);
+        let err = graph.remove_diff((&parent).into()).unwrap_err();
+        assert_eq!(err.not_rebased, vec![child]);
+        assert_eq!(graph.all_nodes().count(), 2);
+    }
+
+    #[test]
+    fn test_remove_rebase() {
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let explored = root.select_subgraph([0, 1].map(SimpleNode::new));
+        // Rewrites all of `explored`
+        let full = explored.select_subgraph(explored.graph().nodes_iter().collect_vec());
+        let mut graph = PortDiffGraph::from_sinks([full.clone()]);
+        graph.create_branch("explored", &explored).unwrap();
+
+        graph.remove_diff((&explored).into()).unwrap();
+        assert_eq!(graph.all_nodes().count(), 2);
+        let [new_full] = graph.sinks().collect_vec().try_into().unwrap();
+        assert_eq!(new_full.all_parents().collect_vec(), vec![root]);
+        assert!(graph.branch("explored").unwrap().is_empty());
+        assert!(graph.validate().is_ok());
+
+        let expected = PortDiff::extract_graph(vec![full]).unwrap();
+        let extracted = PortDiff::extract_graph(vec![new_full]).unwrap();
+        assert!(are_isomorphic(&extracted, &expected));
+    }
+}
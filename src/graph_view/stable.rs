@@ -0,0 +1,329 @@
+//! A stable, versioned serialization format for `PortDiffGraph`.
+//!
+//! Diffs are listed in topological order, ties being broken by comparing
+//! the content of the diffs. Parents are referred to by their position in
+//! the list. The output is thus independent of where the diffs live in
+//! memory.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use derive_where::derive_where;
+use itertools::Itertools;
+use petgraph::visit::{EdgeRef, IntoEdges};
+use relrc::RelRc;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    graph::GraphContent,
+    port::{BoundaryIndex, Port},
+    port_diff::{EdgeData, IncomingEdgeIndex, PortDiffData},
+    BoundarySite, ContentKey, Graph, NodeId, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+/// The current version of the stable serialization format.
+pub const STABLE_FORMAT_VERSION: u32 = 4;
+
+/// A `PortDiffGraph` in stable serialization format.
+///
+/// Obtained with [`PortDiffGraph::to_stable`] and converted back with
+/// [`PortDiffGraph::from_stable`].
+#[derive(Serialize, Deserialize)]
+#[derive_where(Clone; G: Graph, M: Clone)]
+#[serde(bound(
+    serialize = "G: Serialize, G::Node: Serialize, G::PortLabel: Serialize, G::Edge: Serialize, M: Serialize",
+    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>, G::Edge: Deserialize<'de>, M: Deserialize<'de>"
+))]
+pub struct StablePortDiffGraph<G: Graph, M = ()> {
+    version: u32,
+    diffs: Vec<StableDiff<G, M>>,
+    /// The tips of every branch, given by their position in the list of
+    /// diffs.
+    #[serde(default)]
+    branches: BTreeMap<String, Vec<usize>>,
+}
+
+/// A diff along with its incoming edges.
+#[derive(Serialize, Deserialize)]
+#[derive_where(Clone; G: Graph, M: Clone)]
+#[serde(bound(
+    serialize = "G: Serialize, G::Node: Serialize, G::PortLabel: Serialize, G::Edge: Serialize, M: Serialize",
+    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>, G::Edge: Deserialize<'de>, M: Deserialize<'de>"
+))]
+struct StableDiff<G: Graph, M> {
+    data: PortDiffData<G, M>,
+    /// The incoming edges, given by the position of the parent in the list
+    /// of diffs and the edge data.
+    incoming: Vec<(usize, EdgeData<G>)>,
+}
+
+/// Errors when loading a `StablePortDiffGraph`.
+#[derive(Error, Debug)]
+pub enum StableFormatError {
+    #[error("Unsupported format version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Diff {0} refers to a parent that is not listed before it")]
+    InvalidParent(usize),
+    #[error("Branch {0} refers to a diff that is not listed")]
+    InvalidBranch(String),
+    #[error("Invalid diff graph: {}", .0.join("; "))]
+    InvalidDiffs(Vec<String>),
+}
+
+impl<G: Graph, M: Clone> StablePortDiffGraph<G, M> {
+    /// The format version of the serialized data.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The number of diffs.
+    pub fn n_diffs(&self) -> usize {
+        self.diffs.len()
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Convert to the stable serialization format.
+    ///
+    /// All ancestors of the diffs in `self` must be in `self`.
+    pub fn to_stable(&self) -> StablePortDiffGraph<G, M>
+    where
+        G: ContentKey,
+        M: Ord,
+    {
+        self.to_stable_with_indices().0
+    }
+
+    /// Convert to the stable serialization format, along with the position
+    /// of every diff in the list of diffs.
+    pub(crate) fn to_stable_with_indices(
+        &self,
+    ) -> (StablePortDiffGraph<G, M>, BTreeMap<NodeId<G, M>, usize>)
+    where
+        G: ContentKey,
+        M: Ord,
+    {
+        // Count the incoming edges of every diff within the graph
+        let mut n_incoming: BTreeMap<NodeId<G, M>, usize> =
+            self.all_nodes().map(|n| (n, 0)).collect();
+        for n in self.all_nodes() {
+            for edge in self.inner().edges(n.into()) {
+                *n_incoming.get_mut(&NodeId::from(edge.target())).unwrap() += 1;
+            }
+        }
+
+        let mut indices = BTreeMap::new();
+        let mut ready = BTreeSet::new();
+        for (&n, _) in n_incoming.iter().filter(|(_, cnt)| **cnt == 0) {
+            ready.insert((DiffKey::new(&self.get_diff(n), &indices), n));
+        }
+
+        let mut diffs = Vec::with_capacity(n_incoming.len());
+        while let Some((_, n)) = ready.pop_first() {
+            let diff = self.get_diff(n);
+            let incoming = diff
+                .all_incoming()
+                .iter()
+                .map(|e| {
+                    let parent: PortDiff<G, M> = e.source().clone().into();
+                    let parent_index = indices[&NodeId::from(&parent)];
+                    (parent_index, e.value().clone())
+                })
+                .collect();
+            indices.insert(n, diffs.len());
+            diffs.push(StableDiff {
+                data: PortDiffData::clone(&diff),
+                incoming,
+            });
+            for edge in self.inner().edges(n.into()) {
+                let child = NodeId::from(edge.target());
+                let cnt = n_incoming.get_mut(&child).unwrap();
+                *cnt -= 1;
+                if *cnt == 0 {
+                    ready.insert((DiffKey::new(&self.get_diff(child), &indices), child));
+                }
+            }
+        }
+
+        let branches = self
+            .branch_tips()
+            .map(|(name, tips)| {
+                let tips = tips
+                    .iter()
+                    .filter_map(|tip| indices.get(&NodeId::from(tip)).copied());
+                (name.to_string(), tips.collect())
+            })
+            .collect();
+        let stable = StablePortDiffGraph {
+            version: STABLE_FORMAT_VERSION,
+            diffs,
+            branches,
+        };
+        (stable, indices)
+    }
+
+    /// Load a graph from the stable serialization format.
+    ///
+    /// The diffs are validated, see [`PortDiffGraph::validate`].
+    pub fn from_stable(stable: StablePortDiffGraph<G, M>) -> Result<Self, StableFormatError> {
+        Ok(Self::from_stable_with_diffs(stable)?.0)
+    }
+
+    /// Load a graph from the stable serialization format, along with its
+    /// diffs in the order in which they are listed.
+    pub(crate) fn from_stable_with_diffs(
+        stable: StablePortDiffGraph<G, M>,
+    ) -> Result<(Self, Vec<PortDiff<G, M>>), StableFormatError> {
+        if stable.version != STABLE_FORMAT_VERSION {
+            return Err(StableFormatError::UnsupportedVersion(stable.version));
+        }
+        let mut diffs: Vec<PortDiff<G, M>> = Vec::with_capacity(stable.diffs.len());
+        for (i, StableDiff { data, incoming }) in stable.diffs.into_iter().enumerate() {
+            let parents: Vec<_> = incoming
+                .into_iter()
+                .map(|(parent, edge)| {
+                    let parent = diffs
+                        .get(parent)
+                        .ok_or(StableFormatError::InvalidParent(i))?;
+                    Ok((parent.data.clone(), edge))
+                })
+                .collect::<Result<_, StableFormatError>>()?;
+            diffs.push(RelRc::with_parents(data, parents).into());
+        }
+        let sinks = diffs
+            .iter()
+            .filter(|d| !d.has_any_descendants())
+            .cloned()
+            .collect_vec();
+        let mut graph = Self::from_sinks(sinks);
+        for (name, tips) in stable.branches {
+            let tips = tips
+                .iter()
+                .map(|&i| diffs.get(i).map(PortDiff::downgrade))
+                .collect::<Option<_>>()
+                .ok_or_else(|| StableFormatError::InvalidBranch(name.clone()))?;
+            graph.2.insert(name, tips);
+        }
+        if let Err(errors) = graph.validate() {
+            let errors = errors.iter().map(|e| e.to_string()).collect();
+            return Err(StableFormatError::InvalidDiffs(errors));
+        }
+        Ok((graph, diffs))
+    }
+}
+
+/// A sort key for diffs that only depends on their content and the position
+/// of their parents.
+#[derive_where(PartialEq, Eq, PartialOrd, Ord; G: ContentKey, M: Ord)]
+struct DiffKey<G: ContentKey, M> {
+    incoming: Vec<(
+        usize,
+        Vec<G::Node>,
+        Vec<G::Edge>,
+        Vec<(Port<G>, BoundaryIndex)>,
+    )>,
+    boundary: Vec<(BoundarySite<G>, IncomingEdgeIndex)>,
+    value: M,
+    key: Option<String>,
+    content: GraphContent<G>,
+}
+
+impl<G: ContentKey, M: Clone> DiffKey<G, M> {
+    /// The key of `diff`, given the positions of its parents.
+    fn new(diff: &PortDiff<G, M>, indices: &BTreeMap<NodeId<G, M>, usize>) -> Self {
+        let incoming = diff
+            .all_incoming()
+            .iter()
+            .map(|e| {
+                let parent: PortDiff<G, M> = e.source().clone().into();
+                let edge = e.value();
+                (
+                    indices[&NodeId::from(&parent)],
+                    edge.subgraph().nodes().iter().copied().collect(),
+                    edge.subgraph().edges().iter().copied().collect(),
+                    edge.port_map.iter().map(|(p, &b)| (p.clone(), b)).collect(),
+                )
+            })
+            .collect();
+        Self {
+            incoming,
+            boundary: diff.boundary.iter().collect(),
+            value: diff.value.clone(),
+            key: diff.key.clone(),
+            content: GraphContent::new(diff.graph()),
+        }
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::PortView;
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    fn serialize_two_children() -> String {
+        let [_, child_1, child_2] = parent_two_children_diffs();
+        let graph = PortDiffGraph::from_sinks([child_1, child_2]);
+        serde_json::to_string(&graph.to_stable()).unwrap()
+    }
+
+    #[test]
+    fn stable_serialization_is_deterministic() {
+        assert_eq!(serialize_two_children(), serialize_two_children());
+    }
+
+    #[rstest]
+    fn stable_roundtrip(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let mut graph = PortDiffGraph::from_sinks([child_1.clone(), child_2]);
+        graph.create_branch("main", &child_1).unwrap();
+        let stable = graph.to_stable();
+        assert_eq!(stable.version(), STABLE_FORMAT_VERSION);
+        assert_eq!(stable.n_diffs(), 3);
+
+        let serialized = serde_json::to_string(&stable).unwrap();
+        let deserialized: StablePortDiffGraph<_> = serde_json::from_str(&serialized).unwrap();
+        let loaded = PortDiffGraph::from_stable(deserialized).unwrap();
+        assert_eq!(loaded.all_nodes().count(), 3);
+        assert_eq!(loaded.sinks().count(), 2);
+        let tips = loaded.branch("main").unwrap();
+        assert_eq!(tips.len(), 1);
+        assert_eq!(tips[0].graph().node_count(), child_1.graph().node_count());
+        assert_eq!(
+            serde_json::to_string(&loaded.to_stable()).unwrap(),
+            serialized
+        );
+    }
+
+    #[test]
+    fn stable_unsupported_version() {
+        let stable = StablePortDiffGraph::<portgraph::PortGraph> {
+            version: STABLE_FORMAT_VERSION + 1,
+            diffs: vec![],
+            branches: BTreeMap::new(),
+        };
+        assert!(matches!(
+            PortDiffGraph::from_stable(stable),
+            Err(StableFormatError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[rstest]
+    fn stable_invalid_diffs(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let mut stable = PortDiffGraph::from_sinks([child_1, child_2]).to_stable();
+        // Corrupt the incoming edge of the first boundary port of a child
+        let child = stable.diffs.last_mut().unwrap();
+        child.data.boundary.set_edge(0, IncomingEdgeIndex::from(5));
+        assert!(matches!(
+            PortDiffGraph::from_stable(stable),
+            Err(StableFormatError::InvalidDiffs(_))
+        ));
+    }
+}
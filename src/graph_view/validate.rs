@@ -0,0 +1,243 @@
+//! Validation of the diffs in a `PortDiffGraph`.
+
+use std::collections::BTreeSet;
+
+use derive_where::derive_where;
+use relrc::RelRcGraph;
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::{
+    port::BoundaryIndex,
+    port_diff::{EdgeData, IncomingEdgeIndex, PortDiffData},
+    Graph, NodeId, Port, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+/// An inconsistency in the data of a diff.
+///
+/// `diff` is the diff whose data is invalid and `edge` the index of one of
+/// its incoming edges.
+#[derive(Error)]
+#[derive_where(Debug; G: Graph)]
+pub enum ValidationError<G: Graph, M = ()> {
+    /// A boundary port refers to an incoming edge that does not exist.
+    #[error("boundary port {boundary:?} refers to missing incoming edge {edge:?}")]
+    InvalidIncomingEdge {
+        diff: NodeId<G, M>,
+        boundary: BoundaryIndex,
+        edge: IncomingEdgeIndex,
+    },
+    /// A boundary port is not in the port map of its incoming edge.
+    #[error("boundary port {boundary:?} is not in the port map of incoming edge {edge:?}")]
+    UnmappedBoundaryPort {
+        diff: NodeId<G, M>,
+        boundary: BoundaryIndex,
+        edge: IncomingEdgeIndex,
+    },
+    /// The port map of an incoming edge maps to a boundary port that does
+    /// not exist or that belongs to a different incoming edge.
+    #[error("port map of incoming edge {edge:?} refers to invalid boundary port {boundary:?}")]
+    InvalidPortMapBoundary {
+        diff: NodeId<G, M>,
+        edge: IncomingEdgeIndex,
+        boundary: BoundaryIndex,
+    },
+    /// The subgraph of an incoming edge contains nodes or edges that are not
+    /// in the parent graph.
+    #[error("subgraph of incoming edge {edge:?} is not a subgraph of the parent")]
+    MissingSubgraphElement {
+        diff: NodeId<G, M>,
+        edge: IncomingEdgeIndex,
+    },
+    /// The port map of an incoming edge contains a parent port that is not
+    /// at the boundary of the subgraph.
+    #[error("port map of incoming edge {edge:?} is not on the subgraph boundary")]
+    InconsistentPortMap {
+        diff: NodeId<G, M>,
+        edge: IncomingEdgeIndex,
+    },
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Check the consistency of the data of all diffs in the graph.
+    ///
+    /// Checks that boundary indices are in range, that the port maps of the
+    /// incoming edges are consistent with the boundaries of the diffs and of
+    /// the rewritten subgraphs, and that the subgraphs are present in the
+    /// parent graphs.
+    ///
+    /// This is performed automatically on deserialization, as invalid data
+    /// would otherwise cause panics when squashing.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError<G, M>>> {
+        let mut errors = Vec::new();
+        for n in self.all_nodes() {
+            validate_diff(&self.get_diff(n), &mut errors);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+pub(super) fn validate_diff<G: Graph, M: Clone>(
+    diff: &PortDiff<G, M>,
+    errors: &mut Vec<ValidationError<G, M>>,
+) {
+    let id = NodeId::from(diff);
+    let edges: Vec<_> = diff.incoming_edges().collect();
+
+    for (boundary, (_, edge)) in diff.boundary.iter().enumerate() {
+        let boundary = BoundaryIndex::from(boundary);
+        let Some((_, edge_data)) = edges.get(usize::from(edge)) else {
+            errors.push(ValidationError::InvalidIncomingEdge {
+                diff: id,
+                boundary,
+                edge,
+            });
+            continue;
+        };
+        if edge_data
+            .boundary_port_map()
+            .get_by_right(&boundary)
+            .is_none()
+        {
+            errors.push(ValidationError::UnmappedBoundaryPort {
+                diff: id,
+                boundary,
+                edge,
+            });
+        }
+    }
+
+    for (index, (parent, edge_data)) in edges.into_iter().enumerate() {
+        let edge = IncomingEdgeIndex::from(index);
+        for (_, &boundary) in edge_data.boundary_port_map() {
+            let is_valid = diff.boundary.edge(usize::from(boundary)) == Some(edge);
+            if !is_valid {
+                errors.push(ValidationError::InvalidPortMapBoundary {
+                    diff: id,
+                    edge,
+                    boundary,
+                });
+            }
+        }
+        if let Some(error) = validate_edge(&parent, edge_data, id, edge) {
+            errors.push(error);
+        }
+    }
+}
+
+/// Check the subgraph and port map of an incoming edge against the parent.
+fn validate_edge<G: Graph, M: Clone>(
+    parent: &PortDiff<G, M>,
+    edge_data: &EdgeData<G>,
+    diff: NodeId<G, M>,
+    edge: IncomingEdgeIndex,
+) -> Option<ValidationError<G, M>> {
+    let subgraph = edge_data.subgraph();
+    let graph = parent.graph();
+    let nodes: BTreeSet<_> = graph.nodes_iter().collect();
+    let edges: BTreeSet<_> = graph.edges_iter().collect();
+    if !subgraph.nodes().is_subset(&nodes) || !subgraph.edges().is_subset(&edges) {
+        return Some(ValidationError::MissingSubgraphElement { diff, edge });
+    }
+
+    let subgraph_boundary: BTreeSet<_> = subgraph.boundary(graph).collect();
+    let is_consistent = edge_data
+        .boundary_port_map()
+        .left_values()
+        .all(|port| match *port {
+            Port::Bound(port) => subgraph_boundary.contains(&port),
+            Port::Boundary(boundary) => {
+                usize::from(boundary) < parent.n_boundary_ports()
+                    && parent
+                        .boundary_site(boundary)
+                        .try_into_site()
+                        .is_ok_and(|site| subgraph.nodes().contains(&site.node))
+            }
+        });
+    if !is_consistent {
+        return Some(ValidationError::InconsistentPortMap { diff, edge });
+    }
+    None
+}
+
+/// The serialized form of a `PortDiffGraph`, before validation.
+#[derive(Deserialize)]
+#[serde(rename = "PortDiffGraph")]
+#[serde(bound(
+    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>, G::Edge: Deserialize<'de>, M: Deserialize<'de>"
+))]
+struct UncheckedPortDiffGraph<G: Graph, M>(RelRcGraph<PortDiffData<G, M>, EdgeData<G>>);
+
+impl<'de, G: Graph, M: Clone> Deserialize<'de> for PortDiffGraph<G, M>
+where
+    G: Deserialize<'de>,
+    G::Node: Deserialize<'de>,
+    G::PortLabel: Deserialize<'de>,
+    G::Edge: Deserialize<'de>,
+    M: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let UncheckedPortDiffGraph(graph) = UncheckedPortDiffGraph::deserialize(deserializer)?;
+        let graph = Self::from(graph);
+        if let Err(errors) = graph.validate() {
+            let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+            return Err(de::Error::custom(format!(
+                "invalid diff graph: {}",
+                errors.join("; ")
+            )));
+        }
+        Ok(graph)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use relrc::RelRc;
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_validate(parent_child_diffs: [TestPortDiff; 2]) {
+        let [_, child] = parent_child_diffs;
+        let graph = PortDiffGraph::from_sinks([child.clone()]);
+        assert!(graph.validate().is_ok());
+        let ser = serde_json::to_string(&graph).unwrap();
+        assert!(serde_json::from_str::<PortDiffGraph<_>>(&ser).is_ok());
+
+        // Corrupt the incoming edge of the first boundary port
+        let mut data = PortDiffData::clone(&child);
+        data.boundary.set_edge(0, IncomingEdgeIndex::from(5));
+        let parents = child
+            .incoming_edges()
+            .map(|(parent, edge)| (parent.data, edge.clone()))
+            .collect::<Vec<_>>();
+        let corrupted: TestPortDiff = RelRc::with_parents(data, parents).into();
+        let graph = PortDiffGraph::from_sinks([corrupted]);
+
+        let errors = graph.validate().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0],
+            ValidationError::InvalidIncomingEdge { .. }
+        ));
+        assert!(matches!(
+            errors[1],
+            ValidationError::InvalidPortMapBoundary { .. }
+        ));
+        let ser = serde_json::to_string(&graph).unwrap();
+        assert!(serde_json::from_str::<PortDiffGraph<_>>(&ser).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Queries aggregating the values of diffs over the hierarchy.
+//!
+//! Diff values typically record the cost delta of a rewrite, e.g. the change
+//! in the number of gates. The cost of a diff relative to the roots is then
+//! the sum of the values of the diff and all its ancestors.
+
+use std::collections::BTreeMap;
+use std::iter::Sum;
+
+use itertools::Itertools;
+use relrc::RelRc;
+
+use crate::{
+    port_diff::{PortDiffData, TraversalDirection},
+    Graph, NodeId, PortDiff,
+};
+
+use super::PortDiffGraph;
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// The sum of the values of `diff` and all its ancestors in the graph.
+    ///
+    /// Every ancestor is counted once, even if it can be reached along
+    /// several paths. Returns `None` if `diff` is not in the graph.
+    pub fn cumulative_value(&self, diff: &PortDiff<G, M>) -> Option<M>
+    where
+        M: Sum,
+    {
+        let id = NodeId::from(diff);
+        if !self.contains(id) {
+            return None;
+        }
+        let ancestors = self.traverse([id], TraversalDirection::Ancestors);
+        Some(ancestors.map(|d| d.value().clone()).sum())
+    }
+
+    /// The sink of minimum cumulative value, see [`Self::cumulative_value`].
+    ///
+    /// If several sinks have the same cumulative value, the first one is
+    /// returned. Returns `None` if the graph is empty.
+    pub fn best_sink_by_value(&self) -> Option<PortDiff<G, M>>
+    where
+        M: Sum + Ord,
+    {
+        self.sinks()
+            .min_by_key(|sink| self.cumulative_value(sink).unwrap())
+    }
+
+    /// Set the diff values, given the new values of their parents, and create
+    /// a new `PortDiffGraph`.
+    ///
+    /// As [`Self::map_value`], but `f` is also passed the new values of the
+    /// parents of every diff, in the order of [`PortDiff::all_parents`].
+    /// Values are computed from the roots down, so that they can be
+    /// propagated along the hierarchy, e.g. to store cumulative costs.
+    ///
+    /// All ancestors of the diffs in `self` must be in `self`.
+    pub fn map_value_with_parents<N: Clone>(
+        &self,
+        f: impl Fn(&PortDiffData<G, M>, &[&N]) -> N,
+    ) -> PortDiffGraph<G, N> {
+        let mut new_diffs: BTreeMap<NodeId<G, M>, PortDiff<G, N>> = BTreeMap::new();
+        for n in self.all_nodes() {
+            let diff = self.get_diff(n);
+            let parents = diff
+                .all_parents()
+                .map(|p| new_diffs[&NodeId::from(&p)].value())
+                .collect_vec();
+            let value = f(&diff, &parents);
+            let incoming = diff
+                .all_incoming()
+                .iter()
+                .map(|e| {
+                    let parent = NodeId::from(&PortDiff::from(e.source().clone()));
+                    (new_diffs[&parent].data.clone(), e.value().clone())
+                })
+                .collect_vec();
+            let data = PortDiffData {
+                graph: diff.graph.clone(),
+                boundary: diff.boundary.clone(),
+                boundary_labels: diff.boundary_labels.clone(),
+                value,
+                key: diff.key.clone(),
+                bound_ancestors: Default::default(),
+            };
+            new_diffs.insert(n, RelRc::with_parents(data, incoming).into());
+        }
+
+        let sinks = self.sinks().map(|d| new_diffs[&NodeId::from(&d)].clone());
+        let mut graph = PortDiffGraph::from_sinks(sinks.collect_vec());
+        for (name, tips) in self.branch_tips() {
+            let tips = tips
+                .iter()
+                .filter_map(|tip| new_diffs.get(&NodeId::from(tip)));
+            graph
+                .2
+                .insert(name.to_string(), tips.map(PortDiff::downgrade).collect());
+        }
+        graph
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        port_diff::Owned,
+        simple_graph::{SimpleGraph, SimpleNode},
+        Site,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_value_aggregation() {
+        let root: PortDiff<SimpleGraph, usize> =
+            PortDiff::from_graph_with_value(SimpleGraph::linear_chain(3), 1);
+        // Replace the `i`-th node with a copy, with value `value`
+        let child = |i, value| {
+            let mut new_graph = SimpleGraph::new();
+            let node = new_graph.add_node(1, 1);
+            let nodes = [Owned::new(SimpleNode::new(i), root.clone())];
+            PortDiff::rewrite_with_value(
+                nodes,
+                [],
+                new_graph,
+                |p| {
+                    let port = p.site().unwrap().port;
+                    Site { node, port }.into()
+                },
+                value,
+            )
+            .unwrap()
+        };
+        let [first, last] = [child(0, 5), child(2, 2)];
+        let graph = PortDiffGraph::from_sinks([first.clone(), last.clone()]);
+
+        assert_eq!(graph.cumulative_value(&root), Some(1));
+        assert_eq!(graph.cumulative_value(&first), Some(6));
+        assert_eq!(graph.cumulative_value(&last), Some(3));
+        assert_eq!(graph.best_sink_by_value(), Some(last.clone()));
+
+        let cumulative = graph.map_value_with_parents(|data, parents: &[&usize]| {
+            data.value + parents.iter().copied().sum::<usize>()
+        });
+        let values = cumulative.sinks().map(|d| *d.value()).collect_vec();
+        assert_eq!(values, vec![6, 3]);
+        assert_eq!(
+            cumulative.roots().map(|d| *d.value()).collect_vec(),
+            vec![1]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Isomorphism checks between graphs.
+//!
+//! Graphs are compared through the [`Graph`] trait only, so that extracted
+//! graphs of any adapter can be compared to an expected graph regardless of
+//! how their nodes and edges are numbered.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use itertools::Itertools;
+
+use crate::{
+    port::{BoundPort, EdgeEnd},
+    Graph,
+};
+
+/// Whether `a` and `b` are isomorphic.
+///
+/// Two graphs are isomorphic if there is a bijection between their nodes
+/// that preserves the labels of their sites and maps the edges between
+/// every pair of sites of `a` to as many edges between the corresponding
+/// sites of `b`. Node and edge identifiers are ignored.
+///
+/// Uses a backtracking search in the style of VF2, pruned by comparing the
+/// sites and degrees of the nodes.
+pub fn are_isomorphic<G: Graph>(a: &G, b: &G) -> bool {
+    find_isomorphism(a, b).is_some()
+}
+
+/// An isomorphism from `a` to `b`, as a map between their nodes.
+///
+/// Returns `None` if the graphs are not isomorphic. See [`are_isomorphic`].
+pub fn find_isomorphism<G: Graph>(a: &G, b: &G) -> Option<BTreeMap<G::Node, G::Node>> {
+    if a.nodes_iter().count() != b.nodes_iter().count()
+        || a.edges_iter().count() != b.edges_iter().count()
+    {
+        return None;
+    }
+    let mut candidates: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for n in b.nodes_iter() {
+        candidates.entry(signature(b, n)).or_default().push(n);
+    }
+    let order = search_order(a);
+    let candidates = order
+        .iter()
+        .map(|&n| candidates.get(&signature(a, n)).cloned())
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut state = State {
+        a,
+        b,
+        map: BTreeMap::new(),
+        used: BTreeSet::new(),
+    };
+    state.extend(&order, &candidates).then_some(state.map)
+}
+
+/// The labels of the sites of `node`, with the number of edges at each end.
+///
+/// Isomorphic nodes have the same signature.
+fn signature<G: Graph>(graph: &G, node: G::Node) -> Vec<(G::PortLabel, usize, usize)> {
+    graph
+        .get_sites(node)
+        .map(|site| {
+            let ports = graph.get_bound_ports(site.clone()).collect_vec();
+            let n_left = ports.iter().filter(|p| p.end == EdgeEnd::Left).count();
+            (site.port, n_left, ports.len() - n_left)
+        })
+        .sorted()
+        .collect()
+}
+
+/// The nodes of `graph` in breadth-first order, so that every node but the
+/// first of each component is adjacent to an earlier node.
+fn search_order<G: Graph>(graph: &G) -> Vec<G::Node> {
+    let mut order = Vec::new();
+    let mut visited = BTreeSet::new();
+    for root in graph.nodes_iter() {
+        if !visited.insert(root) {
+            continue;
+        }
+        let mut queue = VecDeque::from([root]);
+        while let Some(node) = queue.pop_front() {
+            order.push(node);
+            for (_, _, other, _) in incident_edges(graph, node) {
+                if visited.insert(other) {
+                    queue.push_back(other);
+                }
+            }
+        }
+    }
+    order
+}
+
+/// The edges at `node`, as the label and end at `node` and the node and
+/// label at the other end.
+fn incident_edges<G: Graph>(
+    graph: &G,
+    node: G::Node,
+) -> impl Iterator<Item = (G::PortLabel, EdgeEnd, G::Node, G::PortLabel)> + '_ {
+    graph.get_sites(node).flat_map(move |site| {
+        graph.get_bound_ports(site.clone()).map(move |port| {
+            let opposite = BoundPort {
+                edge: port.edge,
+                end: port.end.opposite(),
+            };
+            let other = graph.get_port_site(opposite);
+            (site.port.clone(), port.end, other.node, other.port)
+        })
+    })
+}
+
+/// A partial isomorphism, extended one node at a time.
+struct State<'g, G: Graph> {
+    a: &'g G,
+    b: &'g G,
+    /// The nodes of `a` mapped so far, and their images.
+    map: BTreeMap<G::Node, G::Node>,
+    /// The images of the nodes mapped so far.
+    used: BTreeSet<G::Node>,
+}
+
+impl<G: Graph> State<'_, G> {
+    /// Extend the map to the nodes `order`, with the candidate images of
+    /// every node in `candidates`.
+    ///
+    /// Returns whether a complete isomorphism was found, in which case it is
+    /// stored in `self.map`.
+    fn extend(&mut self, order: &[G::Node], candidates: &[Vec<G::Node>]) -> bool {
+        let Some((&node, rest)) = order.split_first() else {
+            return true;
+        };
+        for &image in &candidates[0] {
+            if self.used.contains(&image) || !self.is_consistent(node, image) {
+                continue;
+            }
+            self.map.insert(node, image);
+            self.used.insert(image);
+            if self.extend(rest, &candidates[1..]) {
+                return true;
+            }
+            self.map.remove(&node);
+            self.used.remove(&image);
+        }
+        false
+    }
+
+    /// Whether mapping `node` to `image` preserves the edges to the nodes
+    /// mapped so far, including `node` itself.
+    fn is_consistent(&self, node: G::Node, image: G::Node) -> bool {
+        let edges_a = incident_edges(self.a, node)
+            .filter_map(|(port, end, other, other_port)| {
+                let other = if other == node {
+                    image
+                } else {
+                    *self.map.get(&other)?
+                };
+                Some((port, end, other, other_port))
+            })
+            .sorted()
+            .collect_vec();
+        let edges_b = incident_edges(self.b, image)
+            .filter(|(_, _, other, _)| *other == image || self.used.contains(other))
+            .sorted()
+            .collect_vec();
+        edges_a == edges_b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::{
+        simple_graph::{SimpleGraph, SimpleNode},
+        PortDiff,
+    };
+
+    use super::*;
+
+    /// A chain of `n` nodes, with the nodes added in reverse order.
+    fn reversed_chain(n: usize) -> SimpleGraph {
+        let mut graph = SimpleGraph::new();
+        let nodes = (0..n).map(|_| graph.add_node(1, 1)).collect_vec();
+        for pair in nodes.windows(2) {
+            graph.link_nodes(pair[1], 0, pair[0], 0);
+        }
+        graph
+    }
+
+    #[test]
+    fn test_are_isomorphic() {
+        let chain = SimpleGraph::linear_chain(4);
+        assert!(are_isomorphic(&chain, &chain));
+        assert!(are_isomorphic(&chain, &reversed_chain(4)));
+        assert!(!are_isomorphic(&chain, &reversed_chain(3)));
+        assert!(!are_isomorphic(&SimpleGraph::grid(2, 2), &chain));
+
+        // Same degrees, but the edge is attached to different ports
+        let mut a = SimpleGraph::new();
+        let (a0, a1) = (a.add_node(2, 2), a.add_node(2, 2));
+        a.link_nodes(a0, 0, a1, 0);
+        let mut b = SimpleGraph::new();
+        let (b0, b1) = (b.add_node(2, 2), b.add_node(2, 2));
+        b.link_nodes(b0, 0, b1, 1);
+        assert!(!are_isomorphic(&a, &b));
+
+        let iso = find_isomorphism(&chain, &reversed_chain(4)).unwrap();
+        assert_eq!(iso[&SimpleNode::new(0)], SimpleNode::new(3));
+    }
+
+    #[test]
+    fn test_extracted_isomorphic() {
+        // a -> b -> c -> d  =>  a -> c -> d
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(4));
+        let child = root.rewrite_node_to_wires(SimpleNode::new(1)).unwrap();
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        assert!(are_isomorphic(&extracted, &SimpleGraph::linear_chain(3)));
+
+        let nodes = BTreeSet::from([SimpleNode::new(1), SimpleNode::new(2)]);
+        let selection = root.select_subgraph(nodes);
+        let extracted = PortDiff::extract_graph(vec![selection]).unwrap();
+        assert!(are_isomorphic(&extracted, root.graph()));
+    }
+}
@@ -1,13 +1,46 @@
+pub mod ancestry;
+mod fnv;
+pub mod gen;
 mod graph;
 pub mod graph_view;
+pub mod iso;
+pub mod matching;
 mod port;
 pub mod port_diff;
+pub mod raw;
+pub mod rc_graph;
+pub mod rule;
+pub mod session;
+pub mod simple_graph;
 pub mod subgraph;
+mod trace;
+
+#[cfg(feature = "any")]
+pub mod any;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
 #[cfg(feature = "portgraph")]
 pub mod portgraph;
 
-pub use graph::Graph;
+#[cfg(feature = "python")]
+mod python;
+
+#[cfg(feature = "petgraph")]
+pub mod stable_graph;
+
+#[cfg(feature = "store")]
+pub mod store;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+pub use graph::{AddNodes, ContentKey, DotGraph, Graph, NodeWeights, RemoveNodes, TypedGraph};
 pub use graph_view::{NodeId, PortDiffGraph};
 pub use port::{BoundPort, BoundarySite, EdgeEnd, Port, Site};
-pub use port_diff::{InvalidRewriteError, PortDiff};
+pub use port_diff::{
+    BoundaryCtx, BoundaryPortCtx, InvalidEdgeReason, InvalidRewriteError, PortDiff, RewriteBuilder,
+    RewriteIssue, RewriteSpec, WireAllocator,
+};
+pub use session::{Session, SessionState};
@@ -0,0 +1,285 @@
+//! Pattern matching on the graphs of diffs.
+//!
+//! Matches may span several diffs: patterns are matched on the graph obtained
+//! by merging compatible diffs, see [`MergedView`].
+
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+
+use derive_where::derive_where;
+use itertools::Itertools;
+
+use crate::{
+    graph_view::{MergedNode, MergedView},
+    port_diff::Owned,
+    BoundarySite, EdgeEnd, Graph, InvalidRewriteError, Port, PortDiff, PortDiffGraph,
+};
+
+/// An induced occurrence of a pattern in the graph of a set of diffs.
+///
+/// The nodes and edges are expressed in terms of the diffs that own them, so
+/// that they can be passed directly to [`PortDiff::rewrite`].
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: std::fmt::Debug, G::Edge: std::fmt::Debug)]
+pub struct Match<G: Graph, M = ()> {
+    /// The node that each node of the pattern is mapped to.
+    pub nodes: BTreeMap<G::Node, Owned<G::Node, G, M>>,
+    /// The edges between the matched nodes, as pairs of left and right ports.
+    pub edges: Vec<(Owned<Port<G>, G, M>, Owned<Port<G>, G, M>)>,
+}
+
+impl<G: Graph, M: Clone> Match<G, M> {
+    /// Rewrite the matched nodes and edges to `new_graph`.
+    ///
+    /// See [`PortDiff::rewrite`].
+    pub fn rewrite(
+        &self,
+        new_graph: G,
+        boundary_map: impl FnMut(Owned<Port<G>, G, M>) -> BoundarySite<G>,
+    ) -> Result<PortDiff<G, M>, InvalidRewriteError<G, M>>
+    where
+        M: Default,
+    {
+        PortDiff::rewrite(
+            self.nodes.values().cloned(),
+            self.edges.iter().cloned(),
+            new_graph,
+            boundary_map,
+        )
+    }
+}
+
+/// Find all induced occurrences of `pattern` in the graphs of `diffs`.
+///
+/// A pattern node matches a node with the same port labels. Edges between
+/// matched nodes must be in one-to-one correspondence with the edges of the
+/// pattern, with the same port labels at both ends.
+///
+/// Matches may span several diffs, as long as they are compatible. If `diffs`
+/// are not all compatible, every maximal compatible subset is searched, see
+/// [`PortDiffGraph::maximal_compatible_sets`].
+pub fn find_induced_matches<G: Graph, M: Clone>(
+    pattern: &G,
+    diffs: &[PortDiff<G, M>],
+) -> Vec<Match<G, M>> {
+    let mut found = BTreeSet::new();
+    let mut matches = Vec::new();
+    for diffs in PortDiffGraph::maximal_compatible_sets(diffs) {
+        let view = MergedView::new(diffs).expect("diffs are compatible");
+        for m in Matcher::new(pattern, &view).find_all() {
+            let key = m.nodes.values().cloned().collect_vec();
+            if found.insert(key) {
+                matches.push(m);
+            }
+        }
+    }
+    matches
+}
+
+/// A backtracking search for the induced matches of a pattern in a view.
+struct Matcher<'a, G: Graph, M> {
+    pattern: &'a G,
+    view: &'a MergedView<G, M>,
+    /// The pattern nodes, in the order they are matched.
+    order: Vec<G::Node>,
+    /// For every pattern node, an earlier neighbour in `order`, if any.
+    anchors: Vec<Option<usize>>,
+}
+
+impl<'a, G: Graph, M: Clone> Matcher<'a, G, M> {
+    fn new(pattern: &'a G, view: &'a MergedView<G, M>) -> Self {
+        // Order the pattern nodes so that connected nodes are matched in turn
+        let mut order = Vec::new();
+        let mut anchors = Vec::new();
+        let mut position = BTreeMap::new();
+        for root in pattern.nodes_iter() {
+            if position.contains_key(&root) {
+                continue;
+            }
+            position.insert(root, order.len());
+            order.push(root);
+            anchors.push(None);
+            let mut i = order.len() - 1;
+            while i < order.len() {
+                let node = order[i];
+                for neighbour in pattern.neighbours(node) {
+                    if let Entry::Vacant(e) = position.entry(neighbour) {
+                        e.insert(order.len());
+                        order.push(neighbour);
+                        anchors.push(Some(i));
+                    }
+                }
+                i += 1;
+            }
+        }
+        Self {
+            pattern,
+            view,
+            order,
+            anchors,
+        }
+    }
+
+    fn find_all(&self) -> Vec<Match<G, M>> {
+        let mut matches = Vec::new();
+        let mut assignment = Vec::with_capacity(self.order.len());
+        self.extend(&mut assignment, &mut matches);
+        matches
+    }
+
+    /// Extend the partial `assignment` of the pattern nodes in all possible
+    /// ways, recording complete matches.
+    fn extend(&self, assignment: &mut Vec<MergedNode<G, M>>, matches: &mut Vec<Match<G, M>>) {
+        let i = assignment.len();
+        if i == self.order.len() {
+            matches.push(self.to_match(assignment));
+            return;
+        }
+        let candidates: BTreeSet<_> = match self.anchors[i] {
+            Some(anchor) => self.view.neighbours(assignment[anchor]).collect(),
+            None => self.view.nodes_iter().collect(),
+        };
+        for candidate in candidates {
+            if assignment.contains(&candidate) || !self.is_consistent(assignment, candidate) {
+                continue;
+            }
+            assignment.push(candidate);
+            self.extend(assignment, matches);
+            assignment.pop();
+        }
+    }
+
+    /// Whether mapping the next pattern node to `candidate` is consistent
+    /// with `assignment`.
+    fn is_consistent(&self, assignment: &[MergedNode<G, M>], candidate: MergedNode<G, M>) -> bool {
+        let node = self.order[assignment.len()];
+        if port_labels(self.pattern, node) != port_labels(self.view, candidate) {
+            return false;
+        }
+        // The edges to and from matched nodes must correspond exactly
+        let pattern_links = links(self.pattern, node);
+        let view_links = links(self.view, candidate);
+        let pairs = self
+            .order
+            .iter()
+            .zip(assignment)
+            .chain([(&node, &candidate)]);
+        for (&other, &other_candidate) in pairs {
+            let expected = filter_links(&pattern_links, other);
+            if expected != filter_links(&view_links, other_candidate) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn to_match(&self, assignment: &[MergedNode<G, M>]) -> Match<G, M> {
+        let image: BTreeSet<_> = assignment.iter().copied().collect();
+        let nodes = self
+            .order
+            .iter()
+            .zip(assignment)
+            .map(|(&node, &merged)| (node, self.view.owned_node(merged)))
+            .collect();
+        let mut edges = Vec::new();
+        for &node in assignment {
+            for site in self.view.get_sites(node) {
+                for port in self.view.get_bound_ports(site) {
+                    if port.end != EdgeEnd::Left {
+                        continue;
+                    }
+                    let right = port.opposite();
+                    if image.contains(&self.view.get_port_site(right).node) {
+                        edges.push((self.view.owned_port(port), self.view.owned_port(right)));
+                    }
+                }
+            }
+        }
+        Match { nodes, edges }
+    }
+}
+
+/// A link between `node` and another node, as seen from `node`.
+///
+/// Given by the end of the edge at `node`, the port label at `node`, the
+/// other node and the port label at the other node.
+type Link<H> = (
+    EdgeEnd,
+    <H as Graph>::PortLabel,
+    <H as Graph>::Node,
+    <H as Graph>::PortLabel,
+);
+
+/// All links at `node`, sorted.
+fn links<H: Graph>(graph: &H, node: H::Node) -> Vec<Link<H>> {
+    let mut links = Vec::new();
+    for site in graph.get_sites(node) {
+        for port in graph.get_bound_ports(site.clone()) {
+            let other = graph.get_port_site(port.opposite());
+            links.push((port.end, site.port.clone(), other.node, other.port));
+        }
+    }
+    links.sort();
+    links
+}
+
+/// The links to `other`, without the other node.
+fn filter_links<H: Graph>(
+    links: &[Link<H>],
+    other: H::Node,
+) -> Vec<(EdgeEnd, H::PortLabel, H::PortLabel)> {
+    links
+        .iter()
+        .filter(|(_, _, n, _)| n == &other)
+        .map(|(end, port, _, other_port)| (*end, port.clone(), other_port.clone()))
+        .collect()
+}
+
+/// The sorted port labels of `node`.
+fn port_labels<H: Graph>(graph: &H, node: H::Node) -> Vec<H::PortLabel> {
+    graph
+        .get_sites(node)
+        .map(|site| site.port)
+        .sorted()
+        .collect()
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkMut, NodeIndex, PortGraph, PortMut};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_find_induced_matches(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let mut pattern = PortGraph::new();
+        let a = pattern.add_node(0, 3);
+        let b = pattern.add_node(3, 0);
+        for i in 0..3 {
+            pattern.link_nodes(a, i, b, i).unwrap();
+        }
+
+        // The parent has no node with three inputs and no output
+        assert!(find_induced_matches(&pattern, &[parent.clone()]).is_empty());
+
+        // Both matches span the parent and the child
+        let matches = find_induced_matches(&pattern, &[child.clone()]);
+        assert_eq!(matches.len(), 2);
+        for m in &matches {
+            assert_eq!(m.edges.len(), 3);
+            let owners = m.nodes.values().map(|n| n.owner.clone()).collect_vec();
+            assert!(owners.contains(&parent) && owners.contains(&child));
+        }
+        let n0 = Owned::new(NodeIndex::new(0), parent.clone());
+        assert!(matches.iter().any(|m| m.nodes[&a] == n0));
+
+        let rewritten = matches[0]
+            .rewrite(pattern.clone(), |_| unreachable!("match has no boundary"))
+            .unwrap();
+        assert_eq!(rewritten.n_boundary_ports(), 0);
+    }
+}
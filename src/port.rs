@@ -22,6 +22,23 @@ impl EdgeEnd {
             Self::Right => Self::Left,
         }
     }
+
+    /// The index of the edge end: 0 for `Left`, 1 for `Right`.
+    pub fn index(&self) -> usize {
+        match self {
+            Self::Left => 0,
+            Self::Right => 1,
+        }
+    }
+
+    /// The edge end at `index`, if it is a valid index of a binary edge.
+    pub fn from_index(index: usize) -> Option<Self> {
+        match index {
+            0 => Some(Self::Left),
+            1 => Some(Self::Right),
+            _ => None,
+        }
+    }
 }
 
 /// Site: where ports can be connected.
@@ -56,7 +73,7 @@ impl<N, P> Site<N, P> {
 
 /// A boundary port, given by the index of the port in the boundary.
 #[derive(
-    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, From, Into, Serialize, Deserialize,
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, From, Into, Serialize, Deserialize,
 )]
 pub struct BoundaryIndex(usize);
 
@@ -73,6 +90,7 @@ pub struct BoundaryIndex(usize);
 #[derive_where(Eq; G: Graph)]
 #[derive_where(PartialOrd; G: Graph)]
 #[derive_where(Ord; G: Graph)]
+#[derive_where(Hash; G: Graph, G::Edge: Hash)]
 pub enum Port<G: Graph> {
     /// The i-th boundary port of the graph.
     Boundary(BoundaryIndex),
@@ -84,7 +102,7 @@ pub enum Port<G: Graph> {
 ///
 /// This is given by a an edge and an edge end. This always determines the
 /// port uniquely.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct BoundPort<E> {
     /// The edge
     pub edge: E,
@@ -103,10 +121,10 @@ impl<G: Graph> Clone for Port<G> {
 
 impl<G: Graph> Copy for Port<G> where G::Edge: Copy {}
 
-impl<G: Graph> Owned<Port<G>, G> {
+impl<G: Graph, M: Clone> Owned<Port<G>, G, M> {
     pub fn site(&self) -> Option<Site<G::Node, G::PortLabel>> {
         match self.data {
-            Port::Boundary(boundary) => self.owner.boundary_site(boundary).clone().try_into().ok(),
+            Port::Boundary(boundary) => self.owner.boundary_site(boundary).try_into().ok(),
             Port::Bound(port) => Some(self.owner.graph().get_port_site(port)),
         }
     }
@@ -155,8 +173,8 @@ impl<G: Graph> TryFrom<BoundarySite<G>> for Site<G::Node, G::PortLabel> {
 ///
 /// Either a site of the graph or a site on an "imaginary" wire. As many
 /// such wires can be created as needed. For any wire ID, there may be at
-/// most one site for each end. Wires should be assigned increasing indices
-/// starting from 0.
+/// most one site for each end. Wire IDs must be dense, starting from 0, which
+/// is checked when rewriting.
 #[derive(Serialize, Deserialize, From)]
 #[derive_where(PartialEq, Eq, PartialOrd, Ord, Clone; G: Graph)]
 #[derive_where(Debug; G: Graph, G::Node: Debug, G::PortLabel: Debug)]
@@ -171,6 +189,14 @@ pub enum BoundarySite<G: Graph> {
 }
 
 impl<G: Graph> BoundarySite<G> {
+    /// The `end` of the wire with ID `id`.
+    ///
+    /// Use a wire as boundary site to connect boundary ports of a rewrite
+    /// directly, without any node in between.
+    pub fn wire(id: usize, end: EdgeEnd) -> Self {
+        Self::Wire { id, end }
+    }
+
     pub fn try_as_site_ref(&self) -> Option<&Site<G::Node, G::PortLabel>> {
         match self {
             Self::Site(site) => Some(site),
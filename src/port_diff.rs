@@ -1,15 +1,58 @@
+mod apply;
+mod bundle;
+mod cache;
+mod canonical;
+mod compare;
+mod compat_index;
+mod compose;
+mod constraint;
+mod content;
+mod contract;
+mod delete;
 mod extract;
+mod flatten;
+mod invert;
+mod minimize;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod policy;
+mod provenance;
 mod rewrite;
+mod rewrite_builder;
+mod root_builder;
+mod selection;
 mod serial_edge_data;
 mod squash;
-// mod traverser;
-
-pub use extract::IncompatiblePortDiff;
-pub use rewrite::InvalidRewriteError;
+mod stats;
+mod traverser;
+mod typed;
+mod weak;
+mod wires;
+
+pub use apply::ApplyError;
+pub(crate) use bundle::Boundary;
+pub use bundle::BoundaryBundle;
+pub use compare::{BoundaryMismatch, DiffReport, SubgraphMismatch};
+pub use compat_index::CompatibilityIndex;
+pub use compose::ComposeError;
+pub use constraint::{AcyclicConstraint, GraphConstraint};
+pub use contract::MergeNodesError;
+pub use delete::{DeleteNodesError, DeletionPolicy};
+pub use extract::{Conflict, Extractor, IncompatiblePortDiff};
+pub use invert::NotInvertibleError;
+pub use policy::{CompatibilityPolicy, DisjointEdges, DisjointSubgraphs};
+pub use provenance::{EdgeProvenance, ExtractionReport};
+pub use rewrite::{BoundaryCtx, InvalidEdgeReason, InvalidRewriteError, RewriteIssue, RewriteSpec};
+pub use rewrite_builder::{BoundaryPortCtx, RewriteBuilder};
+pub use root_builder::{EdgeListError, RootBuilder};
+pub use squash::SquashError;
+pub use stats::{DiffGraphStats, DiffStats};
+pub use weak::WeakPortDiff;
+pub use wires::WireAllocator;
 
 use std::{
     cmp,
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     fmt::{self, Debug},
     hash::Hash,
     ops::Deref,
@@ -19,9 +62,10 @@ use crate::{
     graph::Graph,
     port::{BoundPort, BoundaryIndex, BoundarySite, Port},
     subgraph::Subgraph,
-    NodeId,
+    NodeId, PortDiffGraph,
 };
 use bimap::BiBTreeMap;
+use cache::BoundAncestorCache;
 use derive_more::{From, Into};
 use derive_where::derive_where;
 use itertools::Itertools;
@@ -30,20 +74,20 @@ use serde::{Deserialize, Serialize};
 
 use crate::port::Site;
 
-// pub use traverser::DiffTraverser;
+pub use traverser::{DiffTraverser, TraversalDirection};
 
 #[derive(From)]
 #[derive_where(Clone; G: Graph)]
-pub struct PortDiff<G: Graph> {
-    pub(crate) data: RelRc<PortDiffData<G>, EdgeData<G>>,
+pub struct PortDiff<G: Graph, M = ()> {
+    pub(crate) data: RelRc<PortDiffData<G, M>, EdgeData<G>>,
 }
 
-pub type PortDiffPtr<G> = *const relrc::node::InnerData<PortDiffData<G>, EdgeData<G>>;
+pub type PortDiffPtr<G, M = ()> = *const relrc::node::InnerData<PortDiffData<G, M>, EdgeData<G>>;
 
-impl<G: Graph> PortDiff<G> {
-    fn try_with_parents(
-        data: PortDiffData<G>,
-        parents: Vec<(PortDiff<G>, EdgeData<G>)>,
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    pub(crate) fn try_with_parents(
+        data: PortDiffData<G, M>,
+        parents: Vec<(PortDiff<G, M>, EdgeData<G>)>,
     ) -> Result<Self, IncompatiblePortDiff> {
         if !are_compatible(&parents) {
             return Err(IncompatiblePortDiff);
@@ -53,7 +97,7 @@ impl<G: Graph> PortDiff<G> {
         })
     }
 
-    pub fn as_ptr(&self) -> PortDiffPtr<G> {
+    pub fn as_ptr(&self) -> PortDiffPtr<G, M> {
         RelRc::as_ptr(&self.data)
     }
 }
@@ -63,7 +107,7 @@ impl<G: Graph> PortDiff<G> {
 /// We check two things:
 ///  - edges outgoing from the same parent are compatible.
 ///  - all parents are compatible with each other.
-fn are_compatible<G: Graph>(parents: &[(PortDiff<G>, EdgeData<G>)]) -> bool {
+fn are_compatible<G: Graph, M: Clone>(parents: &[(PortDiff<G, M>, EdgeData<G>)]) -> bool {
     let mut parents_map: HashMap<_, Vec<_>> = HashMap::new();
     for (parent, edge_data) in parents {
         parents_map
@@ -89,21 +133,21 @@ fn are_compatible<G: Graph>(parents: &[(PortDiff<G>, EdgeData<G>)]) -> bool {
     true
 }
 
-impl<G: Graph> Hash for PortDiff<G> {
+impl<G: Graph, M> Hash for PortDiff<G, M> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.as_ptr().hash(state);
     }
 }
 
-impl<G: Graph> PartialEq for PortDiff<G> {
+impl<G: Graph, M> PartialEq for PortDiff<G, M> {
     fn eq(&self, other: &Self) -> bool {
         RelRc::ptr_eq(&self.data, &other.data)
     }
 }
 
-impl<G: Graph> Eq for PortDiff<G> {}
+impl<G: Graph, M> Eq for PortDiff<G, M> {}
 
-impl<G: Graph> Debug for PortDiff<G> {
+impl<G: Graph, M> Debug for PortDiff<G, M> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
@@ -114,38 +158,49 @@ impl<G: Graph> Debug for PortDiff<G> {
     }
 }
 
-impl<G: Graph> PartialOrd for PortDiff<G> {
+impl<G: Graph, M> PartialOrd for PortDiff<G, M> {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<G: Graph> Ord for PortDiff<G> {
+impl<G: Graph, M> Ord for PortDiff<G, M> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
         self.as_ptr().cmp(&other.as_ptr())
     }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
-#[serde(bound(serialize = "G: Serialize, G::Node: Serialize, G::PortLabel: Serialize"))]
 #[serde(bound(
-    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>"
+    serialize = "G: Serialize, G::Node: Serialize, G::PortLabel: Serialize, M: Serialize"
+))]
+#[serde(bound(
+    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>, M: Deserialize<'de>"
 ))]
-pub struct PortDiffData<G: Graph> {
+pub struct PortDiffData<G: Graph, M = ()> {
     /// The internal graph
     pub(crate) graph: G,
     /// The boundary of the subgraph.
     ///
     /// Each boundary port of `graph` maps to a port in one of the parents,
     /// reachable by following the `IncomingEdgeIndex`.
-    pub(crate) boundary: Vec<(BoundarySite<G>, IncomingEdgeIndex)>,
-    /// Optionally an integer value associated with the diff. TODO: make this generic (or move out)
-    pub(crate) value: Option<usize>,
+    pub(crate) boundary: Boundary<G>,
+    /// Optional names of the boundary ports.
+    #[serde(default)]
+    pub(crate) boundary_labels: BTreeMap<BoundaryIndex, String>,
+    /// User-defined metadata associated with the diff, e.g. a cost.
+    pub(crate) value: M,
+    /// An optional user-supplied key, stable across runs.
+    #[serde(default)]
+    pub(crate) key: Option<String>,
+    /// The bound ancestors of the boundary ports, computed lazily.
+    #[serde(skip)]
+    pub(crate) bound_ancestors: BoundAncestorCache<G, M>,
 }
 
 /// The incoming edge at a portdiff, given by its index.
 #[derive(
-    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, From, Into, Serialize, Deserialize,
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, From, Into, Serialize, Deserialize,
 )]
 pub struct IncomingEdgeIndex(usize);
 
@@ -153,12 +208,12 @@ pub struct IncomingEdgeIndex(usize);
 #[derive_where(Debug; G: Graph, G::Node: Debug, G::Edge: Debug)]
 pub struct EdgeData<G: Graph> {
     /// The parent subgraph that is rewritten.
-    subgraph: Subgraph<G>,
+    pub(crate) subgraph: Subgraph<G>,
     /// Map ports in parent to boundary ports in child
     ///
     /// The domain of the map is the union of the boundary of `subgraph` and
     /// the boundary ports of `parent` that are on `subgraph.nodes`.
-    port_map: BiBTreeMap<Port<G>, BoundaryIndex>,
+    pub(crate) port_map: BiBTreeMap<Port<G>, BoundaryIndex>,
 }
 
 impl<G: Graph> EdgeData<G> {
@@ -174,44 +229,89 @@ impl<G: Graph> EdgeData<G> {
     where
         G: 'a,
     {
-        // All vertex sets must be disjoint
-        let mut all_nodes = BTreeSet::new();
-        for edge in edges {
-            if !edge.subgraph.nodes().iter().all(|&v| all_nodes.insert(v)) {
-                return false;
-            }
-        }
-
-        true
+        DisjointSubgraphs.are_compatible(&edges.into_iter().collect_vec())
     }
 
     /// The subgraph that is rewritten by this edge.    
     pub fn subgraph(&self) -> &Subgraph<G> {
         &self.subgraph
     }
+
+    /// The edges of the parent that are rewritten by this edge.
+    ///
+    /// These are the edges of [`Self::subgraph`], along with the edges that
+    /// cross its boundary.
+    pub fn rewritten_edges(&self) -> BTreeSet<G::Edge> {
+        let boundary_edges = self.port_map.left_values().filter_map(|port| match port {
+            Port::Bound(port) => Some(port.edge),
+            Port::Boundary(_) => None,
+        });
+        self.subgraph
+            .edges()
+            .iter()
+            .copied()
+            .chain(boundary_edges)
+            .collect()
+    }
+
+    /// The map from ports in the parent to boundary ports in the child.
+    ///
+    /// The domain of the map is the boundary of [`Self::subgraph`], along
+    /// with the boundary ports of the parent that are on the subgraph nodes.
+    pub fn boundary_port_map(&self) -> &BiBTreeMap<Port<G>, BoundaryIndex> {
+        &self.port_map
+    }
 }
 
-impl<G: Graph> Deref for PortDiff<G> {
-    type Target = PortDiffData<G>;
+impl<G: Graph, M> Deref for PortDiff<G, M> {
+    type Target = PortDiffData<G, M>;
 
     fn deref(&self) -> &Self::Target {
         self.data.value()
     }
 }
 
-type InEdge<G> = relrc::edge::InnerEdgeData<PortDiffData<G>, EdgeData<G>>;
-type OutEdge<G> = relrc::edge::Edge<PortDiffData<G>, EdgeData<G>>;
+type InEdge<G, M> = relrc::edge::InnerEdgeData<PortDiffData<G, M>, EdgeData<G>>;
+type OutEdge<G, M> = relrc::edge::Edge<PortDiffData<G, M>, EdgeData<G>>;
 
 impl<G: Graph> PortDiff<G> {
     /// Create a diff with no boundary.
     ///
     /// This will be a "root" in the diff hierarchy, as it has no ancestors.
     pub fn from_graph(graph: G) -> Self {
+        Self::from_graph_with_value(graph, ())
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Create a diff with no boundary and the given value.
+    ///
+    /// This will be a "root" in the diff hierarchy, as it has no ancestors.
+    pub fn from_graph_with_value(graph: G, value: M) -> Self {
+        Self::root(graph, value, None)
+    }
+
+    /// Create a diff with no boundary, identified by `key`.
+    ///
+    /// The key can be used to find the diff in a [`PortDiffGraph`] with
+    /// [`PortDiffGraph::get_by_key`]. Unlike [`NodeId`], it is
+    /// serialized and thus remains valid across runs.
+    pub fn from_graph_with_key(graph: G, key: impl Into<String>) -> Self
+    where
+        M: Default,
+    {
+        Self::root(graph, M::default(), Some(key.into()))
+    }
+
+    fn root(graph: G, value: M, key: Option<String>) -> Self {
         Self::try_with_parents(
             PortDiffData {
                 graph,
-                value: None,
-                boundary: Vec::new(),
+                value,
+                key,
+                boundary: Boundary::default(),
+                boundary_labels: BTreeMap::new(),
+                bound_ancestors: Default::default(),
             },
             vec![],
         )
@@ -226,60 +326,92 @@ impl<G: Graph> PortDiff<G> {
     }
 
     /// The i-th incoming edge
-    fn incoming(&self, index: IncomingEdgeIndex) -> Option<&InEdge<G>> {
+    fn incoming(&self, index: IncomingEdgeIndex) -> Option<&InEdge<G, M>> {
         self.data.incoming(index.0)
     }
 
     fn incoming_edge_index(&self, boundary: BoundaryIndex) -> Option<IncomingEdgeIndex> {
-        self.boundary
-            .get(usize::from(boundary))
-            .map(|&(_, index)| index)
+        self.boundary.edge(usize::from(boundary))
     }
 
-    fn port_outgoing(&self, port: Port<G>) -> impl Iterator<Item = OutEdge<G>> + '_ {
+    fn port_outgoing(&self, port: Port<G>) -> impl Iterator<Item = OutEdge<G, M>> + '_ {
         self.all_outgoing()
             .into_iter()
             .filter(move |e| e.value().map_to_child(&port).is_some())
     }
 
     /// All incoming edges.
-    fn all_incoming(&self) -> &[InEdge<G>] {
+    pub(crate) fn all_incoming(&self) -> &[InEdge<G, M>] {
         self.data.all_incoming()
     }
 
+    /// All incoming edges, along with the parent they originate from.
+    pub fn incoming_edges(&self) -> impl Iterator<Item = (Self, &EdgeData<G>)> + '_ {
+        self.all_incoming()
+            .iter()
+            .map(|e| (e.source().clone().into(), e.value()))
+    }
+
+    /// All parents of `self`, in the order of the incoming edges, without
+    /// repetitions.
     pub fn all_parents(&self) -> impl Iterator<Item = Self> + '_ {
         self.data.all_parents().map(|p| p.clone().into()).unique()
     }
 
     /// All outgoing edges.
-    fn all_outgoing(&self) -> Vec<OutEdge<G>> {
+    pub(crate) fn all_outgoing(&self) -> Vec<OutEdge<G, M>> {
         self.data.all_outgoing()
     }
 
-    /// Recursively follow parent ports until finding a bound port.
-    pub fn bound_ancestor(&self, mut boundary: BoundaryIndex) -> Owned<BoundPort<G::Edge>, G> {
-        let mut owner = self.clone();
-        loop {
-            match owner.parent_port(boundary) {
+    /// Follow parent ports until finding a bound port.
+    ///
+    /// The result is cached in every diff along the way. The ancestors are
+    /// visited iteratively, so that deep hierarchies do not overflow the
+    /// stack.
+    pub fn bound_ancestor(&self, boundary: BoundaryIndex) -> Owned<BoundPort<G::Edge>, G, M> {
+        let mut path = Vec::new();
+        let mut diff = self.clone();
+        let mut boundary = boundary;
+        let port = loop {
+            if let Some(port) = diff.bound_ancestors.get(boundary) {
+                break port;
+            }
+            match diff.parent_port(boundary) {
                 Owned {
                     data: Port::Bound(port),
                     owner,
                 } => {
-                    return Owned { data: port, owner };
+                    path.push((diff, boundary));
+                    break Owned { data: port, owner };
                 }
                 Owned {
                     data: Port::Boundary(index),
                     owner: parent,
                 } => {
+                    path.push((diff, boundary));
+                    diff = parent;
                     boundary = index;
-                    owner = parent;
                 }
             }
+        };
+        for (diff, boundary) in path {
+            diff.bound_ancestors.insert(boundary, port.clone());
+        }
+        port
+    }
+
+    /// Resolve the bound ancestors of all boundary ports.
+    ///
+    /// Resolutions are cached, so that subsequent calls to
+    /// [`Self::bound_ancestor`] are constant time.
+    pub fn resolve_all_ports(&self) {
+        for boundary in self.boundary_iter() {
+            self.bound_ancestor(boundary);
         }
     }
 
     /// The parent port of a boundary port.
-    pub fn parent_port(&self, boundary: BoundaryIndex) -> Owned<Port<G>, G> {
+    pub fn parent_port(&self, boundary: BoundaryIndex) -> Owned<Port<G>, G, M> {
         let edge_index = self.incoming_edge_index(boundary).unwrap();
         let edge = self.incoming(edge_index).unwrap();
         let port = edge.value().map_to_parent(boundary).unwrap().clone();
@@ -291,11 +423,26 @@ impl<G: Graph> PortDiff<G> {
     ///
     /// There is no guarantee that the opposite end does not clash with `self`.
     ///
-    /// TODO: return them in toposort order
-    pub fn opposite_ports<'a>(&self, port: Port<G>) -> impl Iterator<Item = Owned<Port<G>, G>>
+    /// The ports are in breadth-first order from the diff in which the edge
+    /// is bound, so that every port comes after the port it maps to in a
+    /// parent, and nearer ports come first. Ties are broken deterministically,
+    /// by the order of the children of the owners. See
+    /// [`Self::opposite_ports_with_depth`].
+    pub fn opposite_ports<'a>(&self, port: Port<G>) -> impl Iterator<Item = Owned<Port<G>, G, M>>
     where
         G: 'a,
     {
+        self.opposite_ports_with_depth(port)
+            .into_iter()
+            .map(|(port, _)| port)
+    }
+
+    /// As [`Self::opposite_ports`], along with the distance in the hierarchy
+    /// of every port to the diff in which the edge is bound.
+    ///
+    /// The opposite port in the diff in which the edge is bound has distance
+    /// 0, the ports of its children that map to it have distance 1, etc.
+    pub fn opposite_ports_with_depth(&self, port: Port<G>) -> Vec<(Owned<Port<G>, G, M>, usize)> {
         // Find the first parent port that is an ancestor of `port`.
         let parent_port = match port {
             Port::Boundary(boundary) => self.bound_ancestor(boundary),
@@ -304,7 +451,35 @@ impl<G: Graph> PortDiff<G> {
                 Owned { data: port, owner }
             }
         };
-        parent_port.owner.descendants(parent_port.data.opposite())
+        DescendantsIter::new(parent_port.data.opposite(), parent_port.owner).collect()
+    }
+
+    /// List the edges between ports of `self` and ports of `other`.
+    ///
+    /// Every pair is made of a port of `self` and a port of `other` that are
+    /// linked across the two diffs, so that it can be passed as an edge to
+    /// [`Self::rewrite`]. At least one of the two ports is a boundary port.
+    pub fn find_cross_edges(
+        &self,
+        other: &Self,
+    ) -> Vec<(Owned<Port<G>, G, M>, Owned<Port<G>, G, M>)> {
+        let mut edges = BTreeSet::new();
+        for boundary in self.boundary_iter() {
+            let port = Port::Boundary(boundary);
+            for opp in self.opposite_ports(port).filter(|p| &p.owner == other) {
+                edges.insert((Owned::new(port, self.clone()), opp));
+            }
+        }
+        for boundary in other.boundary_iter() {
+            let port = Port::Boundary(boundary);
+            // Edges between boundary ports were found above
+            let is_bound =
+                |p: &Owned<Port<G>, G, M>| &p.owner == self && matches!(p.data, Port::Bound(_));
+            for opp in other.opposite_ports(port).filter(is_bound) {
+                edges.insert((opp, Owned::new(port, other.clone())));
+            }
+        }
+        edges.into_iter().collect()
     }
 
     /// Resolve a port to a concrete port.
@@ -316,7 +491,7 @@ impl<G: Graph> PortDiff<G> {
     /// or multiple ports.
     ///
     /// If the port is already a concrete port, it is returned as is.
-    pub fn resolve_port(&self, port: Port<G>) -> Vec<Owned<Port<G>, G>> {
+    pub fn resolve_port(&self, port: Port<G>) -> Vec<Owned<Port<G>, G, M>> {
         let boundary = match port {
             Port::Boundary(index) => index,
             port @ Port::Bound(..) => {
@@ -331,14 +506,16 @@ impl<G: Graph> PortDiff<G> {
                 data: Port::Boundary(boundary),
                 owner: self.clone(),
             }],
-            &BoundarySite::Wire { id, end } => {
+            BoundarySite::Wire { id, end } => {
                 let opp_site = BoundarySite::Wire {
                     id,
                     end: end.opposite(),
                 };
                 let Some(bd_index) = self
-                    .boundary_iter()
-                    .filter(|&bd| self.boundary_site(bd) == &opp_site)
+                    .boundary
+                    .iter()
+                    .positions(|(site, _)| site == opp_site)
+                    .map(BoundaryIndex::from)
                     .at_most_one()
                     .ok()
                     .expect("found more than one wire end")
@@ -354,13 +531,63 @@ impl<G: Graph> PortDiff<G> {
         }
     }
 
+    /// The number of ports of descendants of `self` that map to `boundary`.
+    ///
+    /// Ports are counted recursively, i.e. ports of grandchildren that map to
+    /// a port of a child mapping to `boundary` are counted too.
+    pub fn boundary_fanout(&self, boundary: BoundaryIndex) -> usize {
+        // The iterator starts with the boundary port itself
+        DescendantsIter::new(Port::Boundary(boundary), self.clone()).count() - 1
+    }
+
+    /// The concrete ports at the other end of the edge of `boundary`.
+    ///
+    /// Combines [`Self::opposite_ports`] and [`Self::resolve_port`]: wires
+    /// are followed across the hierarchy until a concrete port is found. As
+    /// for [`Self::opposite_ports`], the ports may not be compatible with
+    /// `self`.
+    pub fn boundary_opposite(&self, boundary: BoundaryIndex) -> Vec<Owned<Port<G>, G, M>> {
+        self.opposite_ports(Port::Boundary(boundary))
+            .flat_map(|Owned { data, owner }| owner.resolve_port(data))
+            .collect()
+    }
+
+    /// The boundary ports that do not resolve to any concrete port.
+    ///
+    /// These are dangling boundary ports on a wire with no opposite end, see
+    /// [`Self::resolve_port`].
+    pub fn unresolved_boundaries(&self) -> Vec<BoundaryIndex> {
+        self.boundary_iter()
+            .filter(|&b| self.resolve_port(Port::Boundary(b)).is_empty())
+            .collect()
+    }
+
     pub fn boundary_iter(&self) -> impl Iterator<Item = BoundaryIndex> {
         (0..self.boundary.len()).map_into()
     }
 
-    /// The boundary port at `boundary`, if it is a site.
-    pub fn boundary_site(&self, boundary: BoundaryIndex) -> &BoundarySite<G> {
-        &self.boundary[usize::from(boundary)].0
+    /// The site of the boundary port at `boundary`.
+    ///
+    /// Panics if `boundary` is not a boundary port of `self`.
+    pub fn boundary_site(&self, boundary: BoundaryIndex) -> BoundarySite<G> {
+        self.boundary.get(usize::from(boundary)).0
+    }
+
+    /// The label of the boundary port at `boundary`, if it has one.
+    ///
+    /// Labels are set at rewrite time, see [`Self::rewrite_labelled`].
+    pub fn boundary_label(&self, boundary: BoundaryIndex) -> Option<&str> {
+        self.boundary_labels.get(&boundary).map(String::as_str)
+    }
+
+    /// The boundary port with label `label`, if there is one.
+    ///
+    /// If several boundary ports share the label, the first one is returned.
+    pub fn find_boundary(&self, label: &str) -> Option<BoundaryIndex> {
+        self.boundary_labels
+            .iter()
+            .find(|(_, l)| l.as_str() == label)
+            .map(|(&b, _)| b)
     }
 
     pub fn is_compatible(&self, other: &Self) -> bool {
@@ -371,16 +598,21 @@ impl<G: Graph> PortDiff<G> {
         self.boundary.len()
     }
 
-    /// All the ports of descendants of `self` that map to `port`.
-    pub fn descendants(&self, port: BoundPort<G::Edge>) -> impl Iterator<Item = Owned<Port<G>, G>> {
-        DescendantsIter::new(port, self.clone())
+    /// All the ports of descendants of `self` that map to `port`, in
+    /// breadth-first order.
+    pub fn descendants(
+        &self,
+        port: BoundPort<G::Edge>,
+    ) -> impl Iterator<Item = Owned<Port<G>, G, M>> {
+        DescendantsIter::new(port, self.clone()).map(|(port, _)| port)
     }
 
-    pub fn all_children(&self) -> impl Iterator<Item = PortDiff<G>> + '_ {
+    /// All children of `self`, in the order they were created.
+    pub fn all_children(&self) -> impl Iterator<Item = PortDiff<G, M>> + '_ {
         self.data.all_children().map(|p| p.into())
     }
 
-    pub fn port_children(&self, port: Port<G>) -> impl Iterator<Item = PortDiff<G>> + '_ {
+    pub fn port_children(&self, port: Port<G>) -> impl Iterator<Item = PortDiff<G, M>> + '_ {
         self.port_outgoing(port).map(|e| e.target().clone().into())
     }
 
@@ -388,6 +620,21 @@ impl<G: Graph> PortDiff<G> {
         self.data.n_outgoing() > 0
     }
 
+    /// Whether `self` has no parents, i.e. it was created from a graph.
+    pub fn is_root(&self) -> bool {
+        self.all_incoming().is_empty()
+    }
+
+    /// The roots of the diff hierarchy that `self` descends from.
+    ///
+    /// A hierarchy may have several roots, e.g. to rewrite a collection of
+    /// independent graphs. Diffs that do not share any root are always
+    /// compatible, and their extracted graph is the disjoint union of the
+    /// graphs of each root.
+    pub fn roots(&self) -> Vec<Self> {
+        PortDiffGraph::from_sinks([self.clone()]).roots().collect()
+    }
+
     // #[cfg(test)]
     // fn find_boundary_edge(&self, node: &V, port: &P) -> Option<BoundaryEdge>
     // where
@@ -401,47 +648,59 @@ impl<G: Graph> PortDiff<G> {
     // }
 }
 
-struct DescendantsIter<G: Graph> {
-    curr_ports: Vec<Owned<Port<G>, G>>,
+/// The ports of descendants that map to a port, along with their distance
+/// to the port in the hierarchy.
+///
+/// Ports are visited breadth-first, children in the order they were created.
+struct DescendantsIter<G: Graph, M> {
+    curr_ports: VecDeque<(Owned<Port<G>, G, M>, usize)>,
 }
 
-impl<G: Graph> DescendantsIter<G> {
-    fn new(port: impl Into<Port<G>>, owner: PortDiff<G>) -> Self {
-        let curr_ports = vec![Owned {
+impl<G: Graph, M: Clone> DescendantsIter<G, M> {
+    fn new(port: impl Into<Port<G>>, owner: PortDiff<G, M>) -> Self {
+        let port = Owned {
             data: port.into(),
             owner,
-        }];
-        Self { curr_ports }
+        };
+        Self {
+            curr_ports: VecDeque::from([(port, 0)]),
+        }
     }
 }
 
-impl<G: Graph> Iterator for DescendantsIter<G> {
-    type Item = Owned<Port<G>, G>;
+impl<G: Graph, M: Clone> Iterator for DescendantsIter<G, M> {
+    type Item = (Owned<Port<G>, G, M>, usize);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let port = self.curr_ports.pop()?;
+        let (port, depth) = self.curr_ports.pop_front()?;
         self.curr_ports
             .extend(port.owner.all_outgoing().iter().filter_map(|e| {
                 let port = e.value().map_to_child(&port.data)?;
                 let owner = e.target().clone().into();
-                Some(Owned {
+                let port = Owned {
                     data: Port::from(port),
                     owner,
-                })
+                };
+                Some((port, depth + 1))
             }));
-        Some(port)
+        Some((port, depth))
     }
 }
 
-impl<G: Graph> PortDiffData<G> {
+impl<G: Graph, M> PortDiffData<G, M> {
     /// The replacement graph of the diff.
     pub fn graph(&self) -> &G {
         &self.graph
     }
 
-    /// The value of the diff, if it is set.
-    pub fn value(&self) -> Option<usize> {
-        self.value
+    /// The value of the diff.
+    pub fn value(&self) -> &M {
+        &self.value
+    }
+
+    /// The user-supplied key of the diff, if any.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
     }
 }
 
@@ -455,18 +714,18 @@ impl<G: Graph> PortDiffData<G> {
 #[derive_where(PartialOrd; G: Graph, D: PartialOrd)]
 #[derive_where(Ord; G: Graph, D: Ord)]
 #[derive_where(Debug; G: Graph, D: Debug)]
-pub struct Owned<D, G: Graph> {
+pub struct Owned<D, G: Graph, M = ()> {
     pub data: D,
-    pub owner: PortDiff<G>,
+    pub owner: PortDiff<G, M>,
 }
 
-impl<D, G: Graph> Owned<D, G> {
-    pub fn new(data: D, owner: PortDiff<G>) -> Self {
+impl<D, G: Graph, M> Owned<D, G, M> {
+    pub fn new(data: D, owner: PortDiff<G, M>) -> Self {
         Self { data, owner }
     }
 }
 
-impl<G: Graph> Owned<BoundPort<G::Edge>, G> {
+impl<G: Graph, M: Clone> Owned<BoundPort<G::Edge>, G, M> {
     fn opposite(&self) -> Self {
         Self {
             data: self.data.opposite(),
@@ -474,7 +733,7 @@ impl<G: Graph> Owned<BoundPort<G::Edge>, G> {
         }
     }
 
-    fn site(&self) -> Owned<Site<G::Node, G::PortLabel>, G> {
+    fn site(&self) -> Owned<Site<G::Node, G::PortLabel>, G, M> {
         Owned {
             data: self.owner.graph.get_port_site(self.data),
             owner: self.owner.clone(),
@@ -490,26 +749,44 @@ mod tests {
     use portgraph::{LinkMut, NodeIndex, PortGraph, PortMut, PortOffset};
     use rstest::{fixture, rstest};
 
-    use crate::{port::EdgeEnd, PortDiffGraph};
+    use crate::{
+        port::EdgeEnd,
+        simple_graph::{SimpleGraph, SimpleNode},
+        PortDiffGraph,
+    };
 
     use super::*;
 
     pub(crate) type TestPortDiff = PortDiff<PortGraph>;
 
-    impl TestPortDiff {
-        pub(crate) fn identity_subgraph(
-            &self,
-            nodes: impl IntoIterator<Item = NodeIndex>,
-        ) -> TestPortDiff {
-            let graph = self.graph.clone();
-            let nodes = nodes.into_iter().collect();
-            self.rewrite_induced(&nodes, graph, |p| {
-                Owned::new(p, self.clone()).site().unwrap().into()
-            })
-            .unwrap()
+    #[rstest]
+    fn test_incoming_edges(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        assert_eq!(parent.incoming_edges().count(), 0);
+
+        let (edge_parent, edge) = child.incoming_edges().exactly_one().ok().unwrap();
+        assert_eq!(edge_parent, parent);
+        assert_eq!(edge.boundary_port_map().len(), child.n_boundary_ports());
+        for (port, &boundary) in edge.boundary_port_map() {
+            assert_eq!(&child.parent_port(boundary).data, port);
         }
     }
 
+    #[rstest]
+    fn test_bound_ancestor_cache(parent_child_diffs: [TestPortDiff; 2]) {
+        let [_, child] = parent_child_diffs;
+        assert_eq!(child.bound_ancestors.len(), 0);
+        child.resolve_all_ports();
+        assert_eq!(child.bound_ancestors.len(), child.n_boundary_ports());
+
+        let boundary = child.boundary_iter().next().unwrap();
+        let parent_port = child.parent_port(boundary);
+        assert_eq!(
+            Port::Bound(child.bound_ancestor(boundary).data),
+            parent_port.data
+        );
+    }
+
     #[fixture]
     pub(crate) fn parent_child_diffs() -> [TestPortDiff; 2] {
         let mut graph = PortGraph::new();
@@ -644,7 +921,6 @@ mod tests {
         insta::assert_snapshot!(serialized);
     }
 
-    #[ignore = "TODO this is currently not deterministic"]
     #[rstest]
     fn serialize_parent_two_children(parent_two_children_diffs: [TestPortDiff; 3]) {
         let [_, child_1, child_2] = parent_two_children_diffs;
@@ -653,7 +929,6 @@ mod tests {
         insta::assert_snapshot!(serialized);
     }
 
-    #[ignore = "TODO this is currently not deterministic"]
     #[rstest]
     fn serialize_parent_two_children_overlapping(
         parent_two_children_overlapping_diffs: [TestPortDiff; 3],
@@ -664,6 +939,100 @@ mod tests {
         insta::assert_snapshot!(serialized);
     }
 
+    #[test]
+    fn test_opposite_ports_order() {
+        // a -> b -> c, with b copied by two children and a grandchild
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let b = SimpleNode::new(1);
+        let child_1 = root.select_subgraph([b]);
+        let grandchild = child_1.select_subgraph(child_1.graph().nodes_iter());
+        let child_2 = root.select_subgraph([b]);
+
+        let edge = root
+            .graph()
+            .edges_iter()
+            .find(|&e| root.graph().incident_node(e, EdgeEnd::Right) == b)
+            .unwrap();
+        let out_port = BoundPort {
+            edge,
+            end: EdgeEnd::Left,
+        };
+        let ports = root.opposite_ports_with_depth(Port::Bound(out_port));
+        let owners = ports.iter().map(|(p, _)| p.owner.clone()).collect_vec();
+        assert_eq!(owners.len(), 4);
+        let in_port = Port::Bound(out_port.opposite());
+        assert_eq!(ports[0], (Owned::new(in_port, root.clone()), 0));
+        let position =
+            |diff: &PortDiff<SimpleGraph>| owners.iter().position(|o| o == diff).unwrap();
+        assert_eq!(
+            owners,
+            vec![
+                root.clone(),
+                child_1.clone(),
+                child_2.clone(),
+                grandchild.clone()
+            ]
+        );
+        let depth = |diff: &PortDiff<SimpleGraph>| ports[position(diff)].1;
+        assert_eq!([&child_1, &child_2, &grandchild].map(depth), [1, 1, 2]);
+
+        // The same ports are found from the other end of the edge
+        let boundary_port = ports[position(&grandchild)].0.data;
+        let opposite = grandchild.opposite_ports(boundary_port).collect_vec();
+        assert_eq!(opposite, vec![Owned::new(Port::Bound(out_port), root)]);
+    }
+
+    #[rstest]
+    fn test_find_cross_edges(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let edges = child.find_cross_edges(&parent);
+        assert_eq!(edges.len(), 6);
+        for (child_port, parent_port) in &edges {
+            assert!(child_port.owner == child);
+            assert!(matches!(child_port.data, Port::Boundary(_)));
+            assert!(parent_port.owner == parent);
+            assert!(matches!(parent_port.data, Port::Bound(_)));
+        }
+
+        let reversed = parent.find_cross_edges(&child);
+        assert_eq!(
+            reversed
+                .into_iter()
+                .map(|(p, c)| (c, p))
+                .collect::<BTreeSet<_>>(),
+            edges.into_iter().collect()
+        );
+    }
+
+    #[rstest]
+    fn test_boundary_queries(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        assert!(child.boundary_iter().all(|b| child.boundary_fanout(b) == 0));
+        assert!(child.unresolved_boundaries().is_empty());
+
+        let grandchild = child.select_subgraph(Graph::nodes_iter(child.graph()));
+        assert!(child.boundary_iter().all(|b| child.boundary_fanout(b) == 1));
+        assert!(grandchild
+            .boundary_iter()
+            .all(|b| grandchild.boundary_fanout(b) == 0));
+
+        //  a --                          a --
+        //  a -- b -- c -- d      =>           c -- d
+        //  a --                          a --
+        let no_b = parent.rewrite_node_to_wires(NodeIndex::new(1)).unwrap();
+        let unresolved = no_b.unresolved_boundaries();
+        assert_eq!(unresolved.len(), 2);
+        for b in no_b.boundary_iter().filter(|b| !unresolved.contains(b)) {
+            assert!(!no_b.resolve_port(Port::Boundary(b)).is_empty());
+        }
+        for b in unresolved {
+            // The dangling inputs of b are still attached to a
+            let opposite = no_b.boundary_opposite(b);
+            assert_eq!(opposite.len(), 1);
+            assert!(opposite[0].owner == parent);
+        }
+    }
+
     #[rstest]
     fn test_register_child(parent_child_diffs: [TestPortDiff; 2]) {
         let [parent, child] = parent_child_diffs;
@@ -762,7 +1131,10 @@ mod tests {
                 PortDiffData {
                     graph: (),
                     boundary: Default::default(),
-                    value: None,
+                    boundary_labels: Default::default(),
+                    value: (),
+                    key: None,
+                    bound_ancestors: Default::default(),
                 },
                 parents,
             )
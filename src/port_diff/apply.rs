@@ -0,0 +1,212 @@
+//! Replaying the rewrite of a diff onto a standalone graph.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::{
+    port::{BoundaryIndex, EdgeEnd, Port, Site},
+    BoundarySite, Graph, PortDiff, RemoveNodes,
+};
+
+use super::Owned;
+
+/// Error when applying the rewrite of a diff to a graph.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ApplyError {
+    /// The diff does not have exactly one parent.
+    #[error("the diff must have exactly one parent")]
+    NotSingleParent,
+    /// A node of the parent is missing from the node map.
+    #[error("a node of the parent is not in the node map")]
+    UnmappedNode,
+    /// A boundary port of the rewrite could not be found in the graph.
+    #[error("a boundary port of the rewrite has no matching edge in the graph")]
+    UnresolvedPort,
+    /// A wire of the rewrite is linked at the wrong end, or more than once
+    /// at the same end.
+    #[error("wire {wire} is linked inconsistently")]
+    InvalidWire { wire: usize },
+}
+
+/// What a boundary port of the rewrite is linked to in the graph.
+enum Endpoint<G: Graph> {
+    /// A site that is not rewritten, at the given end of the new edge.
+    Site(Site<G::Node, G::PortLabel>, EdgeEnd),
+    /// Another boundary port of the rewrite, at the right end of the new edge.
+    Boundary(BoundaryIndex),
+}
+
+/// A site of a new edge, either kept in the graph or in the replacement graph.
+enum LinkSite<S> {
+    Kept(S),
+    New(S),
+}
+
+impl<G: RemoveNodes, M: Clone> PortDiff<G, M> {
+    /// Apply the rewrite of `self` to `graph`.
+    ///
+    /// `graph` must be isomorphic to the extraction of the parent of `self`,
+    /// and `node_map` must map the nodes of the parent to the corresponding
+    /// nodes of `graph`. The nodes rewritten by `self` are removed from
+    /// `graph`, a copy of the replacement graph is added and linked to the
+    /// rest of `graph` at the boundary ports.
+    ///
+    /// This replays a rewrite onto graphs outside of the diff hierarchy.
+    /// `graph` is only modified if no error is returned.
+    pub fn apply_to(
+        &self,
+        graph: &mut G,
+        node_map: &BTreeMap<G::Node, G::Node>,
+    ) -> Result<(), ApplyError> {
+        let (parent, edge) = self
+            .incoming_edges()
+            .exactly_one()
+            .map_err(|_| ApplyError::NotSingleParent)?;
+        let map_site = |site: Site<G::Node, G::PortLabel>| {
+            site.filter_map_node(|n| node_map.get(&n).copied())
+                .ok_or(ApplyError::UnmappedNode)
+        };
+        let removed: BTreeSet<G::Node> = edge
+            .subgraph()
+            .nodes()
+            .iter()
+            .map(|n| node_map.get(n).copied().ok_or(ApplyError::UnmappedNode))
+            .try_collect()?;
+
+        // Find the edge of `graph` at every boundary port. Bound ports are
+        // resolved first, as their opposite site is known.
+        let mut target_ports = BTreeMap::new();
+        let port_map = edge
+            .boundary_port_map()
+            .iter()
+            .sorted_by_key(|(port, _)| matches!(port, Port::Boundary(_)));
+        for (&port, &boundary) in port_map {
+            let site = Owned::new(port, parent.clone())
+                .site()
+                .ok_or(ApplyError::UnresolvedPort)?;
+            let mut candidates = graph
+                .get_bound_ports(map_site(site)?)
+                .filter(|p| !target_ports.contains_key(p));
+            let target_port = match port {
+                Port::Bound(port) => {
+                    let opp_site = map_site(parent.graph().get_port_site(port.opposite()))?;
+                    candidates.find(|p| graph.get_port_site(p.opposite()) == opp_site)
+                }
+                Port::Boundary(_) => candidates
+                    .find(|p| !removed.contains(&graph.incident_node(p.edge, p.end.opposite()))),
+            }
+            .ok_or(ApplyError::UnresolvedPort)?;
+            target_ports.insert(target_port, boundary);
+        }
+        let endpoints: Vec<(BoundaryIndex, Endpoint<G>)> = target_ports
+            .iter()
+            .filter_map(|(port, &boundary)| {
+                let opp = port.opposite();
+                if let Some(&opp_boundary) = target_ports.get(&opp) {
+                    // Both ends are rewritten: link the two new sites
+                    (port.end == EdgeEnd::Left)
+                        .then_some((boundary, Endpoint::Boundary(opp_boundary)))
+                } else {
+                    let site = graph.get_port_site(opp);
+                    Some((boundary, Endpoint::Site(site, opp.end)))
+                }
+            })
+            .collect();
+
+        // Find the new edges before modifying `graph`
+        let new_site = |boundary: BoundaryIndex| self.boundary_site(boundary);
+        let mut links = Vec::new();
+        let mut wires: BTreeMap<usize, [Option<LinkSite<_>>; 2]> = BTreeMap::new();
+        let mut add_link = |site, end: EdgeEnd, other| match other {
+            BoundarySite::Site(other) => {
+                let other = LinkSite::New(other);
+                links.push(match end {
+                    EdgeEnd::Left => (site, other),
+                    EdgeEnd::Right => (other, site),
+                });
+                Ok(())
+            }
+            BoundarySite::Wire { id, end: wire_end } => {
+                let entry = &mut wires.entry(id).or_default()[end.index()];
+                if wire_end != end.opposite() || entry.is_some() {
+                    return Err(ApplyError::InvalidWire { wire: id });
+                }
+                *entry = Some(site);
+                Ok(())
+            }
+        };
+        for (boundary, endpoint) in endpoints {
+            match endpoint {
+                Endpoint::Site(site, end) => {
+                    add_link(LinkSite::Kept(site), end, new_site(boundary))?
+                }
+                Endpoint::Boundary(opp_boundary) => {
+                    match (new_site(boundary), new_site(opp_boundary)) {
+                        (BoundarySite::Site(left), right) => {
+                            add_link(LinkSite::New(left), EdgeEnd::Left, right)?
+                        }
+                        (left, BoundarySite::Site(right)) => {
+                            add_link(LinkSite::New(right), EdgeEnd::Right, left)?
+                        }
+                        (BoundarySite::Wire { id, .. }, BoundarySite::Wire { .. }) => {
+                            return Err(ApplyError::InvalidWire { wire: id })
+                        }
+                    }
+                }
+            }
+        }
+        let wire_links = wires
+            .into_values()
+            .filter_map(|[left, right]| Some((left?, right?)));
+        let links = links.into_iter().chain(wire_links).collect_vec();
+
+        // Rewrite the graph
+        for &node in &removed {
+            graph.remove_node(node);
+        }
+        let all_nodes = self.graph().nodes_iter().collect();
+        let new_nodes = graph.add_subgraph(self.graph(), &all_nodes);
+        let resolve = |site: LinkSite<Site<G::Node, G::PortLabel>>| match site {
+            LinkSite::Kept(site) => site,
+            LinkSite::New(site) => site.map_node(|n| new_nodes[&n]),
+        };
+        for (left, right) in links {
+            graph.link_sites(resolve(left), resolve(right));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkView, PortGraph, PortMut, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_apply_to(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+
+        // A copy of the parent graph with different node indices
+        let mut graph = PortGraph::new();
+        graph.add_node(0, 0);
+        let nodes = Graph::nodes_iter(parent.graph()).collect();
+        let node_map = graph.add_subgraph(parent.graph(), &nodes);
+
+        child.apply_to(&mut graph, &node_map).unwrap();
+        let expected = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(graph.node_count(), expected.node_count() + 1);
+        assert_eq!(graph.link_count(), expected.link_count());
+
+        assert_eq!(
+            parent.apply_to(&mut graph, &node_map),
+            Err(ApplyError::NotSingleParent)
+        );
+    }
+}
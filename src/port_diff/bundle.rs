@@ -0,0 +1,391 @@
+//! Bundles of boundary ports at the same node.
+//!
+//! When many parallel edges cross the cut of a rewrite, the boundary of the
+//! new diff has one port per edge. Diffs store consecutive boundary ports
+//! at the same node of their graph as a single bundle, which squashing and
+//! port resolution expand back into one port per edge. Grouping boundary
+//! ports by the rewritten parent node they were attached to gives a
+//! similarly coarse view of the boundary, with one bundle per parent node.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug},
+    hash::{Hash, Hasher},
+};
+
+use derive_where::derive_where;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    port::{BoundaryIndex, BoundarySite, Port, Site},
+    Graph, PortDiff,
+};
+
+use super::{IncomingEdgeIndex, InvalidRewriteError, Owned};
+
+/// The boundary ports of a diff, along with the incoming edge each port maps
+/// to.
+///
+/// Consecutive boundary ports at the same node of the diff's graph that map
+/// to the same incoming edge are stored as a single bundle. Bundles are
+/// created on insertion, so that equal boundaries are always stored equally.
+#[derive_where(Clone, Default, PartialEq, Eq; G: Graph)]
+pub(crate) struct Boundary<G: Graph> {
+    entries: Vec<BoundaryEntry<G>>,
+    /// The index of the first boundary port of every entry.
+    offsets: Vec<usize>,
+    len: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive_where(Clone, PartialEq, Eq; G: Graph)]
+#[serde(bound(
+    serialize = "G::Node: Serialize, G::PortLabel: Serialize",
+    deserialize = "G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>"
+))]
+enum BoundaryEntry<G: Graph> {
+    /// A single boundary port.
+    Port(BoundarySite<G>, IncomingEdgeIndex),
+    /// Boundary ports at the sites of `node` with labels `ports`.
+    Bundle {
+        node: G::Node,
+        ports: Vec<G::PortLabel>,
+        edge: IncomingEdgeIndex,
+    },
+}
+
+impl<G: Graph> BoundaryEntry<G> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Port(..) => 1,
+            Self::Bundle { ports, .. } => ports.len(),
+        }
+    }
+
+    fn get(&self, pos: usize) -> (BoundarySite<G>, IncomingEdgeIndex) {
+        match self {
+            Self::Port(site, edge) => {
+                debug_assert_eq!(pos, 0);
+                (site.clone(), *edge)
+            }
+            Self::Bundle { node, ports, edge } => {
+                let site = Site {
+                    node: *node,
+                    port: ports[pos].clone(),
+                };
+                (site.into(), *edge)
+            }
+        }
+    }
+}
+
+impl<G: Graph> Boundary<G> {
+    /// The number of boundary ports.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The site and incoming edge of the boundary port at `index`.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub(crate) fn get(&self, index: usize) -> (BoundarySite<G>, IncomingEdgeIndex) {
+        assert!(index < self.len, "boundary index out of bounds");
+        let entry = self.offsets.partition_point(|&offset| offset <= index) - 1;
+        self.entries[entry].get(index - self.offsets[entry])
+    }
+
+    /// The incoming edge of the boundary port at `index`, if it exists.
+    pub(crate) fn edge(&self, index: usize) -> Option<IncomingEdgeIndex> {
+        (index < self.len).then(|| self.get(index).1)
+    }
+
+    /// Iterate over the sites and incoming edges of all boundary ports, in
+    /// order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (BoundarySite<G>, IncomingEdgeIndex)> + '_ {
+        self.entries
+            .iter()
+            .flat_map(|entry| (0..entry.len()).map(move |pos| entry.get(pos)))
+    }
+
+    /// Add a boundary port, bundling it with the previous port if both are
+    /// at the same node and map to the same incoming edge.
+    pub(crate) fn push(&mut self, site: BoundarySite<G>, edge: IncomingEdgeIndex) {
+        self.len += 1;
+        let site = match site {
+            BoundarySite::Site(site) => site,
+            wire @ BoundarySite::Wire { .. } => {
+                self.offsets.push(self.len - 1);
+                self.entries.push(BoundaryEntry::Port(wire, edge));
+                return;
+            }
+        };
+        match self.entries.last_mut() {
+            Some(BoundaryEntry::Bundle {
+                node,
+                ports,
+                edge: bundle_edge,
+            }) if *node == site.node && *bundle_edge == edge => {
+                ports.push(site.port);
+            }
+            Some(last)
+                if matches!(
+                    last,
+                    BoundaryEntry::Port(BoundarySite::Site(prev), last_edge)
+                        if prev.node == site.node && *last_edge == edge
+                ) =>
+            {
+                let BoundaryEntry::Port(BoundarySite::Site(prev), _) = &*last else {
+                    unreachable!()
+                };
+                let ports = vec![prev.port.clone(), site.port];
+                *last = BoundaryEntry::Bundle {
+                    node: site.node,
+                    ports,
+                    edge,
+                };
+            }
+            _ => {
+                self.offsets.push(self.len - 1);
+                self.entries.push(BoundaryEntry::Port(site.into(), edge));
+            }
+        }
+    }
+
+    /// Set the site of the boundary port at `index`.
+    pub(crate) fn set_site(&mut self, index: usize, site: BoundarySite<G>) {
+        let mut ports = self.iter().collect::<Vec<_>>();
+        ports[index].0 = site;
+        *self = ports.into_iter().collect();
+    }
+
+    /// Set the incoming edge of the boundary port at `index`.
+    pub(crate) fn set_edge(&mut self, index: usize, edge: IncomingEdgeIndex) {
+        let mut ports = self.iter().collect::<Vec<_>>();
+        ports[index].1 = edge;
+        *self = ports.into_iter().collect();
+    }
+}
+
+impl<G: Graph> FromIterator<(BoundarySite<G>, IncomingEdgeIndex)> for Boundary<G> {
+    fn from_iter<I: IntoIterator<Item = (BoundarySite<G>, IncomingEdgeIndex)>>(iter: I) -> Self {
+        let mut boundary = Self::default();
+        for (site, edge) in iter {
+            boundary.push(site, edge);
+        }
+        boundary
+    }
+}
+
+impl<G: Graph> Debug for Boundary<G>
+where
+    G::Node: Debug,
+    G::PortLabel: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Hashes like the list of all boundary ports, independently of bundling.
+impl<G: Graph> Hash for Boundary<G>
+where
+    G::Node: Hash,
+    G::PortLabel: Hash,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for port in self.iter() {
+            port.hash(state);
+        }
+    }
+}
+
+impl<G: Graph> Serialize for Boundary<G>
+where
+    G::Node: Serialize,
+    G::PortLabel: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.entries.serialize(serializer)
+    }
+}
+
+impl<'de, G: Graph> Deserialize<'de> for Boundary<G>
+where
+    G::Node: Deserialize<'de>,
+    G::PortLabel: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = Vec::<BoundaryEntry<G>>::deserialize(deserializer)?;
+        // Re-bundle, in case the serialized entries were not bundled
+        Ok(entries
+            .iter()
+            .flat_map(|entry| (0..entry.len()).map(move |pos| entry.get(pos)))
+            .collect())
+    }
+}
+
+/// An ordered group of boundary ports of a diff, whose parent ports are all
+/// at the same node of the same parent.
+#[derive_where(Clone; G: Graph)]
+pub struct BoundaryBundle<G: Graph, M = ()> {
+    /// The node of the parent that the parent ports are at.
+    pub parent_node: Owned<G::Node, G, M>,
+    /// The boundary ports of the bundle, in increasing order.
+    pub ports: Vec<BoundaryIndex>,
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// The boundary ports of `self`, bundled by the parent node of their
+    /// parent ports.
+    ///
+    /// Bundles are ordered by their first boundary port. Boundary ports
+    /// whose parent port is on a wire have no parent node and are not part
+    /// of any bundle.
+    pub fn boundary_bundles(&self) -> Vec<BoundaryBundle<G, M>> {
+        let mut bundles: Vec<BoundaryBundle<G, M>> = Vec::new();
+        let mut bundle_index = BTreeMap::new();
+        for boundary in self.boundary_iter() {
+            let parent_port = self.parent_port(boundary);
+            let Some(site) = parent_port.site() else {
+                continue;
+            };
+            let parent_node = Owned::new(site.node, parent_port.owner);
+            let index = *bundle_index.entry(parent_node.clone()).or_insert_with(|| {
+                bundles.push(BoundaryBundle {
+                    parent_node,
+                    ports: Vec::new(),
+                });
+                bundles.len() - 1
+            });
+            bundles[index].ports.push(boundary);
+        }
+        bundles
+    }
+
+    /// Create a new diff that rewrites `nodes` and `edges` to `new_graph`,
+    /// mapping bundles of boundary ports to nodes.
+    ///
+    /// The function `bundle_map` is called once for every rewritten node
+    /// with boundary ports, and must return the node of `new_graph` that
+    /// these ports are attached to. Every port is mapped to the site of that node
+    /// with the same label as its site in the parent. Errors with
+    /// [`InvalidRewriteError::WireBoundaryPort`] if a boundary port is on a
+    /// wire. Otherwise identical to [`Self::rewrite`].
+    ///
+    /// Consecutive boundary ports of a bundle are stored as a single entry of
+    /// the boundary of the new diff.
+    pub fn rewrite_bundled(
+        nodes: impl IntoIterator<Item = Owned<G::Node, G, M>>,
+        edges: impl IntoIterator<Item = (Owned<Port<G>, G, M>, Owned<Port<G>, G, M>)>,
+        new_graph: G,
+        mut bundle_map: impl FnMut(&Owned<G::Node, G, M>) -> G::Node,
+    ) -> Result<Self, InvalidRewriteError<G, M>>
+    where
+        M: Default,
+    {
+        let mut bundle_nodes = BTreeMap::new();
+        Self::rewrite_sites(nodes, edges, new_graph, |site| {
+            let parent_node = Owned::new(site.data.node, site.owner);
+            let node = *bundle_nodes
+                .entry(parent_node)
+                .or_insert_with_key(&mut bundle_map);
+            Site {
+                node,
+                port: site.data.port,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        simple_graph::{SimpleGraph, SimplePort},
+        EdgeEnd,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_boundary_bundles_ports() {
+        let mut graph = SimpleGraph::new();
+        let a = graph.add_node(2, 0);
+        let b = graph.add_node(1, 0);
+        let site = |node, i| {
+            BoundarySite::<SimpleGraph>::from(Site {
+                node,
+                port: SimplePort::In(i),
+            })
+        };
+        let edge = IncomingEdgeIndex::from;
+        let ports = vec![
+            (site(a, 0), edge(0)),
+            (site(a, 1), edge(0)),
+            (site(a, 1), edge(1)),
+            (BoundarySite::wire(0, EdgeEnd::Left), edge(1)),
+            (site(b, 0), edge(1)),
+        ];
+        let mut boundary: Boundary<SimpleGraph> = ports.iter().cloned().collect();
+        assert_eq!(boundary.entries.len(), 4);
+        assert_eq!(boundary.len(), 5);
+        assert_eq!(boundary.iter().collect::<Vec<_>>(), ports);
+        for (i, port) in ports.iter().enumerate() {
+            assert_eq!(&boundary.get(i), port);
+        }
+        assert_eq!(boundary.edge(5), None);
+
+        let ser = serde_json::to_string(&boundary).unwrap();
+        let de: Boundary<SimpleGraph> = serde_json::from_str(&ser).unwrap();
+        assert_eq!(de, boundary);
+
+        // Moving a port to the previous incoming edge extends the bundle
+        boundary.set_edge(2, edge(0));
+        assert_eq!(boundary.entries.len(), 3);
+        assert_eq!(boundary.get(2), (site(a, 1), edge(0)));
+        // Moving a port off the node splits the bundle
+        boundary.set_site(1, site(b, 0));
+        assert_eq!(boundary.entries.len(), 5);
+        assert_eq!(boundary.get(1), (site(b, 0), edge(0)));
+    }
+
+    #[test]
+    fn test_rewrite_bundled() {
+        // Two nodes linked by three parallel edges
+        let mut graph = SimpleGraph::new();
+        let left = graph.add_node(0, 3);
+        let right = graph.add_node(3, 0);
+        for i in 0..3 {
+            graph.link_nodes(left, i, right, i);
+        }
+        let root = PortDiff::from_graph(graph);
+
+        let mut new_graph = SimpleGraph::new();
+        let new_right = new_graph.add_node(3, 0);
+        let mut n_calls = 0;
+        let child = PortDiff::rewrite_bundled(
+            [Owned::new(right, root.clone())],
+            [],
+            new_graph,
+            |parent_node| {
+                assert_eq!(parent_node.data, right);
+                n_calls += 1;
+                new_right
+            },
+        )
+        .unwrap();
+        assert_eq!(n_calls, 1);
+        assert_eq!(child.n_boundary_ports(), 3);
+        // The three boundary ports are stored as a single bundle
+        assert_eq!(child.boundary.entries.len(), 1);
+
+        let bundles = child.boundary_bundles();
+        assert_eq!(bundles.len(), 1);
+        assert_eq!(bundles[0].parent_node, Owned::new(right, root));
+        assert_eq!(bundles[0].ports, child.boundary_iter().collect::<Vec<_>>());
+
+        let g = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(g.node_count(), 2);
+        assert_eq!(g.edge_count(), 3);
+    }
+}
@@ -0,0 +1,44 @@
+//! Memoization of boundary port resolutions.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use derive_where::derive_where;
+
+use crate::{
+    port::{BoundPort, BoundaryIndex},
+    Graph,
+};
+
+use super::Owned;
+
+/// A cache of the bound ancestors of the boundary ports of a diff.
+///
+/// Diffs are immutable, so entries never need to be invalidated. The cache
+/// is emptied when the diff data is cloned, as the clone may be attached to
+/// different parents.
+#[derive_where(Default; G: Graph)]
+pub(crate) struct BoundAncestorCache<G: Graph, M>(
+    RefCell<BTreeMap<BoundaryIndex, Owned<BoundPort<G::Edge>, G, M>>>,
+);
+
+impl<G: Graph, M> Clone for BoundAncestorCache<G, M> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl<G: Graph, M> BoundAncestorCache<G, M> {
+    pub(crate) fn get(&self, boundary: BoundaryIndex) -> Option<Owned<BoundPort<G::Edge>, G, M>> {
+        self.0.borrow().get(&boundary).cloned()
+    }
+
+    pub(crate) fn insert(&self, boundary: BoundaryIndex, port: Owned<BoundPort<G::Edge>, G, M>) {
+        self.0.borrow_mut().insert(boundary, port);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+}
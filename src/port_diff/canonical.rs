@@ -0,0 +1,130 @@
+//! Canonical ordering of the boundary ports of a diff.
+
+use std::collections::BTreeMap;
+
+use crate::{port::BoundaryIndex, Graph, PortDiff};
+
+use super::{BoundarySite, EdgeData, IncomingEdgeIndex, PortDiffData};
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Whether the boundary of `self` is in canonical order.
+    ///
+    /// See [`Self::canonicalize_boundary`].
+    pub fn is_boundary_canonical(&self) -> bool {
+        let edges = self.incoming_edges().map(|(_, e)| e).collect::<Vec<_>>();
+        canonical_order(&self.boundary.iter().collect::<Vec<_>>(), &edges)
+            .into_iter()
+            .enumerate()
+            .all(|(i, b)| i == usize::from(b))
+    }
+
+    /// A copy of `self` with its boundary ports in canonical order.
+    ///
+    /// Boundary ports are sorted by incoming edge and then by the parent port
+    /// they are attached to, and the port maps of the incoming edges are
+    /// updated accordingly. Equal rewrites then have identical boundaries,
+    /// regardless of the order in which the boundary ports were created.
+    ///
+    /// The returned diff has the same parents, graph and value as `self`. It
+    /// is a new diff: the children of `self` are not attached to it. If the
+    /// boundary is already canonical, `self` is returned.
+    pub fn canonicalize_boundary(&self) -> Self {
+        if self.is_boundary_canonical() {
+            return self.clone();
+        }
+        let (parents, mut edges): (Vec<_>, Vec<_>) = self
+            .incoming_edges()
+            .map(|(parent, edge)| (parent, edge.clone()))
+            .unzip();
+        let mut boundary = self.boundary.iter().collect();
+        let mut boundary_labels = self.boundary_labels.clone();
+        canonicalize(&mut boundary, &mut boundary_labels, &mut edges);
+        let data = PortDiffData {
+            graph: self.graph.clone(),
+            boundary: boundary.into_iter().collect(),
+            boundary_labels,
+            value: self.value.clone(),
+            key: self.key.clone(),
+            bound_ancestors: Default::default(),
+        };
+        Self::try_with_parents(data, parents.into_iter().zip(edges).collect())
+            .expect("parents of an existing diff are compatible")
+    }
+}
+
+/// The boundary ports sorted by incoming edge and parent port.
+fn canonical_order<G: Graph>(
+    boundary: &[(BoundarySite<G>, IncomingEdgeIndex)],
+    edges: &[&EdgeData<G>],
+) -> Vec<BoundaryIndex> {
+    let mut order: Vec<_> = (0..boundary.len()).map(BoundaryIndex::from).collect();
+    order.sort_by_cached_key(|&b| {
+        let edge = boundary[usize::from(b)].1;
+        let parent_port = edges[edge.0].boundary_port_map().get_by_right(&b).cloned();
+        (edge, parent_port)
+    });
+    order
+}
+
+/// Reorder `boundary` in canonical order, updating the boundary labels and
+/// the port maps of `edges`.
+pub(super) fn canonicalize<G: Graph>(
+    boundary: &mut Vec<(BoundarySite<G>, IncomingEdgeIndex)>,
+    labels: &mut BTreeMap<BoundaryIndex, String>,
+    edges: &mut [EdgeData<G>],
+) {
+    let order = canonical_order(boundary, &edges.iter().collect::<Vec<_>>());
+    let mut new_index = vec![BoundaryIndex::from(0); order.len()];
+    for (i, &b) in order.iter().enumerate() {
+        new_index[usize::from(b)] = BoundaryIndex::from(i);
+    }
+    *boundary = order
+        .iter()
+        .map(|&b| boundary[usize::from(b)].clone())
+        .collect();
+    *labels = std::mem::take(labels)
+        .into_iter()
+        .map(|(b, label)| (new_index[usize::from(b)], label))
+        .collect();
+    for edge in edges {
+        edge.port_map = std::mem::take(&mut edge.port_map)
+            .into_iter()
+            .map(|(port, b)| (port, new_index[usize::from(b)]))
+            .collect();
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+    use crate::Port;
+
+    use super::*;
+
+    #[rstest]
+    fn test_canonicalize_boundary(parent_child_diffs: [TestPortDiff; 2]) {
+        let [_, child] = parent_child_diffs;
+        let canonical = child.canonicalize_boundary();
+        assert!(canonical.is_boundary_canonical());
+        assert_eq!(canonical.n_boundary_ports(), child.n_boundary_ports());
+        assert!(canonical.canonicalize_boundary() == canonical);
+
+        // Boundary ports are ordered by parent port
+        let (_, edge) = canonical.incoming_edges().next().unwrap();
+        let ports: Vec<&Port<_>> = canonical
+            .boundary_iter()
+            .map(|b| edge.boundary_port_map().get_by_right(&b).unwrap())
+            .collect();
+        assert!(ports.windows(2).all(|w| w[0] < w[1]));
+
+        // Every boundary port keeps its site
+        for b in child.boundary_iter() {
+            let port = child.parent_port(b).data;
+            let b_canonical = *edge.boundary_port_map().get_by_left(&port).unwrap();
+            assert_eq!(child.boundary_site(b), canonical.boundary_site(b_canonical));
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Structural comparison of two diffs.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+use derive_where::derive_where;
+
+use crate::{
+    port::{BoundPort, BoundaryIndex, EdgeEnd, Port, Site},
+    BoundarySite, Graph, PortDiff,
+};
+
+use super::EdgeData;
+
+/// The structural differences between two diffs.
+///
+/// Returned by [`PortDiff::diff_against`]. "Added" elements are in the other
+/// diff but not in `self`, "removed" elements are in `self` but not in the
+/// other diff. Nodes are compared by identifier, and edges by the sites they
+/// link.
+#[derive_where(Clone, Default; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::Edge: Debug, G::PortLabel: Debug)]
+pub struct DiffReport<G: Graph> {
+    /// Nodes of the replacement graph that were added.
+    pub added_nodes: BTreeSet<G::Node>,
+    /// Nodes of the replacement graph that were removed.
+    pub removed_nodes: BTreeSet<G::Node>,
+    /// Edges of the replacement graph that were added, as pairs of left and
+    /// right sites.
+    pub added_edges: Vec<(Site<G::Node, G::PortLabel>, Site<G::Node, G::PortLabel>)>,
+    /// Edges of the replacement graph that were removed, as pairs of left
+    /// and right sites.
+    pub removed_edges: Vec<(Site<G::Node, G::PortLabel>, Site<G::Node, G::PortLabel>)>,
+    /// Boundary ports that differ.
+    pub boundary_mismatches: Vec<BoundaryMismatch<G>>,
+    /// Incoming edges that differ.
+    pub subgraph_mismatches: Vec<SubgraphMismatch<G>>,
+}
+
+/// A boundary port that differs between two diffs.
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::Edge: Debug, G::PortLabel: Debug)]
+pub struct BoundaryMismatch<G: Graph> {
+    /// The index of the boundary port.
+    pub index: BoundaryIndex,
+    /// The site and parent port of the boundary port in `self`, if it exists.
+    pub this: Option<(BoundarySite<G>, Port<G>)>,
+    /// The site and parent port of the boundary port in the other diff, if it
+    /// exists.
+    pub other: Option<(BoundarySite<G>, Port<G>)>,
+}
+
+/// An incoming edge that differs between two diffs.
+///
+/// A missing incoming edge is treated as an edge that rewrites nothing.
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::Edge: Debug)]
+pub struct SubgraphMismatch<G: Graph> {
+    /// The index of the incoming edge.
+    pub index: usize,
+    /// Whether both incoming edges come from the same parent.
+    pub same_parent: bool,
+    /// Rewritten nodes that were added.
+    pub added_nodes: BTreeSet<G::Node>,
+    /// Rewritten nodes that were removed.
+    pub removed_nodes: BTreeSet<G::Node>,
+    /// Rewritten edges that were added.
+    pub added_edges: BTreeSet<G::Edge>,
+    /// Rewritten edges that were removed.
+    pub removed_edges: BTreeSet<G::Edge>,
+}
+
+impl<G: Graph> DiffReport<G> {
+    /// Whether no differences were found.
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+            && self.boundary_mismatches.is_empty()
+            && self.subgraph_mismatches.is_empty()
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Compare `self` with `other`, listing their structural differences.
+    ///
+    /// Compares the replacement graphs, the boundaries and the subgraphs
+    /// rewritten in the parents. Values are ignored. This is useful to debug
+    /// rewrites that are expected to be identical but behave differently.
+    pub fn diff_against(&self, other: &PortDiff<G, M>) -> DiffReport<G> {
+        let mut report = DiffReport::default();
+
+        // Replacement graphs
+        let self_nodes: BTreeSet<_> = self.graph().nodes_iter().collect();
+        let other_nodes: BTreeSet<_> = other.graph().nodes_iter().collect();
+        report.added_nodes = &other_nodes - &self_nodes;
+        report.removed_nodes = &self_nodes - &other_nodes;
+        let mut links = BTreeMap::<_, isize>::new();
+        for link in graph_links(self.graph()) {
+            *links.entry(link).or_default() -= 1;
+        }
+        for link in graph_links(other.graph()) {
+            *links.entry(link).or_default() += 1;
+        }
+        for (link, count) in links {
+            let edges = if count > 0 {
+                &mut report.added_edges
+            } else {
+                &mut report.removed_edges
+            };
+            edges.extend(std::iter::repeat(link).take(count.unsigned_abs()));
+        }
+
+        // Boundaries
+        let n_boundary = self.n_boundary_ports().max(other.n_boundary_ports());
+        for index in (0..n_boundary).map(BoundaryIndex::from) {
+            let this = boundary_entry(self, index);
+            let that = boundary_entry(other, index);
+            if this != that {
+                report.boundary_mismatches.push(BoundaryMismatch {
+                    index,
+                    this,
+                    other: that,
+                });
+            }
+        }
+
+        // Parent subgraphs
+        let self_incoming: Vec<_> = self.incoming_edges().collect();
+        let other_incoming: Vec<_> = other.incoming_edges().collect();
+        for index in 0..self_incoming.len().max(other_incoming.len()) {
+            let this = self_incoming.get(index);
+            let that = other_incoming.get(index);
+            let same_parent = matches!((this, that), (Some((p1, _)), Some((p2, _))) if p1 == p2);
+            let [(this_nodes, this_edges), (other_nodes, other_edges)] =
+                [this, that].map(|e| rewritten(e.map(|&(_, edge)| edge)));
+            let mismatch = SubgraphMismatch {
+                index,
+                same_parent,
+                added_nodes: &other_nodes - &this_nodes,
+                removed_nodes: &this_nodes - &other_nodes,
+                added_edges: &other_edges - &this_edges,
+                removed_edges: &this_edges - &other_edges,
+            };
+            if !same_parent
+                || !mismatch.added_nodes.is_empty()
+                || !mismatch.removed_nodes.is_empty()
+                || !mismatch.added_edges.is_empty()
+                || !mismatch.removed_edges.is_empty()
+            {
+                report.subgraph_mismatches.push(mismatch);
+            }
+        }
+
+        report
+    }
+}
+
+/// The edges of `graph`, as pairs of left and right sites.
+fn graph_links<G: Graph>(
+    graph: &G,
+) -> impl Iterator<Item = (Site<G::Node, G::PortLabel>, Site<G::Node, G::PortLabel>)> + '_ {
+    graph.edges_iter().map(|edge| {
+        let [left, right] =
+            [EdgeEnd::Left, EdgeEnd::Right].map(|end| graph.get_port_site(BoundPort { edge, end }));
+        (left, right)
+    })
+}
+
+/// The site and parent port of the boundary port `index`, if it exists.
+fn boundary_entry<G: Graph, M: Clone>(
+    diff: &PortDiff<G, M>,
+    index: BoundaryIndex,
+) -> Option<(BoundarySite<G>, Port<G>)> {
+    if usize::from(index) >= diff.n_boundary_ports() {
+        return None;
+    }
+    let site = diff.boundary_site(index);
+    Some((site, diff.parent_port(index).data))
+}
+
+/// The nodes and edges rewritten by an incoming edge, if any.
+fn rewritten<G: Graph>(edge: Option<&EdgeData<G>>) -> (BTreeSet<G::Node>, BTreeSet<G::Edge>) {
+    edge.map(|e| (e.subgraph().nodes().clone(), e.subgraph().edges().clone()))
+        .unwrap_or_default()
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_diff_against(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        assert!(child_1.diff_against(&child_1).is_empty());
+
+        let report = child_1.diff_against(&child_2);
+        assert!(!report.is_empty());
+        assert_eq!(report.subgraph_mismatches.len(), 1);
+        let mismatch = &report.subgraph_mismatches[0];
+        assert!(mismatch.same_parent);
+        let [subgraph_1, subgraph_2] = [&child_1, &child_2].map(|c| {
+            let (_, edge) = c.incoming_edges().next().unwrap();
+            edge.subgraph().nodes().clone()
+        });
+        assert_eq!(mismatch.removed_nodes, subgraph_1);
+        assert_eq!(mismatch.added_nodes, subgraph_2);
+        assert_eq!(report.boundary_mismatches.len(), 1);
+
+        // The root has no parents
+        let report = parent.diff_against(&child_1);
+        assert_eq!(report.subgraph_mismatches.len(), 1);
+        assert!(!report.subgraph_mismatches[0].same_parent);
+        assert!(report.subgraph_mismatches[0].removed_nodes.is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! Fast compatibility queries against a changing selection of diffs.
+
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+
+use derive_where::derive_where;
+use petgraph::visit::{EdgeRef, IntoEdges};
+
+use crate::{Graph, NodeId, PortDiff, PortDiffGraph};
+
+use super::{IncompatiblePortDiff, WeakPortDiff};
+
+/// The nodes of an ancestor that are rewritten by a child.
+#[derive_where(Clone; G: Graph)]
+struct Claim<G: Graph, M> {
+    /// The ancestor whose nodes are rewritten.
+    ancestor: NodeId<G, M>,
+    /// The child of `ancestor` that rewrites the nodes.
+    child: NodeId<G, M>,
+    /// The rewritten nodes of `ancestor`.
+    nodes: BTreeSet<G::Node>,
+}
+
+/// The claims made within the ancestor graphs of queried diffs.
+///
+/// Only weak references to the diffs are kept, so that the claims of diffs
+/// that are dropped can be evicted. The weak references also prevent the IDs
+/// of cached diffs from being reused.
+#[derive_where(Default; G: Graph)]
+struct ClaimCache<G: Graph, M> {
+    claims: BTreeMap<NodeId<G, M>, (WeakPortDiff<G, M>, Vec<Claim<G, M>>)>,
+    /// The number of cached diffs from which dropped diffs are evicted.
+    evict_at: usize,
+}
+
+impl<G: Graph, M: Clone> ClaimCache<G, M> {
+    /// The claims made within the ancestor graph of `diff`.
+    fn get(&mut self, diff: &PortDiff<G, M>) -> &[Claim<G, M>] {
+        let id = NodeId::from(diff);
+        if !self.claims.contains_key(&id) {
+            if self.claims.len() >= self.evict_at {
+                self.claims.retain(|_, (weak, _)| weak.is_alive());
+                self.evict_at = 2 * self.claims.len() + 1;
+            }
+            self.claims
+                .insert(id, (diff.downgrade(), ancestor_claims(diff)));
+        }
+        &self.claims[&id].1
+    }
+}
+
+/// The claims made within the ancestor graph of `diff`.
+fn ancestor_claims<G: Graph, M: Clone>(diff: &PortDiff<G, M>) -> Vec<Claim<G, M>> {
+    let graph = PortDiffGraph::from_sinks([diff.clone()]);
+    let mut claims = Vec::new();
+    for ancestor in graph.all_nodes() {
+        for e in graph.inner().edges(ancestor.into()) {
+            claims.push(Claim {
+                ancestor,
+                child: NodeId::from(e.target()),
+                nodes: e.weight().subgraph.nodes().clone(),
+            });
+        }
+    }
+    claims
+}
+
+/// An index answering whether diffs are compatible with a selection.
+///
+/// Checking compatibility with [`PortDiff::are_compatible`] merges the
+/// ancestor graphs of all diffs, which is linear in the size of the
+/// selection. Instead, the index records, for every ancestor of the selected
+/// diffs, which child rewrites each of its nodes. A diff is compatible with
+/// the selection if none of the nodes rewritten in its ancestor graph are
+/// claimed by a different child. Queries are thus independent of the size of
+/// the selection, and the index is updated incrementally as diffs are
+/// selected and deselected.
+///
+/// The nodes rewritten within the ancestor graph of every diff are computed
+/// once and cached, until the diffs are dropped. Compatibility is decided as
+/// for [`DisjointSubgraphs`].
+///
+/// [`DisjointSubgraphs`]: super::DisjointSubgraphs
+#[derive_where(Default; G: Graph)]
+pub struct CompatibilityIndex<G: Graph, M = ()> {
+    /// The claims made within the ancestor graph of every diff queried.
+    claims: ClaimCache<G, M>,
+    /// The currently selected diffs.
+    selection: BTreeSet<PortDiff<G, M>>,
+    /// For every ancestor node claimed by the selection, the claiming child
+    /// and the number of selected diffs making the claim.
+    claimed: BTreeMap<NodeId<G, M>, BTreeMap<G::Node, (NodeId<G, M>, usize)>>,
+}
+
+impl<G: Graph, M: Clone> CompatibilityIndex<G, M> {
+    /// Create an index with an empty selection.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The currently selected diffs.
+    pub fn selection(&self) -> impl Iterator<Item = &PortDiff<G, M>> + '_ {
+        self.selection.iter()
+    }
+
+    /// Whether `diff` is currently selected.
+    pub fn is_selected(&self, diff: &PortDiff<G, M>) -> bool {
+        self.selection.contains(diff)
+    }
+
+    /// Whether `diff` is compatible with the current selection.
+    pub fn is_compatible(&mut self, diff: &PortDiff<G, M>) -> bool {
+        let claimed = &self.claimed;
+        self.claims.get(diff).iter().all(|claim| {
+            let Some(nodes) = claimed.get(&claim.ancestor) else {
+                return true;
+            };
+            claim.nodes.iter().all(|n| match nodes.get(n) {
+                Some(&(child, _)) => child == claim.child,
+                None => true,
+            })
+        })
+    }
+
+    /// The diffs in `candidates` that are compatible with the current
+    /// selection, in the order of `candidates`.
+    pub fn compatible<'a>(
+        &'a mut self,
+        candidates: impl IntoIterator<Item = &'a PortDiff<G, M>> + 'a,
+    ) -> impl Iterator<Item = &'a PortDiff<G, M>> + 'a {
+        candidates
+            .into_iter()
+            .filter(move |d| self.is_compatible(d))
+    }
+
+    /// Add `diff` to the selection.
+    ///
+    /// Errors if `diff` is incompatible with the selection, in which case the
+    /// selection is left unchanged.
+    pub fn select(&mut self, diff: PortDiff<G, M>) -> Result<(), IncompatiblePortDiff> {
+        if self.selection.contains(&diff) {
+            return Ok(());
+        }
+        if !self.is_compatible(&diff) {
+            return Err(IncompatiblePortDiff);
+        }
+        for claim in self.claims.get(&diff) {
+            let nodes = self.claimed.entry(claim.ancestor).or_default();
+            for &n in &claim.nodes {
+                nodes.entry(n).or_insert((claim.child, 0)).1 += 1;
+            }
+        }
+        self.selection.insert(diff);
+        Ok(())
+    }
+
+    /// Remove `diff` from the selection.
+    ///
+    /// Returns whether `diff` was selected.
+    pub fn deselect(&mut self, diff: &PortDiff<G, M>) -> bool {
+        if !self.selection.remove(diff) {
+            return false;
+        }
+        for claim in self.claims.get(diff) {
+            let Entry::Occupied(mut nodes) = self.claimed.entry(claim.ancestor) else {
+                panic!("claims of selected diffs are indexed");
+            };
+            for n in &claim.nodes {
+                let Entry::Occupied(mut count) = nodes.get_mut().entry(*n) else {
+                    panic!("claims of selected diffs are indexed");
+                };
+                count.get_mut().1 -= 1;
+                if count.get().1 == 0 {
+                    count.remove();
+                }
+            }
+            if nodes.get().is_empty() {
+                nodes.remove();
+            }
+        }
+        true
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{
+        parent_two_children_diffs, parent_two_children_overlapping_diffs, TestPortDiff,
+    };
+
+    use super::*;
+
+    #[rstest]
+    fn test_compatibility_index(
+        parent_two_children_diffs: [TestPortDiff; 3],
+        parent_two_children_overlapping_diffs: [TestPortDiff; 3],
+    ) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let mut index = CompatibilityIndex::new();
+        index.select(child_1.clone()).unwrap();
+        assert!(index.is_compatible(&child_2));
+        index.select(child_2.clone()).unwrap();
+        assert_eq!(index.selection().count(), 2);
+
+        let [root, child_1, child_2] = parent_two_children_overlapping_diffs;
+        let mut index = CompatibilityIndex::new();
+        index.select(child_1.clone()).unwrap();
+        assert!(index.is_compatible(&root));
+        assert!(!index.is_compatible(&child_2));
+        assert!(index.select(child_2.clone()).is_err());
+        assert!(!index.is_selected(&child_2));
+        let candidates = [root.clone(), child_2.clone()];
+        assert_eq!(index.compatible(&candidates).collect_vec(), vec![&root]);
+
+        assert!(index.deselect(&child_1));
+        assert!(!index.deselect(&child_1));
+        assert!(index.is_compatible(&child_2));
+        index.select(child_2).unwrap();
+        assert!(!index.is_compatible(&child_1));
+    }
+
+    #[rstest]
+    fn test_compatibility_index_eviction(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [root, _, _] = parent_two_children_diffs;
+        let node = root.graph().nodes_iter().next().unwrap();
+        let mut index = CompatibilityIndex::new();
+        for _ in 0..100 {
+            let diff = root.select_subgraph([node]);
+            assert!(index.is_compatible(&diff));
+        }
+        // The claims of dropped diffs are evicted
+        assert!(index.claims.claims.len() < 10);
+    }
+}
@@ -0,0 +1,93 @@
+//! Composition of two sequential diffs into a single diff.
+
+use thiserror::Error;
+
+use crate::{Graph, PortDiff, PortDiffGraph};
+
+use super::SquashError;
+
+/// Errors when composing two diffs.
+#[derive(Debug, Error)]
+pub enum ComposeError {
+    /// The second diff is not a child of the first diff.
+    #[error("the second diff is not a child of the first diff")]
+    NotChild,
+    /// The two diffs cannot be squashed into a single diff.
+    #[error("the diffs cannot be composed: {0}")]
+    Squash(#[from] SquashError),
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Compose `first` and its child `second` into a single diff.
+    ///
+    /// The composed diff rewrites the parents of `first` (and any other parent
+    /// of `second`) directly into the graph obtained by applying `first` then
+    /// `second`. The port maps of both diffs are fused, so that the composed
+    /// diff is equivalent to the two diffs applied in sequence. Unlike
+    /// [`PortDiffGraph::try_squash`], exactly two diffs are composed, e.g. to
+    /// normalise a library of rewrite rules.
+    ///
+    /// The value of the composed diff is `M::default()`, see
+    /// [`Self::compose_with`] to combine the values of the two diffs.
+    pub fn compose(first: &Self, second: &Self) -> Result<Self, ComposeError>
+    where
+        M: Default,
+    {
+        Self::compose_with(first, second, |_, _| M::default())
+    }
+
+    /// Compose `first` and its child `second` into a single diff, combining
+    /// their values with `aggregate`.
+    ///
+    /// See [`Self::compose`].
+    pub fn compose_with(
+        first: &Self,
+        second: &Self,
+        aggregate: impl FnOnce(&M, &M) -> M,
+    ) -> Result<Self, ComposeError> {
+        if !second.all_parents().any(|p| &p == first) {
+            return Err(ComposeError::NotChild);
+        }
+        let graph =
+            PortDiffGraph::from_sinks_while([second.clone()], |d| d == first || d == second);
+        let value = aggregate(first.value(), second.value());
+        Ok(graph.try_squash_with(|_| value)?)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use portgraph::{LinkView, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_compose(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        assert!(matches!(
+            PortDiff::compose(&child, &parent),
+            Err(ComposeError::NotChild)
+        ));
+
+        // Composing the child with its inverse rewrites the parent into itself
+        let inverse = child.invert().unwrap();
+        let composed = PortDiff::compose(&child, &inverse).unwrap();
+        let parents = composed.all_parents().collect_vec();
+        assert_eq!(parents, vec![parent.clone()]);
+        let extracted = PortDiff::extract_graph(vec![composed]).unwrap();
+        assert_eq!(extracted.node_count(), parent.graph().node_count());
+        assert_eq!(extracted.link_count(), parent.graph().link_count());
+
+        // Composing with a root yields a root
+        let composed = PortDiff::compose(&parent, &child).unwrap();
+        assert_eq!(composed.all_parents().count(), 0);
+        let expected = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(composed.graph().node_count(), expected.node_count());
+        assert_eq!(composed.graph().link_count(), expected.link_count());
+    }
+}
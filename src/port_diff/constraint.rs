@@ -0,0 +1,131 @@
+//! Constraints on the graphs extracted from diffs.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{graph_view::MergedView, EdgeEnd, Graph, PortDiff, PortDiffGraph};
+
+use super::IncompatiblePortDiff;
+
+/// A constraint that the graph extracted from compatible diffs must satisfy.
+///
+/// Constraints are checked on the [`MergedView`] of the diffs, so that
+/// selections violating them are rejected as incompatible without extracting
+/// the graph, see [`PortDiff::are_compatible_with_constraint`] and
+/// [`PortDiff::try_merge_with_constraint`].
+pub trait GraphConstraint<G: Graph> {
+    /// Whether the graph of the merged diffs `view` satisfies the constraint.
+    fn is_satisfied<M: Clone>(&self, view: &MergedView<G, M>) -> bool;
+}
+
+/// The extracted graph must not have any directed cycle.
+///
+/// Edges are directed from their left end to their right end.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AcyclicConstraint;
+
+impl<G: Graph> GraphConstraint<G> for AcyclicConstraint {
+    fn is_satisfied<M: Clone>(&self, view: &MergedView<G, M>) -> bool {
+        is_acyclic(view)
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Whether `diffs` are compatible and their graph satisfies `constraint`.
+    pub fn are_compatible_with_constraint<'a>(
+        diffs: impl IntoIterator<Item = &'a PortDiff<G, M>>,
+        constraint: &impl GraphConstraint<G>,
+    ) -> bool
+    where
+        G: 'a,
+    {
+        Self::try_merge_with_constraint(diffs.into_iter().cloned(), constraint).is_ok()
+    }
+
+    /// Merge the ancestor graphs of `diffs`, if they are compatible and their
+    /// graph satisfies `constraint`.
+    pub fn try_merge_with_constraint(
+        diffs: impl IntoIterator<Item = PortDiff<G, M>>,
+        constraint: &impl GraphConstraint<G>,
+    ) -> Result<PortDiffGraph<G, M>, IncompatiblePortDiff> {
+        let view = MergedView::new(diffs)?;
+        if !constraint.is_satisfied(&view) {
+            return Err(IncompatiblePortDiff);
+        }
+        Ok(view.diff_graph().clone())
+    }
+}
+
+/// Whether `graph` has no directed cycle, using Kahn's algorithm.
+fn is_acyclic<H: Graph>(graph: &H) -> bool {
+    let mut in_degree: BTreeMap<H::Node, usize> = graph.nodes_iter().map(|n| (n, 0)).collect();
+    let mut successors: BTreeMap<H::Node, Vec<H::Node>> = BTreeMap::new();
+    for edge in graph.edges_iter() {
+        let left = graph.incident_node(edge, EdgeEnd::Left);
+        let right = graph.incident_node(edge, EdgeEnd::Right);
+        *in_degree.entry(right).or_default() += 1;
+        successors.entry(left).or_default().push(right);
+    }
+    let mut queue: VecDeque<_> = in_degree
+        .iter()
+        .filter(|(_, &d)| d == 0)
+        .map(|(&n, _)| n)
+        .collect();
+    let mut n_visited = 0;
+    while let Some(node) = queue.pop_front() {
+        n_visited += 1;
+        for next in successors.remove(&node).unwrap_or_default() {
+            let degree = in_degree.get_mut(&next).expect("known node");
+            *degree -= 1;
+            if *degree == 0 {
+                queue.push_back(next);
+            }
+        }
+    }
+    n_visited == in_degree.len()
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use portgraph::{PortGraph, PortMut};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+    use crate::port_diff::Owned;
+    use crate::Site;
+
+    use super::*;
+
+    #[rstest]
+    fn test_acyclic_constraint(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        let diffs = [&child_1, &child_2];
+        assert!(PortDiff::are_compatible_with_constraint(
+            diffs,
+            &AcyclicConstraint
+        ));
+
+        // Rewrite the first and last nodes into a single node, creating a cycle
+        let nodes: Vec<_> = parent.graph().nodes_iter().collect();
+        let (first, last) = (nodes[0], nodes[3]);
+        let mut rhs = PortGraph::new();
+        let merged = rhs.add_node(3, 3);
+        let cyclic = parent
+            .rewrite_induced(&BTreeSet::from([first, last]), rhs, |p| {
+                let site = Owned::new(p, parent.clone()).site().unwrap();
+                Site {
+                    node: merged,
+                    port: site.port,
+                }
+                .into()
+            })
+            .unwrap();
+        assert!(PortDiff::are_compatible([&cyclic]));
+        assert!(!PortDiff::are_compatible_with_constraint(
+            [&cyclic],
+            &AcyclicConstraint
+        ));
+    }
+}
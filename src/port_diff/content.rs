@@ -0,0 +1,153 @@
+//! Content-based identity of diffs.
+//!
+//! `PortDiff` equality is pointer-based. The functions in this module compare
+//! diffs by their content instead, so that identical diffs that were
+//! constructed independently can be recognised.
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+use crate::{fnv::FnvHasher, graph::GraphContent, ContentKey, Graph, PortDiff};
+
+use super::{EdgeData, PortDiffPtr};
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// A hash of the content of the diff.
+    ///
+    /// The hash is computed from the replacement graph, including its weights,
+    /// the boundary, the value and the incoming edges of the diff, as well as
+    /// the content hashes of its parents. It does not depend on the memory
+    /// location of the diff, and uses the FNV-1a hash function, so that it
+    /// is stable across platforms and compiler versions.
+    pub fn content_hash(&self) -> u64
+    where
+        G: ContentKey,
+        G::Node: Hash,
+        G::Edge: Hash,
+        G::PortLabel: Hash,
+        G::NodeKey: Hash,
+        G::EdgeKey: Hash,
+        M: Hash,
+    {
+        self.content_hash_memo(&mut HashMap::new())
+    }
+
+    fn content_hash_memo(&self, memo: &mut HashMap<PortDiffPtr<G, M>, u64>) -> u64
+    where
+        G: ContentKey,
+        G::Node: Hash,
+        G::Edge: Hash,
+        G::PortLabel: Hash,
+        G::NodeKey: Hash,
+        G::EdgeKey: Hash,
+        M: Hash,
+    {
+        if let Some(&hash) = memo.get(&self.as_ptr()) {
+            return hash;
+        }
+        let mut hasher = FnvHasher::default();
+        GraphContent::new(self.graph()).hash(&mut hasher);
+        self.boundary.hash(&mut hasher);
+        self.value.hash(&mut hasher);
+        for edge in self.all_incoming() {
+            let parent: PortDiff<G, M> = edge.source().clone().into();
+            parent.content_hash_memo(memo).hash(&mut hasher);
+            edge.value().hash_content(&mut hasher);
+        }
+        let hash = hasher.finish();
+        memo.insert(self.as_ptr(), hash);
+        hash
+    }
+
+    /// Whether `self` and `other` have the same content.
+    ///
+    /// This holds if the diffs have equal replacement graphs, including their
+    /// weights, boundaries, values and incoming edges, and their parents are
+    /// structurally equal. Replacement graphs must be identical, not merely
+    /// isomorphic.
+    pub fn structurally_eq(&self, other: &Self) -> bool
+    where
+        G: ContentKey,
+        M: PartialEq,
+    {
+        self.structurally_eq_memo(other, &mut BTreeSet::new())
+    }
+
+    fn structurally_eq_memo(
+        &self,
+        other: &Self,
+        memo: &mut BTreeSet<(PortDiffPtr<G, M>, PortDiffPtr<G, M>)>,
+    ) -> bool
+    where
+        G: ContentKey,
+        M: PartialEq,
+    {
+        if self == other || memo.contains(&(self.as_ptr(), other.as_ptr())) {
+            return true;
+        }
+        let self_incoming = self.all_incoming();
+        let other_incoming = other.all_incoming();
+        let is_eq = self.value == other.value
+            && self.boundary == other.boundary
+            && self_incoming.len() == other_incoming.len()
+            && GraphContent::new(self.graph()) == GraphContent::new(other.graph())
+            && self_incoming
+                .iter()
+                .zip(other_incoming)
+                .all(|(self_edge, other_edge)| {
+                    let self_parent: PortDiff<G, M> = self_edge.source().clone().into();
+                    let other_parent: PortDiff<G, M> = other_edge.source().clone().into();
+                    self_edge.value().structurally_eq(other_edge.value())
+                        && self_parent.structurally_eq_memo(&other_parent, memo)
+                });
+        if is_eq {
+            memo.insert((self.as_ptr(), other.as_ptr()));
+        }
+        is_eq
+    }
+}
+
+impl<G: Graph> EdgeData<G> {
+    fn hash_content<H: Hasher>(&self, state: &mut H)
+    where
+        G::Node: Hash,
+        G::Edge: Hash,
+    {
+        self.subgraph.nodes().hash(state);
+        self.subgraph.edges().hash(state);
+        for (port, boundary) in self.port_map.iter() {
+            port.hash(state);
+            boundary.hash(state);
+        }
+    }
+
+    fn structurally_eq(&self, other: &Self) -> bool {
+        self.subgraph.nodes() == other.subgraph.nodes()
+            && self.subgraph.edges() == other.subgraph.edges()
+            && self.port_map.iter().eq(other.port_map.iter())
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_content_hash(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let [other_parent, other_child] = super::super::tests::parent_child_diffs();
+
+        assert_ne!(child, other_child);
+        assert_eq!(child.content_hash(), other_child.content_hash());
+        assert!(child.structurally_eq(&other_child));
+        assert!(parent.structurally_eq(&other_parent));
+
+        assert_ne!(parent.content_hash(), child.content_hash());
+        assert!(!parent.structurally_eq(&child));
+    }
+}
@@ -0,0 +1,197 @@
+//! Rewrites that merge nodes into a single node.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+use derive_where::derive_where;
+use thiserror::Error;
+
+use crate::{
+    port::{BoundPort, EdgeEnd, Site},
+    AddNodes, Graph, PortDiff,
+};
+
+use super::Owned;
+
+/// Errors when merging nodes, see [`PortDiff::merge_nodes`].
+#[derive(Error)]
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::PortLabel: Debug)]
+pub enum MergeNodesError<G: Graph> {
+    /// There are no nodes to merge.
+    #[error("No nodes to merge")]
+    NoNodes,
+    /// Two distinct sites are mapped to the same port label of the merged
+    /// node.
+    #[error("Distinct sites are mapped to the same port label")]
+    LabelCollision {
+        first: Site<G::Node, G::PortLabel>,
+        second: Site<G::Node, G::PortLabel>,
+    },
+}
+
+impl<G: AddNodes, M: Clone> PortDiff<G, M> {
+    /// Create a new diff that contracts `edge`, merging its two ends into a
+    /// single node.
+    ///
+    /// The merged node has a site for every site of the two ends of `edge`,
+    /// labelled by `merged_node_sites`, except the sites whose only edge is
+    /// `edge`. Other edges between the two ends become self-loops of the
+    /// merged node.
+    ///
+    /// Errors if two distinct sites are mapped to the same label.
+    pub fn contract_edge(
+        &self,
+        edge: G::Edge,
+        merged_node_sites: impl FnMut(Site<G::Node, G::PortLabel>) -> G::PortLabel,
+    ) -> Result<Self, MergeNodesError<G>>
+    where
+        M: Default,
+    {
+        let nodes = [EdgeEnd::Left, EdgeEnd::Right]
+            .map(|end| self.graph().incident_node(edge, end))
+            .into_iter()
+            .collect();
+        self.merge_sites(&nodes, &BTreeSet::from([edge]), merged_node_sites)
+    }
+
+    /// Create a new diff that merges `nodes` into a single node.
+    ///
+    /// All edges between `nodes` are removed. The merged node has a site for
+    /// every site of `nodes` that is not only incident to removed edges,
+    /// labelled by `merged_node_sites`.
+    ///
+    /// Errors if two distinct sites are mapped to the same label, or if
+    /// `nodes` is empty.
+    pub fn merge_nodes(
+        &self,
+        nodes: impl IntoIterator<Item = G::Node>,
+        merged_node_sites: impl FnMut(Site<G::Node, G::PortLabel>) -> G::PortLabel,
+    ) -> Result<Self, MergeNodesError<G>>
+    where
+        M: Default,
+    {
+        let nodes: BTreeSet<_> = nodes.into_iter().collect();
+        let internal_edges = self
+            .graph()
+            .edges_iter()
+            .filter(|&e| {
+                [EdgeEnd::Left, EdgeEnd::Right]
+                    .into_iter()
+                    .all(|end| nodes.contains(&self.graph().incident_node(e, end)))
+            })
+            .collect();
+        self.merge_sites(&nodes, &internal_edges, merged_node_sites)
+    }
+
+    /// Merge `nodes` into a single node, removing `removed_edges`.
+    ///
+    /// Edges between `nodes` that are not removed become self-loops.
+    fn merge_sites(
+        &self,
+        nodes: &BTreeSet<G::Node>,
+        removed_edges: &BTreeSet<G::Edge>,
+        mut label_map: impl FnMut(Site<G::Node, G::PortLabel>) -> G::PortLabel,
+    ) -> Result<Self, MergeNodesError<G>>
+    where
+        M: Default,
+    {
+        if nodes.is_empty() {
+            return Err(MergeNodesError::NoNodes);
+        }
+        let graph = self.graph();
+
+        // The label of every site that is kept, and its inverse
+        let mut labels = BTreeMap::new();
+        let mut label_sites = BTreeMap::new();
+        for site in nodes.iter().flat_map(|&n| graph.get_sites(n)) {
+            let mut ports = graph.get_bound_ports(site.clone()).peekable();
+            let is_removed =
+                ports.peek().is_some() && ports.all(|p| removed_edges.contains(&p.edge));
+            if is_removed {
+                continue;
+            }
+            let label = label_map(site.clone());
+            if let Some(first) = label_sites.insert(label.clone(), site.clone()) {
+                return Err(MergeNodesError::LabelCollision {
+                    first,
+                    second: site,
+                });
+            }
+            labels.insert(site, label);
+        }
+
+        let mut new_graph = G::default();
+        let merged = new_graph.add_node_with_sites(label_sites.into_keys());
+        let merged_site = |site: &Site<G::Node, G::PortLabel>| Site {
+            node: merged,
+            port: labels[site].clone(),
+        };
+        for edge in graph.edges_iter() {
+            let [left, right] = [EdgeEnd::Left, EdgeEnd::Right]
+                .map(|end| graph.get_port_site(BoundPort { edge, end }));
+            let is_internal = nodes.contains(&left.node) && nodes.contains(&right.node);
+            if is_internal && !removed_edges.contains(&edge) {
+                new_graph.link_sites(merged_site(&left), merged_site(&right));
+            }
+        }
+
+        let diff = self
+            .rewrite_induced(nodes, new_graph, |port| {
+                let site = Owned::new(port, self.clone())
+                    .site()
+                    .expect("boundary port of a merged node has a site");
+                merged_site(&site).into()
+            })
+            .expect("nodes of a single diff can always be merged");
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_graph::{SimpleGraph, SimpleNode, SimplePort};
+
+    use super::*;
+
+    #[test]
+    fn test_contract_edge() {
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let edge = root
+            .graph()
+            .edges_iter()
+            .find(|&e| root.graph().incident_node(e, EdgeEnd::Left) == SimpleNode::new(0))
+            .unwrap();
+        let child = root.contract_edge(edge, |site| site.port).unwrap();
+        assert_eq!(child.graph().node_count(), 1);
+        assert_eq!(child.graph().edge_count(), 0);
+        assert_eq!(child.n_boundary_ports(), 1);
+
+        let g = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(g.node_count(), 2);
+        assert_eq!(g.edge_count(), 1);
+    }
+
+    #[test]
+    fn test_merge_nodes() {
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(4));
+        let nodes = [1, 2].map(SimpleNode::new);
+        let child = root.merge_nodes(nodes, |site| site.port).unwrap();
+        assert_eq!(child.n_boundary_ports(), 2);
+        let g = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(g.node_count(), 3);
+        assert_eq!(g.edge_count(), 2);
+
+        // The inputs of the first and last nodes collide
+        let nodes = [0, 2].map(SimpleNode::new);
+        assert!(matches!(
+            root.merge_nodes(nodes, |site| site.port),
+            Err(MergeNodesError::LabelCollision { first, second })
+                if first.port == SimplePort::In(0) && second.port == SimplePort::In(0)
+        ));
+        assert!(matches!(
+            root.merge_nodes([], |site| site.port),
+            Err(MergeNodesError::NoNodes)
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+//! Rewrites that delete nodes, reconnecting their neighbours with wires.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+use derive_where::derive_where;
+use thiserror::Error;
+
+use crate::{
+    port::{EdgeEnd, Port, Site},
+    Graph, PortDiff,
+};
+
+use super::BoundarySite;
+
+/// How the neighbours of deleted nodes are reconnected, see
+/// [`PortDiff::delete_nodes`].
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::PortLabel: Debug)]
+pub enum DeletionPolicy<G: Graph> {
+    /// Remove all edges between deleted and kept nodes.
+    DropEdges,
+    /// Link the kept ends of the edges at every pair of sites.
+    ///
+    /// Every site must have exactly one edge to a kept node, and the sites
+    /// of a pair must be at opposite ends of their edges. The edges at sites
+    /// that are not in a pair are removed.
+    ReconnectPairs(Vec<(Site<G::Node, G::PortLabel>, Site<G::Node, G::PortLabel>)>),
+    /// Link the kept ends of the edges in order.
+    ///
+    /// The edges to kept nodes are split by the end of the edge at the
+    /// deleted nodes and sorted by site. The i-th edge with its right end at
+    /// a deleted node is linked to the i-th edge with its left end at a
+    /// deleted node. Edges beyond the shorter of the two lists are removed.
+    WireThrough,
+}
+
+/// Errors when deleting nodes, see [`PortDiff::delete_nodes`].
+#[derive(Error)]
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::PortLabel: Debug)]
+pub enum DeleteNodesError<G: Graph> {
+    /// A site to reconnect does not have exactly one edge to a kept node.
+    #[error("A site to reconnect must have exactly one edge to a kept node")]
+    NotOneEdge(Site<G::Node, G::PortLabel>),
+    /// A site appears in more than one pair to reconnect.
+    #[error("A site is reconnected more than once")]
+    DuplicateSite(Site<G::Node, G::PortLabel>),
+    /// The sites of a pair are at the same end of their edges.
+    #[error("Reconnected sites must be at opposite ends of their edges")]
+    SameEnds(Site<G::Node, G::PortLabel>, Site<G::Node, G::PortLabel>),
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Create a new diff that deletes `nodes` of `self`, reconnecting their
+    /// neighbours according to `policy`.
+    ///
+    /// The replacement graph is empty and every boundary port is mapped to
+    /// the end of a wire: wires linking two boundary ports reconnect the
+    /// neighbours, while wires with a single boundary port remove its edge.
+    pub fn delete_nodes(
+        &self,
+        nodes: impl IntoIterator<Item = G::Node>,
+        policy: DeletionPolicy<G>,
+    ) -> Result<Self, DeleteNodesError<G>>
+    where
+        M: Default,
+    {
+        let nodes: BTreeSet<_> = nodes.into_iter().collect();
+        let ports = self.deletion_boundary(&nodes);
+
+        // Wires linking pairs of ports come first, dangling wires after
+        let mut wires = BTreeMap::new();
+        let mut n_wires = 0;
+        let mut link = |left: &Port<G>, right: &Port<G>| {
+            wires.insert(*right, BoundarySite::wire(n_wires, EdgeEnd::Right));
+            wires.insert(*left, BoundarySite::wire(n_wires, EdgeEnd::Left));
+            n_wires += 1;
+        };
+        match policy {
+            DeletionPolicy::DropEdges => {}
+            DeletionPolicy::ReconnectPairs(pairs) => {
+                let mut seen = BTreeSet::new();
+                for (a, b) in pairs {
+                    let mut find = |site: &Site<G::Node, G::PortLabel>| {
+                        if !seen.insert(site.clone()) {
+                            return Err(DeleteNodesError::DuplicateSite(site.clone()));
+                        }
+                        let mut at_site = ports.iter().filter(|(_, s, _)| s == site);
+                        match (at_site.next(), at_site.next()) {
+                            (Some(&(port, _, end)), None) => Ok((port, end)),
+                            _ => Err(DeleteNodesError::NotOneEdge(site.clone())),
+                        }
+                    };
+                    let (port_a, end_a) = find(&a)?;
+                    let (port_b, end_b) = find(&b)?;
+                    match (end_a, end_b) {
+                        (EdgeEnd::Left, EdgeEnd::Right) => link(&port_a, &port_b),
+                        (EdgeEnd::Right, EdgeEnd::Left) => link(&port_b, &port_a),
+                        _ => return Err(DeleteNodesError::SameEnds(a, b)),
+                    }
+                }
+            }
+            DeletionPolicy::WireThrough => {
+                let mut sorted = ports.iter().collect::<Vec<_>>();
+                sorted.sort_by(|(_, s1, _), (_, s2, _)| s1.cmp(s2));
+                let at_end = |end| sorted.iter().filter(move |&&&(_, _, e)| e == end);
+                for ((right, _, _), (left, _, _)) in
+                    at_end(EdgeEnd::Right).zip(at_end(EdgeEnd::Left))
+                {
+                    link(left, right);
+                }
+            }
+        }
+        for (port, _, end) in &ports {
+            wires.entry(*port).or_insert_with(|| {
+                n_wires += 1;
+                BoundarySite::wire(n_wires - 1, *end)
+            });
+        }
+
+        let diff = self
+            .rewrite_induced(&nodes, G::default(), |port| wires[&port].clone())
+            .expect("nodes of a single diff can always be deleted");
+        Ok(diff)
+    }
+
+    /// The boundary ports of a rewrite of `nodes`, along with their site and
+    /// the end of their edge.
+    fn deletion_boundary(
+        &self,
+        nodes: &BTreeSet<G::Node>,
+    ) -> Vec<(Port<G>, Site<G::Node, G::PortLabel>, EdgeEnd)> {
+        let graph = self.graph();
+        let mut ports = Vec::new();
+        for site in nodes.iter().flat_map(|&n| graph.get_sites(n)) {
+            for port in graph.get_bound_ports(site.clone()) {
+                let opposite = graph.incident_node(port.edge, port.end.opposite());
+                if !nodes.contains(&opposite) {
+                    ports.push((Port::Bound(port), site.clone(), port.end));
+                }
+            }
+        }
+        for boundary in self.boundary_iter() {
+            let Ok(site) = self.boundary_site(boundary).try_into_site() else {
+                continue;
+            };
+            if nodes.contains(&site.node) {
+                let end = self.bound_ancestor(boundary).data.end;
+                ports.push((Port::Boundary(boundary), site, end));
+            }
+        }
+        ports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_graph::{SimpleGraph, SimpleNode, SimplePort};
+
+    use super::*;
+
+    fn site(node: usize, port: SimplePort) -> Site<SimpleNode, SimplePort> {
+        Site {
+            node: SimpleNode::new(node),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_delete_nodes() {
+        // a -> b -> c
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let b = [SimpleNode::new(1)];
+        let n_edges = |policy| {
+            let child = root.delete_nodes(b, policy).unwrap();
+            assert_eq!(child.graph().node_count(), 0);
+            let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+            assert_eq!(extracted.node_count(), 2);
+            extracted.edge_count()
+        };
+        assert_eq!(n_edges(DeletionPolicy::DropEdges), 0);
+        assert_eq!(n_edges(DeletionPolicy::WireThrough), 1);
+        let pairs = vec![(site(1, SimplePort::Out(0)), site(1, SimplePort::In(0)))];
+        assert_eq!(n_edges(DeletionPolicy::ReconnectPairs(pairs)), 1);
+    }
+
+    #[test]
+    fn test_delete_nodes_errors() {
+        let root = PortDiff::from_graph(SimpleGraph::grid(2, 2));
+        let delete = |node, pairs| {
+            let policy = DeletionPolicy::ReconnectPairs(pairs);
+            root.delete_nodes([SimpleNode::new(node)], policy)
+        };
+        // The last node has two inputs and no outputs
+        let inputs = (site(3, SimplePort::In(0)), site(3, SimplePort::In(1)));
+        assert!(matches!(
+            delete(3, vec![inputs]),
+            Err(DeleteNodesError::SameEnds(..))
+        ));
+        let unlinked = (site(3, SimplePort::In(0)), site(3, SimplePort::Out(0)));
+        assert!(matches!(
+            delete(3, vec![unlinked]),
+            Err(DeleteNodesError::NotOneEdge(s)) if s == site(3, SimplePort::Out(0))
+        ));
+        let repeated = (site(3, SimplePort::In(0)), site(3, SimplePort::In(0)));
+        assert!(matches!(
+            delete(3, vec![repeated]),
+            Err(DeleteNodesError::DuplicateSite(_))
+        ));
+    }
+}
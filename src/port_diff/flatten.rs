@@ -0,0 +1,94 @@
+//! Inlining of the nearest ancestors of a diff.
+
+use std::collections::BTreeMap;
+
+use crate::{Graph, NodeId, PortDiff, PortDiffGraph};
+
+use super::{SquashError, TraversalDirection};
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Squash `self` with its nearest `depth` ancestors into an equivalent diff.
+    ///
+    /// The squashed diffs are `self` and all ancestors whose longest path to
+    /// `self` has at most `depth` edges. The new diff is a child of the older
+    /// ancestors, so that extracting it yields the same graph as extracting
+    /// `self`, with a hierarchy that is `depth` levels shallower. The new diff
+    /// has the value of `self`.
+    ///
+    /// `self` is returned if `depth` is 0. Other diffs, including the
+    /// descendants of `self`, are left unchanged.
+    pub fn flatten_ancestors(&self, depth: usize) -> Result<Self, SquashError> {
+        if depth == 0 {
+            return Ok(self.clone());
+        }
+        let ancestors = PortDiffGraph::from_sinks([self.clone()]);
+
+        // The length of the longest path from every ancestor to `self`
+        let mut distance: BTreeMap<NodeId<G, M>, usize> = BTreeMap::new();
+        distance.insert(NodeId::from(self), 0);
+        // Children are visited before their parents
+        for diff in ancestors.traverse([NodeId::from(self)], TraversalDirection::Ancestors) {
+            let dist = distance[&NodeId::from(&diff)];
+            for parent in diff.all_parents() {
+                let parent_dist = distance.entry(NodeId::from(&parent)).or_default();
+                *parent_dist = (*parent_dist).max(dist + 1);
+            }
+        }
+
+        let flattened = PortDiffGraph::from_sinks_while([self.clone()], |d| {
+            distance[&NodeId::from(d)] <= depth
+        });
+        flattened.try_squash_with(|_| self.value().clone())
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use portgraph::{LinkView, PortGraph, PortMut, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+    use crate::port_diff::Owned;
+    use crate::Site;
+
+    use super::*;
+
+    #[rstest]
+    fn test_flatten_ancestors(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        // Rewrite a node of the child with a copy
+        let node = PortView::nodes_iter(child.graph()).next().unwrap();
+        let mut rhs = PortGraph::new();
+        let copy = rhs.add_node(
+            child.graph().num_inputs(node),
+            child.graph().num_outputs(node),
+        );
+        let grandchild = PortDiff::rewrite([Owned::new(node, child.clone())], [], rhs, |p| {
+            let site = Owned::new(p, child.clone()).site().unwrap();
+            Site {
+                node: copy,
+                port: site.port,
+            }
+            .into()
+        })
+        .unwrap();
+        assert_eq!(grandchild.stats().depth, 2);
+
+        assert!(grandchild.flatten_ancestors(0).unwrap() == grandchild);
+
+        let flattened = grandchild.flatten_ancestors(1).unwrap();
+        assert_eq!(flattened.stats().depth, 1);
+        assert_eq!(flattened.all_parents().collect_vec(), vec![parent.clone()]);
+
+        let expected = PortDiff::extract_graph(vec![grandchild.clone()]).unwrap();
+        let extracted = PortDiff::extract_graph(vec![flattened]).unwrap();
+        assert_eq!(extracted.node_count(), expected.node_count());
+        assert_eq!(extracted.link_count(), expected.link_count());
+
+        let flattened = grandchild.flatten_ancestors(5).unwrap();
+        assert_eq!(flattened.stats().depth, 0);
+        assert_eq!(flattened.graph().node_count(), expected.node_count());
+    }
+}
@@ -0,0 +1,145 @@
+//! Inverse diffs, to undo rewrites.
+
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+
+use derive_where::derive_where;
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::{
+    port::{EdgeEnd, Port},
+    Graph, PortDiff,
+};
+
+use super::InvalidRewriteError;
+
+/// Errors when inverting a diff.
+#[derive(Error)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::Edge: Debug)]
+pub enum NotInvertibleError<G: Graph, M = ()> {
+    /// The diff is a root, it does not rewrite anything.
+    #[error("A root diff cannot be inverted")]
+    NoParent,
+    /// The diff rewrites several parents.
+    #[error("Diffs with more than one parent cannot be inverted")]
+    MultipleParents,
+    /// The diff has boundary ports on wires, that cannot be rewritten.
+    #[error("Diffs with boundary ports on wires cannot be inverted")]
+    WireBoundary,
+    /// The rewritten subgraph of the parent is not induced by its nodes.
+    #[error("The rewritten subgraph is not induced by its nodes")]
+    PartialSubgraph,
+    /// The replacement graph has no nodes to rewrite.
+    #[error("Diffs with an empty replacement graph cannot be inverted")]
+    EmptyGraph,
+    #[error("Invalid inverse rewrite: {0}")]
+    InvalidRewrite(#[from] InvalidRewriteError<G, M>),
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Create a diff that undoes `self`.
+    ///
+    /// The inverse is a child of `self` that rewrites the whole replacement
+    /// graph of `self` back to a copy of the subgraph of the parent that
+    /// `self` rewrites. Its boundary ports are mapped to the sites of the
+    /// parent ports of the boundary ports of `self`. Extracting the inverse
+    /// thus yields the same graph as extracting the parent, up to node and
+    /// edge identifiers.
+    ///
+    /// Only diffs with a single parent, an induced parent subgraph and no
+    /// boundary ports on wires can be inverted.
+    pub fn invert(&self) -> Result<Self, NotInvertibleError<G, M>>
+    where
+        M: Default,
+    {
+        let (parent, edge) = match self.incoming_edges().at_most_one() {
+            Ok(Some(incoming)) => incoming,
+            Ok(None) => return Err(NotInvertibleError::NoParent),
+            Err(_) => return Err(NotInvertibleError::MultipleParents),
+        };
+        if self
+            .boundary_iter()
+            .any(|b| self.boundary_site(b).try_into_site().is_err())
+        {
+            return Err(NotInvertibleError::WireBoundary);
+        }
+        let nodes: BTreeSet<_> = self.graph().nodes_iter().collect();
+        if nodes.is_empty() {
+            return Err(NotInvertibleError::EmptyGraph);
+        }
+
+        // Copy the rewritten subgraph of the parent
+        let subgraph = edge.subgraph();
+        let parent_graph = parent.graph();
+        let is_induced = parent_graph
+            .edges_iter()
+            .filter(|&e| {
+                [EdgeEnd::Left, EdgeEnd::Right].iter().all(|&end| {
+                    subgraph
+                        .nodes()
+                        .contains(&parent_graph.incident_node(e, end))
+                })
+            })
+            .all(|e| subgraph.edges().contains(&e));
+        if !is_induced {
+            return Err(NotInvertibleError::PartialSubgraph);
+        }
+        let mut new_graph = G::default();
+        let node_map = new_graph.add_subgraph(parent_graph, subgraph.nodes());
+
+        let inverse = self.rewrite_induced(&nodes, new_graph, |port| {
+            let Port::Boundary(boundary) = port else {
+                unreachable!("all edges of the replacement graph are rewritten")
+            };
+            let site = self
+                .parent_port(boundary)
+                .site()
+                .expect("rewritten parent ports are on sites");
+            site.map_node(|n| node_map[&n]).into()
+        })?;
+        Ok(inverse)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkView, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_invert(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        assert!(matches!(parent.invert(), Err(NotInvertibleError::NoParent)));
+
+        // The child removes the edge between b and c, the inverse restores it
+        let inverse = child.invert().unwrap();
+        assert!(inverse.all_parents().exactly_one().ok() == Some(child.clone()));
+        let extracted = PortDiff::extract_graph(vec![inverse.clone()]).unwrap();
+        assert_eq!(extracted.node_count(), parent.graph().node_count());
+        assert_eq!(extracted.link_count(), parent.graph().link_count());
+
+        // Inverting twice is equivalent to the child
+        let expected = PortDiff::extract_graph(vec![child]).unwrap();
+        let extracted = PortDiff::extract_graph(vec![inverse.invert().unwrap()]).unwrap();
+        assert_eq!(extracted.node_count(), expected.node_count());
+        assert_eq!(extracted.link_count(), expected.link_count());
+    }
+
+    #[rstest]
+    fn test_invert_wires(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, _] = parent_child_diffs;
+        let no_b = parent
+            .rewrite_node_to_wires(portgraph::NodeIndex::new(1))
+            .unwrap();
+        assert!(matches!(
+            no_b.invert(),
+            Err(NotInvertibleError::WireBoundary)
+        ));
+    }
+}
@@ -0,0 +1,544 @@
+//! Shrinking diffs to the region they actually change.
+//!
+//! Rewrites often include nodes that are copied verbatim to the replacement
+//! graph. A rewritten node is a copy of a replacement node if they have the
+//! same sites, the same boundary ports, and their edges within the rewrite
+//! link them to copies of the same nodes. Removing copies from both sides of
+//! a diff does not change its extracted graph, but the diff rewrites fewer
+//! nodes and thus conflicts with fewer diffs.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use bimap::BiBTreeMap;
+use itertools::Itertools;
+
+use crate::{
+    port::{BoundPort, BoundaryIndex, EdgeEnd, Port, Site},
+    subgraph::Subgraph,
+    Graph, PortDiff,
+};
+
+use super::{BoundarySite, EdgeData, IncomingEdgeIndex, PortDiffData};
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Shrink `self` to the nodes of its parent that it actually changes.
+    ///
+    /// Rewritten nodes that are copied verbatim to the replacement graph are
+    /// removed from both the rewritten subgraph and the replacement graph.
+    /// The returned diff has the same parent, value and key as `self` and
+    /// the same extracted graph, but a smaller subgraph and boundary.
+    ///
+    /// Nodes are compared by their sites only, use [`Self::minimize_with`]
+    /// for graphs whose nodes carry weights. Diffs that do not have exactly
+    /// one parent are returned unchanged.
+    pub fn minimize(&self) -> Self {
+        self.minimize_with(|_, _| true)
+    }
+
+    /// As [`Self::minimize`], but a rewritten node `node` can only be a copy
+    /// of a node `copy` of the replacement graph if `same_node(node, copy)`.
+    pub fn minimize_with(&self, same_node: impl FnMut(G::Node, G::Node) -> bool) -> Self {
+        let mut incoming = self.incoming_edges();
+        let (Some((parent, edge)), None) = (incoming.next(), incoming.next()) else {
+            return self.clone();
+        };
+        let copies = Copies::new(&parent, edge, self);
+        let copied = copies.find_copies(same_node);
+        if copied.is_empty() {
+            return self.clone();
+        }
+        copies.without(&copied)
+    }
+}
+
+/// The rewrite of a diff from its single parent.
+struct Copies<'a, G: Graph, M> {
+    parent: &'a PortDiff<G, M>,
+    edge: &'a EdgeData<G>,
+    child: &'a PortDiff<G, M>,
+    /// The boundary ports of the child at every site of the parent.
+    parent_boundary: BTreeMap<Site<G::Node, G::PortLabel>, BTreeSet<BoundaryIndex>>,
+    /// The boundary ports of the child at every site of the child.
+    child_boundary: BTreeMap<Site<G::Node, G::PortLabel>, BTreeSet<BoundaryIndex>>,
+}
+
+impl<'a, G: Graph, M: Clone> Copies<'a, G, M> {
+    fn new(parent: &'a PortDiff<G, M>, edge: &'a EdgeData<G>, child: &'a PortDiff<G, M>) -> Self {
+        let mut parent_boundary: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        for (port, &boundary) in edge.port_map.iter() {
+            let site = match *port {
+                Port::Bound(port) => parent.graph().get_port_site(port),
+                Port::Boundary(index) => {
+                    let Ok(site) = parent.boundary_site(index).try_into_site() else {
+                        continue;
+                    };
+                    site
+                }
+            };
+            parent_boundary.entry(site).or_default().insert(boundary);
+        }
+        let mut child_boundary: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        for boundary in child.boundary_iter() {
+            if let Ok(site) = child.boundary_site(boundary).try_into_site() {
+                child_boundary.entry(site).or_default().insert(boundary);
+            }
+        }
+        Self {
+            parent,
+            edge,
+            child,
+            parent_boundary,
+            child_boundary,
+        }
+    }
+
+    /// The edges of the rewritten subgraph at `site`, as the end at `site`,
+    /// the edge and the site at the other end.
+    fn parent_edges(
+        &self,
+        site: Site<G::Node, G::PortLabel>,
+    ) -> impl Iterator<Item = (EdgeEnd, G::Edge, Site<G::Node, G::PortLabel>)> + '_ {
+        let graph = self.parent.graph();
+        graph
+            .get_bound_ports(site)
+            .filter(|port| self.edge.subgraph.edges().contains(&port.edge))
+            .map(|port| (port.end, port.edge, graph.get_port_site(port.opposite())))
+    }
+
+    /// The edges of the replacement graph at `site`, as the end at `site`,
+    /// the edge and the site at the other end.
+    fn child_edges(
+        &self,
+        site: Site<G::Node, G::PortLabel>,
+    ) -> impl Iterator<Item = (EdgeEnd, G::Edge, Site<G::Node, G::PortLabel>)> + '_ {
+        let graph = self.child.graph();
+        graph
+            .get_bound_ports(site)
+            .map(|port| (port.end, port.edge, graph.get_port_site(port.opposite())))
+    }
+
+    /// Map the rewritten nodes that are copied verbatim to the node of the
+    /// replacement graph they are copied to.
+    ///
+    /// Nodes with boundary ports are matched to the node their boundary
+    /// ports are mapped to, and other nodes are matched along edges from
+    /// matched nodes. Matches that are not copies are then removed until all
+    /// remaining matches are copies.
+    fn find_copies(
+        &self,
+        mut same_node: impl FnMut(G::Node, G::Node) -> bool,
+    ) -> BTreeMap<G::Node, G::Node> {
+        let mut copies = BTreeMap::new();
+        let mut images = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        for &node in self.edge.subgraph.nodes() {
+            let boundary = self.parent.graph().get_sites(node).find_map(|site| {
+                let boundary = self.parent_boundary.get(&site)?;
+                boundary.first().copied()
+            });
+            let Some(boundary) = boundary else {
+                continue;
+            };
+            let Ok(site) = self.child.boundary_site(boundary).try_into_site() else {
+                continue;
+            };
+            if same_node(node, site.node) && images.insert(site.node) {
+                copies.insert(node, site.node);
+                queue.push_back(node);
+            }
+        }
+        while let Some(node) = queue.pop_front() {
+            let copy = copies[&node];
+            for site in self.parent.graph().get_sites(node) {
+                let copy_site = Site {
+                    node: copy,
+                    port: site.port.clone(),
+                };
+                for (end, _, other) in self.parent_edges(site) {
+                    if copies.contains_key(&other.node) {
+                        continue;
+                    }
+                    let candidates = self
+                        .child_edges(copy_site.clone())
+                        .filter(|(e, _, o)| {
+                            *e == end && o.port == other.port && !images.contains(&o.node)
+                        })
+                        .map(|(_, _, o)| o.node)
+                        .sorted()
+                        .dedup()
+                        .collect_vec();
+                    if let [candidate] = candidates[..] {
+                        if same_node(other.node, candidate) {
+                            copies.insert(other.node, candidate);
+                            images.insert(candidate);
+                            queue.push_back(other.node);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Removing a match may invalidate others, repeat until stable
+        loop {
+            let images = copies.values().copied().collect();
+            let not_copies = copies
+                .iter()
+                .filter(|(&node, &copy)| !self.is_copy(node, copy, &copies, &images))
+                .map(|(&node, _)| node)
+                .collect_vec();
+            if not_copies.is_empty() {
+                return copies;
+            }
+            for node in not_copies {
+                copies.remove(&node);
+            }
+        }
+    }
+
+    /// Whether `node` is copied verbatim to `copy`, given the other copies.
+    fn is_copy(
+        &self,
+        node: G::Node,
+        copy: G::Node,
+        copies: &BTreeMap<G::Node, G::Node>,
+        images: &BTreeSet<G::Node>,
+    ) -> bool {
+        let labels = |graph: &G, n| graph.get_sites(n).map(|s| s.port).collect::<BTreeSet<_>>();
+        if labels(self.parent.graph(), node) != labels(self.child.graph(), copy) {
+            return false;
+        }
+        self.parent.graph().get_sites(node).all(|site| {
+            let copy_site = Site {
+                node: copy,
+                port: site.port.clone(),
+            };
+            if self.parent_boundary.get(&site) != self.child_boundary.get(&copy_site) {
+                return false;
+            }
+            // Edges to copies must be copied, other edges must be replaced
+            // by edges to nodes that are not copies
+            let parent_edges = self
+                .parent_edges(site)
+                .map(|(end, _, other)| {
+                    let other = copies.get(&other.node).map(|&n| (n, other.port));
+                    (end, other)
+                })
+                .sorted()
+                .collect_vec();
+            let child_edges = self
+                .child_edges(copy_site)
+                .map(|(end, _, other)| {
+                    let other = images
+                        .contains(&other.node)
+                        .then_some((other.node, other.port));
+                    (end, other)
+                })
+                .sorted()
+                .collect_vec();
+            parent_edges == child_edges
+        })
+    }
+
+    /// The diff without the rewritten nodes in `copies` and their copies.
+    fn without(&self, copies: &BTreeMap<G::Node, G::Node>) -> PortDiff<G, M> {
+        let parent_graph = self.parent.graph();
+        let child_graph = self.child.graph();
+        let images: BTreeSet<_> = copies.values().copied().collect();
+
+        let nodes: BTreeSet<_> = self
+            .edge
+            .subgraph
+            .nodes()
+            .iter()
+            .filter(|n| !copies.contains_key(n))
+            .copied()
+            .collect();
+        let edges = self
+            .edge
+            .subgraph
+            .edges()
+            .iter()
+            .filter(|&&e| {
+                [EdgeEnd::Left, EdgeEnd::Right]
+                    .into_iter()
+                    .all(|end| nodes.contains(&parent_graph.incident_node(e, end)))
+            })
+            .copied()
+            .collect();
+        let subgraph = Subgraph::new(parent_graph, nodes, edges);
+
+        let mut new_graph = G::default();
+        let kept = child_graph
+            .nodes_iter()
+            .filter(|n| !images.contains(n))
+            .collect();
+        let nodes_map = new_graph.add_subgraph(child_graph, &kept);
+        let map_site = |site: Site<G::Node, G::PortLabel>| site.map_node(|n| nodes_map[&n]);
+
+        // Keep the boundary ports that are not at copies
+        let mut boundary = Vec::new();
+        let mut boundary_labels = BTreeMap::new();
+        let mut index_map = BTreeMap::new();
+        for old_index in self.child.boundary_iter() {
+            let new_site = match self.child.boundary_site(old_index) {
+                BoundarySite::Site(site) if images.contains(&site.node) => continue,
+                BoundarySite::Site(site) => map_site(site).into(),
+                wire => wire,
+            };
+            let new_index = BoundaryIndex::from(boundary.len());
+            boundary.push((new_site, IncomingEdgeIndex::from(0)));
+            if let Some(label) = self.child.boundary_label(old_index) {
+                boundary_labels.insert(new_index, label.to_string());
+            }
+            index_map.insert(old_index, new_index);
+        }
+        let mut port_map = BiBTreeMap::new();
+        for (port, old_index) in self.edge.port_map.iter() {
+            if let Some(&new_index) = index_map.get(old_index) {
+                port_map.insert(*port, new_index);
+            }
+        }
+
+        // Edges between copies and other rewritten nodes become boundary
+        // edges, mapped to the edges that replace them
+        for (&node, &copy) in copies {
+            for site in parent_graph.get_sites(node) {
+                let copy_site = Site {
+                    node: copy,
+                    port: site.port.clone(),
+                };
+                let parent_edges = self
+                    .parent_edges(site)
+                    .filter(|(_, _, other)| !copies.contains_key(&other.node))
+                    .map(|(end, edge, _)| (end, edge))
+                    .sorted();
+                let child_edges = self
+                    .child_edges(copy_site)
+                    .filter(|(_, _, other)| !images.contains(&other.node))
+                    .map(|(end, _, other)| (end, other))
+                    .sorted();
+                for ((end, edge), (_, other)) in parent_edges.zip(child_edges) {
+                    let port = Port::Bound(BoundPort {
+                        edge,
+                        end: end.opposite(),
+                    });
+                    let new_index = BoundaryIndex::from(boundary.len());
+                    boundary.push((map_site(other).into(), IncomingEdgeIndex::from(0)));
+                    port_map.insert(port, new_index);
+                }
+            }
+        }
+
+        let data = PortDiffData {
+            graph: new_graph,
+            boundary: boundary.into_iter().collect(),
+            boundary_labels,
+            value: self.child.value().clone(),
+            key: self.child.key().map(str::to_string),
+            bound_ancestors: Default::default(),
+        };
+        let edge = EdgeData { subgraph, port_map };
+        PortDiff::try_with_parents(data, vec![(self.parent.clone(), edge)])
+            .expect("a minimized diff has a single parent")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        iso::are_isomorphic,
+        port_diff::Owned,
+        simple_graph::{passthrough_wire, SimpleGraph, SimpleNode, SimplePort},
+    };
+
+    use super::*;
+
+    /// Rewrite `nodes` of `root` into `new_graph`, mapping the boundary
+    /// ports at every site with `map`.
+    fn rewrite(
+        root: &PortDiff<SimpleGraph>,
+        nodes: impl IntoIterator<Item = SimpleNode>,
+        new_graph: SimpleGraph,
+        map: impl Fn(Site<SimpleNode, SimplePort>) -> BoundarySite<SimpleGraph>,
+    ) -> PortDiff<SimpleGraph> {
+        root.rewrite_induced(&nodes.into_iter().collect(), new_graph, |port| {
+            map(Owned::new(port, root.clone()).site().unwrap())
+        })
+        .unwrap()
+    }
+
+    /// Minimize `child`, checking that its extracted graph is unchanged.
+    fn minimize_checked(child: &PortDiff<SimpleGraph>) -> PortDiff<SimpleGraph> {
+        let minimized = child.minimize();
+        let expected = PortDiff::extract_graph(vec![child.clone()]).unwrap();
+        let extracted = PortDiff::extract_graph(vec![minimized.clone()]).unwrap();
+        assert!(are_isomorphic(&extracted, &expected));
+        minimized
+    }
+
+    /// The nodes rewritten by the single incoming edge of `diff`.
+    fn rewritten_nodes(diff: &PortDiff<SimpleGraph>) -> BTreeSet<SimpleNode> {
+        let (_, edge) = diff.incoming_edges().exactly_one().ok().unwrap();
+        edge.subgraph().nodes().clone()
+    }
+
+    #[test]
+    fn test_minimize() {
+        // a -> b -> c -> d, rewriting b and c, but only changing c
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(4));
+        let [b, c] = [1, 2].map(SimpleNode::new);
+        let mut new_graph = SimpleGraph::new();
+        let new_b = new_graph.add_node(1, 1);
+        let new_c = new_graph.add_node(1, 2);
+        new_graph.link_nodes(new_b, 0, new_c, 0);
+        let child = root
+            .rewrite_induced(&BTreeSet::from([b, c]), new_graph, |port| {
+                let site = Owned::new(port, root.clone()).site().unwrap();
+                site.map_node(|n| if n == b { new_b } else { new_c }).into()
+            })
+            .unwrap();
+        let other = root.select_subgraph([b]);
+        assert!(!child.is_compatible(&other));
+
+        let minimized = child.minimize();
+        assert_eq!(minimized.graph().node_count(), 1);
+        assert_eq!(minimized.n_boundary_ports(), 2);
+        let (_, edge) = minimized.incoming_edges().exactly_one().ok().unwrap();
+        assert_eq!(edge.subgraph().nodes(), &BTreeSet::from([c]));
+        assert!(minimized.is_compatible(&other));
+
+        let expected = PortDiff::extract_graph(vec![child]).unwrap();
+        let extracted = PortDiff::extract_graph(vec![minimized.clone()]).unwrap();
+        assert_eq!(extracted.node_count(), expected.node_count());
+        assert_eq!(extracted.edge_count(), expected.edge_count());
+        let new_c = extracted
+            .nodes_iter()
+            .find(|&n| extracted.get_sites(n).count() == 3)
+            .unwrap();
+        let out = Site {
+            node: new_c,
+            port: SimplePort::Out(1),
+        };
+        assert_eq!(extracted.get_bound_ports(out).count(), 0);
+
+        // Nothing is copied in the minimized diff, nor in the root
+        assert_eq!(minimized.minimize().graph().node_count(), 1);
+        assert!(root.minimize() == root);
+    }
+
+    #[test]
+    fn test_minimize_wires() {
+        // a -> b -> c -> d -> e, copying b and replacing d with a wire
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(5));
+        let [b, d] = [1, 3].map(SimpleNode::new);
+        let mut new_graph = SimpleGraph::new();
+        let new_b = new_graph.add_node(1, 1);
+        let child = rewrite(&root, [b, d], new_graph, |site| {
+            if site.node == b {
+                site.map_node(|_| new_b).into()
+            } else {
+                passthrough_wire(site.port)
+            }
+        });
+        assert_eq!(child.n_boundary_ports(), 4);
+
+        let minimized = minimize_checked(&child);
+        assert_eq!(minimized.graph().node_count(), 0);
+        assert_eq!(minimized.n_boundary_ports(), 2);
+        assert!(minimized
+            .boundary_iter()
+            .all(|b| minimized.boundary_site(b).try_into_site().is_err()));
+        assert_eq!(rewritten_nodes(&minimized), BTreeSet::from([d]));
+    }
+
+    #[test]
+    fn test_minimize_multiple_boundary_ports() {
+        // x -> b, y -> b, b -> c -> z, copying b and changing c
+        let mut graph = SimpleGraph::new();
+        let [x, y, b, c, z] = [(); 5].map(|()| graph.add_node(0, 0));
+        graph.link_nodes(x, 0, b, 0);
+        graph.link_nodes(y, 0, b, 1);
+        graph.link_nodes(b, 0, c, 0);
+        graph.link_nodes(c, 0, z, 0);
+        let root = PortDiff::from_graph(graph);
+        let new_graph = || {
+            let mut new_graph = SimpleGraph::new();
+            let new_b = new_graph.add_node(2, 1);
+            let new_c = new_graph.add_node(1, 2);
+            new_graph.link_nodes(new_b, 0, new_c, 0);
+            (new_graph, new_b, new_c)
+        };
+
+        let (graph, new_b, new_c) = new_graph();
+        let child = rewrite(&root, [b, c], graph, |site| {
+            site.map_node(|n| if n == b { new_b } else { new_c }).into()
+        });
+        assert_eq!(child.n_boundary_ports(), 3);
+        let minimized = minimize_checked(&child);
+        assert_eq!(minimized.graph().node_count(), 1);
+        assert_eq!(minimized.n_boundary_ports(), 2);
+        assert_eq!(rewritten_nodes(&minimized), BTreeSet::from([c]));
+
+        // Swapping the inputs of b: b is not copied
+        let (graph, new_b, new_c) = new_graph();
+        let child = rewrite(&root, [b, c], graph, |site| {
+            let port = match site.port {
+                SimplePort::In(i) if site.node == b => SimplePort::In(1 - i),
+                port => port,
+            };
+            Site {
+                node: if site.node == b { new_b } else { new_c },
+                port,
+            }
+            .into()
+        });
+        assert!(minimize_checked(&child) == child);
+    }
+
+    #[test]
+    fn test_minimize_inner_nodes() {
+        // a -> b -> c -> d -> e, copying b and c and changing d. c has no
+        // boundary ports.
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(5));
+        let [b, c, d] = [1, 2, 3].map(SimpleNode::new);
+        let mut new_graph = SimpleGraph::new();
+        let new_b = new_graph.add_node(1, 1);
+        let new_c = new_graph.add_node(1, 1);
+        let new_d = new_graph.add_node(1, 2);
+        new_graph.link_nodes(new_b, 0, new_c, 0);
+        new_graph.link_nodes(new_c, 0, new_d, 0);
+        let child = rewrite(&root, [b, c, d], new_graph, |site| {
+            site.map_node(|n| if n == b { new_b } else { new_d }).into()
+        });
+        assert_eq!(child.n_boundary_ports(), 2);
+
+        let minimized = minimize_checked(&child);
+        assert_eq!(minimized.graph().node_count(), 1);
+        assert_eq!(minimized.n_boundary_ports(), 2);
+        assert_eq!(rewritten_nodes(&minimized), BTreeSet::from([d]));
+    }
+
+    #[test]
+    fn test_minimize_nothing() {
+        // a -> b -> c, changing the ports of b
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let b = SimpleNode::new(1);
+        let mut new_graph = SimpleGraph::new();
+        let new_b = new_graph.add_node(1, 2);
+        let child = rewrite(&root, [b], new_graph, |site| {
+            site.map_node(|_| new_b).into()
+        });
+        assert!(minimize_checked(&child) == child);
+
+        // a -> b -> c -> d, removing the edge between b and c
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(4));
+        let [b, c] = [1, 2].map(SimpleNode::new);
+        let mut new_graph = SimpleGraph::new();
+        let new_b = new_graph.add_node(1, 1);
+        let new_c = new_graph.add_node(1, 1);
+        let child = rewrite(&root, [b, c], new_graph, |site| {
+            site.map_node(|n| if n == b { new_b } else { new_c }).into()
+        });
+        assert!(minimize_checked(&child) == child);
+    }
+}
@@ -0,0 +1,212 @@
+//! Parallel compatibility checks, enabled by the `rayon` feature.
+//!
+//! Diffs cannot be shared across threads, so the rewrites within the
+//! ancestor graph of all diffs are first indexed into plain data, in a single
+//! serial traversal. The index is then checked pairwise in parallel.
+//!
+//! Rewrites conflict if they rewrite common nodes, as for
+//! [`super::DisjointSubgraphs`]. Other compatibility policies are not
+//! supported.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use petgraph::visit::{EdgeRef, IntoEdges};
+use rayon::prelude::*;
+
+use crate::{Graph, NodeId, PortDiff, PortDiffGraph};
+
+use super::{Conflict, TraversalDirection};
+
+/// The nodes and edges of an ancestor that are rewritten by a child.
+///
+/// Diffs are given by their index in the ancestor graph of [`ClaimIndex`].
+struct Claim<G: Graph> {
+    child: usize,
+    nodes: BTreeSet<G::Node>,
+    edges: BTreeSet<G::Edge>,
+}
+
+/// A read-only index of the claims of a list of diffs, shared across
+/// threads.
+///
+/// Diffs of the ancestor graph are indexed in topological order.
+struct ClaimIndex<G: Graph> {
+    /// The claims on every diff of the ancestor graph.
+    claims: Vec<Vec<Claim<G>>>,
+    /// The ancestors of every diff of the list, including itself, in
+    /// topological order.
+    ancestors: Vec<Vec<usize>>,
+}
+
+impl<G: Graph> ClaimIndex<G> {
+    /// Index the claims of `diffs`.
+    ///
+    /// Returns the index along with all ancestors of `diffs`, by index.
+    fn new<M: Clone>(diffs: &[PortDiff<G, M>]) -> (Self, Vec<PortDiff<G, M>>) {
+        let graph = PortDiffGraph::from_sinks(diffs.iter().cloned());
+        let nodes = graph.all_nodes().collect_vec();
+        let indices: BTreeMap<_, _> = nodes.iter().enumerate().map(|(i, &n)| (n, i)).collect();
+        let claims = nodes
+            .iter()
+            .map(|&n| {
+                let edges = graph.inner().edges(n.into());
+                edges
+                    .filter_map(|e| {
+                        let child = *indices.get(&NodeId::from(e.target()))?;
+                        Some(Claim {
+                            child,
+                            nodes: e.weight().subgraph.nodes().clone(),
+                            edges: e.weight().rewritten_edges(),
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+        let ancestors = diffs
+            .iter()
+            .map(|diff| {
+                graph
+                    .traverse([NodeId::from(diff)], TraversalDirection::Ancestors)
+                    .map(|ancestor| indices[&NodeId::from(&ancestor)])
+                    .sorted()
+                    .collect()
+            })
+            .collect();
+        let diffs = nodes.into_iter().map(|n| graph.get_diff(n)).collect();
+        (Self { claims, ancestors }, diffs)
+    }
+
+    /// The conflicting claims of the `i`-th and `j`-th diffs, along with the
+    /// ancestor they are made on.
+    ///
+    /// Claims conflict if they are made by distinct children within the
+    /// ancestor graphs of the diffs and rewrite common nodes.
+    fn conflicts(
+        &self,
+        i: usize,
+        j: usize,
+    ) -> impl Iterator<Item = (usize, &Claim<G>, &Claim<G>)> + '_ {
+        let (ancestors1, ancestors2) = (&self.ancestors[i], &self.ancestors[j]);
+        let is_ancestor1 = move |n: &usize| ancestors1.binary_search(n).is_ok();
+        let is_ancestor2 = move |n: &usize| ancestors2.binary_search(n).is_ok();
+        ancestors1
+            .iter()
+            .filter(move |&a| is_ancestor2(a))
+            .flat_map(move |&ancestor| {
+                let claims = &self.claims[ancestor];
+                let claims1 = claims.iter().filter(move |c| is_ancestor1(&c.child));
+                let claims2 = claims.iter().filter(move |c| is_ancestor2(&c.child));
+                claims1
+                    .cartesian_product(claims2)
+                    .filter(|(c1, c2)| c1.child != c2.child && !c1.nodes.is_disjoint(&c2.nodes))
+                    .map(move |(c1, c2)| (ancestor, c1, c2))
+            })
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M>
+where
+    G::Node: Send + Sync,
+    G::Edge: Send + Sync,
+{
+    /// Whether `diffs` are compatible, checking pairs of diffs in parallel.
+    ///
+    /// Equivalent to [`PortDiff::are_compatible`], i.e. with the
+    /// [`super::DisjointSubgraphs`] policy.
+    pub fn par_are_compatible<'a>(diffs: impl IntoIterator<Item = &'a PortDiff<G, M>>) -> bool
+    where
+        G: 'a,
+    {
+        let diffs = diffs.into_iter().unique().cloned().collect_vec();
+        let (index, _) = ClaimIndex::new(&diffs);
+        let n = diffs.len();
+        (0..n)
+            .into_par_iter()
+            .all(|i| (i + 1..n).all(|j| index.conflicts(i, j).next().is_none()))
+    }
+
+    /// List all pairs of conflicting diffs in `diffs`, checking pairs of
+    /// diffs in parallel.
+    ///
+    /// Equivalent to [`PortDiff::find_conflicts`], i.e. with the
+    /// [`super::DisjointSubgraphs`] policy. Conflicts are ordered by pair of
+    /// diffs as in [`PortDiff::find_conflicts`], but the conflicts of a pair
+    /// may be listed in a different order.
+    pub fn par_find_conflicts<'a>(
+        diffs: impl IntoIterator<Item = &'a PortDiff<G, M>>,
+    ) -> Vec<Conflict<G, M>>
+    where
+        G: 'a,
+    {
+        let diffs = diffs.into_iter().unique().cloned().collect_vec();
+        let (index, ancestors) = ClaimIndex::new(&diffs);
+        let n = diffs.len();
+        let conflicts: Vec<_> = (0..n)
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let index = &index;
+                (i + 1..n).flat_map(move |j| {
+                    index.conflicts(i, j).map(move |(ancestor, c1, c2)| {
+                        let nodes = c1.nodes.intersection(&c2.nodes).copied().collect();
+                        let edges = c1.edges.intersection(&c2.edges).copied().collect();
+                        (i, j, ancestor, nodes, edges)
+                    })
+                })
+            })
+            .collect();
+        conflicts
+            .into_iter()
+            .map(|(i, j, ancestor, nodes, edges)| Conflict {
+                diffs: (diffs[i].clone(), diffs[j].clone()),
+                ancestor: ancestors[ancestor].clone(),
+                nodes,
+                edges,
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{
+        parent_two_children_diffs, parent_two_children_overlapping_diffs, TestPortDiff,
+    };
+
+    use super::*;
+
+    #[rstest]
+    fn test_par_compatibility(
+        parent_two_children_diffs: [TestPortDiff; 3],
+        parent_two_children_overlapping_diffs: [TestPortDiff; 3],
+    ) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        assert!(PortDiff::par_are_compatible(&[
+            child_1.clone(),
+            child_2.clone()
+        ]));
+        assert!(PortDiff::par_find_conflicts(&[child_1, child_2]).is_empty());
+
+        let diffs = parent_two_children_overlapping_diffs;
+        assert!(!PortDiff::par_are_compatible(&diffs));
+        // The conflicts of a pair of diffs may be in a different order
+        let keys = |conflicts: Vec<Conflict<_>>| {
+            conflicts
+                .into_iter()
+                .map(|c| {
+                    let [d1, d2, ancestor] =
+                        [&c.diffs.0, &c.diffs.1, &c.ancestor].map(NodeId::from);
+                    (d1, d2, ancestor, c.nodes, c.edges)
+                })
+                .sorted()
+                .collect_vec()
+        };
+        let expected = PortDiff::find_conflicts(&diffs);
+        let conflicts = PortDiff::par_find_conflicts(&diffs);
+        assert!(!conflicts.is_empty());
+        assert_eq!(keys(conflicts), keys(expected));
+    }
+}
@@ -0,0 +1,116 @@
+//! Policies deciding when rewrites of the same diff conflict.
+
+use std::collections::BTreeSet;
+
+use crate::Graph;
+
+use super::EdgeData;
+
+/// A policy deciding whether rewrites of the same parent diff are compatible.
+///
+/// The policy is passed the edges of all rewrites of a parent diff that
+/// should coexist and decides whether they are compatible. It is used when
+/// merging diff graphs, see [`crate::PortDiffGraph::merge_with_policy`] and
+/// [`crate::PortDiff::are_compatible_with_policy`].
+///
+/// The default policy is [`DisjointSubgraphs`]. Policies are implemented for
+/// all closures `Fn(&[&EdgeData<G>]) -> bool`.
+///
+/// Note that squashing always requires the rewritten subgraphs to be
+/// disjoint: diffs that are only compatible under a more permissive policy
+/// cannot be extracted.
+pub trait CompatibilityPolicy<G: Graph> {
+    /// Whether the rewrites `edges` of the same parent are compatible.
+    fn are_compatible(&self, edges: &[&EdgeData<G>]) -> bool;
+}
+
+/// Rewrites are compatible if their rewritten node sets are disjoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DisjointSubgraphs;
+
+impl<G: Graph> CompatibilityPolicy<G> for DisjointSubgraphs {
+    fn are_compatible(&self, edges: &[&EdgeData<G>]) -> bool {
+        // All vertex sets must be disjoint
+        let mut all_nodes = BTreeSet::new();
+        for edge in edges {
+            if !edge.subgraph.nodes().iter().all(|&v| all_nodes.insert(v)) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Rewrites are compatible if both their rewritten node sets and their
+/// rewritten edge sets are disjoint.
+///
+/// Stricter than [`DisjointSubgraphs`]: rewrites of adjacent nodes conflict,
+/// as they both rewrite the edges between them. See
+/// [`EdgeData::rewritten_edges`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DisjointEdges;
+
+impl<G: Graph> CompatibilityPolicy<G> for DisjointEdges {
+    fn are_compatible(&self, edges: &[&EdgeData<G>]) -> bool {
+        let mut all_edges = BTreeSet::new();
+        for edge in edges {
+            if !edge
+                .rewritten_edges()
+                .into_iter()
+                .all(|e| all_edges.insert(e))
+            {
+                return false;
+            }
+        }
+
+        DisjointSubgraphs.are_compatible(edges)
+    }
+}
+
+impl<G: Graph, F: Fn(&[&EdgeData<G>]) -> bool> CompatibilityPolicy<G> for F {
+    fn are_compatible(&self, edges: &[&EdgeData<G>]) -> bool {
+        self(edges)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::PortGraph;
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{
+        parent_two_children_diffs, parent_two_children_overlapping_diffs, TestPortDiff,
+    };
+    use crate::PortDiff;
+
+    use super::*;
+
+    #[rstest]
+    fn test_compatibility_policy(parent_two_children_overlapping_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_overlapping_diffs;
+        let diffs = [&child_1, &child_2];
+        assert!(!PortDiff::are_compatible(diffs));
+        assert!(!PortDiff::are_compatible_with_policy(
+            diffs,
+            &DisjointSubgraphs
+        ));
+
+        let allow_all = |_: &[&EdgeData<PortGraph>]| true;
+        assert!(PortDiff::are_compatible_with_policy(diffs, &allow_all));
+    }
+
+    #[rstest]
+    fn test_disjoint_edges(parent_two_children_diffs: [TestPortDiff; 3]) {
+        // The two children rewrite the two ends of the same edge
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let diffs = [&child_1, &child_2];
+        assert!(PortDiff::are_compatible(diffs));
+        assert!(!PortDiff::are_compatible_with_policy(diffs, &DisjointEdges));
+        assert!(PortDiff::are_compatible_with_policy(
+            [&child_1],
+            &DisjointEdges
+        ));
+    }
+}
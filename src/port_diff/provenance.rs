@@ -0,0 +1,127 @@
+//! Provenance of the nodes and edges of extracted graphs.
+
+use std::collections::BTreeMap;
+
+use derive_where::derive_where;
+
+use crate::{
+    port::{BoundPort, BoundaryIndex, EdgeEnd, Site},
+    Graph, PortDiff,
+};
+
+use super::{Owned, SquashError};
+
+/// The origin of an edge in an extracted graph.
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph)]
+pub enum EdgeProvenance<G: Graph, M = ()> {
+    /// The edge is an edge of the replacement graph of a diff.
+    Diff(PortDiff<G, M>),
+    /// The edge was created by joining boundary ports of one or more diffs.
+    ///
+    /// Lists the boundary ports that were crossed, including the boundary
+    /// ports of the wires that the edge goes through.
+    Boundary(Vec<Owned<BoundaryIndex, G, M>>),
+}
+
+/// The provenance of all nodes and edges of an extracted graph.
+#[derive_where(Clone, Default; G: Graph)]
+pub struct ExtractionReport<G: Graph, M = ()> {
+    /// The diff whose replacement graph introduced each node.
+    pub nodes: BTreeMap<G::Node, PortDiff<G, M>>,
+    /// The origin of each edge.
+    pub edges: BTreeMap<G::Edge, EdgeProvenance<G, M>>,
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Extract the graph of `diffs`, along with the provenance of its nodes
+    /// and edges.
+    ///
+    /// See [`ExtractionReport`] and [`EdgeProvenance`].
+    pub fn extract_graph_reported(
+        diffs: Vec<PortDiff<G, M>>,
+    ) -> Result<(G, ExtractionReport<G, M>), SquashError> {
+        let graph = Self::try_merge(diffs)?;
+        let (extracted, nodes_map, boundary_links) = PortDiff::squash_graph_with_links(&graph)?;
+
+        let mut report = ExtractionReport::default();
+        for (id, map) in nodes_map {
+            let diff = graph.get_diff(id);
+            for edge in diff.graph().edges_iter() {
+                let [left, right] = [EdgeEnd::Left, EdgeEnd::Right].map(|end| {
+                    diff.graph()
+                        .get_port_site(BoundPort { edge, end })
+                        .filter_map_node(|n| map.get(&n).copied())
+                });
+                let (Some(left), Some(right)) = (left, right) else {
+                    // The edge was rewritten
+                    continue;
+                };
+                let new_edge = find_edge(&extracted, left, &right, &report.edges)
+                    .expect("edge of a diff is in the extracted graph");
+                report
+                    .edges
+                    .insert(new_edge, EdgeProvenance::Diff(diff.clone()));
+            }
+            report
+                .nodes
+                .extend(map.into_values().map(|n| (n, diff.clone())));
+        }
+        for link in boundary_links {
+            let new_edge = find_edge(&extracted, link.left, &link.right, &report.edges)
+                .expect("boundary link is in the extracted graph");
+            report
+                .edges
+                .insert(new_edge, EdgeProvenance::Boundary(link.boundaries));
+        }
+        Ok((extracted, report))
+    }
+}
+
+/// Find an edge from `left` to `right` in `graph` that is not in `known`.
+fn find_edge<G: Graph, V>(
+    graph: &G,
+    left: Site<G::Node, G::PortLabel>,
+    right: &Site<G::Node, G::PortLabel>,
+    known: &BTreeMap<G::Edge, V>,
+) -> Option<G::Edge> {
+    // There may be parallel edges, of which some are already known
+    graph
+        .parallel_edges(left, right.clone())
+        .find(|edge| !known.contains_key(edge))
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkView, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_extract_graph_reported(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let (graph, report) = PortDiff::extract_graph_reported(vec![child.clone()]).unwrap();
+
+        assert_eq!(report.nodes.len(), graph.node_count());
+        assert_eq!(report.edges.len(), graph.link_count());
+
+        // The child has no edges: all edges are between boundary sites
+        assert!(report.edges.values().all(|p| match p {
+            EdgeProvenance::Boundary(boundaries) => {
+                boundaries.len() == 1 && boundaries[0].owner == child
+            }
+            EdgeProvenance::Diff(_) => false,
+        }));
+
+        let (graph, report) = PortDiff::extract_graph_reported(vec![parent.clone()]).unwrap();
+        assert_eq!(report.edges.len(), graph.link_count());
+        assert!(report
+            .edges
+            .values()
+            .all(|p| matches!(p, EdgeProvenance::Diff(d) if d == &parent)));
+    }
+}
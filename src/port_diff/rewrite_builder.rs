@@ -0,0 +1,258 @@
+//! A builder for rewrites, as an alternative to the positional arguments of
+//! [`PortDiff::rewrite`].
+
+use crate::{
+    port::{BoundaryIndex, EdgeEnd},
+    BoundarySite, Graph, Port, PortDiff,
+};
+
+use super::{InvalidRewriteError, Owned, RewriteIssue, WireAllocator};
+
+type BoundaryMap<'a, G, M> = Box<dyn FnMut(BoundaryPortCtx<'_, G, M>) -> BoundarySite<G> + 'a>;
+
+/// A builder for a rewrite of one or several diffs.
+///
+/// Nodes and edges to be rewritten are added one call at a time, along with
+/// the replacement graph and the boundary map. The rewrite is only created
+/// by [`RewriteBuilder::build`], which performs the same checks as
+/// [`PortDiff::rewrite`]. Use [`RewriteBuilder::validate`] to check the
+/// rewrite while it is being built.
+pub struct RewriteBuilder<'a, G: Graph, M = ()> {
+    diff: PortDiff<G, M>,
+    nodes: Vec<Owned<G::Node, G, M>>,
+    edges: Vec<(Owned<Port<G>, G, M>, Owned<Port<G>, G, M>)>,
+    replacement: G,
+    boundary_map: Option<BoundaryMap<'a, G, M>>,
+    value: Option<M>,
+    key: Option<String>,
+}
+
+/// The context of a boundary port, passed to the boundary map of a
+/// [`RewriteBuilder`].
+pub struct BoundaryPortCtx<'b, G: Graph, M = ()> {
+    /// The image of the boundary port in a parent diff.
+    pub port: Owned<Port<G>, G, M>,
+    /// The index of the boundary port in the new diff.
+    pub index: BoundaryIndex,
+    /// The allocator of wire IDs. If any wire is allocated, all wires of the
+    /// new diff must be allocated, see [`PortDiff::rewrite_with_wires`].
+    pub wires: &'b mut WireAllocator,
+    label: &'b mut Option<String>,
+}
+
+impl<G: Graph, M> BoundaryPortCtx<'_, G, M> {
+    /// Label the boundary port, see [`PortDiff::rewrite_labelled`].
+    pub fn set_label(&mut self, label: impl Into<String>) {
+        *self.label = Some(label.into());
+    }
+}
+
+impl<'a, G: Graph, M: Clone> RewriteBuilder<'a, G, M> {
+    /// Create a builder for a rewrite of `diff`.
+    ///
+    /// The replacement graph is empty until set with
+    /// [`Self::with_replacement`].
+    pub fn new(diff: &PortDiff<G, M>) -> Self {
+        Self {
+            diff: diff.clone(),
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            replacement: G::default(),
+            boundary_map: None,
+            value: None,
+            key: None,
+        }
+    }
+
+    /// Rewrite `nodes` of the diff of the builder.
+    pub fn remove_nodes(self, nodes: impl IntoIterator<Item = G::Node>) -> Self {
+        let diff = self.diff.clone();
+        self.remove_owned_nodes(nodes.into_iter().map(|n| Owned::new(n, diff.clone())))
+    }
+
+    /// Rewrite `nodes`, owned by the diff of the builder or any other diff.
+    ///
+    /// The new diff will be a child of all owners of `nodes`.
+    pub fn remove_owned_nodes(
+        mut self,
+        nodes: impl IntoIterator<Item = Owned<G::Node, G, M>>,
+    ) -> Self {
+        self.nodes.extend(nodes);
+        self
+    }
+
+    /// Rewrite the edge between the ports `left` and `right` of the diff of
+    /// the builder.
+    pub fn remove_edge(self, left: Port<G>, right: Port<G>) -> Self {
+        let left = Owned::new(left, self.diff.clone());
+        let right = Owned::new(right, self.diff.clone());
+        self.remove_owned_edge(left, right)
+    }
+
+    /// Rewrite the edge between the ports `left` and `right`, owned by the
+    /// diff of the builder or any other diff.
+    pub fn remove_owned_edge(
+        mut self,
+        left: Owned<Port<G>, G, M>,
+        right: Owned<Port<G>, G, M>,
+    ) -> Self {
+        self.edges.push((left, right));
+        self
+    }
+
+    /// Set the replacement graph.
+    pub fn with_replacement(mut self, graph: G) -> Self {
+        self.replacement = graph;
+        self
+    }
+
+    /// Set the boundary map.
+    ///
+    /// The function is called once for every boundary port of the new diff,
+    /// in order, and must return its site in the replacement graph. See
+    /// [`PortDiff::rewrite`].
+    pub fn map_boundary(
+        mut self,
+        boundary_map: impl FnMut(BoundaryPortCtx<'_, G, M>) -> BoundarySite<G> + 'a,
+    ) -> Self {
+        self.boundary_map = Some(Box::new(boundary_map));
+        self
+    }
+
+    /// Set the value of the new diff, `M::default()` otherwise.
+    pub fn with_value(mut self, value: M) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    /// Set the key of the new diff, see [`PortDiff::rewrite_with_key`].
+    pub fn with_key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// List the issues of the rewrite so far, see
+    /// [`PortDiff::validate_rewrite`].
+    pub fn validate(&self) -> Vec<RewriteIssue<G, M>> {
+        PortDiff::validate_rewrite(self.nodes.iter().cloned(), self.edges.iter().cloned())
+    }
+
+    /// Create the new diff.
+    ///
+    /// Errors with [`InvalidRewriteError::MissingBoundaryMap`] if the new
+    /// diff has boundary ports but no boundary map was set.
+    pub fn build(self) -> Result<PortDiff<G, M>, InvalidRewriteError<G, M>>
+    where
+        M: Default,
+    {
+        let mut boundary_map = self.boundary_map;
+        let mut wires = WireAllocator::new();
+        let mut index = 0;
+        let mut missing_map = false;
+        let diff = PortDiff::build_rewrite(
+            self.nodes,
+            self.edges,
+            self.replacement,
+            |port| {
+                let mut label = None;
+                let ctx = BoundaryPortCtx {
+                    port,
+                    index: BoundaryIndex::from(index),
+                    wires: &mut wires,
+                    label: &mut label,
+                };
+                index += 1;
+                let site = match boundary_map.as_mut() {
+                    Some(boundary_map) => boundary_map(ctx),
+                    None => {
+                        // The diff is discarded below
+                        missing_map = true;
+                        BoundarySite::wire(index, EdgeEnd::Left)
+                    }
+                };
+                (site, label)
+            },
+            self.value.unwrap_or_default(),
+            self.key,
+            false,
+        );
+        if missing_map {
+            return Err(InvalidRewriteError::MissingBoundaryMap);
+        }
+        let diff = diff?;
+        if wires.n_wires() > 0 {
+            wires.check(diff.boundary.iter().map(|(site, _)| site))?;
+        }
+        Ok(diff)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{NodeIndex, PortGraph, PortMut};
+    use rstest::rstest;
+
+    use crate::{
+        port_diff::tests::{parent_child_diffs, TestPortDiff},
+        Site,
+    };
+
+    use super::*;
+
+    #[rstest]
+    fn test_rewrite_builder(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, _] = parent_child_diffs;
+        let n1 = NodeIndex::new(1);
+        let mut new_graph = PortGraph::new();
+        let new_node = new_graph.add_node(3, 3);
+
+        let builder = RewriteBuilder::new(&parent).remove_nodes([n1]);
+        assert!(builder.validate().is_empty());
+        let child = builder
+            .with_replacement(new_graph.clone())
+            .map_boundary(|mut ctx| {
+                ctx.set_label(format!("b{}", usize::from(ctx.index)));
+                let port = ctx.port.site().unwrap().port;
+                Site {
+                    node: new_node,
+                    port,
+                }
+                .into()
+            })
+            .with_key("child")
+            .build()
+            .unwrap();
+        let expected = PortDiff::rewrite([Owned::new(n1, parent.clone())], [], new_graph, |p| {
+            let port = p.site().unwrap().port;
+            Site {
+                node: new_node,
+                port,
+            }
+            .into()
+        })
+        .unwrap();
+        assert_eq!(child.n_boundary_ports(), expected.n_boundary_ports());
+        assert_eq!(child.key(), Some("child"));
+        let first = BoundaryIndex::from(0);
+        assert_eq!(child.boundary_label(first), Some("b0"));
+        assert_eq!(child.boundary_site(first), expected.boundary_site(first));
+
+        let err = RewriteBuilder::new(&parent)
+            .remove_nodes([n1])
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, InvalidRewriteError::MissingBoundaryMap));
+
+        let err = RewriteBuilder::new(&parent)
+            .remove_nodes([n1])
+            .map_boundary(|ctx| {
+                // Skip a wire for every boundary port
+                ctx.wires.fresh();
+                BoundarySite::wire(ctx.wires.fresh(), EdgeEnd::Left)
+            })
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, InvalidRewriteError::UnusedWire { id: 0 }));
+    }
+}
@@ -0,0 +1,281 @@
+//! Incremental construction of root diffs from streamed nodes and edges.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+
+use derive_where::derive_where;
+use thiserror::Error;
+
+use crate::{
+    port::{EdgeEnd, Site},
+    AddNodes, PortDiff,
+};
+
+/// Errors when reading a graph in the edge list format.
+///
+/// Lines are numbered from 1.
+#[derive(Error, Debug)]
+pub enum EdgeListError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// A line that is neither a node, an edge, a comment nor empty.
+    #[error("Line {0}: expected a node or an edge")]
+    InvalidLine(usize),
+    /// A node or port identifier that cannot be parsed.
+    #[error("Line {0}: invalid node or port")]
+    InvalidToken(usize),
+    /// An edge at a node that has not been declared yet.
+    #[error("Line {line}: unknown node {node}")]
+    UnknownNode { line: usize, node: u64 },
+    /// A node that is declared twice.
+    #[error("Line {line}: node {node} is declared twice")]
+    DuplicateNode { line: usize, node: u64 },
+    /// An edge at a port that is not declared, that is already linked or
+    /// that cannot be the end of the edge, e.g. an input at its left end.
+    #[error("Line {line}: invalid edge end at node {node}")]
+    InvalidEdgeEnd { line: usize, node: u64 },
+}
+
+/// A builder for root diffs, adding nodes and edges one at a time.
+///
+/// Nodes and edges are added to the graph as they are streamed in, without
+/// collecting them first, so that the peak memory is that of the final
+/// graph. Use [`RootBuilder::read_edge_list`] to stream them from a reader.
+#[derive_where(Default; G: AddNodes)]
+pub struct RootBuilder<G: AddNodes> {
+    graph: G,
+}
+
+impl<G: AddNodes> RootBuilder<G> {
+    /// Create a builder for an empty graph.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a node with the sites labelled by `sites`.
+    ///
+    /// See [`AddNodes::add_node_with_sites`].
+    pub fn add_node(&mut self, sites: impl IntoIterator<Item = G::PortLabel>) -> G::Node {
+        self.graph.add_node_with_sites(sites)
+    }
+
+    /// Add an edge from the `left` site to the `right` site.
+    pub fn add_edge(
+        &mut self,
+        left: Site<G::Node, G::PortLabel>,
+        right: Site<G::Node, G::PortLabel>,
+    ) {
+        self.graph.link_sites(left, right);
+    }
+
+    /// Add all edges of `edges`, consuming the iterator lazily.
+    pub fn extend_edges(
+        &mut self,
+        edges: impl IntoIterator<Item = (Site<G::Node, G::PortLabel>, Site<G::Node, G::PortLabel>)>,
+    ) {
+        for (left, right) in edges {
+            self.add_edge(left, right);
+        }
+    }
+
+    /// Add the nodes and edges read from `reader`, in the edge list format.
+    ///
+    /// Every line is either empty, a comment starting with `#`, a node or an
+    /// edge:
+    ///
+    /// ```text
+    /// # node <id> <port>...
+    /// node 0 out0
+    /// node 1 in0
+    /// # edge <left id> <left port> <right id> <right port>
+    /// edge 0 out0 1 in0
+    /// ```
+    ///
+    /// Node identifiers are unsigned integers, local to the reader. Nodes
+    /// must be declared before their edges, along with all their ports. Ports
+    /// are parsed with [`FromStr`]. Every port is the end of at most one edge,
+    /// and must be a valid end for the graph, see
+    /// [`crate::Graph::is_valid_end`].
+    ///
+    /// Returns the map from node identifiers to the nodes of the graph. Lines
+    /// are processed one at a time, so that only this map is kept in memory
+    /// besides the graph.
+    pub fn read_edge_list(
+        &mut self,
+        reader: impl BufRead,
+    ) -> Result<BTreeMap<u64, G::Node>, EdgeListError>
+    where
+        G::PortLabel: FromStr,
+    {
+        let mut nodes = BTreeMap::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line_nb = i + 1;
+            let line = line?;
+            let mut tokens = line.split_whitespace();
+            let parse_node = |token: Option<&str>| {
+                token
+                    .and_then(|t| t.parse::<u64>().ok())
+                    .ok_or(EdgeListError::InvalidToken(line_nb))
+            };
+            let parse_port = |token: Option<&str>| {
+                token
+                    .and_then(|t| t.parse::<G::PortLabel>().ok())
+                    .ok_or(EdgeListError::InvalidToken(line_nb))
+            };
+            match tokens.next() {
+                None => {}
+                Some(t) if t.starts_with('#') => {}
+                Some("node") => {
+                    let id = parse_node(tokens.next())?;
+                    let ports = tokens
+                        .map(|t| parse_port(Some(t)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if nodes.contains_key(&id) {
+                        return Err(EdgeListError::DuplicateNode {
+                            line: line_nb,
+                            node: id,
+                        });
+                    }
+                    nodes.insert(id, self.add_node(ports));
+                }
+                Some("edge") => {
+                    let graph = &self.graph;
+                    let mut site = |end| {
+                        let id = parse_node(tokens.next())?;
+                        let port = parse_port(tokens.next())?;
+                        let &node = nodes.get(&id).ok_or(EdgeListError::UnknownNode {
+                            line: line_nb,
+                            node: id,
+                        })?;
+                        let site = Site { node, port };
+                        let is_valid = graph.get_sites(node).any(|s| s == site)
+                            && graph.get_bound_ports(site.clone()).next().is_none()
+                            && graph.is_valid_end(site.clone(), end);
+                        if !is_valid {
+                            return Err(EdgeListError::InvalidEdgeEnd {
+                                line: line_nb,
+                                node: id,
+                            });
+                        }
+                        Ok(site)
+                    };
+                    let left = site(EdgeEnd::Left)?;
+                    let right = site(EdgeEnd::Right)?;
+                    if tokens.next().is_some() {
+                        return Err(EdgeListError::InvalidLine(line_nb));
+                    }
+                    self.add_edge(left, right);
+                }
+                Some(_) => return Err(EdgeListError::InvalidLine(line_nb)),
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// The graph built so far.
+    pub fn graph(&self) -> &G {
+        &self.graph
+    }
+
+    /// Create the root diff of the graph.
+    pub fn finish(self) -> PortDiff<G> {
+        PortDiff::from_graph(self.graph)
+    }
+
+    /// Create the root diff of the graph, with the given value.
+    pub fn finish_with_value<M: Clone>(self, value: M) -> PortDiff<G, M> {
+        PortDiff::from_graph_with_value(self.graph, value)
+    }
+}
+
+impl<G: AddNodes> PortDiff<G>
+where
+    G::PortLabel: FromStr,
+{
+    /// Create a root diff from a graph in the edge list format.
+    ///
+    /// See [`RootBuilder::read_edge_list`] for the format.
+    pub fn from_edge_list(reader: impl BufRead) -> Result<Self, EdgeListError> {
+        let mut builder = RootBuilder::new();
+        builder.read_edge_list(reader)?;
+        Ok(builder.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        simple_graph::{SimpleGraph, SimplePort},
+        Graph,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_read_edge_list() {
+        let input = "\
+            # a chain of three nodes\n\
+            node 10 in0 out0\n\
+            node 20 in0 out0\n\
+            node 30 in0 out0\n\
+            \n\
+            edge 10 out0 20 in0\n\
+            edge 20 out0 30 in0\n";
+        let root = PortDiff::<SimpleGraph>::from_edge_list(input.as_bytes()).unwrap();
+        assert_eq!(root.graph(), &SimpleGraph::linear_chain(3));
+
+        let mut builder = RootBuilder::<SimpleGraph>::new();
+        let nodes = builder.read_edge_list("node 1 out0\n".as_bytes()).unwrap();
+        let n = builder.add_node([SimplePort::In(0)]);
+        builder.add_edge(
+            Site {
+                node: nodes[&1],
+                port: SimplePort::Out(0),
+            },
+            Site {
+                node: n,
+                port: SimplePort::In(0),
+            },
+        );
+        assert_eq!(builder.finish().graph().edges_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_read_edge_list_errors() {
+        let read = |input: &str| PortDiff::<SimpleGraph>::from_edge_list(input.as_bytes());
+        assert!(matches!(
+            read("node 0 out0\nedge 0 out0 1 in0"),
+            Err(EdgeListError::UnknownNode { line: 2, node: 1 })
+        ));
+        assert!(matches!(
+            read("node 0\nnode 0"),
+            Err(EdgeListError::DuplicateNode { line: 2, node: 0 })
+        ));
+        assert!(matches!(
+            read("node 0 up0"),
+            Err(EdgeListError::InvalidToken(1))
+        ));
+        assert!(matches!(
+            read("vertex 0"),
+            Err(EdgeListError::InvalidLine(1))
+        ));
+
+        let nodes = "node 0 in0 out0\nnode 1 in0 out0\n";
+        // Input at the left end of an edge
+        assert!(matches!(
+            read(&format!("{nodes}edge 0 in0 1 out0")),
+            Err(EdgeListError::InvalidEdgeEnd { line: 3, node: 0 })
+        ));
+        // Undeclared port
+        assert!(matches!(
+            read(&format!("{nodes}edge 0 out1 1 in0")),
+            Err(EdgeListError::InvalidEdgeEnd { line: 3, node: 0 })
+        ));
+        // Port that is already linked
+        assert!(matches!(
+            read(&format!("{nodes}edge 0 out0 1 in0\nedge 1 out0 1 in0")),
+            Err(EdgeListError::InvalidEdgeEnd { line: 4, node: 1 })
+        ));
+    }
+}
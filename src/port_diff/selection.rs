@@ -0,0 +1,118 @@
+//! Automatic selection of subgraphs with a small boundary.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{port::EdgeEnd, Graph, PortDiff};
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Grow `seed_nodes` into a set of nodes of `self` with at most
+    /// `max_boundary` boundary ports.
+    ///
+    /// The boundary ports of a set of nodes are the boundary ports of the diff
+    /// that rewrites the subgraph induced by the nodes, see
+    /// [`Self::rewrite_induced`]: the ports of edges with a single end in the
+    /// set, along with the boundary ports of `self` at the nodes in the set.
+    ///
+    /// The set is grown greedily: at every step, the neighbouring node that
+    /// results in the smallest boundary is added, with ties broken by the
+    /// node order. Stops as soon as the boundary is small enough. If it never
+    /// is, the smallest set with the smallest boundary found is returned.
+    pub fn grow_selection(
+        &self,
+        seed_nodes: impl IntoIterator<Item = G::Node>,
+        max_boundary: usize,
+    ) -> BTreeSet<G::Node> {
+        let graph = self.graph();
+        let mut n_boundary_ports = BTreeMap::<G::Node, usize>::new();
+        for b in self.boundary_iter() {
+            if let Ok(site) = self.boundary_site(b).try_into_site() {
+                *n_boundary_ports.entry(site.node).or_default() += 1;
+            }
+        }
+
+        // The change in boundary size when adding `node` to `selection`
+        let delta = |selection: &BTreeSet<G::Node>, node: G::Node| {
+            let mut delta = n_boundary_ports.get(&node).copied().unwrap_or(0) as isize;
+            for site in graph.get_sites(node) {
+                for port in graph.get_bound_ports(site) {
+                    let other = graph.incident_node(port.edge, port.end.opposite());
+                    if selection.contains(&other) {
+                        // The edge becomes internal
+                        delta -= 1;
+                    } else if other != node {
+                        delta += 1;
+                    }
+                }
+            }
+            delta
+        };
+
+        let mut selection = BTreeSet::new();
+        let mut size = 0;
+        for node in seed_nodes {
+            if !selection.contains(&node) {
+                size += delta(&selection, node);
+                selection.insert(node);
+            }
+        }
+        let mut best = (size, selection.clone());
+        while size > max_boundary as isize {
+            let neighbours: BTreeSet<_> = selection
+                .iter()
+                .flat_map(|&n| graph.get_sites(n))
+                .flat_map(|site| graph.get_bound_ports(site))
+                .map(|port| graph.incident_node(port.edge, port.end.opposite()))
+                .filter(|n| !selection.contains(n))
+                .collect();
+            let Some((node_delta, node)) = neighbours
+                .into_iter()
+                .map(|n| (delta(&selection, n), n))
+                .min()
+            else {
+                break;
+            };
+            size += node_delta;
+            selection.insert(node);
+            if size < best.0 {
+                best = (size, selection.clone());
+            }
+        }
+        if size <= max_boundary as isize {
+            selection
+        } else {
+            best.1
+        }
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::NodeIndex;
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_grow_selection(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let [n0, n1, n2, n3] = [0, 1, 2, 3].map(NodeIndex::new);
+
+        //  a --
+        //  a -- b -- c -- d
+        //  a --
+        assert_eq!(parent.grow_selection([n1], 4), BTreeSet::from([n1]));
+        assert_eq!(parent.grow_selection([n1], 1), BTreeSet::from([n0, n1]));
+        assert_eq!(
+            parent.grow_selection([n1], 0),
+            BTreeSet::from([n0, n1, n2, n3])
+        );
+
+        // The boundary ports of the child count towards the boundary
+        let (b, c) = (NodeIndex::new(0), NodeIndex::new(1));
+        assert_eq!(child.grow_selection([b], 0), BTreeSet::from([b]));
+        assert_eq!(child.grow_selection([b, c], 5), BTreeSet::from([b, c]));
+    }
+}
@@ -1,30 +1,208 @@
 use std::collections::{BTreeMap, BTreeSet};
 
+use derive_where::derive_where;
 use itertools::Itertools;
 use petgraph::visit::{EdgeRef, IntoEdges};
+use thiserror::Error;
 use union_find::{QuickUnionUf, UnionBySize, UnionFind};
 
 use crate::{
     port::{BoundPort, BoundaryIndex, EdgeEnd, Port, Site},
+    trace::{debug_event, debug_span},
     Graph, NodeId, PortDiff, PortDiffGraph,
 };
 
 use super::{BoundarySite, EdgeData, IncomingEdgeIndex, IncompatiblePortDiff, Owned, PortDiffData};
 
-impl<G: Graph> PortDiff<G> {
+/// Error when squashing diffs.
+///
+/// Squashing fails if the diffs are not compatible, or if their data is
+/// inconsistent, e.g. after deserializing malformed input.
+#[derive(Debug, Error)]
+pub enum SquashError {
+    /// The diffs are not compatible.
+    #[error("incompatible diffs")]
+    IncompatibleDiffs,
+    /// A node is rewritten by more than one diff.
+    #[error("a node is rewritten by more than one diff")]
+    OverlappingRewrites,
+    /// A rewritten port is neither mapped to a boundary port of a child nor
+    /// at a node that is kept.
+    #[error("a parent port was neither a boundary port nor a non-rewritten port in child")]
+    UnresolvedPort,
+    /// A wire is linked to a site at the wrong end.
+    #[error("wire {wire} is linked at the wrong end")]
+    InvalidWireEnd { wire: usize },
+    /// An end of a wire is linked to more than one site.
+    #[error("more than one value for the same end of wire {wire}")]
+    DuplicateWireEnd { wire: usize },
+    /// A wire is linked both to a boundary port and to an internal edge at
+    /// the same end.
+    #[error("found both a boundary and an internal edge at the same end of wire {wire}")]
+    WireBoundaryConflict { wire: usize },
+    /// A boundary port has a different type than its parent port, see
+    /// [`crate::PortDiffGraph::try_squash_typed`].
+    #[error("a boundary port has a different type than its parent port")]
+    PortTypeMismatch,
+}
+
+impl From<IncompatiblePortDiff> for SquashError {
+    fn from(_: IncompatiblePortDiff) -> Self {
+        SquashError::IncompatibleDiffs
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
     /// Squash all diffs in `graph` into a single equivalent diff.
     ///
     /// The incoming edges of the new diff is the union of the incoming edges into
     /// `graph`. The new diff has no outgoing edges.
     ///
-    /// Note: this will panic if the diffs in `graph` are not compatible (the
-    /// public-facing [Self::extract_graph] will check for compatibility first).
-    pub(crate) fn squash(graph: &PortDiffGraph<G>) -> Self {
-        let mut builder = Builder::new();
+    /// Errors if the diffs in `graph` are not compatible or inconsistent
+    /// (the public-facing [Self::extract_graph] will check for compatibility
+    /// first).
+    ///
+    /// The value of the new diff is `M::default()`.
+    pub(crate) fn squash(graph: &PortDiffGraph<G, M>) -> Result<Self, SquashError>
+    where
+        M: Default,
+    {
+        Self::squash_with(graph, |_| M::default())
+    }
+
+    /// Squash all diffs in `graph` into a single equivalent diff, combining
+    /// their values with `aggregate`.
+    ///
+    /// `aggregate` is passed the values of all diffs in `graph` and returns
+    /// the value of the squashed diff.
+    ///
+    /// Errors if the diffs in `graph` are not compatible or inconsistent.
+    pub(crate) fn squash_with(
+        graph: &PortDiffGraph<G, M>,
+        aggregate: impl FnOnce(&[M]) -> M,
+    ) -> Result<Self, SquashError> {
+        let values = graph
+            .all_nodes()
+            .map(|n| graph.get_diff(n).value().clone())
+            .collect_vec();
+        Self::squash_builder(graph)?.finish(aggregate(&values))
+    }
+
+    /// Squash all diffs in `graph` into a single equivalent diff, along with
+    /// a map from the nodes of every diff in `graph` to the nodes of the new
+    /// diff.
+    ///
+    /// Errors if the diffs in `graph` are not compatible or inconsistent.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn squash_with_map(
+        graph: &PortDiffGraph<G, M>,
+    ) -> Result<(Self, BTreeMap<NodeId<G, M>, BTreeMap<G::Node, G::Node>>), SquashError>
+    where
+        M: Default,
+    {
+        let mut builder = Self::squash_builder(graph)?;
+        let nodes_map = std::mem::take(&mut builder.nodes_map);
+        Ok((builder.finish(M::default())?, nodes_map))
+    }
+
+    /// Squash all diffs in `graph` and return the resulting graph.
+    ///
+    /// Errors if the diffs in `graph` are not compatible or inconsistent.
+    pub(crate) fn squash_graph(graph: &PortDiffGraph<G, M>) -> Result<G, SquashError> {
+        Ok(Self::squash_builder(graph)?.graph)
+    }
+
+    /// Squash all diffs in `graph` and return the resulting graph, reusing
+    /// the parts of the diffs memoized in `memo` by previous squashes.
+    ///
+    /// `graph` must contain all ancestors of its diffs. Only the parts of the
+    /// diffs that are new or whose children changed are computed, see
+    /// [`SquashMemo`].
+    ///
+    /// Errors if the diffs in `graph` are not compatible or inconsistent.
+    pub(crate) fn squash_graph_memoized(
+        graph: &PortDiffGraph<G, M>,
+        memo: &mut SquashMemo<G, M>,
+    ) -> Result<G, SquashError> {
+        let mut builder = Builder::new(G::default());
+        Self::build_squashed_memoized(&mut builder, graph, memo)?;
+        Ok(builder.graph)
+    }
+
+    /// Squash all diffs in `graph` and return the resulting graph, along with
+    /// a map from the nodes of every diff in `graph` to the nodes of the new
+    /// graph.
+    ///
+    /// Errors if the diffs in `graph` are not compatible or inconsistent.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn squash_graph_with_map(
+        graph: &PortDiffGraph<G, M>,
+    ) -> Result<(G, BTreeMap<NodeId<G, M>, BTreeMap<G::Node, G::Node>>), SquashError> {
+        let builder = Self::squash_builder(graph)?;
+        Ok((builder.graph, builder.nodes_map))
+    }
+
+    /// Squash all diffs in `graph` and return the resulting graph, along with
+    /// a map from the nodes of every diff in `graph` to the nodes of the new
+    /// graph and the edges that were added between boundary sites.
+    ///
+    /// Errors if the diffs in `graph` are not compatible or inconsistent.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn squash_graph_with_links(
+        graph: &PortDiffGraph<G, M>,
+    ) -> Result<
+        (
+            G,
+            BTreeMap<NodeId<G, M>, BTreeMap<G::Node, G::Node>>,
+            Vec<BoundaryLink<G, M>>,
+        ),
+        SquashError,
+    > {
+        let builder = Self::squash_builder(graph)?;
+        Ok((builder.graph, builder.nodes_map, builder.boundary_links))
+    }
+
+    /// Squash all diffs in `graph` into `target` and return a map from the
+    /// nodes of every diff in `graph` to the nodes of `target`.
+    ///
+    /// The existing nodes and edges of `target` are kept. On error, `target`
+    /// may contain part of the squashed graph.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn squash_graph_into(
+        graph: &PortDiffGraph<G, M>,
+        target: &mut G,
+    ) -> Result<BTreeMap<NodeId<G, M>, BTreeMap<G::Node, G::Node>>, SquashError> {
+        // Move the target into the builder, so that it is never copied
+        let mut builder = Builder::new(std::mem::take(target));
+        let result = Self::build_squashed(&mut builder, graph);
+        *target = builder.graph;
+        result.map(|()| builder.nodes_map)
+    }
+
+    fn squash_builder(graph: &PortDiffGraph<G, M>) -> Result<Builder<G, M>, SquashError> {
+        let mut builder = Builder::new(G::default());
+        Self::build_squashed(&mut builder, graph)?;
+        Ok(builder)
+    }
+
+    fn build_squashed(
+        builder: &mut Builder<G, M>,
+        graph: &PortDiffGraph<G, M>,
+    ) -> Result<(), SquashError> {
+        Self::build_squashed_memoized(builder, graph, &mut SquashMemo::default())
+    }
+
+    fn build_squashed_memoized(
+        builder: &mut Builder<G, M>,
+        graph: &PortDiffGraph<G, M>,
+        memo: &mut SquashMemo<G, M>,
+    ) -> Result<(), SquashError> {
+        debug_span!("squash", n_diffs = graph.all_nodes().count());
+        memo.update(graph)?;
 
         // For each diff in `graph`, add the subgraph of the replacement graph
         // minus the nodes removed by other diffs in `graph`.
-        builder.add_subgraphs(graph);
+        builder.add_subgraphs(graph, memo);
 
         builder.flatten_incoming_edges(graph);
 
@@ -34,51 +212,159 @@ impl<G: Graph> PortDiff<G> {
         //  - if so, then store the mapping to the resolved port (we will add an
         //    edge in the next step)
         //  - otherwise, add to new boundary.
-        let mut resolved_ports_map = BTreeMap::new();
+        let resolved_ports_map = {
+            debug_span!("resolve_boundary");
+            let mut resolved_ports_map = BTreeMap::new();
 
+            // Position of every diff in `graph`, to order ports independently
+            // of memory addresses
+            let rank: BTreeMap<_, _> = graph.all_nodes().enumerate().map(|(i, n)| (n, i)).collect();
+            let mut new_wire_id = 0; // Give each wire a unique id
+            for diff_id in graph.all_nodes() {
+                let diff = graph.get_diff(diff_id);
+                let mut wire_map = BTreeMap::new(); // Map wire ids in diff to new wires
+                for (bd_index, old_site, resolved) in &memo.parts[&diff].boundary {
+                    let bd_index = *bd_index;
+                    let new_site = match old_site.clone().try_into_site() {
+                        Ok(site) => {
+                            let Some(site) = builder.map_site(Owned::new(site, diff.clone()))
+                            else {
+                                // Site is outside of the rewritten region.
+                                continue;
+                            };
+                            site.into()
+                        }
+                        Err(BoundarySite::Wire { id, end }) => {
+                            // Map wire ID (diff local) to a new wire ID (graph-wide unique).
+                            let id = *wire_map.entry(id).or_insert_with(|| {
+                                let id = new_wire_id;
+                                new_wire_id += 1;
+                                id
+                            });
+                            BoundarySite::Wire { id, end }
+                        }
+                        Err(_) => unreachable!(),
+                    };
+
+                    let boundary = Owned::new(bd_index, diff.clone());
+                    match resolved.clone() {
+                        Ok(bound_port) => {
+                            let key = (rank[&(&bound_port.owner).into()], bound_port.data);
+                            resolved_ports_map.insert(key, (bound_port, new_site, boundary));
+                        }
+                        Err(ancestor) => {
+                            // Prefer the label closest to the squashed diff
+                            let label = [&boundary, &ancestor]
+                                .into_iter()
+                                .find_map(|b| b.owner.boundary_label(b.data))
+                                .map(str::to_string);
+                            builder.append_boundary(new_site, ancestor, label);
+                        }
+                    }
+                }
+            }
+            debug_event!(
+                n_resolved = resolved_ports_map.len(),
+                n_boundary_ports = builder.boundary.len(),
+                "resolved boundary ports"
+            );
+            resolved_ports_map
+        };
+
+        builder.add_boundary_edges(resolved_ports_map)?;
+
+        Ok(())
+    }
+}
+
+/// The port that a boundary port resolves to, see [`try_resolve_port`].
+type ResolvedPort<G, M> =
+    Result<Owned<BoundPort<<G as Graph>::Edge>, G, M>, Owned<BoundaryIndex, G, M>>;
+
+/// The parts of the diffs of a squashed graph, memoized across squashes.
+///
+/// The part of a diff is the subgraph of its replacement graph that is not
+/// rewritten by its children, along with the boundary ports at that
+/// subgraph and the ports they resolve to. It only depends on the children
+/// of the diff, as long as the squashed graphs contain all ancestors of
+/// their diffs. Parts are recomputed when the children of their diff change,
+/// and dropped when their diff is no longer in the squashed graph.
+#[derive_where(Default; G: Graph)]
+pub(crate) struct SquashMemo<G: Graph, M> {
+    pub(super) parts: BTreeMap<PortDiff<G, M>, SquashPart<G, M>>,
+}
+
+/// The part of a diff that is kept when squashing, see [`SquashMemo`].
+pub(super) struct SquashPart<G: Graph, M> {
+    /// The children of the diff within the squashed graph, sorted.
+    children: Vec<PortDiff<G, M>>,
+    /// The nodes of the diff that are not rewritten by its children.
+    pub(super) nodes: BTreeSet<G::Node>,
+    /// The boundary ports of the diff at `nodes` or on wires, along with
+    /// their site and the port they resolve to. Bundles are expanded into
+    /// one entry per port.
+    boundary: Vec<(BoundaryIndex, BoundarySite<G>, ResolvedPort<G, M>)>,
+}
+
+impl<G: Graph, M: Clone> SquashMemo<G, M> {
+    /// Compute the parts of the diffs of `graph` that are new or whose
+    /// children changed, and drop the parts of diffs that are not in `graph`.
+    fn update(&mut self, graph: &PortDiffGraph<G, M>) -> Result<(), SquashError> {
+        self.retain(graph);
         let all_nodes = graph.all_nodes().collect::<BTreeSet<_>>();
-        let mut new_wire_id = 0; // Give each wire a unique id
-        for &diff_id in &all_nodes {
+        for diff_id in graph.all_nodes() {
             let diff = graph.get_diff(diff_id);
-            let mut wire_map = BTreeMap::new(); // Map wire ids in diff to new wires
-            for bd_index in diff.boundary_iter() {
-                let old_site = diff.boundary_site(bd_index);
-                let new_site = match old_site.clone().try_into_site() {
-                    Ok(site) => {
-                        let Some(site) = builder.map_site(Owned::new(site, diff.clone())) else {
-                            // Site is outside of the rewritten region.
-                            continue;
-                        };
-                        site.into()
-                    }
-                    Err(BoundarySite::Wire { id, end }) => {
-                        // Map wire ID (diff local) to a new wire ID (graph-wide unique).
-                        let id = *wire_map.entry(id).or_insert_with(|| {
-                            let id = new_wire_id;
-                            new_wire_id += 1;
-                            id
-                        });
-                        BoundarySite::Wire { id, end }
-                    }
-                    Err(_) => unreachable!(),
-                };
+            let children = graph
+                .inner()
+                .edges(diff_id.into())
+                .map(|e| graph.get_diff(NodeId::from(e.target())))
+                .sorted()
+                .collect_vec();
+            if self
+                .parts
+                .get(&diff)
+                .is_some_and(|part| part.children == children)
+            {
+                continue;
+            }
 
-                match try_resolve_port(Owned::new(bd_index, diff.clone()), &all_nodes) {
-                    Ok(bound_port) => {
-                        resolved_ports_map.insert(bound_port, new_site);
-                    }
-                    Err(boundary) => {
-                        builder.append_boundary(new_site, boundary);
+            let mut nodes = diff.graph.nodes_iter().collect::<BTreeSet<_>>();
+            for edge in graph.inner().edges(diff_id.into()) {
+                for n in edge.weight().subgraph.nodes() {
+                    if !nodes.remove(&n) {
+                        debug_event!(diff = ?diff_id, "node rewritten by more than one diff");
+                        return Err(SquashError::OverlappingRewrites);
                     }
                 }
             }
+            let boundary = diff
+                .boundary
+                .iter()
+                .enumerate()
+                .filter(|(_, (site, _))| match site.try_as_site_ref() {
+                    Some(site) => nodes.contains(&site.node),
+                    None => true,
+                })
+                .map(|(b, (site, _))| {
+                    let b = BoundaryIndex::from(b);
+                    let resolved = try_resolve_port(Owned::new(b, diff.clone()), &all_nodes);
+                    (b, site, resolved)
+                })
+                .collect();
+            let part = SquashPart {
+                children,
+                nodes,
+                boundary,
+            };
+            self.parts.insert(diff, part);
         }
+        Ok(())
+    }
 
-        builder.add_boundary_edges(resolved_ports_map);
-
-        builder
-            .finish()
-            .expect("found incompatible diffs in GraphView")
+    /// Drop the parts of the diffs that are not in `graph`.
+    pub(crate) fn retain(&mut self, graph: &PortDiffGraph<G, M>) {
+        self.parts
+            .retain(|diff, _| graph.contains(NodeId::from(diff)));
     }
 }
 
@@ -86,10 +372,10 @@ impl<G: Graph> PortDiff<G> {
 ///
 /// If a bound port could not be found, return the last boundary port that
 /// is still in `all_nodes`, i.e. it's parent is not in `all_nodes`.
-fn try_resolve_port<G: Graph>(
-    mut boundary: Owned<BoundaryIndex, G>,
-    all_nodes: &BTreeSet<NodeId<G>>,
-) -> Result<Owned<BoundPort<G::Edge>, G>, Owned<BoundaryIndex, G>> {
+fn try_resolve_port<G: Graph, M: Clone>(
+    mut boundary: Owned<BoundaryIndex, G, M>,
+    all_nodes: &BTreeSet<NodeId<G, M>>,
+) -> Result<Owned<BoundPort<G::Edge>, G, M>, Owned<BoundaryIndex, G, M>> {
     let mut port = boundary.owner.parent_port(boundary.data);
     while all_nodes.contains(&(&port.owner).into()) {
         match port.data {
@@ -111,60 +397,78 @@ fn try_resolve_port<G: Graph>(
     Err(boundary)
 }
 
-struct Builder<G: Graph> {
+/// An edge added between two boundary sites when squashing.
+pub(crate) struct BoundaryLink<G: Graph, M> {
+    /// The site at the left end of the edge.
+    pub(crate) left: Site<G::Node, G::PortLabel>,
+    /// The site at the right end of the edge.
+    pub(crate) right: Site<G::Node, G::PortLabel>,
+    /// The boundary ports that were joined to create the edge.
+    pub(crate) boundaries: Vec<Owned<BoundaryIndex, G, M>>,
+}
+
+struct Builder<G: Graph, M> {
     /// The new boundary
     boundary: Vec<(BoundarySite<G>, IncomingEdgeIndex)>,
+    /// The labels of the new boundary
+    boundary_labels: BTreeMap<BoundaryIndex, String>,
     /// The new incoming edges and their parent
-    incoming_edges: Vec<(PortDiff<G>, EdgeData<G>)>,
+    incoming_edges: Vec<(PortDiff<G, M>, EdgeData<G>)>,
     /// For each parent, a map from the old edge index to the new edge index
-    edge_index_map: BTreeMap<NodeId<G>, BTreeMap<IncomingEdgeIndex, IncomingEdgeIndex>>,
+    edge_index_map: BTreeMap<NodeId<G, M>, BTreeMap<IncomingEdgeIndex, IncomingEdgeIndex>>,
     /// For each parent, a map from the old node to the new node
-    nodes_map: BTreeMap<NodeId<G>, BTreeMap<G::Node, G::Node>>,
+    nodes_map: BTreeMap<NodeId<G, M>, BTreeMap<G::Node, G::Node>>,
+    /// The edges added between boundary sites, along with the boundary
+    /// ports they were created from
+    boundary_links: Vec<BoundaryLink<G, M>>,
     /// The new replacement graph
     graph: G,
 }
 
-impl<G: Graph> Builder<G> {
-    fn new() -> Self {
+impl<G: Graph, M: Clone> Builder<G, M> {
+    /// A builder adding the squashed graph to `graph`.
+    fn new(graph: G) -> Self {
         Self {
             boundary: vec![],
+            boundary_labels: BTreeMap::new(),
             incoming_edges: vec![],
             edge_index_map: BTreeMap::new(),
             nodes_map: BTreeMap::new(),
-            graph: G::default(),
+            boundary_links: vec![],
+            graph,
         }
     }
 
     /// Add the subgraphs of the replacement graphs that are not rewritten within `graph`.
     ///
-    /// For each node in `graph`, store a map from nodes in the old graph to nodes
-    /// in the new graph.
-    fn add_subgraphs(&mut self, graph: &PortDiffGraph<G>) {
+    /// The subgraphs are the memoized parts of the diffs. For each node in
+    /// `graph`, store a map from nodes in the old graph to nodes in the new
+    /// graph.
+    fn add_subgraphs(&mut self, graph: &PortDiffGraph<G, M>, memo: &SquashMemo<G, M>) {
+        debug_span!("add_subgraphs");
         for diff_id in graph.all_nodes() {
             let diff = graph.get_diff(diff_id);
-            let mut nodes = diff.graph.nodes_iter().collect::<BTreeSet<_>>();
-            for edge in graph.inner().edges(diff_id.into()) {
-                for n in edge.weight().subgraph.nodes() {
-                    if !nodes.remove(&n) {
-                        panic!("found incompatible diffs in GraphView");
-                    }
-                }
-            }
-            let nodes_map = self.graph.add_subgraph(&diff.graph, &nodes);
+            let nodes = &memo.parts[&diff].nodes;
+            let nodes_map = self.graph.add_subgraph(&diff.graph, nodes);
             self.nodes_map.insert(diff_id, nodes_map);
         }
+        debug_event!(
+            n_nodes = self.nodes_map.values().map(BTreeMap::len).sum::<usize>(),
+            "added subgraphs"
+        );
     }
 
     /// Collect all incoming edges into `graph` and flatten into a single list of edges.
     ///
     /// Store a map from the old edge indices to the new edge indices.
-    fn flatten_incoming_edges(&mut self, graph: &PortDiffGraph<G>) {
+    fn flatten_incoming_edges(&mut self, graph: &PortDiffGraph<G, M>) {
+        debug_span!("flatten_incoming_edges");
         let all_nodes = graph.all_nodes().collect::<BTreeSet<_>>();
-        for &diff_id in &all_nodes {
+        for diff_id in graph.all_nodes() {
             let mut edge_index_map = BTreeMap::new();
             let diff = graph.get_diff(diff_id);
             for (index, edge) in diff.all_incoming().iter().enumerate() {
-                let edge_source: PortDiff<G> = edge.source().clone().into();
+                let edge_source: PortDiff<G, M> = edge.source().clone().into();
                 if all_nodes.contains(&(&edge_source).into()) {
                     // internal edge
                     continue;
@@ -181,10 +485,19 @@ impl<G: Graph> Builder<G> {
             }
             self.edge_index_map.insert(diff_id, edge_index_map);
         }
+        debug_event!(
+            n_incoming_edges = self.incoming_edges.len(),
+            "flattened incoming edges"
+        );
     }
 
     /// Add a new boundary site at `site`, linked to the same parent port as `port`.
-    fn append_boundary(&mut self, boundary: BoundarySite<G>, port: Owned<BoundaryIndex, G>) {
+    fn append_boundary(
+        &mut self,
+        boundary: BoundarySite<G>,
+        port: Owned<BoundaryIndex, G, M>,
+        label: Option<String>,
+    ) {
         let Owned { data: port, owner } = port;
         let edge_index = owner.incoming_edge_index(port).unwrap();
         let new_edge_index = self.edge_index_map[&(&owner).into()][&edge_index];
@@ -194,6 +507,8 @@ impl<G: Graph> Builder<G> {
 
         // Link the new boundary port to the parent port
         let new_index = self.boundary.len() - 1;
+        self.boundary_labels
+            .extend(label.map(|l| (BoundaryIndex::from(new_index), l)));
         let parent_port = owner.parent_port(port).data;
         let (_, edge_data) = &mut self.incoming_edges[new_edge_index.0];
         edge_data.port_map.insert(parent_port, new_index.into());
@@ -201,7 +516,7 @@ impl<G: Graph> Builder<G> {
 
     fn map_site(
         &self,
-        site: Owned<Site<G::Node, G::PortLabel>, G>,
+        site: Owned<Site<G::Node, G::PortLabel>, G, M>,
     ) -> Option<Site<G::Node, G::PortLabel>> {
         let Owned { data: site, owner } = site;
         site.filter_map_node(|n| self.nodes_map.get(&(&owner).into())?.get(&n).copied())
@@ -209,36 +524,57 @@ impl<G: Graph> Builder<G> {
 
     /// Given a map from parent ports to boundary ports, find all boundary edges
     /// that need to be added.
+    ///
+    /// The parent ports are keyed by the position of their owner in the
+    /// squashed graph, so that edges are added in a deterministic order. Every
+    /// boundary site is given along with the boundary port it was created
+    /// from.
+    #[allow(clippy::type_complexity)]
     fn add_boundary_edges(
         &mut self,
-        mut port_map: BTreeMap<Owned<BoundPort<G::Edge>, G>, BoundarySite<G>>,
-    ) {
+        mut port_map: BTreeMap<
+            (usize, BoundPort<G::Edge>),
+            (
+                Owned<BoundPort<G::Edge>, G, M>,
+                BoundarySite<G>,
+                Owned<BoundaryIndex, G, M>,
+            ),
+        >,
+    ) -> Result<(), SquashError> {
+        debug_span!("add_boundary_edges", n_ports = port_map.len());
         // Find the maximum wire ID so we can initialize the UnionFind with the
         // correct capacity.
         let max_wire_id = port_map
             .values()
-            .filter_map(|v| match *v {
+            .filter_map(|(_, v, _)| match *v {
                 BoundarySite::Site(..) => None,
                 BoundarySite::Wire { id, .. } => Some(id),
             })
             .max()
             .unwrap_or_default();
         let mut wires_uf = QuickUnionUf::<UnionBySize>::new(max_wire_id + 1);
-        // Store for each wire its left/right ends (if they exist).
-        let mut wires_opp_ends: Vec<[Option<Site<_, _>>; 2]> = vec![[None, None]; max_wire_id + 1];
+        // Store for each wire its left/right ends (if they exist), along with
+        // the edges they were created from.
+        let mut wires_opp_ends: Vec<[Option<(Site<_, _>, Owned<BoundPort<_>, _, _>)>; 2]> =
+            vec![[None, None]; max_wire_id + 1];
+        // Store for each wire the boundary ports it was created from.
+        let mut wires_boundaries = vec![Vec::new(); max_wire_id + 1];
 
-        while let Some((parent_port, new_boundary)) = port_map.pop_first() {
+        while let Some(((rank, _), (parent_port, new_boundary, boundary))) = port_map.pop_first() {
             let parent_opp_port = parent_port.opposite();
-            let new_opp_boundary = if let Some(new_opp_boundary) = port_map.remove(&parent_opp_port)
+            let mut boundaries = vec![boundary];
+            let new_opp_boundary = if let Some((_, new_opp_boundary, opp_boundary)) =
+                port_map.remove(&(rank, parent_opp_port.data))
             {
                 // The new edge is between two new sites
+                boundaries.push(opp_boundary);
                 new_opp_boundary
             } else {
                 // Find (old) opposite site by following the edge in parent and
                 // then translating to the new site with `node_map`
-                self.map_site(parent_opp_port.site()).expect(
-                    "a parent port was neither a boundary port nor a non-rewritten port in child",
-                ).into()
+                self.map_site(parent_opp_port.site())
+                    .ok_or(SquashError::UnresolvedPort)?
+                    .into()
             };
             let (left, right) = match parent_port.data.end {
                 EdgeEnd::Left => (new_boundary, new_opp_boundary),
@@ -246,43 +582,80 @@ impl<G: Graph> Builder<G> {
             };
             match (left, right) {
                 (BoundarySite::Site(left), BoundarySite::Site(right)) => {
-                    self.graph.link_sites(left, right);
+                    let Owned { data, owner } = parent_port;
+                    self.graph.link_sites_like(
+                        left.clone(),
+                        right.clone(),
+                        owner.graph(),
+                        data.edge,
+                    );
+                    self.boundary_links.push(BoundaryLink {
+                        left,
+                        right,
+                        boundaries,
+                    });
                 }
                 (BoundarySite::Site(left), BoundarySite::Wire { id, end }) => {
-                    assert!(matches!(end, EdgeEnd::Right));
+                    if end != EdgeEnd::Right {
+                        return Err(SquashError::InvalidWireEnd { wire: id });
+                    }
                     let entry = &mut wires_opp_ends[id][0];
-                    assert!(entry.is_none(), "more than one value for same wire end");
-                    *entry = Some(left);
+                    if entry.is_some() {
+                        return Err(SquashError::DuplicateWireEnd { wire: id });
+                    }
+                    *entry = Some((left, parent_port));
+                    wires_boundaries[id].extend(boundaries);
                 }
                 (BoundarySite::Wire { id, end }, BoundarySite::Site(right)) => {
-                    assert!(matches!(end, EdgeEnd::Left));
+                    if end != EdgeEnd::Left {
+                        return Err(SquashError::InvalidWireEnd { wire: id });
+                    }
                     let entry = &mut wires_opp_ends[id][1];
-                    assert!(entry.is_none(), "more than one value for same wire end");
-                    *entry = Some(right);
+                    if entry.is_some() {
+                        return Err(SquashError::DuplicateWireEnd { wire: id });
+                    }
+                    *entry = Some((right, parent_port));
+                    wires_boundaries[id].extend(boundaries);
                 }
                 (BoundarySite::Wire { id: id1, .. }, BoundarySite::Wire { id: id2, .. }) => {
                     wires_uf.union(id1, id2);
+                    wires_boundaries[id1].extend(boundaries);
                 }
             }
         }
 
         // The values of wires_opp_ends, but indexed at the root wires
         let mut wires_opp_ends_root = BTreeMap::new();
-        for (i, sites) in wires_opp_ends.into_iter().enumerate() {
+        let mut wires_boundaries_root = BTreeMap::new();
+        for (i, (sites, boundaries)) in wires_opp_ends.into_iter().zip(wires_boundaries).enumerate()
+        {
             let root = wires_uf.find(i);
+            wires_boundaries_root
+                .entry(root)
+                .or_insert_with(Vec::new)
+                .extend(boundaries);
             let root_site = wires_opp_ends_root.entry(root).or_insert([None, None]);
             for (s, ns) in sites.into_iter().zip(root_site.iter_mut()) {
                 if let Some(s) = s {
-                    assert!(ns.is_none(), "more than one value for same wire end");
+                    if ns.is_some() {
+                        return Err(SquashError::DuplicateWireEnd { wire: root });
+                    }
                     *ns = Some(s);
                 }
             }
         }
 
         // Link all wires endpoints
-        for [left, right] in wires_opp_ends_root.values() {
-            if let (Some(left), Some(right)) = (left, right) {
-                self.graph.link_sites(left.clone(), right.clone());
+        for (root, [left, right]) in &wires_opp_ends_root {
+            if let (Some((left, port)), Some((right, _))) = (left, right) {
+                let Owned { data, owner } = port;
+                self.graph
+                    .link_sites_like(left.clone(), right.clone(), owner.graph(), data.edge);
+                self.boundary_links.push(BoundaryLink {
+                    left: left.clone(),
+                    right: right.clone(),
+                    boundaries: wires_boundaries_root.remove(root).unwrap_or_default(),
+                });
             }
         }
 
@@ -310,25 +683,82 @@ impl<G: Graph> Builder<G> {
             let Some(sites) = wires_opp_ends_root.get(&id) else {
                 continue;
             };
-            assert!(
-                sites[1 - index].is_none(),
-                "found both a boundary and internal edge at same port"
-            );
-            if let Some(site) = sites[index].as_ref() {
+            if sites[1 - index].is_some() {
+                return Err(SquashError::WireBoundaryConflict { wire: id });
+            }
+            if let Some((site, _)) = sites[index].as_ref() {
                 // change away from wire to concrete site
                 self.boundary[i].0 = BoundarySite::Site(site.clone());
             }
         }
+        Ok(())
     }
 
-    fn finish(self) -> Result<PortDiff<G>, IncompatiblePortDiff> {
-        PortDiff::try_with_parents(
+    fn finish(self, value: M) -> Result<PortDiff<G, M>, SquashError> {
+        let diff = PortDiff::try_with_parents(
             PortDiffData {
                 graph: self.graph,
-                boundary: self.boundary,
-                value: None,
+                boundary: self.boundary.into_iter().collect(),
+                boundary_labels: self.boundary_labels,
+                value,
+                key: None,
+                bound_ancestors: Default::default(),
             },
             self.incoming_edges,
-        )
+        )?;
+        Ok(diff)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::graph_view::MergeStrategy;
+    use crate::port_diff::tests::{
+        parent_child_diffs, parent_two_children_overlapping_diffs, TestPortDiff,
+    };
+
+    use super::*;
+
+    #[rstest]
+    fn test_squash_with_values(parent_child_diffs: [TestPortDiff; 2]) {
+        let [_, child] = parent_child_diffs;
+        let graph = PortDiffGraph::from_sinks([child]).map_value(|_| Some(3));
+
+        let squashed = graph.try_squash().unwrap();
+        assert_eq!(squashed.value(), &None);
+
+        let squashed = graph
+            .try_squash_with(|values| Some(values.iter().flatten().sum()))
+            .unwrap();
+        assert_eq!(squashed.value(), &Some(6));
+
+        let squashed = graph
+            .try_squash_with(|values| values.iter().flatten().copied().min())
+            .unwrap();
+        assert_eq!(squashed.value(), &Some(3));
+    }
+
+    #[rstest]
+    fn test_squash_error(parent_two_children_overlapping_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_overlapping_diffs;
+        let mut graph = PortDiffGraph::from_sinks([child_1]);
+        graph
+            .merge(
+                PortDiffGraph::from_sinks([child_2]),
+                MergeStrategy::IgnoreConflicts,
+            )
+            .unwrap();
+
+        assert!(matches!(
+            graph.try_squash(),
+            Err(SquashError::IncompatibleDiffs)
+        ));
+        assert!(matches!(
+            PortDiff::squash_graph(&graph),
+            Err(SquashError::OverlappingRewrites)
+        ));
     }
 }
@@ -0,0 +1,168 @@
+//! Statistics on diffs and diff graphs.
+
+use std::collections::BTreeMap;
+
+use crate::{Graph, NodeId, PortDiff, PortDiffGraph};
+
+use super::TraversalDirection;
+
+/// Statistics on a single diff.
+///
+/// Returned by [`PortDiff::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiffStats {
+    /// The number of nodes of the replacement graph.
+    pub n_nodes: usize,
+    /// The number of edges of the replacement graph.
+    pub n_edges: usize,
+    /// The number of boundary ports.
+    pub n_boundary_ports: usize,
+    /// The number of incoming edges, i.e. of parents counted with
+    /// multiplicity.
+    pub n_parents: usize,
+    /// The length of the longest path from a root to the diff.
+    pub depth: usize,
+    /// The total number of nodes rewritten in the parents.
+    pub n_rewritten_nodes: usize,
+    /// The total number of edges rewritten in the parents.
+    pub n_rewritten_edges: usize,
+}
+
+/// Statistics on all diffs of a [`PortDiffGraph`].
+///
+/// Returned by [`PortDiffGraph::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiffGraphStats {
+    /// The number of diffs.
+    pub n_diffs: usize,
+    /// The number of diffs without parents.
+    pub n_roots: usize,
+    /// The number of diffs without children in the graph.
+    pub n_sinks: usize,
+    /// The largest depth of a diff.
+    pub max_depth: usize,
+    /// The total number of nodes of the replacement graphs.
+    pub n_nodes: usize,
+    /// The total number of edges of the replacement graphs.
+    pub n_edges: usize,
+    /// The total number of boundary ports.
+    pub n_boundary_ports: usize,
+    /// The total number of nodes rewritten by the diffs.
+    pub n_rewritten_nodes: usize,
+    /// The total number of edges rewritten by the diffs.
+    pub n_rewritten_edges: usize,
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Statistics on the diff.
+    ///
+    /// Computing the depth requires traversing all ancestors of the diff.
+    pub fn stats(&self) -> DiffStats {
+        let graph = PortDiffGraph::from_sinks([self.clone()]);
+        let depths = graph.depths();
+        self.stats_with_depth(depths[&NodeId::from(self)])
+    }
+
+    fn stats_with_depth(&self, depth: usize) -> DiffStats {
+        let mut stats = DiffStats {
+            n_nodes: self.graph().nodes_iter().count(),
+            n_edges: self.graph().edges_iter().count(),
+            n_boundary_ports: self.n_boundary_ports(),
+            depth,
+            ..Default::default()
+        };
+        for (_, edge) in self.incoming_edges() {
+            stats.n_parents += 1;
+            stats.n_rewritten_nodes += edge.subgraph().nodes().len();
+            stats.n_rewritten_edges += edge.subgraph().edges().len();
+        }
+        stats
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Statistics on all diffs in the graph.
+    pub fn stats(&self) -> DiffGraphStats {
+        let depths = self.depths();
+        let n_sinks = self.sinks().count();
+        let mut stats = DiffGraphStats {
+            n_sinks,
+            ..Default::default()
+        };
+        for (&id, &depth) in &depths {
+            let diff = self.get_diff(id).stats_with_depth(depth);
+            stats.n_diffs += 1;
+            stats.n_roots += usize::from(diff.n_parents == 0);
+            stats.max_depth = stats.max_depth.max(depth);
+            stats.n_nodes += diff.n_nodes;
+            stats.n_edges += diff.n_edges;
+            stats.n_boundary_ports += diff.n_boundary_ports;
+            stats.n_rewritten_nodes += diff.n_rewritten_nodes;
+            stats.n_rewritten_edges += diff.n_rewritten_edges;
+        }
+        stats
+    }
+
+    /// The depth of every diff in the graph.
+    fn depths(&self) -> BTreeMap<NodeId<G, M>, usize> {
+        let roots = self.roots().map(|d| NodeId::from(&d)).collect::<Vec<_>>();
+        let mut depths: BTreeMap<NodeId<G, M>, usize> = BTreeMap::new();
+        // Parents are visited before their children
+        for diff in self.traverse(roots, TraversalDirection::Descendants) {
+            let depth = diff
+                .all_parents()
+                .filter_map(|p| depths.get(&NodeId::from(&p)))
+                .map(|d| d + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(NodeId::from(&diff), depth);
+        }
+        depths
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_stats(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        let parent_stats = parent.stats();
+        assert_eq!(parent_stats.depth, 0);
+        assert_eq!(parent_stats.n_parents, 0);
+        assert_eq!(parent_stats.n_nodes, 4);
+        assert_eq!(parent_stats.n_edges, 7);
+
+        let child_stats = child_1.stats();
+        assert_eq!(child_stats.depth, 1);
+        assert_eq!(child_stats.n_parents, 1);
+        assert_eq!(
+            child_stats.n_rewritten_nodes,
+            child_1
+                .incoming_edges()
+                .next()
+                .unwrap()
+                .1
+                .subgraph()
+                .nodes()
+                .len()
+        );
+
+        let graph = PortDiffGraph::from_sinks([child_1, child_2.clone()]);
+        let stats = graph.stats();
+        assert_eq!(stats.n_diffs, 3);
+        assert_eq!(stats.n_roots, 1);
+        assert_eq!(stats.n_sinks, 2);
+        assert_eq!(stats.max_depth, 1);
+        assert_eq!(
+            stats.n_nodes,
+            parent_stats.n_nodes + child_stats.n_nodes + child_2.stats().n_nodes
+        );
+    }
+}
@@ -1,112 +1,231 @@
-use std::collections::{BTreeMap, BTreeSet};
+//! Lazy traversal of the diff hierarchy.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use itertools::Itertools;
+use petgraph::visit::{EdgeRef, IntoEdges};
 
-use crate::{
-    port::{Port, Site},
-    Graph, PortDiff,
-};
-
-use super::{EdgeData, PortDiffPtr, UniqueNodeId};
-
-impl<G: Graph> PortDiff<G> {
-    fn all_ports<'a>(
-        &'a self,
-        unbound_port: &'a Site<G::Node, G::PortLabel>,
-    ) -> impl Iterator<Item = Port<G>> + 'a {
-        let internal = self
-            .graph
-            .get_bound_ports(unbound_port)
-            .map(|port| Port::Bound {
-                port,
-                owner: self.clone(),
-            });
-        let boundary = self
-            .boundary
-            .contains_key(unbound_port)
-            .then_some(Port::Unbound {
-                port: unbound_port.clone(),
-                owner: self.clone(),
-            });
-        internal.chain(boundary)
-    }
+use crate::{Graph, NodeId, PortDiff, PortDiffGraph};
+
+/// The direction in which to traverse the diff hierarchy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraversalDirection {
+    /// Traverse from parents to children.
+    Descendants,
+    /// Traverse from children to parents.
+    Ancestors,
 }
 
-pub struct DiffTraverser<G: Graph> {
-    exclude_nodes: BTreeMap<PortDiffPtr<G>, BTreeSet<G::Node>>,
+type DiffPredicate<'a, G, M> = Box<dyn FnMut(&PortDiff<G, M>) -> bool + 'a>;
+
+/// A lazy traversal of the diffs of a [`PortDiffGraph`].
+///
+/// Starting from a set of diffs, yields all diffs reachable in the given
+/// direction. A diff is only yielded once all its predecessors have been
+/// visited, so that diffs are in topological order when traversing
+/// descendants, and in reverse topological order when traversing ancestors.
+///
+/// The order of the traversal is deterministic: it only depends on the order
+/// of the `start` diffs and of the parents and children of every diff.
+///
+/// Use [`DiffTraverser::skip_if`] and [`DiffTraverser::stop_at`] to prune
+/// the traversal. Predicates are only evaluated when a diff is visited.
+pub struct DiffTraverser<'a, G: Graph, M = ()> {
+    graph: &'a PortDiffGraph<G, M>,
+    direction: TraversalDirection,
+    /// The number of unvisited predecessors of every reachable diff.
+    n_pending: BTreeMap<NodeId<G, M>, usize>,
+    /// The diffs that are reached from an expanded predecessor.
+    reached: BTreeSet<NodeId<G, M>>,
+    /// The diffs whose predecessors have all been visited.
+    ready: VecDeque<NodeId<G, M>>,
+    skip: Option<DiffPredicate<'a, G, M>>,
+    stop: Option<DiffPredicate<'a, G, M>>,
 }
 
-impl<G: Graph> Clone for DiffTraverser<G> {
-    fn clone(&self) -> Self {
+impl<'a, G: Graph, M: Clone> DiffTraverser<'a, G, M> {
+    /// Traverse the diffs of `graph` reachable from `start` in `direction`.
+    pub fn new(
+        graph: &'a PortDiffGraph<G, M>,
+        start: impl IntoIterator<Item = NodeId<G, M>>,
+        direction: TraversalDirection,
+    ) -> Self {
+        // Keep the order of `start`, so that the traversal is deterministic
+        let start_order = start.into_iter().unique().collect_vec();
+        let start: BTreeSet<_> = start_order.iter().copied().collect();
+
+        // Count the predecessors of all reachable diffs
+        let mut n_pending = BTreeMap::new();
+        let mut visited = start.clone();
+        let mut queue = start.iter().copied().collect_vec();
+        while let Some(n) = queue.pop() {
+            n_pending.entry(n).or_insert(0);
+            for succ in successors(graph, n, direction) {
+                *n_pending.entry(succ).or_insert(0) += 1;
+                if visited.insert(succ) {
+                    queue.push(succ);
+                }
+            }
+        }
+
+        let ready = start_order
+            .into_iter()
+            .filter(|n| n_pending[n] == 0)
+            .collect();
         Self {
-            exclude_nodes: self.exclude_nodes.clone(),
+            graph,
+            direction,
+            n_pending,
+            reached: start,
+            ready,
+            skip: None,
+            stop: None,
         }
     }
+
+    /// Skip the diffs for which `predicate` returns true.
+    ///
+    /// Skipped diffs are not yielded and the traversal does not continue
+    /// through them.
+    pub fn skip_if(mut self, predicate: impl FnMut(&PortDiff<G, M>) -> bool + 'a) -> Self {
+        self.skip = Some(Box::new(predicate));
+        self
+    }
+
+    /// Stop the traversal at the diffs for which `predicate` returns true.
+    ///
+    /// These diffs are yielded, but the traversal does not continue through
+    /// them.
+    pub fn stop_at(mut self, predicate: impl FnMut(&PortDiff<G, M>) -> bool + 'a) -> Self {
+        self.stop = Some(Box::new(predicate));
+        self
+    }
 }
 
-impl<G: Graph> Default for DiffTraverser<G> {
-    fn default() -> Self {
-        Self {
-            exclude_nodes: BTreeMap::new(),
+impl<'a, G: Graph, M: Clone> Iterator for DiffTraverser<'a, G, M> {
+    type Item = PortDiff<G, M>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let n = self.ready.pop_front()?;
+            let diff = self.graph.get_diff(n);
+            let is_skipped =
+                !self.reached.contains(&n) || self.skip.as_mut().is_some_and(|f| f(&diff));
+            let is_expanded = !is_skipped && !self.stop.as_mut().is_some_and(|f| f(&diff));
+            for succ in successors(self.graph, n, self.direction) {
+                if is_expanded {
+                    self.reached.insert(succ);
+                }
+                let cnt = self.n_pending.get_mut(&succ).unwrap();
+                *cnt -= 1;
+                if *cnt == 0 {
+                    self.ready.push_back(succ);
+                }
+            }
+            if !is_skipped {
+                return Some(diff);
+            }
         }
     }
 }
 
-impl<G: Graph> PortDiff<G> {
-    pub fn traverse<'a>(
-        node: UniqueNodeId<G>,
-        port_label: G::PortLabel,
-        known_edges: &[EdgeData<G>],
-    ) -> Vec<EdgeData<G>>
-    where
-        G: 'a,
-    {
-        let diff = node.owner;
-        let unbound_port = Site {
-            node: node.node,
-            port: port_label,
-        };
-        diff.all_ports(&unbound_port)
-            .flat_map(|port| PortDiff::opposite_ports(port))
-            // TODO: This should be awfully slow
-            .filter(|edge| {
-                PortDiff::are_compatible(edge_diffs(known_edges.iter().chain(Some(edge))))
+/// The neighbours of `n` within `graph` in the traversal direction.
+///
+/// There is one entry per edge, so neighbours may be repeated.
+fn successors<G: Graph, M: Clone>(
+    graph: &PortDiffGraph<G, M>,
+    n: NodeId<G, M>,
+    direction: TraversalDirection,
+) -> Vec<NodeId<G, M>> {
+    match direction {
+        TraversalDirection::Descendants => graph
+            .inner()
+            .edges(n.into())
+            .map(|e| NodeId::from(e.target()))
+            .collect(),
+        TraversalDirection::Ancestors => graph
+            .get_diff(n)
+            .all_incoming()
+            .iter()
+            .map(|e| {
+                let parent: PortDiff<G, M> = e.source().clone().into();
+                NodeId::from(&parent)
             })
-            .collect_vec()
+            .filter(|&p| graph.contains(p))
+            .collect(),
     }
 }
 
-fn edge_diffs<'d, G: Graph>(
-    edges: impl IntoIterator<Item = &'d EdgeData<G>>,
-) -> impl Iterator<Item = &'d PortDiff<G>>
-where
-    G: 'd,
-{
-    edges
-        .into_iter()
-        .flat_map(|edge| match edge {
-            EdgeData::Internal { owner, .. } => vec![owner],
-            EdgeData::Boundary { left, right } => vec![left.owner(), right.owner()],
-        })
-        .unique()
-}
-
+#[cfg(feature = "portgraph")]
 #[cfg(test)]
 mod tests {
     use rstest::rstest;
 
-    use crate::port_diff::tests::{root_diff, TestPortDiff};
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
 
     use super::*;
 
     #[rstest]
-    fn test_traverse(root_diff: TestPortDiff) {
-        let (n0, n1, n2, n3) = root_diff.nodes().collect_tuple().unwrap();
-        let child_a = root_diff.identity_subgraph([n0, n1]);
-        let child_b = root_diff.identity_subgraph([n2, n3]);
-
-        let edges = child_a.traverse(n0, 0, &[]);
-        assert_eq!(edges.len(), 1);
-        assert_eq!(edges[0].right().node(), n1);
+    fn test_traverse_descendants(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [root, child_1, child_2] = parent_two_children_diffs;
+        let graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+        let start = || [NodeId::from(&root)];
+
+        let all = graph
+            .traverse(start(), TraversalDirection::Descendants)
+            .collect_vec();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0], root);
+
+        let stopped = graph
+            .traverse(start(), TraversalDirection::Descendants)
+            .stop_at(|d| d == &root)
+            .collect_vec();
+        assert_eq!(stopped, vec![root.clone()]);
+
+        let skipped = graph
+            .traverse(start(), TraversalDirection::Descendants)
+            .skip_if(|d| d == &child_1)
+            .collect_vec();
+        assert_eq!(skipped, vec![root.clone(), child_2.clone()]);
+    }
+
+    #[rstest]
+    fn test_traverse_ancestors(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [root, child_1, child_2] = parent_two_children_diffs;
+        let graph = PortDiffGraph::from_sinks([child_1.clone(), child_2]);
+        let ancestors = graph
+            .traverse([NodeId::from(&child_1)], TraversalDirection::Ancestors)
+            .collect_vec();
+        assert_eq!(ancestors, vec![child_1, root]);
+    }
+
+    #[rstest]
+    fn test_deterministic_order(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [root, child_1, child_2] = parent_two_children_diffs;
+        let ids = |diffs: &[&TestPortDiff]| diffs.iter().map(|&d| NodeId::from(d)).collect_vec();
+
+        // Diffs are ordered by creation, not by address or sinks
+        let graph = PortDiffGraph::from_sinks([child_1.clone(), child_2.clone()]);
+        assert_eq!(
+            graph.all_nodes().collect_vec(),
+            ids(&[&root, &child_1, &child_2])
+        );
+        let graph = PortDiffGraph::from_sinks([child_2.clone(), child_1.clone()]);
+        assert_eq!(
+            graph.all_nodes().collect_vec(),
+            ids(&[&root, &child_1, &child_2])
+        );
+        assert_eq!(
+            root.all_children().collect_vec(),
+            vec![child_1.clone(), child_2.clone()]
+        );
+
+        // Traversals follow the order of the start diffs
+        let start = ids(&[&child_2, &child_1]);
+        let ancestors = graph
+            .traverse(start, TraversalDirection::Ancestors)
+            .collect_vec();
+        assert_eq!(ancestors, vec![child_2, child_1, root]);
     }
 }
@@ -0,0 +1,139 @@
+//! Rewrites that preserve the types of boundary ports.
+
+use crate::{
+    port::{BoundaryIndex, Port},
+    NodeId, PortDiff, PortDiffGraph, TypedGraph,
+};
+
+use super::{BoundarySite, InvalidRewriteError, Owned, SquashError};
+
+impl<G: TypedGraph, M: Clone> PortDiff<G, M> {
+    /// Create a new diff that rewrites `nodes` and `edges` to `new_graph`,
+    /// checking that boundary ports keep their type.
+    ///
+    /// Errors with [`InvalidRewriteError::PortTypeMismatch`] if a port is
+    /// mapped to a site with a different type than the site of the port.
+    /// Ports on wires are not checked. Otherwise identical to
+    /// [`Self::rewrite`].
+    pub fn rewrite_typed(
+        nodes: impl IntoIterator<Item = Owned<G::Node, G, M>>,
+        edges: impl IntoIterator<Item = (Owned<Port<G>, G, M>, Owned<Port<G>, G, M>)>,
+        new_graph: G,
+        boundary_map: impl FnMut(Owned<Port<G>, G, M>) -> BoundarySite<G>,
+    ) -> Result<Self, InvalidRewriteError<G, M>>
+    where
+        M: Default,
+    {
+        let diff = Self::rewrite(nodes, edges, new_graph, boundary_map)?;
+        if let Some(boundary) = diff.port_type_mismatches().into_iter().next() {
+            return Err(InvalidRewriteError::PortTypeMismatch(
+                diff.parent_port(boundary),
+            ));
+        }
+        Ok(diff)
+    }
+
+    /// The boundary ports whose type differs from the type of their parent
+    /// port.
+    ///
+    /// Boundary ports on wires, or whose parent port is on a wire, are
+    /// ignored.
+    pub fn port_type_mismatches(&self) -> Vec<BoundaryIndex> {
+        self.boundary_iter()
+            .filter(|&b| {
+                let Ok(site) = self.boundary_site(b).try_into_site() else {
+                    return false;
+                };
+                let parent_port = self.parent_port(b);
+                let Some(parent_site) = parent_port.site() else {
+                    return false;
+                };
+                let parent_type = parent_port.owner.graph().port_type(parent_site);
+                self.graph().port_type(site) != parent_type
+            })
+            .collect()
+    }
+}
+
+impl<G: TypedGraph, M: Clone> PortDiffGraph<G, M> {
+    /// Squash all diffs in the graph view into a single equivalent diff,
+    /// checking that boundary ports have the same type as the ports they
+    /// are joined to.
+    ///
+    /// Errors with [`SquashError::PortTypeMismatch`] if a boundary port of a
+    /// diff has a different type than its parent port in the graph.
+    /// Otherwise identical to [`Self::try_squash`].
+    pub fn try_squash_typed(&self) -> Result<PortDiff<G, M>, SquashError>
+    where
+        M: Default,
+    {
+        for diff in self.all_nodes().map(|n| self.get_diff(n)) {
+            let is_joined = |&b: &BoundaryIndex| {
+                let parent = NodeId::from(&diff.parent_port(b).owner);
+                self.contains(parent)
+            };
+            if diff.port_type_mismatches().iter().any(is_joined) {
+                return Err(SquashError::PortTypeMismatch);
+            }
+        }
+        self.try_squash()
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use portgraph::{Direction, PortGraph, PortMut, PortOffset};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+    use crate::{Graph, Site};
+
+    use super::*;
+
+    impl TypedGraph for PortGraph {
+        type PortType = Direction;
+
+        fn port_type(&self, site: Site<Self::Node, Self::PortLabel>) -> Direction {
+            site.port.direction()
+        }
+    }
+
+    #[rstest]
+    fn test_rewrite_typed(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        assert!(child.port_type_mismatches().is_empty());
+        PortDiffGraph::from_sinks([child])
+            .try_squash_typed()
+            .unwrap();
+
+        let (_, n1, _, _) = Graph::nodes_iter(parent.graph()).collect_tuple().unwrap();
+        let mut rhs = PortGraph::new();
+        let new_n1 = rhs.add_node(1, 3);
+        let nodes = [Owned::new(n1, parent.clone())];
+        // Map the inputs of n1 to outputs and vice versa
+        let flip = |p: Owned<Port<PortGraph>, PortGraph>| {
+            let site = p.site().unwrap();
+            let port = match site.port {
+                PortOffset::Incoming(i) => PortOffset::Outgoing(i),
+                PortOffset::Outgoing(i) => PortOffset::Incoming(i),
+            };
+            Site { node: new_n1, port }.into()
+        };
+        let err = PortDiff::rewrite_typed(nodes.clone(), [], rhs.clone(), flip).unwrap_err();
+        assert!(matches!(err, InvalidRewriteError::PortTypeMismatch(p) if p.owner == parent));
+
+        // Untyped rewrites are rejected when squashing
+        let flipped = PortDiff::rewrite(nodes, [], rhs, flip).unwrap();
+        assert_eq!(
+            flipped.port_type_mismatches().len(),
+            flipped.n_boundary_ports()
+        );
+        let graph = PortDiffGraph::from_sinks([flipped]);
+        assert!(matches!(
+            graph.try_squash_typed(),
+            Err(SquashError::PortTypeMismatch)
+        ));
+    }
+}
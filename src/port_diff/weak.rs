@@ -0,0 +1,90 @@
+//! Weak references to diffs.
+
+use derive_where::derive_where;
+use relrc::{RelRc, RelWeak};
+
+use crate::{Graph, NodeId, PortDiff, PortDiffGraph};
+
+use super::{EdgeData, PortDiffData};
+
+/// A weak reference to a [`PortDiff`].
+///
+/// Does not keep the diff alive: abandoned branches of the hierarchy are
+/// dropped even if weak references to them remain. Use [`Self::upgrade`] to
+/// access the diff if it is still alive.
+#[derive_where(Clone; G: Graph)]
+pub struct WeakPortDiff<G: Graph, M = ()> {
+    data: RelWeak<PortDiffData<G, M>, EdgeData<G>>,
+    id: NodeId<G, M>,
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Create a weak reference to `self`.
+    pub fn downgrade(&self) -> WeakPortDiff<G, M> {
+        WeakPortDiff {
+            data: RelRc::downgrade(&self.data),
+            id: self.into(),
+        }
+    }
+}
+
+impl<G: Graph, M> WeakPortDiff<G, M> {
+    /// The diff, if it is still alive.
+    pub fn upgrade(&self) -> Option<PortDiff<G, M>> {
+        self.data.upgrade().map(|data| PortDiff { data })
+    }
+
+    /// Whether the diff is still alive.
+    pub fn is_alive(&self) -> bool {
+        self.upgrade().is_some()
+    }
+
+    /// The ID of the diff at the time the reference was created.
+    ///
+    /// IDs are derived from addresses, so the ID of a dropped diff may be
+    /// reused by a new diff.
+    pub fn id(&self) -> NodeId<G, M> {
+        self.id
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiffGraph<G, M> {
+    /// Whether the diff `id` is alive and in the graph.
+    ///
+    /// The graph keeps all its diffs alive, so that `id` remains valid as long
+    /// as it is in the graph. Combine with [`WeakPortDiff::id`] to check that
+    /// a cached diff was not removed, e.g. with [`Self::remove_diff`].
+    pub fn is_alive(&self, id: NodeId<G, M>) -> bool {
+        self.contains(id)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_weak_port_diff(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let weak = child.downgrade();
+        assert!(weak.upgrade() == Some(child.clone()));
+        assert_eq!(weak.id(), NodeId::from(&child));
+
+        // The graph keeps the child alive
+        let graph = PortDiffGraph::from_sinks([child]);
+        assert!(weak.is_alive());
+        assert!(graph.is_alive(weak.id()));
+        assert!(graph.is_alive(NodeId::from(&parent)));
+
+        // Parents do not keep their children alive
+        drop(graph);
+        assert!(!weak.is_alive());
+        assert!(weak.upgrade().is_none());
+        assert!(!PortDiffGraph::from_sinks([parent]).is_alive(weak.id()));
+    }
+}
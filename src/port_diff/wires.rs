@@ -0,0 +1,147 @@
+use std::collections::BTreeSet;
+
+use crate::{port::EdgeEnd, BoundarySite, Graph, Port, PortDiff};
+
+use super::{InvalidRewriteError, Owned};
+
+/// Allocate fresh wire IDs for the boundary ports of a rewrite.
+///
+/// Wire IDs must be unique within a diff and dense from 0. An allocator is
+/// passed to the boundary map of [`PortDiff::rewrite_with_wires`], which
+/// checks that the wires of the new diff are exactly the allocated ones.
+#[derive(Debug, Clone, Default)]
+pub struct WireAllocator {
+    n_wires: usize,
+}
+
+impl WireAllocator {
+    /// Create an allocator with no wires.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate a fresh wire ID.
+    pub fn fresh(&mut self) -> usize {
+        self.n_wires += 1;
+        self.n_wires - 1
+    }
+
+    /// Allocate a fresh wire and return its left and right ends.
+    pub fn pair<G: Graph>(&mut self) -> (BoundarySite<G>, BoundarySite<G>) {
+        let id = self.fresh();
+        (
+            BoundarySite::wire(id, EdgeEnd::Left),
+            BoundarySite::wire(id, EdgeEnd::Right),
+        )
+    }
+
+    /// The number of allocated wires.
+    pub fn n_wires(&self) -> usize {
+        self.n_wires
+    }
+
+    /// Check that the wire IDs of `boundary` are exactly the allocated ones.
+    pub(super) fn check<G: Graph, M>(
+        &self,
+        boundary: impl IntoIterator<Item = BoundarySite<G>>,
+    ) -> Result<(), InvalidRewriteError<G, M>> {
+        let used: BTreeSet<_> = boundary
+            .into_iter()
+            .filter_map(|site| match site {
+                BoundarySite::Wire { id, .. } => Some(id),
+                BoundarySite::Site(_) => None,
+            })
+            .collect();
+        if let Some(&id) = used.iter().find(|&&id| id >= self.n_wires) {
+            return Err(InvalidRewriteError::UnallocatedWire { id });
+        }
+        if let Some(id) = (0..self.n_wires).find(|id| !used.contains(id)) {
+            return Err(InvalidRewriteError::UnusedWire { id });
+        }
+        Ok(())
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// Create a new diff that rewrites `nodes` and `edges` to `new_graph`,
+    /// allocating wire IDs automatically.
+    ///
+    /// The function `boundary_map` is passed a [`WireAllocator`] along with
+    /// every boundary port, from which wire IDs must be obtained. Errors if a
+    /// wire ID was not allocated, or if an allocated wire is not used.
+    /// Otherwise identical to [`Self::rewrite`].
+    pub fn rewrite_with_wires(
+        nodes: impl IntoIterator<Item = Owned<G::Node, G, M>>,
+        edges: impl IntoIterator<Item = (Owned<Port<G>, G, M>, Owned<Port<G>, G, M>)>,
+        new_graph: G,
+        mut boundary_map: impl FnMut(Owned<Port<G>, G, M>, &mut WireAllocator) -> BoundarySite<G>,
+    ) -> Result<Self, InvalidRewriteError<G, M>>
+    where
+        M: Default,
+    {
+        let mut wires = WireAllocator::new();
+        let diff = Self::rewrite(nodes, edges, new_graph, |port| {
+            boundary_map(port, &mut wires)
+        })?;
+        wires.check(diff.boundary.iter().map(|(site, _)| site))?;
+        Ok(diff)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use portgraph::{NodeIndex, PortGraph};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[test]
+    fn test_wire_allocator() {
+        let mut wires = WireAllocator::new();
+        assert_eq!(wires.fresh(), 0);
+        let (left, right) = wires.pair::<PortGraph>();
+        assert_eq!(left, BoundarySite::wire(1, EdgeEnd::Left));
+        assert_eq!(right, BoundarySite::wire(1, EdgeEnd::Right));
+        assert_eq!(wires.n_wires(), 2);
+    }
+
+    #[rstest]
+    fn test_rewrite_with_wires(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, _] = parent_child_diffs;
+        let nodes = || [Owned::new(NodeIndex::new(1), parent.clone())];
+
+        let diff = PortDiff::rewrite_with_wires(nodes(), [], PortGraph::new(), |_, wires| {
+            BoundarySite::wire(wires.fresh(), EdgeEnd::Left)
+        })
+        .unwrap();
+        let ids = diff
+            .boundary_iter()
+            .map(|b| match diff.boundary_site(b) {
+                BoundarySite::Wire { id, .. } => id,
+                BoundarySite::Site(_) => panic!("expected a wire"),
+            })
+            .collect_vec();
+        assert_eq!(ids, (0..diff.n_boundary_ports()).collect_vec());
+
+        let err = PortDiff::rewrite_with_wires(nodes(), [], PortGraph::new(), |_, wires| {
+            BoundarySite::wire(wires.fresh() + 5, EdgeEnd::Left)
+        })
+        .unwrap_err();
+        assert!(matches!(err, InvalidRewriteError::UnallocatedWire { .. }));
+
+        let mut first = true;
+        let err = PortDiff::rewrite_with_wires(nodes(), [], PortGraph::new(), |_, wires| {
+            // Skip wire 0
+            if std::mem::take(&mut first) {
+                wires.fresh();
+            }
+            BoundarySite::wire(wires.fresh(), EdgeEnd::Left)
+        })
+        .unwrap_err();
+        assert!(matches!(err, InvalidRewriteError::UnusedWire { id: 0 }));
+    }
+}
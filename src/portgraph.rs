@@ -1,8 +1,11 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use crate::{
     port::{BoundPort, EdgeEnd, Site},
-    Graph, PortDiff,
+    port_diff::Owned,
+    simple_graph::SimplePort,
+    AddNodes, BoundarySite, ContentKey, DotGraph, Graph, InvalidRewriteError, PortDiff,
+    RemoveNodes, WireAllocator,
 };
 
 use itertools::Itertools;
@@ -10,7 +13,7 @@ use pg::{LinkMut, LinkView, PortGraph, PortMut, PortView};
 use portgraph as pg;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct PortgraphEdge {
     outgoing: u16,
     node: pg::NodeIndex,
@@ -54,6 +57,24 @@ impl TryFrom<(pg::NodeIndex, pg::PortOffset)> for PortgraphEdge {
     }
 }
 
+impl From<pg::PortOffset> for SimplePort {
+    fn from(port: pg::PortOffset) -> Self {
+        match port.direction() {
+            pg::Direction::Incoming => SimplePort::In(port.index()),
+            pg::Direction::Outgoing => SimplePort::Out(port.index()),
+        }
+    }
+}
+
+impl From<SimplePort> for pg::PortOffset {
+    fn from(port: SimplePort) -> Self {
+        match port {
+            SimplePort::In(i) => pg::PortOffset::new_incoming(i),
+            SimplePort::Out(i) => pg::PortOffset::new_outgoing(i),
+        }
+    }
+}
+
 impl Graph for pg::PortGraph {
     type Node = pg::NodeIndex;
 
@@ -143,6 +164,18 @@ impl Graph for pg::PortGraph {
             .unwrap();
     }
 
+    /// Ports can only be linked once, from an outgoing to an incoming port.
+    fn is_valid_end(&self, site: Site<Self::Node, Self::PortLabel>, end: EdgeEnd) -> bool {
+        let direction = match end {
+            EdgeEnd::Left => pg::Direction::Outgoing,
+            EdgeEnd::Right => pg::Direction::Incoming,
+        };
+        let is_free = self
+            .port_index(site.node, site.port)
+            .map_or(true, |port| self.port_links(port).next().is_none());
+        site.port.direction() == direction && is_free
+    }
+
     fn add_subgraph(
         &mut self,
         graph: &Self,
@@ -187,6 +220,45 @@ impl Graph for pg::PortGraph {
     }
 }
 
+impl AddNodes for pg::PortGraph {
+    /// Ports are indexed contiguously, so that the node has as many input
+    /// (resp. output) ports as needed for the largest requested offset.
+    fn add_node_with_sites(
+        &mut self,
+        sites: impl IntoIterator<Item = Self::PortLabel>,
+    ) -> Self::Node {
+        let (mut num_inputs, mut num_outputs) = (0, 0);
+        for port in sites {
+            match port.direction() {
+                pg::Direction::Incoming => num_inputs = num_inputs.max(port.index() + 1),
+                pg::Direction::Outgoing => num_outputs = num_outputs.max(port.index() + 1),
+            }
+        }
+        self.add_node(num_inputs, num_outputs)
+    }
+}
+
+impl ContentKey for pg::PortGraph {
+    type NodeKey = ();
+    type EdgeKey = ();
+
+    fn node_key(&self, _node: Self::Node) {}
+
+    fn edge_key(&self, _edge: Self::Edge) {}
+}
+
+impl RemoveNodes for pg::PortGraph {
+    fn remove_node(&mut self, node: Self::Node) {
+        PortMut::remove_node(self, node);
+    }
+}
+
+impl DotGraph for pg::PortGraph {
+    fn dot_node_label(&self, node: Self::Node) -> String {
+        format!("{}", node.index())
+    }
+}
+
 fn ensure_site_exists(graph: &mut PortGraph, site: Site<pg::NodeIndex, pg::PortOffset>) {
     let num_inputs = graph.num_inputs(site.node);
     let num_outputs = graph.num_outputs(site.node);
@@ -203,8 +275,129 @@ fn ensure_site_exists(graph: &mut PortGraph, site: Site<pg::NodeIndex, pg::PortO
     }
 }
 
+/// The wire end that replaces the port `port` of a deleted node.
+///
+/// The incoming port at offset `i` is mapped to the right end of wire `i`, and
+/// the outgoing port at offset `i` to the left end of wire `i`. When used as
+/// boundary sites of a rewrite, the i-th input of the deleted node is thus
+/// connected to its i-th output. Ports without a counterpart are left
+/// unconnected.
+pub fn passthrough_wire(port: pg::PortOffset) -> BoundarySite<PortGraph> {
+    let end = match port.direction() {
+        pg::Direction::Incoming => EdgeEnd::Right,
+        pg::Direction::Outgoing => EdgeEnd::Left,
+    };
+    BoundarySite::wire(port.index(), end)
+}
+
 impl PortDiff<PortGraph> {
     pub fn nodes(&self) -> impl Iterator<Item = pg::NodeIndex> + '_ {
         PortView::nodes_iter(self.graph())
     }
+
+    /// Replace `node` with identity wires.
+    ///
+    /// The new diff deletes `node` and connects its i-th input to its i-th
+    /// output, see [`passthrough_wire`]. Wire IDs are renumbered so that they
+    /// are dense, as ports that are not linked are not at the boundary.
+    pub fn rewrite_node_to_wires(
+        &self,
+        node: pg::NodeIndex,
+    ) -> Result<Self, InvalidRewriteError<PortGraph>> {
+        let mut wires = WireAllocator::new();
+        let mut wire_ids = BTreeMap::new();
+        self.rewrite_induced(&BTreeSet::from([node]), PortGraph::new(), |port| {
+            let site = Owned::new(port, self.clone())
+                .site()
+                .expect("boundary port of a node is a site");
+            match passthrough_wire(site.port) {
+                BoundarySite::Wire { id, end } => {
+                    let id = *wire_ids.entry(id).or_insert_with(|| wires.fresh());
+                    BoundarySite::wire(id, end)
+                }
+                site => site,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_rewrite_node_to_wires(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, _] = parent_child_diffs;
+        let (n1, n2) = (pg::NodeIndex::new(1), pg::NodeIndex::new(2));
+
+        //  a --                          a --
+        //  a -- b -- c -- d      =>           c -- d
+        //  a --                          a --
+        let no_b = parent.rewrite_node_to_wires(n1).unwrap();
+        let g = PortDiff::extract_graph(vec![no_b.clone()]).unwrap();
+        assert_eq!(g.node_count(), 3);
+        assert_eq!(g.link_count(), 4);
+
+        // Wires of distinct diffs are joined when squashing
+        let no_c = parent.rewrite_node_to_wires(n2).unwrap();
+        let g = PortDiff::extract_graph(vec![no_b, no_c]).unwrap();
+        assert_eq!(g.node_count(), 2);
+        assert_eq!(g.link_count(), 1);
+    }
+
+    #[test]
+    fn test_multiple_links_between_nodes() {
+        // a => b, with three links between the same pair of nodes
+        let mut graph = PortGraph::new();
+        let a = graph.add_node(0, 3);
+        let b = graph.add_node(3, 0);
+        for i in 0..3 {
+            graph.link_nodes(a, i, b, i).unwrap();
+        }
+        let root = PortDiff::from_graph(graph);
+
+        // Rewrite a and b in distinct diffs, into copies of themselves
+        let copy = |node: pg::NodeIndex| {
+            let mut rhs = PortGraph::new();
+            let new_node = rhs.add_node(
+                root.graph().num_inputs(node),
+                root.graph().num_outputs(node),
+            );
+            root.rewrite_induced(&BTreeSet::from([node]), rhs, |p| {
+                let site = Owned::new(p, root.clone()).site().unwrap();
+                site.map_node(|_| new_node).into()
+            })
+            .unwrap()
+        };
+        let (child_a, child_b) = (copy(a), copy(b));
+        assert_eq!(child_a.n_boundary_ports(), 3);
+        assert_eq!(child_b.n_boundary_ports(), 3);
+
+        // Squashing keeps every link, between the same ports
+        for diffs in [vec![child_b.clone()], vec![child_a, child_b]] {
+            let g = PortDiff::extract_graph(diffs).unwrap();
+            assert_eq!(g.node_count(), 2);
+            assert_eq!(g.link_count(), 3);
+            let [out_node, in_node] = [0, 3].map(|n_inputs| {
+                PortView::nodes_iter(&g)
+                    .find(|&n| g.num_inputs(n) == n_inputs)
+                    .unwrap()
+            });
+            for i in 0..3 {
+                let out_site = Site {
+                    node: out_node,
+                    port: pg::PortOffset::Outgoing(i),
+                };
+                let in_site = Site {
+                    node: in_node,
+                    port: pg::PortOffset::Incoming(i),
+                };
+                assert_eq!(g.parallel_edges(out_site, in_site).count(), 1);
+            }
+        }
+    }
 }
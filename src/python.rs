@@ -0,0 +1,344 @@
+//! Python bindings, behind the `python` feature.
+//!
+//! The `pyportdiff` module exposes [`SimpleGraph`], [`PortDiff`] and
+//! [`PortDiffGraph`] over simple graphs, so that rewriting experiments can be
+//! scripted from Python. Nodes are passed as integers and ports as
+//! `("in", offset)` or `("out", offset)` tuples. Simple graphs convert to and
+//! from `networkx` multigraphs and to `numpy` edge arrays.
+//!
+//! Build the module with [maturin](https://www.maturin.rs), see
+//! `pyproject.toml`.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::{
+    port_diff::Owned,
+    simple_graph::{SimpleGraph, SimpleNode, SimplePort},
+    BoundPort, BoundarySite, EdgeEnd, Graph, PortDiff, PortDiffGraph, Site,
+};
+
+type PyPort = (String, usize);
+
+fn port_to_py(port: SimplePort) -> PyPort {
+    match port {
+        SimplePort::In(i) => ("in".to_string(), i),
+        SimplePort::Out(i) => ("out".to_string(), i),
+    }
+}
+
+fn port_from_py((direction, offset): PyPort) -> PyResult<SimplePort> {
+    match direction.as_str() {
+        "in" => Ok(SimplePort::In(offset)),
+        "out" => Ok(SimplePort::Out(offset)),
+        _ => Err(PyValueError::new_err(format!(
+            "invalid port direction {direction:?}, expected \"in\" or \"out\""
+        ))),
+    }
+}
+
+fn value_error(err: impl ToString) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+/// A directed graph with ordered input and output ports on every node.
+#[pyclass(name = "SimpleGraph")]
+#[derive(Clone, Default)]
+struct PySimpleGraph(SimpleGraph);
+
+impl PySimpleGraph {
+    fn node(&self, index: usize) -> PyResult<SimpleNode> {
+        if index < self.0.node_count() {
+            Ok(SimpleNode::new(index))
+        } else {
+            Err(PyValueError::new_err(format!("no node with index {index}")))
+        }
+    }
+}
+
+#[pymethods]
+impl PySimpleGraph {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a node and return its index.
+    fn add_node(&mut self, n_inputs: usize, n_outputs: usize) -> usize {
+        self.0.add_node(n_inputs, n_outputs).index()
+    }
+
+    /// Link the output `out` of `left` to the input `inp` of `right`.
+    fn link_nodes(&mut self, left: usize, out: usize, right: usize, inp: usize) -> PyResult<usize> {
+        let (left, right) = (self.node(left)?, self.node(right)?);
+        Ok(self.0.link_nodes(left, out, right, inp).index())
+    }
+
+    fn node_count(&self) -> usize {
+        self.0.node_count()
+    }
+
+    fn edge_count(&self) -> usize {
+        self.0.edge_count()
+    }
+
+    fn num_inputs(&self, node: usize) -> PyResult<usize> {
+        Ok(self.0.num_inputs(self.node(node)?))
+    }
+
+    fn num_outputs(&self, node: usize) -> PyResult<usize> {
+        Ok(self.0.num_outputs(self.node(node)?))
+    }
+
+    /// The edges as `(left, out, right, inp)` tuples.
+    fn edges(&self) -> Vec<(usize, usize, usize, usize)> {
+        self.0
+            .edges_iter()
+            .map(|edge| {
+                let [left, right] = [EdgeEnd::Left, EdgeEnd::Right]
+                    .map(|end| self.0.get_port_site(BoundPort { edge, end }));
+                let (SimplePort::Out(out), SimplePort::In(inp)) = (left.port, right.port) else {
+                    unreachable!("edges go from outputs to inputs")
+                };
+                (left.node.index(), out, right.node.index(), inp)
+            })
+            .collect()
+    }
+
+    /// The edges as a `numpy` array of shape `(n_edges, 4)`, see `edges`.
+    fn to_numpy(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let np = py.import_bound("numpy")?;
+        let array = np.call_method1("array", (self.edges(),))?;
+        let array = array.call_method1("reshape", ((-1, 4),))?;
+        Ok(array.call_method1("astype", ("int64",))?.unbind())
+    }
+
+    /// Convert to a `networkx.MultiDiGraph`.
+    ///
+    /// Nodes have `n_inputs` and `n_outputs` attributes, edges `out` and
+    /// `inp` attributes.
+    fn to_networkx(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let nx = py.import_bound("networkx")?;
+        let graph = nx.call_method0("MultiDiGraph")?;
+        for node in self.0.nodes_iter() {
+            let attrs = PyDict::new_bound(py);
+            attrs.set_item("n_inputs", self.0.num_inputs(node))?;
+            attrs.set_item("n_outputs", self.0.num_outputs(node))?;
+            graph.call_method("add_node", (node.index(),), Some(&attrs))?;
+        }
+        for (left, out, right, inp) in self.edges() {
+            let attrs = PyDict::new_bound(py);
+            attrs.set_item("out", out)?;
+            attrs.set_item("inp", inp)?;
+            graph.call_method("add_edge", (left, right), Some(&attrs))?;
+        }
+        Ok(graph.unbind())
+    }
+
+    /// Convert from a `networkx` directed graph, as created by `to_networkx`.
+    ///
+    /// Nodes are numbered in iteration order. Missing attributes default to
+    /// 0.
+    #[staticmethod]
+    fn from_networkx(graph: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let mut ret = SimpleGraph::new();
+        let index = PyDict::new_bound(graph.py());
+        let with_data = PyDict::new_bound(graph.py());
+        with_data.set_item("data", true)?;
+        for item in graph.call_method("nodes", (), Some(&with_data))?.iter()? {
+            let (node, attrs): (Bound<'_, PyAny>, Bound<'_, PyDict>) = item?.extract()?;
+            let get = |key: &str| -> PyResult<usize> {
+                attrs.get_item(key)?.map_or(Ok(0), |v| v.extract())
+            };
+            let new_node = ret.add_node(get("n_inputs")?, get("n_outputs")?);
+            index.set_item(node, new_node.index())?;
+        }
+        for item in graph.call_method("edges", (), Some(&with_data))?.iter()? {
+            let (left, right, attrs): (Bound<'_, PyAny>, Bound<'_, PyAny>, Bound<'_, PyDict>) =
+                item?.extract()?;
+            let get = |key: &str| -> PyResult<usize> {
+                attrs.get_item(key)?.map_or(Ok(0), |v| v.extract())
+            };
+            let left = SimpleNode::new(index.as_any().get_item(left)?.extract()?);
+            let right = SimpleNode::new(index.as_any().get_item(right)?.extract()?);
+            ret.link_nodes(left, get("out")?, right, get("inp")?);
+        }
+        Ok(Self(ret))
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "SimpleGraph(nodes={}, edges={})",
+            self.0.node_count(),
+            self.0.edge_count()
+        )
+    }
+}
+
+/// A diff: a rewrite of its parents into a replacement graph.
+#[pyclass(name = "PortDiff", unsendable)]
+#[derive(Clone)]
+struct PyPortDiff(PortDiff<SimpleGraph>);
+
+#[pymethods]
+impl PyPortDiff {
+    /// Create a root diff from a graph.
+    #[staticmethod]
+    fn from_graph(graph: PySimpleGraph) -> Self {
+        Self(PortDiff::from_graph(graph.0))
+    }
+
+    /// The replacement graph of the diff.
+    fn graph(&self) -> PySimpleGraph {
+        PySimpleGraph(self.0.graph().clone())
+    }
+
+    /// Rewrite the subgraph induced by `nodes` into `new_graph`.
+    ///
+    /// `boundary_map` is called with the node and port of every boundary
+    /// port and returns the `(node, port)` site in `new_graph` it is mapped
+    /// to.
+    fn rewrite_induced(
+        &self,
+        nodes: Vec<usize>,
+        new_graph: PySimpleGraph,
+        boundary_map: &Bound<'_, PyAny>,
+    ) -> PyResult<Self> {
+        let nodes = nodes.into_iter().map(SimpleNode::new).collect();
+        let mut error = None;
+        let diff = self.0.rewrite_induced(&nodes, new_graph.0, |port| {
+            let site = Owned::new(port, self.0.clone())
+                .site()
+                .ok_or_else(|| PyValueError::new_err("boundary port is not at a site"))
+                .and_then(|site| {
+                    let args = (site.node.index(), port_to_py(site.port));
+                    let (node, port): (usize, PyPort) = boundary_map.call1(args)?.extract()?;
+                    Ok(Site {
+                        node: SimpleNode::new(node),
+                        port: port_from_py(port)?,
+                    })
+                });
+            match site {
+                Ok(site) => site.into(),
+                Err(err) => {
+                    // The diff is discarded below
+                    error.get_or_insert(err);
+                    BoundarySite::wire(usize::MAX, EdgeEnd::Left)
+                }
+            }
+        });
+        if let Some(err) = error {
+            return Err(err);
+        }
+        diff.map(Self).map_err(value_error)
+    }
+
+    fn n_boundary_ports(&self) -> usize {
+        self.0.n_boundary_ports()
+    }
+
+    fn is_root(&self) -> bool {
+        self.0.is_root()
+    }
+
+    fn parents(&self) -> Vec<Self> {
+        self.0.all_parents().map(Self).collect()
+    }
+
+    fn children(&self) -> Vec<Self> {
+        self.0.all_children().map(Self).collect()
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PortDiff(nodes={}, boundary={})",
+            self.0.graph().node_count(),
+            self.0.n_boundary_ports()
+        )
+    }
+}
+
+/// A set of diffs along with all their ancestors.
+#[pyclass(name = "PortDiffGraph", unsendable)]
+#[derive(Clone)]
+struct PyPortDiffGraph(PortDiffGraph<SimpleGraph>);
+
+#[pymethods]
+impl PyPortDiffGraph {
+    /// The graph of `sinks` and all their ancestors.
+    #[staticmethod]
+    fn from_sinks(sinks: Vec<PyPortDiff>) -> Self {
+        Self(PortDiffGraph::from_sinks(sinks.into_iter().map(|d| d.0)))
+    }
+
+    fn sinks(&self) -> Vec<PyPortDiff> {
+        self.0.sinks().map(PyPortDiff).collect()
+    }
+
+    fn roots(&self) -> Vec<PyPortDiff> {
+        self.0.roots().map(PyPortDiff).collect()
+    }
+
+    /// All diffs, in topological order.
+    fn diffs(&self) -> Vec<PyPortDiff> {
+        self.0
+            .all_nodes()
+            .map(|n| PyPortDiff(self.0.get_diff(n)))
+            .collect()
+    }
+
+    /// Squash all diffs into a single equivalent diff.
+    fn squash(&self) -> PyResult<PyPortDiff> {
+        self.0.try_squash().map(PyPortDiff).map_err(value_error)
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        serde_json::to_string(&self.0).map_err(value_error)
+    }
+
+    #[staticmethod]
+    fn from_json(json: &str) -> PyResult<Self> {
+        serde_json::from_str(json).map(Self).map_err(value_error)
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.all_nodes().count()
+    }
+}
+
+/// Extract the graph of a set of compatible diffs.
+#[pyfunction]
+fn extract_graph(diffs: Vec<PyPortDiff>) -> PyResult<PySimpleGraph> {
+    let diffs = diffs.into_iter().map(|d| d.0).collect();
+    PortDiff::extract_graph(diffs)
+        .map(PySimpleGraph)
+        .map_err(value_error)
+}
+
+/// Local graph rewriting with diff hierarchies.
+#[pymodule]
+fn pyportdiff(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySimpleGraph>()?;
+    m.add_class::<PyPortDiff>()?;
+    m.add_class::<PyPortDiffGraph>()?;
+    m.add_function(wrap_pyfunction!(extract_graph, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_conversion() {
+        for port in [SimplePort::In(0), SimplePort::Out(2)] {
+            assert_eq!(port_from_py(port_to_py(port)).unwrap(), port);
+        }
+        assert!(port_from_py(("up".to_string(), 0)).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Low-level access to the edges of the diff hierarchy.
+//!
+//! Diffs are stored as the nodes of a [`RelRc`] graph, in which every edge
+//! goes from a parent to one of its children and carries the [`EdgeData`] of
+//! the rewrite: the rewritten subgraph of the parent and the map from parent
+//! ports to boundary ports of the child. A child may have several incoming
+//! edges from the same parent, identified by their [`IncomingEdgeIndex`].
+//!
+//! This module exposes these edges as [`RawEdge`]s, along with conversions
+//! between [`PortDiff`] and the underlying [`RawDiff`]. It is intended for
+//! algorithms that need to walk the hierarchy edge by edge, such as custom
+//! squashing or serialization schemes. Most applications should use the
+//! methods of [`PortDiff`] and [`crate::PortDiffGraph`] instead.
+
+use derive_where::derive_where;
+use relrc::RelRc;
+
+use crate::{
+    port::BoundaryIndex,
+    port_diff::{EdgeData, IncomingEdgeIndex, PortDiffData},
+    Graph, Port, PortDiff,
+};
+
+/// The reference-counted node of the hierarchy underlying a [`PortDiff`].
+pub type RawDiff<G, M = ()> = RelRc<PortDiffData<G, M>, EdgeData<G>>;
+
+/// An edge of the diff hierarchy, from a parent to a child.
+///
+/// The edge is identified by its child and its index among the incoming
+/// edges of the child. It keeps the child, and thus the parent, alive.
+#[derive_where(Clone; G: Graph)]
+pub struct RawEdge<G: Graph, M = ()> {
+    child: PortDiff<G, M>,
+    index: IncomingEdgeIndex,
+}
+
+impl<G: Graph, M: Clone> RawEdge<G, M> {
+    /// The `index`-th incoming edge of `child`, if it exists.
+    pub fn new(child: PortDiff<G, M>, index: IncomingEdgeIndex) -> Option<Self> {
+        (usize::from(index) < child.all_incoming().len()).then_some(Self { child, index })
+    }
+
+    /// The parent the edge originates from.
+    pub fn parent(&self) -> PortDiff<G, M> {
+        self.child.all_incoming()[usize::from(self.index)]
+            .source()
+            .clone()
+            .into()
+    }
+
+    /// The child the edge points to.
+    pub fn child(&self) -> &PortDiff<G, M> {
+        &self.child
+    }
+
+    /// The index of the edge among the incoming edges of the child.
+    pub fn index(&self) -> IncomingEdgeIndex {
+        self.index
+    }
+
+    /// The data of the rewrite.
+    pub fn data(&self) -> &EdgeData<G> {
+        self.child.all_incoming()[usize::from(self.index)].value()
+    }
+
+    /// The boundary port of the child that the parent port `port` maps to,
+    /// if any.
+    pub fn map_to_child(&self, port: &Port<G>) -> Option<BoundaryIndex> {
+        self.data().boundary_port_map().get_by_left(port).copied()
+    }
+
+    /// The parent port that the boundary port `boundary` of the child maps
+    /// to, if any.
+    pub fn map_to_parent(&self, boundary: BoundaryIndex) -> Option<&Port<G>> {
+        self.data().boundary_port_map().get_by_right(&boundary)
+    }
+}
+
+impl<G: Graph, M: Clone> PortDiff<G, M> {
+    /// All incoming edges of `self`, in order of their index.
+    pub fn raw_incoming(&self) -> Vec<RawEdge<G, M>> {
+        (0..self.all_incoming().len())
+            .map(|i| RawEdge {
+                child: self.clone(),
+                index: i.into(),
+            })
+            .collect()
+    }
+
+    /// All outgoing edges of `self`.
+    ///
+    /// Edges are in the order of the children of `self`, and in order of
+    /// their index for every child.
+    pub fn raw_outgoing(&self) -> Vec<RawEdge<G, M>> {
+        self.all_outgoing()
+            .into_iter()
+            .map(|e| {
+                let child = PortDiff::from(e.target().clone());
+                // The edge data is stored once, in the child
+                let index = child
+                    .all_incoming()
+                    .iter()
+                    .position(|in_edge| std::ptr::eq(in_edge.value(), e.value()))
+                    .expect("outgoing edge is an incoming edge of its target");
+                RawEdge {
+                    child,
+                    index: index.into(),
+                }
+            })
+            .collect()
+    }
+
+    /// The underlying node of the hierarchy.
+    pub fn as_raw(&self) -> &RawDiff<G, M> {
+        &self.data
+    }
+
+    /// Convert into the underlying node of the hierarchy.
+    ///
+    /// The diff can be recovered with `PortDiff::from`.
+    pub fn into_raw(self) -> RawDiff<G, M> {
+        self.data
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_two_children_diffs, TestPortDiff};
+
+    use super::*;
+
+    #[rstest]
+    fn test_raw_edges(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        assert!(parent.raw_incoming().is_empty());
+
+        let outgoing = parent.raw_outgoing();
+        let children = outgoing.iter().map(|e| e.child().clone()).collect_vec();
+        assert_eq!(children, vec![child_1.clone(), child_2.clone()]);
+
+        for edge in &outgoing {
+            assert_eq!(edge.parent(), parent);
+            let incoming = edge.child().raw_incoming();
+            assert_eq!(incoming.len(), 1);
+            assert_eq!(incoming[0].index(), edge.index());
+            assert!(std::ptr::eq(incoming[0].data(), edge.data()));
+            for (port, &boundary) in edge.data().boundary_port_map() {
+                assert_eq!(edge.map_to_child(port), Some(boundary));
+                assert_eq!(edge.map_to_parent(boundary), Some(port));
+            }
+        }
+        assert!(RawEdge::new(child_1.clone(), 1.into()).is_none());
+
+        let raw = child_1.clone().into_raw();
+        assert_eq!(PortDiff::from(raw), child_1);
+        assert_eq!(PortDiff::from(child_2.as_raw().clone()), child_2);
+    }
+}
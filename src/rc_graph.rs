@@ -0,0 +1,337 @@
+//! A reference-counted wrapper for graphs, copied as a whole on write.
+//!
+//! Diffs own their replacement graphs, so that rewriting a large graph
+//! typically starts by cloning it. Wrapping the graph in an [`RcGraph`] makes
+//! clones cheap: the clones point to the same graph until one of them is
+//! modified. The first modification copies the whole graph; storage is not
+//! shared at a finer grain, so a clone that is modified is as large as a
+//! plain copy.
+//!
+//! A [`GraphInterner`] goes one step further and shares the storage of
+//! identical graphs created independently, e.g. the small replacement graphs
+//! that optimisation loops generate over and over.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use derive_where::derive_where;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    graph::GraphContent,
+    port::{BoundPort, EdgeEnd, Site},
+    AddNodes, ContentKey, DotGraph, Graph, NodeWeights, RemoveNodes, TypedGraph,
+};
+
+/// A graph behind a reference count, copied as a whole when a shared clone
+/// is modified.
+///
+/// Implements [`Graph`] by delegating to the wrapped graph. Read access is
+/// given through [`Deref`].
+#[derive_where(Clone; G: Graph)]
+#[derive_where(Debug; G: Graph + std::fmt::Debug)]
+pub struct RcGraph<G: Graph>(Rc<G>);
+
+impl<G: Graph> RcGraph<G> {
+    /// Wrap `graph`.
+    pub fn new(graph: G) -> Self {
+        Self(Rc::new(graph))
+    }
+
+    /// Unwrap the graph, copying it if it is shared.
+    pub fn into_inner(self) -> G {
+        Rc::try_unwrap(self.0).unwrap_or_else(|graph| (*graph).clone())
+    }
+
+    /// Whether `self` and `other` point to the same graph.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// A mutable reference to the graph, copying the whole graph if it is
+    /// shared.
+    pub fn make_mut(&mut self) -> &mut G {
+        Rc::make_mut(&mut self.0)
+    }
+}
+
+impl<G: Graph> Default for RcGraph<G> {
+    fn default() -> Self {
+        Self::new(G::default())
+    }
+}
+
+impl<G: Graph> From<G> for RcGraph<G> {
+    fn from(graph: G) -> Self {
+        Self::new(graph)
+    }
+}
+
+impl<G: Graph> Deref for RcGraph<G> {
+    type Target = G;
+
+    fn deref(&self) -> &G {
+        &self.0
+    }
+}
+
+impl<G: Graph> Graph for RcGraph<G> {
+    type Node = G::Node;
+
+    type Edge = G::Edge;
+
+    type PortLabel = G::PortLabel;
+
+    fn nodes_iter(&self) -> impl Iterator<Item = Self::Node> + '_ {
+        self.0.nodes_iter()
+    }
+
+    fn edges_iter(&self) -> impl Iterator<Item = Self::Edge> + '_ {
+        self.0.edges_iter()
+    }
+
+    fn get_port_site(
+        &self,
+        bound_port: BoundPort<Self::Edge>,
+    ) -> Site<Self::Node, Self::PortLabel> {
+        self.0.get_port_site(bound_port)
+    }
+
+    fn get_bound_ports(
+        &self,
+        site: Site<Self::Node, Self::PortLabel>,
+    ) -> impl Iterator<Item = BoundPort<Self::Edge>> + '_ {
+        self.0.get_bound_ports(site)
+    }
+
+    fn get_sites(
+        &self,
+        node: Self::Node,
+    ) -> impl Iterator<Item = Site<Self::Node, Self::PortLabel>> + '_ {
+        self.0.get_sites(node)
+    }
+
+    fn link_sites(
+        &mut self,
+        left: Site<Self::Node, Self::PortLabel>,
+        right: Site<Self::Node, Self::PortLabel>,
+    ) {
+        self.make_mut().link_sites(left, right)
+    }
+
+    fn link_sites_like(
+        &mut self,
+        left: Site<Self::Node, Self::PortLabel>,
+        right: Site<Self::Node, Self::PortLabel>,
+        graph: &Self,
+        edge: Self::Edge,
+    ) {
+        self.make_mut().link_sites_like(left, right, &graph.0, edge)
+    }
+
+    fn is_valid_end(&self, site: Site<Self::Node, Self::PortLabel>, end: EdgeEnd) -> bool {
+        self.0.is_valid_end(site, end)
+    }
+
+    fn add_subgraph(
+        &mut self,
+        graph: &Self,
+        nodes: &BTreeSet<Self::Node>,
+    ) -> BTreeMap<Self::Node, Self::Node> {
+        self.make_mut().add_subgraph(&graph.0, nodes)
+    }
+}
+
+impl<G: ContentKey> ContentKey for RcGraph<G> {
+    type NodeKey = G::NodeKey;
+    type EdgeKey = G::EdgeKey;
+
+    fn node_key(&self, node: Self::Node) -> Self::NodeKey {
+        self.0.node_key(node)
+    }
+
+    fn edge_key(&self, edge: Self::Edge) -> Self::EdgeKey {
+        self.0.edge_key(edge)
+    }
+}
+
+impl<G: NodeWeights> NodeWeights for RcGraph<G> {
+    type NodeWeight = G::NodeWeight;
+
+    fn set_node_weight(&mut self, node: Self::Node, weight: Self::NodeWeight) {
+        self.make_mut().set_node_weight(node, weight)
+    }
+}
+
+impl<G: AddNodes> AddNodes for RcGraph<G> {
+    fn add_node_with_sites(
+        &mut self,
+        sites: impl IntoIterator<Item = Self::PortLabel>,
+    ) -> Self::Node {
+        self.make_mut().add_node_with_sites(sites)
+    }
+}
+
+impl<G: TypedGraph> TypedGraph for RcGraph<G> {
+    type PortType = G::PortType;
+
+    fn port_type(&self, site: Site<Self::Node, Self::PortLabel>) -> Self::PortType {
+        self.0.port_type(site)
+    }
+}
+
+impl<G: RemoveNodes> RemoveNodes for RcGraph<G> {
+    fn remove_node(&mut self, node: Self::Node) {
+        self.make_mut().remove_node(node)
+    }
+}
+
+impl<G: DotGraph> DotGraph for RcGraph<G> {
+    fn dot_node_label(&self, node: Self::Node) -> String {
+        self.0.dot_node_label(node)
+    }
+}
+
+/// Hash-consing of graphs, so that identical graphs share their storage.
+///
+/// Graphs are keyed by their content: two graphs are identical if they have
+/// the same nodes, sites and links, and the same node and edge weights, see
+/// [`ContentKey`]. This is exact identity, not equivalence up to isomorphism:
+/// graphs that only differ by the numbering of their nodes are not shared.
+/// Interning a graph returns an [`RcGraph`] that shares its storage with all
+/// previously interned identical graphs.
+///
+/// The edge identifiers of the returned graph may differ from those of the
+/// interned graph, but nodes and sites are preserved, so that boundary maps
+/// can be built before interning.
+#[derive_where(Default; G: ContentKey)]
+pub struct GraphInterner<G: ContentKey> {
+    graphs: BTreeMap<GraphContent<G>, RcGraph<G>>,
+    /// The number of calls to [`GraphInterner::intern`].
+    n_interned: usize,
+}
+
+impl<G: ContentKey> GraphInterner<G> {
+    /// Create an empty interner.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The shared copy of `graph`.
+    pub fn intern(&mut self, graph: G) -> RcGraph<G> {
+        self.n_interned += 1;
+        self.graphs
+            .entry(GraphContent::new(&graph))
+            .or_insert_with(|| RcGraph::new(graph))
+            .clone()
+    }
+
+    /// The number of distinct graphs in the interner.
+    pub fn len(&self) -> usize {
+        self.graphs.len()
+    }
+
+    /// Whether the interner is empty.
+    pub fn is_empty(&self) -> bool {
+        self.graphs.is_empty()
+    }
+
+    /// The number of graphs interned so far, counting duplicates.
+    pub fn n_interned(&self) -> usize {
+        self.n_interned
+    }
+
+    /// Drop the graphs that are not used outside of the interner.
+    pub fn purge(&mut self) {
+        self.graphs
+            .retain(|_, graph| Rc::strong_count(&graph.0) > 1);
+    }
+}
+
+impl<G: Graph + Serialize> Serialize for RcGraph<G> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, G: Graph + Deserialize<'de>> Deserialize<'de> for RcGraph<G> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        G::deserialize(deserializer).map(Self::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use itertools::Itertools;
+
+    use crate::{
+        port_diff::Owned,
+        simple_graph::{SimpleGraph, SimpleNode},
+        PortDiff,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_rc_graph() {
+        let graph = RcGraph::new(SimpleGraph::linear_chain(3));
+        let root = PortDiff::from_graph(graph);
+
+        // Rewrite a node into a copy of the whole graph, without copying it
+        let copy = root.graph().clone();
+        assert!(copy.ptr_eq(root.graph()));
+        let b = root.graph().nodes_iter().nth(1).unwrap();
+        let child = root
+            .rewrite_induced(&BTreeSet::from([b]), copy, |p| {
+                let site: Site<SimpleNode, _> = Owned::new(p, root.clone()).site().unwrap();
+                site.into()
+            })
+            .unwrap();
+        assert!(child.graph().ptr_eq(root.graph()));
+
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(extracted.node_count(), 5);
+        assert!(!extracted.ptr_eq(root.graph()));
+        assert_eq!(root.graph().node_count(), 3);
+    }
+
+    #[test]
+    fn test_graph_interner() {
+        let mut interner = GraphInterner::new();
+        let graphs = (0..100)
+            .map(|_| interner.intern(SimpleGraph::linear_chain(2)))
+            .collect_vec();
+        let other = interner.intern(SimpleGraph::linear_chain(3));
+        assert!(graphs.iter().all(|g| g.ptr_eq(&graphs[0])));
+        assert!(!other.ptr_eq(&graphs[0]));
+        assert_eq!(interner.len(), 2);
+        assert_eq!(interner.n_interned(), 101);
+
+        drop(graphs);
+        interner.purge();
+        assert_eq!(interner.len(), 1);
+        assert!(interner.intern(SimpleGraph::linear_chain(3)).ptr_eq(&other));
+    }
+
+    #[cfg(feature = "petgraph")]
+    #[test]
+    fn test_graph_interner_weights() {
+        use petgraph::stable_graph::StableGraph;
+
+        let graph = |weight: u32| {
+            let mut graph = StableGraph::<u32, u32>::new();
+            let a = graph.add_node(weight);
+            let b = graph.add_node(0);
+            graph.add_edge(a, b, weight);
+            graph
+        };
+        let mut interner = GraphInterner::new();
+        let shared = interner.intern(graph(1));
+        assert!(interner.intern(graph(1)).ptr_eq(&shared));
+        assert!(!interner.intern(graph(2)).ptr_eq(&shared));
+        assert_eq!(interner.len(), 2);
+    }
+}
@@ -0,0 +1,194 @@
+//! Rewrite rules.
+//!
+//! A [`RewriteRule`] bundles a pattern, its replacement and the
+//! correspondence between their boundaries, so that rewrites can be defined
+//! declaratively and applied to the matches of the pattern, see
+//! [`find_induced_matches`].
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+use derive_where::derive_where;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    matching::{find_induced_matches, Match},
+    port_diff::Owned,
+    BoundarySite, EdgeEnd, Graph, InvalidRewriteError, NodeId, PortDiff, PortDiffGraph, Site,
+};
+
+/// Error when applying a rewrite rule.
+#[derive(Error)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::Edge: Debug)]
+pub enum RuleError<G: Graph, M = ()> {
+    /// A matched node is not in the graph of the diff.
+    #[error("the match is not in the graph of the diff")]
+    MatchNotInDiff,
+    /// A boundary port of the match has no image in the replacement graph.
+    #[error("a boundary port of the match has no image in the replacement graph")]
+    UnmappedBoundary,
+    /// The rewrite is invalid.
+    #[error("invalid rewrite: {0}")]
+    InvalidRewrite(#[from] InvalidRewriteError<G, M>),
+}
+
+/// A rule that replaces occurrences of a pattern with a replacement graph.
+///
+/// Every site of the pattern that may be on the boundary of a match is
+/// mapped to a site of the replacement graph, or to a wire.
+#[derive(Serialize, Deserialize)]
+#[derive_where(Clone; G: Graph)]
+#[serde(bound(
+    serialize = "G: Serialize, G::Node: Serialize, G::PortLabel: Serialize",
+    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>"
+))]
+pub struct RewriteRule<G: Graph> {
+    lhs: G,
+    rhs: G,
+    /// The image of the sites of `lhs`.
+    boundary: Vec<(Site<G::Node, G::PortLabel>, BoundarySite<G>)>,
+}
+
+impl<G: Graph> RewriteRule<G> {
+    /// A rule that replaces `lhs` with `rhs`.
+    ///
+    /// `boundary` maps the sites of `lhs` to their image in `rhs`.
+    pub fn new(
+        lhs: G,
+        rhs: G,
+        boundary: impl IntoIterator<Item = (Site<G::Node, G::PortLabel>, BoundarySite<G>)>,
+    ) -> Self {
+        Self {
+            lhs,
+            rhs,
+            boundary: boundary.into_iter().collect(),
+        }
+    }
+
+    /// The pattern of the rule.
+    pub fn lhs(&self) -> &G {
+        &self.lhs
+    }
+
+    /// The replacement graph of the rule.
+    pub fn rhs(&self) -> &G {
+        &self.rhs
+    }
+
+    /// The image in the replacement graph of the pattern site `site`.
+    pub fn boundary_site(&self, site: &Site<G::Node, G::PortLabel>) -> Option<&BoundarySite<G>> {
+        self.boundary
+            .iter()
+            .find(|(s, _)| s == site)
+            .map(|(_, b)| b)
+    }
+
+    /// Find all matches of the pattern in the graphs of `diffs`.
+    ///
+    /// See [`find_induced_matches`].
+    pub fn find_matches<M: Clone>(&self, diffs: &[PortDiff<G, M>]) -> Vec<Match<G, M>> {
+        find_induced_matches(&self.lhs, diffs)
+    }
+
+    /// Apply the rule at `match_`, a match of the pattern in the graph of
+    /// `diff`.
+    ///
+    /// The matched nodes must belong to `diff` or its ancestors. Returns the
+    /// new diff, a child of the owners of the matched nodes.
+    pub fn apply_at<M: Clone + Default>(
+        &self,
+        diff: &PortDiff<G, M>,
+        match_: &Match<G, M>,
+    ) -> Result<PortDiff<G, M>, RuleError<G, M>> {
+        let ancestors = PortDiffGraph::from_sinks([diff.clone()]);
+        if !match_
+            .nodes
+            .values()
+            .all(|n| ancestors.contains(NodeId::from(&n.owner)))
+        {
+            return Err(RuleError::MatchNotInDiff);
+        }
+
+        let pattern_nodes: BTreeMap<_, _> = match_
+            .nodes
+            .iter()
+            .map(|(&pattern_node, node)| (node.clone(), pattern_node))
+            .collect();
+        let mut unmapped = false;
+        let diff = match_.rewrite(self.rhs.clone(), |port| {
+            let boundary = port.site().and_then(|site| {
+                let node = Owned::new(site.node, port.owner.clone());
+                let pattern_site = Site {
+                    node: *pattern_nodes.get(&node)?,
+                    port: site.port,
+                };
+                self.boundary_site(&pattern_site).cloned()
+            });
+            boundary.unwrap_or_else(|| {
+                // The diff is discarded below
+                unmapped = true;
+                BoundarySite::wire(usize::MAX, EdgeEnd::Left)
+            })
+        });
+        // Checked first, as repeated sentinel wires are rejected by `rewrite`
+        if unmapped {
+            return Err(RuleError::UnmappedBoundary);
+        }
+        Ok(diff?)
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkView, PortGraph, PortMut, PortOffset, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{parent_child_diffs, TestPortDiff};
+
+    use super::*;
+
+    /// A rule that replaces a node with 3 inputs and 1 output by a copy.
+    fn copy_rule() -> RewriteRule<PortGraph> {
+        let mut lhs = PortGraph::new();
+        let lhs_node = lhs.add_node(3, 1);
+        let mut rhs = PortGraph::new();
+        let rhs_node = rhs.add_node(3, 1);
+        let ports = (0..3)
+            .map(PortOffset::Incoming)
+            .chain([PortOffset::Outgoing(0)]);
+        let boundary = ports.map(|port| {
+            let lhs_site = Site {
+                node: lhs_node,
+                port,
+            };
+            let rhs_site = Site {
+                node: rhs_node,
+                port,
+            };
+            (lhs_site, rhs_site.into())
+        });
+        RewriteRule::new(lhs, rhs, boundary)
+    }
+
+    #[rstest]
+    fn test_apply_rule(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let rule = copy_rule();
+
+        let matches = rule.find_matches(&[parent.clone()]);
+        assert_eq!(matches.len(), 1);
+        let new_diff = rule.apply_at(&parent, &matches[0]).unwrap();
+        let graph = PortDiff::extract_graph(vec![new_diff]).unwrap();
+        assert_eq!(graph.node_count(), parent.graph().node_count());
+        assert_eq!(graph.link_count(), parent.graph().link_count());
+
+        // The child does not descend from the new diff
+        let new_diff = rule.apply_at(&parent, &matches[0]).unwrap();
+        assert!(matches!(
+            rule.apply_at(&child, &rule.find_matches(&[new_diff])[0]),
+            Err(RuleError::MatchNotInDiff)
+        ));
+    }
+}
@@ -0,0 +1,592 @@
+//! A session of interactive rewriting.
+//!
+//! A [`Session`] keeps track of all diffs committed so far, identifies them
+//! with stable [`DiffId`]s and maintains a selection of compatible diffs,
+//! the graph of which is the current graph. This is the state machine that
+//! embedders such as the viewer need: commit diffs, select some of them,
+//! rewrite the current graph and extract it.
+//!
+//! Sessions can also work on named branches, see [`Session::switch`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+use derive_more::{From, Into};
+use derive_where::derive_where;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    graph_view::{BranchError, MergeStrategy, StableFormatError, StablePortDiffGraph},
+    port_diff::{Owned, SquashError},
+    BoundarySite, ContentKey, Graph, InvalidRewriteError, NodeId, Port, PortDiff, PortDiffGraph,
+};
+
+/// The identifier of a diff in a [`Session`].
+///
+/// Identifiers are assigned in the order in which diffs are added to the
+/// session, starting from 0.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, From, Into, Serialize, Deserialize,
+)]
+pub struct DiffId(usize);
+
+/// Error in a [`Session`].
+#[derive(Error)]
+#[derive_where(Debug; G: Graph, G::Node: Debug, G::Edge: Debug)]
+pub enum SessionError<G: Graph, M = ()> {
+    /// The diff is not in the session.
+    #[error("unknown diff {0:?}")]
+    UnknownDiff(DiffId),
+    /// The selected diffs are not compatible.
+    #[error("the selected diffs are not compatible")]
+    IncompatibleSelection,
+    /// The rewrite is invalid.
+    #[error("invalid rewrite: {0}")]
+    InvalidRewrite(#[from] InvalidRewriteError<G, M>),
+    /// The new diff could not be flattened.
+    #[error("cannot flatten the new diff: {0}")]
+    Flatten(#[from] SquashError),
+    /// The diffs of a saved state could not be loaded.
+    #[error("cannot load the saved diffs: {0}")]
+    Load(#[from] StableFormatError),
+    /// The IDs of a saved state do not match its diffs.
+    #[error("the saved IDs do not match the saved diffs")]
+    InvalidState,
+    /// A branch could not be created or switched to.
+    #[error("invalid branch: {0}")]
+    Branch(#[from] BranchError),
+}
+
+/// When to flatten the diffs created by a [`Session`].
+///
+/// See [`Session::set_flatten_policy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlattenPolicy {
+    /// The largest depth of a new diff that is not flattened.
+    pub max_depth: usize,
+    /// The number of ancestors to flatten, see [`PortDiff::flatten_ancestors`].
+    pub depth: usize,
+}
+
+/// The state of a [`Session`], in a serializable form.
+///
+/// Obtained with [`Session::snapshot`] and restored with
+/// [`Session::restore`]. The diffs are stored in the stable serialization
+/// format, see [`StablePortDiffGraph`], so that a state can be saved to disk
+/// and loaded in another process.
+#[derive(Serialize, Deserialize)]
+#[derive_where(Clone; G: Graph, M: Clone)]
+#[serde(bound(
+    serialize = "G: Serialize, G::Node: Serialize, G::PortLabel: Serialize, G::Edge: Serialize, M: Serialize",
+    deserialize = "G: Deserialize<'de>, G::Node: Deserialize<'de>, G::PortLabel: Deserialize<'de>, G::Edge: Deserialize<'de>, M: Deserialize<'de>"
+))]
+pub struct SessionState<G: Graph, M = ()> {
+    diffs: StablePortDiffGraph<G, M>,
+    /// The position in `diffs` of the diff with every ID.
+    ids: Vec<usize>,
+    selected: BTreeSet<DiffId>,
+    flatten_policy: Option<FlattenPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current_branch: Option<String>,
+}
+
+/// All diffs of a rewriting session, along with the selected diffs.
+///
+/// Every diff added to the session, either explicitly with [`Self::commit`]
+/// or as an ancestor of a committed diff, is assigned a [`DiffId`].
+///
+/// While the session is on a branch, the tips of the branch follow the
+/// selection, see [`Self::switch`].
+#[derive_where(Clone, Default; G: Graph)]
+pub struct Session<G: Graph, M = ()> {
+    diffs: PortDiffGraph<G, M>,
+    ids: Vec<NodeId<G, M>>,
+    id_map: BTreeMap<NodeId<G, M>, DiffId>,
+    selected: BTreeSet<DiffId>,
+    flatten_policy: Option<FlattenPolicy>,
+    current_branch: Option<String>,
+}
+
+impl<G: Graph, M: Clone> Session<G, M> {
+    /// A new session starting from `graph`.
+    ///
+    /// The root diff of `graph` is selected and has ID 0.
+    pub fn new(graph: G) -> Self
+    where
+        M: Default,
+    {
+        Self::from_diffs(PortDiffGraph::from_sinks([PortDiff::from_graph(graph)]))
+    }
+
+    /// A new session with all diffs of `diffs`.
+    ///
+    /// The sinks of `diffs` are selected.
+    pub fn from_diffs(diffs: PortDiffGraph<G, M>) -> Self {
+        let mut session = Self::default();
+        let sinks = diffs.sinks().collect::<Vec<_>>();
+        session.diffs = diffs;
+        session.assign_ids();
+        session.selected = sinks
+            .iter()
+            .map(|d| session.id_map[&NodeId::from(d)])
+            .collect();
+        session
+    }
+
+    /// Add `diff` and all its ancestors to the session.
+    ///
+    /// Returns the ID of `diff`. If `diff` was already in the session, its
+    /// existing ID is returned. The selection is unchanged.
+    pub fn commit(&mut self, diff: PortDiff<G, M>) -> DiffId {
+        if let Some(&id) = self.id_map.get(&NodeId::from(&diff)) {
+            return id;
+        }
+        let id = NodeId::from(&diff);
+        self.diffs
+            .merge(
+                PortDiffGraph::from_sinks([diff]),
+                MergeStrategy::IgnoreConflicts,
+            )
+            .expect("merge never fails when ignoring conflicts");
+        self.assign_ids();
+        self.id_map[&id]
+    }
+
+    /// Replace the selection with `ids`.
+    ///
+    /// Fails if an ID is unknown or the diffs are not compatible, in which
+    /// case the selection is unchanged.
+    pub fn select(
+        &mut self,
+        ids: impl IntoIterator<Item = DiffId>,
+    ) -> Result<(), SessionError<G, M>> {
+        let ids: BTreeSet<DiffId> = ids.into_iter().collect();
+        let diffs = ids
+            .iter()
+            .map(|&id| self.diff(id).ok_or(SessionError::UnknownDiff(id)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !PortDiff::are_compatible(&diffs) {
+            return Err(SessionError::IncompatibleSelection);
+        }
+        self.selected = ids;
+        self.update_branch();
+        Ok(())
+    }
+
+    /// Rewrite `nodes` and `edges` of the current graph to `new_graph`.
+    ///
+    /// The nodes and edges must belong to selected diffs or their ancestors,
+    /// see [`PortDiff::rewrite`]. The new diff is committed and selected in
+    /// place of its parents. Returns the ID of the new diff.
+    ///
+    /// If the depth of the new diff exceeds the maximum depth of the flatten
+    /// policy, its nearest ancestors are flattened into it before it is
+    /// committed, see [`Self::set_flatten_policy`].
+    pub fn rewrite(
+        &mut self,
+        nodes: impl IntoIterator<Item = Owned<G::Node, G, M>>,
+        edges: impl IntoIterator<Item = (Owned<Port<G>, G, M>, Owned<Port<G>, G, M>)>,
+        new_graph: G,
+        boundary_map: impl FnMut(Owned<Port<G>, G, M>) -> BoundarySite<G>,
+    ) -> Result<DiffId, SessionError<G, M>>
+    where
+        M: Default,
+    {
+        let mut diff = PortDiff::rewrite(nodes, edges, new_graph, boundary_map)?;
+        let parents: Vec<_> = diff.all_parents().collect();
+        if let Some(policy) = self.flatten_policy {
+            if diff.stats().depth > policy.max_depth {
+                diff = diff.flatten_ancestors(policy.depth)?;
+            }
+        }
+        let id = self.commit(diff);
+        for parent in &parents {
+            if let Some(parent_id) = self.id_map.get(&NodeId::from(parent)) {
+                self.selected.remove(parent_id);
+            }
+        }
+        self.selected.insert(id);
+        self.update_branch();
+        Ok(id)
+    }
+
+    /// Set the policy to flatten new diffs, or `None` to never flatten them.
+    ///
+    /// Flattening caps the depth of the diff hierarchy, so that resolving
+    /// ports and checking compatibility remain fast after many rewrites. It
+    /// only applies to diffs created with [`Self::rewrite`].
+    pub fn set_flatten_policy(&mut self, policy: Option<FlattenPolicy>) {
+        self.flatten_policy = policy;
+    }
+
+    /// The policy to flatten new diffs.
+    pub fn flatten_policy(&self) -> Option<FlattenPolicy> {
+        self.flatten_policy
+    }
+
+    /// The graph of the selected diffs.
+    pub fn current_graph(&self) -> Result<G, SquashError> {
+        PortDiff::extract_graph(self.selected_diffs().collect())
+    }
+
+    /// The graph of the selected diffs, along with the diff that introduced
+    /// each of its nodes.
+    pub fn current_graph_with_origins(
+        &self,
+    ) -> Result<(G, BTreeMap<G::Node, DiffId>), SquashError> {
+        let (graph, provenance) =
+            PortDiff::extract_graph_with_provenance(self.selected_diffs().collect())?;
+        let origins = provenance
+            .into_iter()
+            .map(|(node, diff)| (node, self.id_map[&NodeId::from(&diff)]))
+            .collect();
+        Ok((graph, origins))
+    }
+
+    /// The IDs of the selected diffs.
+    pub fn selected(&self) -> &BTreeSet<DiffId> {
+        &self.selected
+    }
+
+    /// The selected diffs.
+    pub fn selected_diffs(&self) -> impl Iterator<Item = PortDiff<G, M>> + '_ {
+        self.selected
+            .iter()
+            .map(|&id| self.diffs.get_diff(self.ids[id.0]))
+    }
+
+    /// The diff with ID `id`, if it is in the session.
+    pub fn diff(&self, id: DiffId) -> Option<PortDiff<G, M>> {
+        let &node = self.ids.get(id.0)?;
+        Some(self.diffs.get_diff(node))
+    }
+
+    /// The ID of `diff`, if it is in the session.
+    pub fn id(&self, diff: &PortDiff<G, M>) -> Option<DiffId> {
+        self.id_map.get(&NodeId::from(diff)).copied()
+    }
+
+    /// The IDs of all diffs in the session, in increasing order.
+    pub fn ids(&self) -> impl Iterator<Item = DiffId> {
+        (0..self.ids.len()).map(DiffId)
+    }
+
+    /// All diffs in the session.
+    pub fn diffs(&self) -> &PortDiffGraph<G, M> {
+        &self.diffs
+    }
+
+    /// Create a branch `name` with the diff `base` as its tip.
+    ///
+    /// The current branch and the selection are unchanged.
+    pub fn create_branch(
+        &mut self,
+        name: impl Into<String>,
+        base: DiffId,
+    ) -> Result<(), SessionError<G, M>> {
+        let base = self.diff(base).ok_or(SessionError::UnknownDiff(base))?;
+        self.diffs.create_branch(name, &base)?;
+        Ok(())
+    }
+
+    /// Switch to the branch `name`, selecting its tips.
+    ///
+    /// Until the next switch, the tips of the branch are updated whenever
+    /// the selection changes, i.e. on [`Self::select`] and [`Self::rewrite`].
+    pub fn switch(&mut self, name: &str) -> Result<(), SessionError<G, M>> {
+        let tips = self
+            .diffs
+            .branch(name)
+            .ok_or_else(|| BranchError::UnknownBranch(name.to_string()))?;
+        self.selected = tips
+            .iter()
+            .map(|tip| self.id_map[&NodeId::from(tip)])
+            .collect();
+        self.current_branch = Some(name.to_string());
+        Ok(())
+    }
+
+    /// The branch the session is on, if any.
+    pub fn current_branch(&self) -> Option<&str> {
+        self.current_branch.as_deref()
+    }
+
+    /// Capture the diffs, their IDs, the selection, the flatten policy and
+    /// the current branch.
+    ///
+    /// Restoring the state with [`Self::restore`] yields a session with the
+    /// same IDs, so that IDs held by the embedder remain valid.
+    pub fn snapshot(&self) -> SessionState<G, M>
+    where
+        G: ContentKey,
+        M: Ord,
+    {
+        let (diffs, indices) = self.diffs.to_stable_with_indices();
+        let ids = self.ids.iter().map(|n| indices[n]).collect();
+        SessionState {
+            diffs,
+            ids,
+            selected: self.selected.clone(),
+            flatten_policy: self.flatten_policy,
+            current_branch: self.current_branch.clone(),
+        }
+    }
+
+    /// Replace the state of the session with `state`.
+    ///
+    /// Fails if the state is invalid, in which case the session is unchanged.
+    pub fn restore(&mut self, state: SessionState<G, M>) -> Result<(), SessionError<G, M>> {
+        let SessionState {
+            diffs,
+            ids,
+            selected,
+            flatten_policy,
+            current_branch,
+        } = state;
+        let (diffs, all_diffs) = PortDiffGraph::from_stable_with_diffs(diffs)?;
+        let mut session = Self {
+            diffs,
+            flatten_policy,
+            ..Self::default()
+        };
+        for index in ids {
+            let diff = all_diffs.get(index).ok_or(SessionError::InvalidState)?;
+            let node = NodeId::from(diff);
+            let id = DiffId(session.ids.len());
+            if session.id_map.insert(node, id).is_some() {
+                return Err(SessionError::InvalidState);
+            }
+            session.ids.push(node);
+        }
+        if session.ids.len() != all_diffs.len() {
+            return Err(SessionError::InvalidState);
+        }
+        if let Some(&id) = selected.iter().find(|id| id.0 >= session.ids.len()) {
+            return Err(SessionError::UnknownDiff(id));
+        }
+        session.selected = selected;
+        if let Some(name) = &current_branch {
+            if session.diffs.branch(name).is_none() {
+                return Err(BranchError::UnknownBranch(name.clone()).into());
+            }
+        }
+        session.current_branch = current_branch;
+        *self = session;
+        Ok(())
+    }
+
+    /// Move the tips of the current branch, if any, to the selection.
+    fn update_branch(&mut self) {
+        if let Some(name) = &self.current_branch {
+            let tips = self.selected_diffs().collect::<Vec<_>>();
+            self.diffs
+                .set_branch_tips(name, tips)
+                .expect("the selection of a session is compatible");
+        }
+    }
+
+    /// Assign IDs to the diffs that do not have one yet.
+    fn assign_ids(&mut self) {
+        let new_nodes = self
+            .diffs
+            .all_nodes()
+            .filter(|n| !self.id_map.contains_key(n))
+            .collect::<Vec<_>>();
+        for node in new_nodes {
+            self.id_map.insert(node, DiffId(self.ids.len()));
+            self.ids.push(node);
+        }
+    }
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use portgraph::{LinkView, PortGraph, PortMut, PortView};
+    use rstest::rstest;
+
+    use crate::port_diff::tests::{
+        parent_child_diffs, parent_two_children_diffs, parent_two_children_overlapping_diffs,
+        TestPortDiff,
+    };
+    use crate::Site;
+
+    use super::*;
+
+    #[rstest]
+    fn test_session(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let mut session = Session::from_diffs(PortDiffGraph::from_sinks([parent.clone()]));
+        let parent_id = session.id(&parent).unwrap();
+        assert_eq!(session.selected(), &BTreeSet::from([parent_id]));
+
+        let child_id = session.commit(child.clone());
+        assert_eq!(session.commit(child.clone()), child_id);
+        assert_eq!(session.ids().count(), 2);
+        assert!(session.diff(child_id).unwrap() == child);
+
+        session.select([child_id]).unwrap();
+        let expected = PortDiff::extract_graph(vec![child]).unwrap();
+        let (graph, origins) = session.current_graph_with_origins().unwrap();
+        assert_eq!(graph.node_count(), expected.node_count());
+        assert_eq!(graph.link_count(), expected.link_count());
+        assert_eq!(origins.len(), graph.node_count());
+        assert!(origins.values().any(|&id| id == child_id));
+        assert!(origins.values().any(|&id| id == parent_id));
+
+        // Replace a node of the child with a copy
+        let node = PortView::nodes_iter(child.graph()).next().unwrap();
+        let mut new_graph = PortGraph::new();
+        let new_node = new_graph.add_node(
+            child.graph().num_inputs(node),
+            child.graph().num_outputs(node),
+        );
+        let new_id = session
+            .rewrite([Owned::new(node, child.clone())], [], new_graph, |p| {
+                let site = Owned::new(p, child.clone()).site().unwrap();
+                Site {
+                    node: new_node,
+                    port: site.port,
+                }
+                .into()
+            })
+            .unwrap();
+        assert_eq!(session.selected(), &BTreeSet::from([new_id]));
+        assert_eq!(
+            session.current_graph().unwrap().node_count(),
+            expected.node_count()
+        );
+    }
+
+    #[rstest]
+    fn test_session_flatten(parent_child_diffs: [TestPortDiff; 2]) {
+        let [parent, child] = parent_child_diffs;
+        let mut session = Session::from_diffs(PortDiffGraph::from_sinks([child.clone()]));
+        session.set_flatten_policy(Some(FlattenPolicy {
+            max_depth: 1,
+            depth: 1,
+        }));
+
+        // Replace a node of the child with a copy
+        let node = PortView::nodes_iter(child.graph()).next().unwrap();
+        let mut new_graph = PortGraph::new();
+        let new_node = new_graph.add_node(
+            child.graph().num_inputs(node),
+            child.graph().num_outputs(node),
+        );
+        let new_id = session
+            .rewrite([Owned::new(node, child.clone())], [], new_graph, |p| {
+                let site = Owned::new(p, child.clone()).site().unwrap();
+                Site {
+                    node: new_node,
+                    port: site.port,
+                }
+                .into()
+            })
+            .unwrap();
+        let new_diff = session.diff(new_id).unwrap();
+        assert_eq!(new_diff.stats().depth, 1);
+        assert!(new_diff.all_parents().all(|p| p == parent));
+        assert_eq!(session.selected(), &BTreeSet::from([new_id]));
+        assert_eq!(
+            session.current_graph().unwrap().node_count(),
+            PortDiff::extract_graph(vec![child]).unwrap().node_count()
+        );
+    }
+
+    #[rstest]
+    fn test_session_snapshot(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        let mut session = Session::from_diffs(PortDiffGraph::from_sinks([parent]));
+        let id_2 = session.commit(child_2);
+        let id_1 = session.commit(child_1);
+        session.select([id_1]).unwrap();
+        session.set_flatten_policy(Some(FlattenPolicy {
+            max_depth: 3,
+            depth: 2,
+        }));
+        let state = session.snapshot();
+
+        let serialized = serde_json::to_string(&state).unwrap();
+        let state: SessionState<PortGraph> = serde_json::from_str(&serialized).unwrap();
+        session.select([id_2]).unwrap();
+        session.set_flatten_policy(None);
+        let expected = session.diff(id_1).unwrap();
+
+        let mut restored = Session::new(PortGraph::new());
+        restored.restore(state).unwrap();
+        assert_eq!(restored.ids().count(), 3);
+        assert_eq!(restored.selected(), &BTreeSet::from([id_1]));
+        assert_eq!(
+            restored.flatten_policy(),
+            Some(FlattenPolicy {
+                max_depth: 3,
+                depth: 2,
+            })
+        );
+        assert_eq!(
+            restored.diff(id_1).unwrap().graph().node_count(),
+            expected.graph().node_count()
+        );
+        assert_eq!(
+            restored.current_graph().unwrap().node_count(),
+            PortDiff::extract_graph(vec![expected])
+                .unwrap()
+                .node_count()
+        );
+        assert_eq!(
+            serde_json::to_string(&restored.snapshot()).unwrap(),
+            serialized
+        );
+    }
+
+    #[rstest]
+    fn test_session_branches(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [parent, child_1, child_2] = parent_two_children_diffs;
+        let mut session = Session::from_diffs(PortDiffGraph::from_sinks([parent]));
+        let id_1 = session.commit(child_1);
+        let id_2 = session.commit(child_2);
+        session.create_branch("first", id_1).unwrap();
+        session.create_branch("second", id_2).unwrap();
+        assert!(matches!(
+            session.create_branch("first", id_2),
+            Err(SessionError::Branch(BranchError::DuplicateBranch(_)))
+        ));
+        assert_eq!(session.current_branch(), None);
+
+        session.switch("first").unwrap();
+        assert_eq!(session.current_branch(), Some("first"));
+        assert_eq!(session.selected(), &BTreeSet::from([id_1]));
+        session.select([id_1, id_2]).unwrap();
+        assert_eq!(session.diffs().branch("first").unwrap().len(), 2);
+
+        session.switch("second").unwrap();
+        assert_eq!(session.selected(), &BTreeSet::from([id_2]));
+        assert!(session.switch("third").is_err());
+        assert_eq!(session.current_branch(), Some("second"));
+
+        let mut restored = Session::new(PortGraph::new());
+        restored.restore(session.snapshot()).unwrap();
+        assert_eq!(restored.current_branch(), Some("second"));
+        restored.switch("first").unwrap();
+        assert_eq!(restored.selected(), &BTreeSet::from([id_1, id_2]));
+    }
+
+    #[rstest]
+    fn test_session_select_incompatible(parent_two_children_overlapping_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_overlapping_diffs;
+        let mut session = Session::from_diffs(PortDiffGraph::from_sinks([child_1.clone()]));
+        let id_1 = session.id(&child_1).unwrap();
+        let id_2 = session.commit(child_2);
+        assert!(matches!(
+            session.select([id_1, id_2]),
+            Err(SessionError::IncompatibleSelection)
+        ));
+        assert!(matches!(
+            session.select([DiffId(10)]),
+            Err(SessionError::UnknownDiff(DiffId(10)))
+        ));
+        assert_eq!(session.selected(), &BTreeSet::from([id_1]));
+    }
+}
@@ -0,0 +1,541 @@
+//! A small reference implementation of [`Graph`].
+//!
+//! [`SimpleGraph`] is a directed graph stored as adjacency lists, with
+//! ordered input and output ports on every node. It has no dependencies and
+//! is meant for tests and examples, e.g. to test adapters without depending
+//! on `portgraph`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    gen::SplitMix64,
+    port::{BoundPort, EdgeEnd, Site},
+    port_diff::Owned,
+    AddNodes, BoundarySite, ContentKey, DotGraph, Graph, InvalidRewriteError, PortDiff,
+    WireAllocator,
+};
+
+/// A node of a [`SimpleGraph`].
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub struct SimpleNode(usize);
+
+impl SimpleNode {
+    /// The node at `index`.
+    ///
+    /// Nodes are numbered in the order they are added, starting from 0.
+    pub fn new(index: usize) -> Self {
+        Self(index)
+    }
+
+    /// The index of the node.
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// An edge of a [`SimpleGraph`].
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub struct SimpleEdge(usize);
+
+impl SimpleEdge {
+    /// The index of the edge.
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// A port of a node of a [`SimpleGraph`].
+///
+/// Edges go from an output port (their left end) to an input port (their
+/// right end).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum SimplePort {
+    /// The input port at the given offset.
+    In(usize),
+    /// The output port at the given offset.
+    Out(usize),
+}
+
+impl fmt::Display for SimplePort {
+    /// Ports are written `in<offset>` or `out<offset>`, e.g. `in0`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::In(i) => write!(f, "in{i}"),
+            Self::Out(i) => write!(f, "out{i}"),
+        }
+    }
+}
+
+/// Error when parsing a [`SimplePort`].
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[error("expected a port of the form `in<offset>` or `out<offset>`")]
+pub struct ParseSimplePortError;
+
+impl FromStr for SimplePort {
+    type Err = ParseSimplePortError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, offset): (fn(usize) -> Self, _) = if let Some(offset) = s.strip_prefix("in") {
+            (Self::In, offset)
+        } else if let Some(offset) = s.strip_prefix("out") {
+            (Self::Out, offset)
+        } else {
+            return Err(ParseSimplePortError);
+        };
+        offset.parse().map(port).map_err(|_| ParseSimplePortError)
+    }
+}
+
+/// A directed graph with ordered ports, stored as adjacency lists.
+///
+/// Ports are created as needed when linking nodes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimpleGraph {
+    /// The number of input and output ports of every node.
+    nodes: Vec<(usize, usize)>,
+    /// The left and right sites of every edge.
+    edges: Vec<[Site<SimpleNode, SimplePort>; 2]>,
+    /// The edges incident to every node.
+    adjacency: Vec<Vec<SimpleEdge>>,
+}
+
+impl SimpleGraph {
+    /// Create an empty graph.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a node with `n_inputs` input and `n_outputs` output ports.
+    pub fn add_node(&mut self, n_inputs: usize, n_outputs: usize) -> SimpleNode {
+        self.nodes.push((n_inputs, n_outputs));
+        self.adjacency.push(Vec::new());
+        SimpleNode(self.nodes.len() - 1)
+    }
+
+    /// Link the output port `out` of `left` to the input port `inp` of
+    /// `right`.
+    pub fn link_nodes(
+        &mut self,
+        left: SimpleNode,
+        out: usize,
+        right: SimpleNode,
+        inp: usize,
+    ) -> SimpleEdge {
+        let (n_inputs, _) = &mut self.nodes[right.0];
+        *n_inputs = (*n_inputs).max(inp + 1);
+        let (_, n_outputs) = &mut self.nodes[left.0];
+        *n_outputs = (*n_outputs).max(out + 1);
+
+        let edge = SimpleEdge(self.edges.len());
+        self.edges.push([
+            Site {
+                node: left,
+                port: SimplePort::Out(out),
+            },
+            Site {
+                node: right,
+                port: SimplePort::In(inp),
+            },
+        ]);
+        self.adjacency[left.0].push(edge);
+        if left != right {
+            self.adjacency[right.0].push(edge);
+        }
+        edge
+    }
+
+    /// The number of nodes.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The number of edges.
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// The number of input ports of `node`.
+    pub fn num_inputs(&self, node: SimpleNode) -> usize {
+        self.nodes[node.0].0
+    }
+
+    /// The number of output ports of `node`.
+    pub fn num_outputs(&self, node: SimpleNode) -> usize {
+        self.nodes[node.0].1
+    }
+
+    /// A chain of `n` nodes, each linked to the next.
+    ///
+    /// Every node has one input and one output port.
+    pub fn linear_chain(n: usize) -> Self {
+        let mut graph = Self::new();
+        let nodes: Vec<_> = (0..n).map(|_| graph.add_node(1, 1)).collect();
+        for pair in nodes.windows(2) {
+            graph.link_nodes(pair[0], 0, pair[1], 0);
+        }
+        graph
+    }
+
+    /// A grid of `rows` x `cols` nodes.
+    ///
+    /// Every node has two input and two output ports. Output 0 is linked to
+    /// input 0 of the node to the right, output 1 to input 1 of the node
+    /// below. Nodes are numbered row by row.
+    pub fn grid(rows: usize, cols: usize) -> Self {
+        let mut graph = Self::new();
+        let nodes: Vec<_> = (0..rows * cols).map(|_| graph.add_node(2, 2)).collect();
+        for r in 0..rows {
+            for c in 0..cols {
+                let node = nodes[r * cols + c];
+                if c + 1 < cols {
+                    graph.link_nodes(node, 0, nodes[r * cols + c + 1], 0);
+                }
+                if r + 1 < rows {
+                    graph.link_nodes(node, 1, nodes[(r + 1) * cols + c], 1);
+                }
+            }
+        }
+        graph
+    }
+
+    /// A random graph with `n_nodes` nodes and `n_edges` edges.
+    ///
+    /// The ends of every edge are chosen uniformly at random, using a
+    /// pseudo-random generator initialised with `seed`. Every edge is linked
+    /// to a fresh port, so that no two edges share a port. The same seed
+    /// always gives the same graph.
+    pub fn random(n_nodes: usize, n_edges: usize, seed: u64) -> Self {
+        let mut graph = Self::new();
+        let nodes: Vec<_> = (0..n_nodes).map(|_| graph.add_node(0, 0)).collect();
+        if nodes.is_empty() {
+            return graph;
+        }
+        let mut rng = SplitMix64(seed);
+        for _ in 0..n_edges {
+            let left = nodes[rng.next_below(n_nodes)];
+            let right = nodes[rng.next_below(n_nodes)];
+            let out = graph.num_outputs(left);
+            let inp = graph.num_inputs(right);
+            graph.link_nodes(left, out, right, inp);
+        }
+        graph
+    }
+}
+
+impl Graph for SimpleGraph {
+    type Node = SimpleNode;
+
+    type Edge = SimpleEdge;
+
+    type PortLabel = SimplePort;
+
+    fn nodes_iter(&self) -> impl Iterator<Item = Self::Node> + '_ {
+        (0..self.nodes.len()).map(SimpleNode)
+    }
+
+    fn edges_iter(&self) -> impl Iterator<Item = Self::Edge> + '_ {
+        (0..self.edges.len()).map(SimpleEdge)
+    }
+
+    fn get_port_site(
+        &self,
+        BoundPort { edge, end }: BoundPort<Self::Edge>,
+    ) -> Site<Self::Node, Self::PortLabel> {
+        self.edges[edge.0][end.index()]
+    }
+
+    fn get_bound_ports(
+        &self,
+        site: Site<Self::Node, Self::PortLabel>,
+    ) -> impl Iterator<Item = BoundPort<Self::Edge>> + '_ {
+        let end = match site.port {
+            SimplePort::Out(_) => EdgeEnd::Left,
+            SimplePort::In(_) => EdgeEnd::Right,
+        };
+        self.adjacency[site.node.0]
+            .iter()
+            .copied()
+            .filter(move |&edge| self.edges[edge.0][end.index()] == site)
+            .map(move |edge| BoundPort { edge, end })
+    }
+
+    fn get_sites(
+        &self,
+        node: Self::Node,
+    ) -> impl Iterator<Item = Site<Self::Node, Self::PortLabel>> + '_ {
+        let (n_inputs, n_outputs) = self.nodes[node.0];
+        let inputs = (0..n_inputs).map(SimplePort::In);
+        let outputs = (0..n_outputs).map(SimplePort::Out);
+        inputs.chain(outputs).map(move |port| Site { node, port })
+    }
+
+    fn link_sites(
+        &mut self,
+        left: Site<Self::Node, Self::PortLabel>,
+        right: Site<Self::Node, Self::PortLabel>,
+    ) {
+        let SimplePort::Out(out) = left.port else {
+            panic!("left site must be an output port");
+        };
+        let SimplePort::In(inp) = right.port else {
+            panic!("right site must be an input port");
+        };
+        self.link_nodes(left.node, out, right.node, inp);
+    }
+
+    fn is_valid_end(&self, site: Site<Self::Node, Self::PortLabel>, end: EdgeEnd) -> bool {
+        matches!(
+            (site.port, end),
+            (SimplePort::Out(_), EdgeEnd::Left) | (SimplePort::In(_), EdgeEnd::Right)
+        )
+    }
+
+    fn add_subgraph(
+        &mut self,
+        graph: &Self,
+        nodes: &BTreeSet<Self::Node>,
+    ) -> BTreeMap<Self::Node, Self::Node> {
+        let nodes_map: BTreeMap<_, _> = nodes
+            .iter()
+            .map(|&n| (n, self.add_node(graph.num_inputs(n), graph.num_outputs(n))))
+            .collect();
+        for [left, right] in &graph.edges {
+            let (Some(&new_left), Some(&new_right)) =
+                (nodes_map.get(&left.node), nodes_map.get(&right.node))
+            else {
+                continue;
+            };
+            self.link_sites(left.map_node(|_| new_left), right.map_node(|_| new_right));
+        }
+        nodes_map
+    }
+}
+
+impl AddNodes for SimpleGraph {
+    /// Ports are indexed contiguously, so that the node has as many input
+    /// (resp. output) ports as needed for the largest requested index.
+    fn add_node_with_sites(
+        &mut self,
+        sites: impl IntoIterator<Item = Self::PortLabel>,
+    ) -> Self::Node {
+        let (mut n_inputs, mut n_outputs) = (0, 0);
+        for port in sites {
+            match port {
+                SimplePort::In(i) => n_inputs = n_inputs.max(i + 1),
+                SimplePort::Out(i) => n_outputs = n_outputs.max(i + 1),
+            }
+        }
+        self.add_node(n_inputs, n_outputs)
+    }
+}
+
+impl ContentKey for SimpleGraph {
+    type NodeKey = ();
+    type EdgeKey = ();
+
+    fn node_key(&self, _node: Self::Node) {}
+
+    fn edge_key(&self, _edge: Self::Edge) {}
+}
+
+impl DotGraph for SimpleGraph {
+    fn dot_node_label(&self, node: Self::Node) -> String {
+        node.0.to_string()
+    }
+}
+
+/// The wire end that replaces the port `port` of a deleted node.
+///
+/// The input port at offset `i` is mapped to the right end of wire `i`, and
+/// the output port at offset `i` to the left end of wire `i`, so that the
+/// i-th input of the deleted node is connected to its i-th output.
+pub fn passthrough_wire(port: SimplePort) -> BoundarySite<SimpleGraph> {
+    match port {
+        SimplePort::In(i) => BoundarySite::wire(i, EdgeEnd::Right),
+        SimplePort::Out(i) => BoundarySite::wire(i, EdgeEnd::Left),
+    }
+}
+
+impl PortDiff<SimpleGraph> {
+    /// Replace `node` with identity wires.
+    ///
+    /// The new diff deletes `node` and connects its i-th input to its i-th
+    /// output, see [`passthrough_wire`]. Wire IDs are renumbered so that they
+    /// are dense, as ports that are not linked are not at the boundary.
+    pub fn rewrite_node_to_wires(
+        &self,
+        node: SimpleNode,
+    ) -> Result<Self, InvalidRewriteError<SimpleGraph>> {
+        let mut wires = WireAllocator::new();
+        let mut wire_ids = BTreeMap::new();
+        self.rewrite_induced(&BTreeSet::from([node]), SimpleGraph::new(), |port| {
+            let site = Owned::new(port, self.clone())
+                .site()
+                .expect("boundary port of a node is a site");
+            match passthrough_wire(site.port) {
+                BoundarySite::Wire { id, end } => {
+                    let id = *wire_ids.entry(id).or_insert_with(|| wires.fresh());
+                    BoundarySite::wire(id, end)
+                }
+                site => site,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graph_helpers() {
+        let mut graph = SimpleGraph::new();
+        let [a, b, c] = [(); 3].map(|()| graph.add_node(1, 1));
+        let edge = graph.link_nodes(a, 0, b, 0);
+        graph.link_nodes(b, 0, c, 0);
+        graph.link_nodes(c, 0, c, 0);
+
+        assert_eq!(graph.incident_nodes(edge), [a, b]);
+        assert_eq!(graph.degree(b), 2);
+        assert_eq!(graph.degree(c), 3);
+        assert_eq!(graph.neighbours(b).collect::<Vec<_>>(), vec![a, c]);
+        assert_eq!(graph.neighbours(c).collect::<Vec<_>>(), vec![b, c]);
+        assert!(graph.has_edge_between(b, a));
+        assert!(!graph.has_edge_between(a, c));
+    }
+
+    #[test]
+    fn test_builders() {
+        let chain = SimpleGraph::linear_chain(4);
+        assert_eq!(chain.node_count(), 4);
+        assert_eq!(chain.edge_count(), 3);
+
+        let grid = SimpleGraph::grid(2, 3);
+        assert_eq!(grid.node_count(), 6);
+        assert_eq!(grid.edge_count(), 7);
+
+        let random = SimpleGraph::random(5, 10, 42);
+        assert_eq!(random.node_count(), 5);
+        assert_eq!(random.edge_count(), 10);
+        assert_eq!(random, SimpleGraph::random(5, 10, 42));
+        for edge in random.edges_iter() {
+            for end in [EdgeEnd::Left, EdgeEnd::Right] {
+                let site = random.get_port_site(BoundPort { edge, end });
+                let ports: Vec<_> = random.get_bound_ports(site).collect();
+                assert_eq!(ports, vec![BoundPort { edge, end }]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rewrite_simple_graph() {
+        // Replace the middle node of a -> b -> c
+        let graph = SimpleGraph::linear_chain(3);
+        let b = SimpleNode(1);
+        let root = PortDiff::from_graph(graph);
+
+        let mut rhs = SimpleGraph::new();
+        let new_b = rhs.add_node(1, 1);
+        let child = root
+            .rewrite_induced(&BTreeSet::from([b]), rhs, |p| {
+                let site = Owned::new(p, root.clone()).site().unwrap();
+                Site {
+                    node: new_b,
+                    port: site.port,
+                }
+                .into()
+            })
+            .unwrap();
+        assert_eq!(child.n_boundary_ports(), 2);
+
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(extracted.node_count(), 3);
+        assert_eq!(extracted.edge_count(), 2);
+    }
+
+    #[test]
+    fn test_replace_node_with_sites() {
+        let mut graph = SimpleGraph::new();
+        let node = graph.add_node_with_sites([SimplePort::In(1), SimplePort::Out(0)]);
+        assert_eq!((graph.num_inputs(node), graph.num_outputs(node)), (2, 1));
+
+        // Replace the middle node of a -> b -> c by a node with more ports
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let sites = [SimplePort::In(0), SimplePort::Out(0), SimplePort::Out(1)];
+        let child = root.replace_node_with_sites(SimpleNode(1), sites).unwrap();
+        assert_eq!(child.graph().node_count(), 1);
+        assert_eq!(child.graph().num_outputs(SimpleNode(0)), 2);
+
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(extracted.node_count(), 3);
+        assert_eq!(extracted.edge_count(), 2);
+    }
+
+    #[test]
+    fn test_parallel_edges() {
+        // a => b, with two edges between the same sites
+        let mut graph = SimpleGraph::new();
+        let a = graph.add_node(0, 1);
+        let b = graph.add_node(1, 0);
+        graph.link_nodes(a, 0, b, 0);
+        graph.link_nodes(a, 0, b, 0);
+        let out_a = Site {
+            node: a,
+            port: SimplePort::Out(0),
+        };
+        let in_b = Site {
+            node: b,
+            port: SimplePort::In(0),
+        };
+        assert_eq!(graph.parallel_edges(out_a, in_b).count(), 2);
+        assert_eq!(graph.parallel_edges(in_b, out_a).count(), 0);
+
+        // Rewrite a and b in distinct diffs, into copies of themselves
+        let root = PortDiff::from_graph(graph);
+        let copy = |node: SimpleNode| {
+            let mut rhs = SimpleGraph::new();
+            let new_node = rhs.add_node(
+                root.graph().num_inputs(node),
+                root.graph().num_outputs(node),
+            );
+            root.rewrite_induced(&BTreeSet::from([node]), rhs, |p| {
+                let site = Owned::new(p, root.clone()).site().unwrap();
+                site.map_node(|_| new_node).into()
+            })
+            .unwrap()
+        };
+        let (child_a, child_b) = (copy(a), copy(b));
+        assert_eq!(child_a.n_boundary_ports(), 2);
+
+        // Squashing preserves the multiplicity
+        for diffs in [vec![child_a.clone()], vec![child_a, child_b]] {
+            let extracted = PortDiff::extract_graph(diffs).unwrap();
+            assert_eq!(extracted.edge_count(), 2);
+            let edge = extracted.edges_iter().next().unwrap();
+            let [left, right] = [EdgeEnd::Left, EdgeEnd::Right]
+                .map(|end| extracted.get_port_site(BoundPort { edge, end }));
+            assert_eq!(extracted.parallel_edges(left, right).count(), 2);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_node_to_wires() {
+        // a -> b -> c  =>  a -> c
+        let root = PortDiff::from_graph(SimpleGraph::linear_chain(3));
+        let child = root.rewrite_node_to_wires(SimpleNode(1)).unwrap();
+        assert_eq!(child.graph().node_count(), 0);
+
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(extracted.node_count(), 2);
+        assert_eq!(extracted.edge_count(), 1);
+    }
+}
@@ -0,0 +1,295 @@
+//! Implementation of [`Graph`] for petgraph's [`StableGraph`].
+//!
+//! Edges go from their left end to their right end. A node has two sites:
+//! all outgoing edges of the node share the [`EdgeEnd::Left`] site and all
+//! incoming edges the [`EdgeEnd::Right`] site.
+//!
+//! Edges relinked when squashing diffs keep their weight. Other edges
+//! created by [`Graph::link_sites`] are given the default edge weight.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use petgraph::{
+    stable_graph::{EdgeIndex, IndexType, NodeIndex, StableGraph},
+    visit::EdgeRef,
+    Directed, Direction,
+};
+
+use crate::{
+    port::{BoundPort, EdgeEnd, Site},
+    AddNodes, ContentKey, DotGraph, Graph, NodeWeights, RemoveNodes,
+};
+
+impl<N: Clone, E: Clone + Default, Ix: IndexType> Graph for StableGraph<N, E, Directed, Ix> {
+    type Node = NodeIndex<Ix>;
+
+    type Edge = EdgeIndex<Ix>;
+
+    type PortLabel = EdgeEnd;
+
+    fn nodes_iter(&self) -> impl Iterator<Item = Self::Node> + '_ {
+        self.node_indices()
+    }
+
+    fn edges_iter(&self) -> impl Iterator<Item = Self::Edge> + '_ {
+        self.edge_indices()
+    }
+
+    fn get_port_site(
+        &self,
+        BoundPort { edge, end }: BoundPort<Self::Edge>,
+    ) -> Site<Self::Node, Self::PortLabel> {
+        let (source, target) = self.edge_endpoints(edge).expect("invalid edge");
+        let node = match end {
+            EdgeEnd::Left => source,
+            EdgeEnd::Right => target,
+        };
+        Site { node, port: end }
+    }
+
+    fn get_bound_ports(
+        &self,
+        site: Site<Self::Node, Self::PortLabel>,
+    ) -> impl Iterator<Item = BoundPort<Self::Edge>> + '_ {
+        let dir = match site.port {
+            EdgeEnd::Left => Direction::Outgoing,
+            EdgeEnd::Right => Direction::Incoming,
+        };
+        self.edges_directed(site.node, dir).map(move |e| BoundPort {
+            edge: e.id(),
+            end: site.port,
+        })
+    }
+
+    fn get_sites(
+        &self,
+        node: Self::Node,
+    ) -> impl Iterator<Item = Site<Self::Node, Self::PortLabel>> + '_ {
+        [EdgeEnd::Left, EdgeEnd::Right]
+            .into_iter()
+            .map(move |port| Site { node, port })
+    }
+
+    fn link_sites(
+        &mut self,
+        left: Site<Self::Node, Self::PortLabel>,
+        right: Site<Self::Node, Self::PortLabel>,
+    ) {
+        add_site_edge(self, left, right, E::default());
+    }
+
+    fn link_sites_like(
+        &mut self,
+        left: Site<Self::Node, Self::PortLabel>,
+        right: Site<Self::Node, Self::PortLabel>,
+        graph: &Self,
+        edge: Self::Edge,
+    ) {
+        add_site_edge(self, left, right, graph[edge].clone());
+    }
+
+    fn is_valid_end(&self, site: Site<Self::Node, Self::PortLabel>, end: EdgeEnd) -> bool {
+        site.port == end
+    }
+
+    fn add_subgraph(
+        &mut self,
+        graph: &Self,
+        nodes: &BTreeSet<Self::Node>,
+    ) -> BTreeMap<Self::Node, Self::Node> {
+        let nodes_map: BTreeMap<_, _> = nodes
+            .iter()
+            .map(|&n| (n, self.add_node(graph[n].clone())))
+            .collect();
+        for edge in graph.edge_references() {
+            let source = nodes_map.get(&edge.source());
+            let target = nodes_map.get(&edge.target());
+            if let (Some(&source), Some(&target)) = (source, target) {
+                self.add_edge(source, target, edge.weight().clone());
+            }
+        }
+        nodes_map
+    }
+}
+
+/// Add an edge with weight `weight` from the `left` site to the `right` site.
+fn add_site_edge<N, E, Ix: IndexType>(
+    graph: &mut StableGraph<N, E, Directed, Ix>,
+    left: Site<NodeIndex<Ix>, EdgeEnd>,
+    right: Site<NodeIndex<Ix>, EdgeEnd>,
+    weight: E,
+) {
+    assert_eq!(
+        left.port,
+        EdgeEnd::Left,
+        "left site must be an outgoing site"
+    );
+    assert_eq!(
+        right.port,
+        EdgeEnd::Right,
+        "right site must be an incoming site"
+    );
+    graph.add_edge(left.node, right.node, weight);
+}
+
+impl<N: Clone, E: Clone + Default, Ix: IndexType> NodeWeights for StableGraph<N, E, Directed, Ix> {
+    type NodeWeight = N;
+
+    fn set_node_weight(&mut self, node: Self::Node, weight: N) {
+        self[node] = weight;
+    }
+}
+
+impl<N: Clone + Ord, E: Clone + Ord + Default, Ix: IndexType> ContentKey
+    for StableGraph<N, E, Directed, Ix>
+{
+    type NodeKey = N;
+    type EdgeKey = E;
+
+    fn node_key(&self, node: Self::Node) -> N {
+        self[node].clone()
+    }
+
+    fn edge_key(&self, edge: Self::Edge) -> E {
+        self[edge].clone()
+    }
+}
+
+impl<N: Clone + Default, E: Clone + Default, Ix: IndexType> AddNodes
+    for StableGraph<N, E, Directed, Ix>
+{
+    /// Every node has both sites, so `sites` is ignored. The node is given
+    /// the default weight.
+    fn add_node_with_sites(
+        &mut self,
+        _sites: impl IntoIterator<Item = Self::PortLabel>,
+    ) -> Self::Node {
+        self.add_node(N::default())
+    }
+}
+
+impl<N: Clone, E: Clone + Default, Ix: IndexType> RemoveNodes for StableGraph<N, E, Directed, Ix> {
+    fn remove_node(&mut self, node: Self::Node) {
+        StableGraph::remove_node(self, node);
+    }
+}
+
+impl<N: Clone + Display, E: Clone + Default, Ix: IndexType> DotGraph
+    for StableGraph<N, E, Directed, Ix>
+{
+    fn dot_node_label(&self, node: Self::Node) -> String {
+        self[node].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use petgraph::stable_graph::StableGraph;
+
+    use crate::{port_diff::Owned, BoundarySite, PortDiff, Site};
+
+    #[test]
+    fn test_rewrite_stable_graph() {
+        // a -> b -> c
+        let mut graph = StableGraph::<&str, ()>::new();
+        let a = graph.add_node("a");
+        let b = graph.add_node("b");
+        let c = graph.add_node("c");
+        graph.add_edge(a, b, ());
+        graph.add_edge(b, c, ());
+        let root = PortDiff::from_graph(graph);
+
+        // Replace b with new_b
+        let mut rhs = StableGraph::new();
+        let new_b = rhs.add_node("new_b");
+        let child = root
+            .rewrite_induced(&BTreeSet::from([b]), rhs, |p| {
+                let site = Owned::new(p, root.clone()).site().unwrap();
+                Site {
+                    node: new_b,
+                    port: site.port,
+                }
+                .into()
+            })
+            .unwrap();
+        assert_eq!(child.n_boundary_ports(), 2);
+
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(extracted.node_count(), 3);
+        assert_eq!(extracted.edge_count(), 2);
+        assert!(extracted.node_indices().any(|n| extracted[n] == "new_b"));
+        assert!(!extracted.node_indices().any(|n| extracted[n] == "b"));
+    }
+
+    #[test]
+    fn test_squash_edge_weights() {
+        // a -> b -> c
+        let mut graph = StableGraph::<&str, u32>::new();
+        let a = graph.add_node("a");
+        let b = graph.add_node("b");
+        let c = graph.add_node("c");
+        graph.add_edge(a, b, 1);
+        graph.add_edge(b, c, 2);
+        let root = PortDiff::from_graph(graph);
+        let nodes = BTreeSet::from([b]);
+
+        // Replace b with new_b: the boundary edges keep their weights
+        let mut rhs = StableGraph::new();
+        let new_b = rhs.add_node("new_b");
+        let child = root
+            .rewrite_induced(&nodes, rhs, |p| {
+                let site = Owned::new(p, root.clone()).site().unwrap();
+                Site {
+                    node: new_b,
+                    port: site.port,
+                }
+                .into()
+            })
+            .unwrap();
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        let mut weights = extracted.edge_weights().copied().collect::<Vec<_>>();
+        weights.sort();
+        assert_eq!(weights, vec![1, 2]);
+
+        // Replace b with a wire: a -> c has the weight of a -> b
+        let child = root
+            .rewrite_induced(&nodes, StableGraph::new(), |p| {
+                let site = Owned::new(p, root.clone()).site().unwrap();
+                BoundarySite::wire(0, site.port)
+            })
+            .unwrap();
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        assert_eq!(
+            extracted.edge_weights().copied().collect::<Vec<_>>(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_relabel_nodes() {
+        // a -> b -> c
+        let mut graph = StableGraph::<&str, ()>::new();
+        let a = graph.add_node("a");
+        let b = graph.add_node("b");
+        let c = graph.add_node("c");
+        graph.add_edge(a, b, ());
+        graph.add_edge(b, c, ());
+        let root = PortDiff::from_graph(graph);
+
+        let child = root
+            .relabel_nodes(BTreeMap::from([(a, "x"), (b, "y")]))
+            .unwrap();
+        assert_eq!(child.graph().node_count(), 2);
+        assert_eq!(child.graph().edge_count(), 1);
+        assert_eq!(child.n_boundary_ports(), 1);
+
+        let extracted = PortDiff::extract_graph(vec![child]).unwrap();
+        let mut weights = extracted.node_weights().copied().collect::<Vec<_>>();
+        weights.sort();
+        assert_eq!(weights, vec!["c", "x", "y"]);
+        assert_eq!(extracted.edge_count(), 2);
+    }
+}
@@ -0,0 +1,267 @@
+//! Persistent on-disk storage of diff hierarchies.
+//!
+//! A store file is made of a fixed-size header followed by the payload:
+//!  - the magic bytes `PDST`,
+//!  - the store format version, as a little-endian `u32`,
+//!  - the length of the payload in bytes, as a little-endian `u64`,
+//!  - the FNV-1a hash of the payload, as a little-endian `u64`,
+//!  - the payload, the bincode encoding of a [`StablePortDiffGraph`].
+
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::{
+    fnv::FnvHasher,
+    graph_view::{StableFormatError, StablePortDiffGraph},
+    ContentKey, Graph, PortDiffGraph,
+};
+
+/// The current version of the store format.
+pub const STORE_FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"PDST";
+const HEADER_LEN: usize = 4 + 4 + 8 + 8;
+
+/// Errors when saving or loading a diff store.
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not a diff store")]
+    InvalidMagic,
+    #[error("Unsupported store version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Truncated diff store")]
+    Truncated,
+    #[error("Checksum mismatch, the diff store is corrupted")]
+    ChecksumMismatch,
+    #[error("Encoding error: {0}")]
+    Encoding(#[from] bincode::Error),
+    #[error("Invalid diff hierarchy: {0}")]
+    Format(#[from] StableFormatError),
+}
+
+/// Save and load `PortDiffGraph`s to and from disk.
+pub struct DiffStore;
+
+impl DiffStore {
+    /// Save `graph` to the file at `path`.
+    ///
+    /// The file is first written to a temporary file next to `path` and then
+    /// renamed, so that an existing store is never left half-written.
+    pub fn save<G: ContentKey, M: Clone + Ord>(
+        path: impl AsRef<Path>,
+        graph: &PortDiffGraph<G, M>,
+    ) -> Result<(), StoreError>
+    where
+        StablePortDiffGraph<G, M>: Serialize,
+    {
+        let path = path.as_ref();
+        let bytes = Self::encode(graph)?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Load a graph from the file at `path`.
+    pub fn load<G: Graph, M: Clone>(
+        path: impl AsRef<Path>,
+    ) -> Result<PortDiffGraph<G, M>, StoreError>
+    where
+        StablePortDiffGraph<G, M>: DeserializeOwned,
+    {
+        let bytes = fs::read(path)?;
+        Self::decode(&bytes)
+    }
+
+    /// Encode `graph` in the store format.
+    pub fn encode<G: ContentKey, M: Clone + Ord>(
+        graph: &PortDiffGraph<G, M>,
+    ) -> Result<Vec<u8>, StoreError>
+    where
+        StablePortDiffGraph<G, M>: Serialize,
+    {
+        let payload = bincode::serialize(&graph.to_stable())?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&STORE_FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&fnv1a(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+
+    /// Decode a graph in the store format.
+    pub fn decode<G: Graph, M: Clone>(bytes: &[u8]) -> Result<PortDiffGraph<G, M>, StoreError>
+    where
+        StablePortDiffGraph<G, M>: DeserializeOwned,
+    {
+        if bytes.len() < HEADER_LEN {
+            return Err(StoreError::Truncated);
+        }
+        let (header, payload) = bytes.split_at(HEADER_LEN);
+        if &header[0..4] != MAGIC {
+            return Err(StoreError::InvalidMagic);
+        }
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if version != STORE_FORMAT_VERSION {
+            return Err(StoreError::UnsupportedVersion(version));
+        }
+        let len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        if payload.len() as u64 != len {
+            return Err(StoreError::Truncated);
+        }
+        let checksum = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        if fnv1a(payload) != checksum {
+            return Err(StoreError::ChecksumMismatch);
+        }
+        let stable: StablePortDiffGraph<G, M> = bincode::deserialize(payload)?;
+        Ok(PortDiffGraph::from_stable(stable)?)
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+///
+/// Unlike the standard library hashers, it is stable across platforms and
+/// compiler versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(feature = "portgraph")]
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use portgraph::{NodeIndex, PortGraph, PortMut};
+    use rstest::rstest;
+
+    use crate::{
+        port::BoundaryIndex,
+        port_diff::{
+            tests::{parent_child_diffs, parent_two_children_diffs, TestPortDiff},
+            Owned,
+        },
+        PortDiff, Site,
+    };
+
+    use super::*;
+
+    #[rstest]
+    fn test_store_roundtrip(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let mut graph = PortDiffGraph::from_sinks([child_1.clone(), child_2]);
+        graph.create_branch("main", &child_1).unwrap();
+
+        let path = std::env::temp_dir().join(format!("portdiff-{}.pdst", uuid::Uuid::new_v4()));
+        DiffStore::save(&path, &graph).unwrap();
+        let loaded: PortDiffGraph<PortGraph> = DiffStore::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.all_nodes().count(), 3);
+        assert_eq!(loaded.sinks().count(), 2);
+        let [tip] = loaded.branch("main").unwrap().try_into().unwrap();
+        assert_eq!(tip.graph(), child_1.graph());
+    }
+
+    #[rstest]
+    fn test_store_roundtrip_labels(parent_child_diffs: [TestPortDiff; 2]) {
+        let [root, child] = parent_child_diffs;
+        let mut rhs = PortGraph::new();
+        let new_node = rhs.add_node(3, 1);
+        let nodes = [Owned::new(NodeIndex::new(1), root.clone())];
+        let labelled = PortDiff::rewrite_labelled(nodes, [], rhs, |p| {
+            let port = p.site().unwrap().port;
+            let site = Site {
+                node: new_node,
+                port,
+            };
+            (site.into(), Some(format!("{port:?}")))
+        })
+        .unwrap();
+        // Unlabelled and labelled diffs in the same store
+        let graph = PortDiffGraph::from_sinks([child.clone(), labelled.clone()]);
+
+        let bytes = DiffStore::encode(&graph).unwrap();
+        let loaded: PortDiffGraph<PortGraph> = DiffStore::decode(&bytes).unwrap();
+        assert_eq!(loaded.all_nodes().count(), 3);
+        let first = BoundaryIndex::from(0);
+        let (loaded_labelled, loaded_child) = loaded
+            .sinks()
+            .partition::<Vec<_>, _>(|d| d.boundary_label(first).is_some());
+        let [loaded_labelled] = loaded_labelled.try_into().unwrap();
+        let [loaded_child] = loaded_child.try_into().unwrap();
+        for b in labelled.boundary_iter() {
+            assert_eq!(
+                loaded_labelled.boundary_label(b),
+                labelled.boundary_label(b)
+            );
+        }
+        assert!(child
+            .boundary_iter()
+            .all(|b| loaded_child.boundary_label(b).is_none()));
+        assert_eq!(loaded_child.graph(), child.graph());
+    }
+
+    #[rstest]
+    fn test_store_roundtrip_keys(parent_child_diffs: [TestPortDiff; 2]) {
+        let [root, child] = parent_child_diffs;
+        let mut rhs = PortGraph::new();
+        let new_node = rhs.add_node(3, 1);
+        let nodes = [Owned::new(NodeIndex::new(1), root.clone())];
+        let keyed = PortDiff::rewrite_with_key(
+            nodes,
+            [],
+            rhs,
+            |p| {
+                let port = p.site().unwrap().port;
+                Site {
+                    node: new_node,
+                    port,
+                }
+                .into()
+            },
+            "keyed",
+        )
+        .unwrap();
+        // Keyed and unkeyed diffs in the same store
+        let graph = PortDiffGraph::from_sinks([child, keyed]);
+
+        let bytes = DiffStore::encode(&graph).unwrap();
+        let loaded: PortDiffGraph<PortGraph> = DiffStore::decode(&bytes).unwrap();
+        let keys = loaded.sinks().map(|d| d.key().map(String::from));
+        assert_eq!(
+            keys.sorted().collect_vec(),
+            vec![None, Some("keyed".to_string())]
+        );
+    }
+
+    #[rstest]
+    fn test_store_corrupted(parent_two_children_diffs: [TestPortDiff; 3]) {
+        let [_, child_1, child_2] = parent_two_children_diffs;
+        let graph = PortDiffGraph::from_sinks([child_1, child_2]);
+        let bytes = DiffStore::encode(&graph).unwrap();
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            DiffStore::decode::<PortGraph, ()>(&corrupted),
+            Err(StoreError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            DiffStore::decode::<PortGraph, ()>(&bytes[..bytes.len() - 1]),
+            Err(StoreError::Truncated)
+        ));
+        assert!(matches!(
+            DiffStore::decode::<PortGraph, ()>(b"not a store at all, really"),
+            Err(StoreError::InvalidMagic)
+        ));
+    }
+}
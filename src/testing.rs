@@ -0,0 +1,237 @@
+//! Utilities for property-based testing with [`proptest`].
+//!
+//! Provides strategies that generate random graphs, random induced rewrites
+//! and random selections of diffs, along with checks of the invariants that
+//! extraction must satisfy. They can be used to test [`Graph`]
+//! implementations against the squash and extract logic: generate a diff
+//! hierarchy from a graph of the implementation with [`arb_diffs`], select
+//! diffs with [`arb_selection`] and check them with
+//! [`check_extract_invariants`].
+//!
+//! Requires the `testing` feature.
+
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+
+use itertools::Itertools;
+use proptest::{
+    collection::vec,
+    prelude::*,
+    sample::{subsequence, Index},
+    test_runner::TestCaseError,
+};
+
+use crate::{
+    graph_view::MergedView, port_diff::Owned, simple_graph::SimpleGraph, BoundPort, EdgeEnd, Graph,
+    PortDiff,
+};
+
+/// A random [`SimpleGraph`] with at most `max_nodes` nodes and `max_edges`
+/// edges.
+pub fn arb_simple_graph(max_nodes: usize, max_edges: usize) -> impl Strategy<Value = SimpleGraph> {
+    (0..=max_nodes, 0..=max_edges, any::<u64>())
+        .prop_map(|(n_nodes, n_edges, seed)| SimpleGraph::random(n_nodes, n_edges, seed))
+}
+
+/// A random non-empty set of nodes of `graph`.
+///
+/// Generates the empty set if `graph` has no nodes.
+pub fn arb_node_subset<G: Graph + 'static>(graph: &G) -> impl Strategy<Value = BTreeSet<G::Node>>
+where
+    G::Node: Debug,
+{
+    let nodes: Vec<_> = graph.nodes_iter().collect();
+    let size = 1.min(nodes.len())..=nodes.len();
+    subsequence(nodes, size).prop_map(BTreeSet::from_iter)
+}
+
+/// Rewrite `nodes` of `diff` into a copy of the subgraph they induce.
+///
+/// The copy is created with [`Graph::add_subgraph`], so that the rewrite is
+/// valid for any graph implementation. The extracted graph is thus unchanged
+/// up to node and edge identifiers.
+pub fn copy_rewrite<G: Graph, M: Clone + Default>(
+    diff: &PortDiff<G, M>,
+    nodes: &BTreeSet<G::Node>,
+) -> PortDiff<G, M> {
+    let mut new_graph = G::default();
+    let node_map = new_graph.add_subgraph(diff.graph(), nodes);
+    diff.rewrite_induced(nodes, new_graph, |port| {
+        let site = Owned::new(port, diff.clone())
+            .site()
+            .expect("rewritten boundary ports are on sites");
+        site.map_node(|n| node_map[&n]).into()
+    })
+    .expect("induced rewrites of a single diff are valid")
+}
+
+/// A random diff hierarchy rooted at `graph`, made of up to `max_rewrites`
+/// induced rewrites.
+///
+/// Every rewrite replaces one to three nodes of the root or of a previous
+/// rewrite by a copy of themselves, see [`copy_rewrite`]. Returns all diffs,
+/// starting with the root, in creation order.
+pub fn arb_diffs<G: Graph>(
+    graph: G,
+    max_rewrites: usize,
+) -> impl Strategy<Value = Vec<PortDiff<G>>> {
+    let rewrites = vec(
+        (any::<Index>(), vec(any::<Index>(), 1..=3)),
+        0..=max_rewrites,
+    );
+    rewrites.prop_map(move |rewrites| {
+        let mut diffs = vec![PortDiff::from_graph(graph.clone())];
+        for (parent, nodes) in rewrites {
+            let parent = diffs[parent.index(diffs.len())].clone();
+            let parent_nodes: Vec<_> = parent.graph().nodes_iter().collect();
+            if parent_nodes.is_empty() {
+                continue;
+            }
+            let nodes = nodes
+                .iter()
+                .map(|i| parent_nodes[i.index(parent_nodes.len())])
+                .collect();
+            diffs.push(copy_rewrite(&parent, &nodes));
+        }
+        diffs
+    })
+}
+
+/// A random non-empty selection of `diffs`, compatible or not.
+pub fn arb_selection<G: Graph + 'static, M: Clone + 'static>(
+    diffs: Vec<PortDiff<G, M>>,
+) -> impl Strategy<Value = Vec<PortDiff<G, M>>> {
+    let size = 1.min(diffs.len())..=diffs.len();
+    subsequence(diffs, size)
+}
+
+/// A random non-empty selection of `diffs` that is compatible.
+///
+/// Rejects the selections that are not compatible, so `diffs` should not be
+/// too conflicting.
+pub fn arb_compatible_selection<G: Graph + 'static, M: Clone + 'static>(
+    diffs: Vec<PortDiff<G, M>>,
+) -> impl Strategy<Value = Vec<PortDiff<G, M>>> {
+    arb_selection(diffs).prop_filter("incompatible diffs", |diffs| {
+        PortDiff::are_compatible(diffs)
+    })
+}
+
+/// Check that extracting the graph of `diffs` is consistent.
+///
+/// Compatible diffs must be extractable, and the extracted graph must have
+/// the nodes and edges of their [`MergedView`]. Extracting incompatible
+/// diffs must fail.
+pub fn check_extract_invariants<G: Graph, M: Clone>(
+    diffs: &[PortDiff<G, M>],
+) -> Result<(), TestCaseError> {
+    let extracted = PortDiff::extract_graph(diffs.to_vec());
+    if !PortDiff::are_compatible(diffs) {
+        prop_assert!(extracted.is_err(), "extracted incompatible diffs");
+        return Ok(());
+    }
+    let extracted = extracted.map_err(|e| TestCaseError::fail(e.to_string()))?;
+    let view = MergedView::new(diffs.iter().cloned())
+        .map_err(|_| TestCaseError::fail("compatible diffs cannot be merged"))?;
+    prop_assert_eq!(extracted.nodes_iter().count(), view.nodes_iter().count());
+    prop_assert_eq!(extracted.edges_iter().count(), view.edges_iter().count());
+    Ok(())
+}
+
+/// Assert that the [`Graph`] implementation is consistent on `graph`.
+///
+/// Checks that
+///  - [`Graph::nodes_iter`], [`Graph::edges_iter`] and [`Graph::get_sites`]
+///    have no repetitions,
+///  - both ends of every edge are at a site of a node of the graph,
+///  - every end of an edge is listed once among the bound ports of its
+///    site, and
+///  - every bound port listed at a site is at that site.
+///
+/// Use it to test new graph implementations, e.g. on graphs generated with
+/// proptest.
+///
+/// # Panics
+///
+/// If any of the checks fails.
+pub fn assert_graph_invariants<G: Graph>(graph: &G)
+where
+    G::Node: Debug,
+    G::Edge: Debug,
+    G::PortLabel: Debug,
+{
+    let nodes: BTreeSet<_> = graph.nodes_iter().collect();
+    assert_eq!(nodes.len(), graph.nodes_iter().count(), "repeated nodes");
+    let edges: BTreeSet<_> = graph.edges_iter().collect();
+    assert_eq!(edges.len(), graph.edges_iter().count(), "repeated edges");
+
+    for &edge in &edges {
+        for end in [EdgeEnd::Left, EdgeEnd::Right] {
+            let port = BoundPort { edge, end };
+            let site = graph.get_port_site(port);
+            assert!(
+                nodes.contains(&site.node),
+                "{port:?} is at unknown node {:?}",
+                site.node
+            );
+            assert!(
+                graph.get_sites(site.node).contains(&site),
+                "{port:?} is at {site:?}, which is not a site of its node"
+            );
+            let count = graph
+                .get_bound_ports(site.clone())
+                .filter(|&p| p == port)
+                .count();
+            assert_eq!(count, 1, "{port:?} is not listed once at its site {site:?}");
+        }
+    }
+
+    for &node in &nodes {
+        let sites = graph.get_sites(node).collect_vec();
+        let n_unique = sites.iter().collect::<BTreeSet<_>>().len();
+        assert_eq!(n_unique, sites.len(), "repeated sites at {node:?}");
+        for site in sites {
+            assert_eq!(site.node, node, "{site:?} is listed as a site of {node:?}");
+            for port in graph.get_bound_ports(site.clone()) {
+                assert!(edges.contains(&port.edge), "unknown edge {:?}", port.edge);
+                assert_eq!(
+                    graph.get_port_site(port),
+                    site,
+                    "{port:?} is not at {site:?}"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn test_extract_invariants(
+            (diffs, selection) in arb_simple_graph(8, 12)
+                .prop_flat_map(|graph| arb_diffs(graph, 5))
+                .prop_flat_map(|diffs| (Just(diffs.clone()), arb_selection(diffs)))
+        ) {
+            prop_assert!(!diffs.is_empty());
+            check_extract_invariants(&selection)?;
+        }
+
+        #[test]
+        fn test_simple_graph_invariants(graph in arb_simple_graph(8, 12)) {
+            assert_graph_invariants(&graph);
+        }
+
+        #[test]
+        fn test_copy_rewrite_preserves_graph(
+            diffs in arb_simple_graph(8, 12).prop_flat_map(|graph| arb_diffs(graph, 3))
+        ) {
+            let sink = diffs.last().unwrap().clone();
+            let extracted = PortDiff::extract_graph(vec![sink]).unwrap();
+            prop_assert_eq!(extracted.node_count(), diffs[0].graph().node_count());
+            prop_assert_eq!(extracted.edge_count(), diffs[0].graph().edge_count());
+        }
+    }
+}
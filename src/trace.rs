@@ -0,0 +1,24 @@
+//! Instrumentation with `tracing`, enabled by the `tracing` feature.
+//!
+//! The macros below forward to their `tracing` counterparts when the feature
+//! is enabled and expand to nothing otherwise, in which case their arguments
+//! are not evaluated. Spans are entered until the end of the enclosing scope,
+//! so that subscribers can report the time spent in every phase.
+
+/// Enter a debug span until the end of the enclosing scope.
+macro_rules! debug_span {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = ::tracing::debug_span!($($arg)*).entered();
+    };
+}
+
+/// Record a debug event.
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!($($arg)*);
+    };
+}
+
+pub(crate) use {debug_event, debug_span};